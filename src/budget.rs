@@ -0,0 +1,245 @@
+use std::{
+    fmt, fs,
+    sync::atomic::{AtomicI32, Ordering},
+    thread::sleep,
+    time::Duration,
+};
+
+use termion::{color, style};
+
+use crate::state::CampaignState;
+
+/// The last SIGINT/SIGTERM we got, 0 if none
+static STOP_SIGNAL: AtomicI32 = AtomicI32::new(0);
+
+/// How long children get to exit after SIGTERM, before we SIGKILL them
+const CHILD_GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+/// Limits for a whole campaign, over all resumed runs. `None` means unlimited.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Budget {
+    /// Total fuzzing time
+    pub wall_time: Option<Duration>,
+    /// Total execs over all snapshots
+    pub execs: Option<u64>,
+    /// Generations fuzzed (process_stage calls)
+    pub generations: Option<u64>,
+    /// Snapshots in all generations
+    pub snapshots: Option<u64>,
+}
+
+/// Why a campaign ended
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StopReason {
+    WallTime(Duration),
+    Execs(u64),
+    Generations(u64),
+    Snapshots(u64),
+    Signal(i32),
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StopReason::WallTime(limit) => write!(f, "reached the time budget of {:?}", limit),
+            StopReason::Execs(limit) => write!(f, "reached the budget of {} execs", limit),
+            StopReason::Generations(limit) => {
+                write!(f, "reached the budget of {} generations", limit)
+            }
+            StopReason::Snapshots(limit) => write!(f, "reached the budget of {} snapshots", limit),
+            StopReason::Signal(signal) => write!(f, "got signal {}", signal),
+        }
+    }
+}
+
+/// Total execs over all snapshots
+pub fn total_execs(state: &CampaignState) -> u64 {
+    state.history.values().map(|history| history.execs).sum()
+}
+
+/// Snapshots in all generations
+pub fn total_snapshots(state: &CampaignState) -> u64 {
+    state
+        .generation_snaps
+        .iter()
+        .map(|snaps| snaps.len())
+        .sum::<usize>() as u64
+}
+
+impl Budget {
+    /// The first exhausted limit, `state.elapsed` has to be up to date
+    pub fn exhausted(&self, state: &CampaignState) -> Option<StopReason> {
+        let reached = |limit: Option<u64>, value: u64| limit.filter(|limit| value >= *limit);
+        if let Some(limit) = self.wall_time.filter(|limit| state.elapsed >= *limit) {
+            Some(StopReason::WallTime(limit))
+        } else if let Some(limit) = reached(self.execs, total_execs(state)) {
+            Some(StopReason::Execs(limit))
+        } else if let Some(limit) = reached(self.generations, state.stages) {
+            Some(StopReason::Generations(limit))
+        } else {
+            reached(self.snapshots, total_snapshots(state)).map(StopReason::Snapshots)
+        }
+    }
+
+    /// Like `exhausted`, but a SIGINT/SIGTERM stops us, too
+    pub fn stop_reason(&self, state: &CampaignState) -> Option<StopReason> {
+        match STOP_SIGNAL.load(Ordering::SeqCst) {
+            0 => self.exhausted(state),
+            signal => Some(StopReason::Signal(signal)),
+        }
+    }
+}
+
+/// Whether we got SIGINT/SIGTERM and are shutting down
+pub fn stop_requested() -> bool {
+    STOP_SIGNAL.load(Ordering::SeqCst) != 0
+}
+
+extern "C" fn handle_stop_signal(signal: libc::c_int) {
+    // A second signal means the user doesn't want to wait for the stage to finish
+    if STOP_SIGNAL.swap(signal, Ordering::SeqCst) != 0 {
+        unsafe { libc::_exit(128 + signal) };
+    }
+}
+
+/// On the first SIGINT/SIGTERM, finish the current stage and shut down. On the second, exit right away.
+pub fn install_signal_handlers() {
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        unsafe {
+            libc::signal(
+                signal,
+                handle_stop_signal as extern "C" fn(libc::c_int) as libc::sighandler_t,
+            )
+        };
+    }
+}
+
+/// Parent pid of `pid`, from /proc
+fn parent_pid(pid: i32) -> Option<i32> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    // The command may contain spaces, the ppid is the second field after its closing paren
+    stat.rsplit_once(')')?
+        .1
+        .split_whitespace()
+        .nth(1)?
+        .parse()
+        .ok()
+}
+
+/// All (transitive) children of `pid`, parents first
+pub fn descendants(pid: i32) -> Vec<i32> {
+    let parents: Vec<(i32, i32)> = fs::read_dir("/proc")
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+                .filter_map(|pid| Some((pid, parent_pid(pid)?)))
+                .collect()
+        })
+        .unwrap_or_default();
+    let mut found = vec![pid];
+    let mut i = 0;
+    while i < found.len() {
+        let parent = found[i];
+        found.extend(
+            parents
+                .iter()
+                .filter(|(_, ppid)| *ppid == parent)
+                .map(|(pid, _)| *pid),
+        );
+        i += 1;
+    }
+    found.remove(0);
+    found
+}
+
+/// SIGTERMs all our child processes (AFL, CRIU, targets), then SIGKILLs whatever is left
+pub fn kill_children() {
+    let children = descendants(std::process::id() as i32);
+    if children.is_empty() {
+        return;
+    }
+    println!("[*] Stopping {} child processes", children.len());
+    for pid in &children {
+        unsafe { libc::kill(*pid, libc::SIGTERM) };
+    }
+    sleep(CHILD_GRACE_PERIOD);
+    for pid in descendants(std::process::id() as i32) {
+        unsafe { libc::kill(pid, libc::SIGKILL) };
+    }
+    // Reap them, so they don't linger as zombies
+    while unsafe { libc::waitpid(-1, std::ptr::null_mut(), libc::WNOHANG) } > 0 {}
+}
+
+/// Prints what the campaign achieved, and why it ended
+pub fn print_summary(state: &CampaignState, reason: &StopReason) {
+    println!(
+        "{}{}==== [*] FitM stopped: {} ===={}",
+        color::Fg(color::Green),
+        style::Bold,
+        reason,
+        style::Reset
+    );
+    println!("    fuzzed for:   {:?}", state.elapsed);
+    println!(
+        "    generations:  {} fuzzed, {} deep, at gen {} of round {}",
+        state.stages,
+        state.generation_snaps.len(),
+        state.current_gen,
+        state.round
+    );
+    println!("    snapshots:    {}", total_snapshots(state));
+    println!("    execs:        {}", total_execs(state));
+    println!(
+        "    new paths:    {}",
+        state.history.values().map(|h| h.new_paths).sum::<u64>()
+    );
+    println!(
+        "    crashes:      {}",
+        state.history.values().map(|h| h.crashes).sum::<u64>()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_budget() {
+        let mut state = CampaignState::new(1, vec![vec![], vec![], vec![]]);
+        state.elapsed = Duration::from_secs(100);
+        state.stages = 3;
+        state
+            .history
+            .entry("fitm-gen1-state0".to_string())
+            .or_default()
+            .add_run(Duration::from_secs(60), 500, 0, 0, 0);
+
+        assert_eq!(Budget::default().exhausted(&state), None);
+        let budget = Budget {
+            wall_time: Some(Duration::from_secs(101)),
+            execs: Some(501),
+            generations: Some(4),
+            snapshots: Some(0),
+        };
+        assert_eq!(budget.exhausted(&state), Some(StopReason::Snapshots(0)));
+        let budget = Budget {
+            snapshots: None,
+            ..budget
+        };
+        assert_eq!(budget.exhausted(&state), None);
+        state.stages = 4;
+        assert_eq!(budget.exhausted(&state), Some(StopReason::Generations(4)));
+        state.elapsed = Duration::from_secs(101);
+        assert_eq!(
+            budget.exhausted(&state),
+            Some(StopReason::WallTime(Duration::from_secs(101)))
+        );
+
+        // We only ever find our own children
+        assert!(!descendants(std::process::id() as i32).contains(&(std::process::id() as i32)));
+        assert_eq!(
+            parent_pid(std::process::id() as i32),
+            Some(unsafe { libc::getppid() })
+        );
+    }
+}
@@ -0,0 +1,352 @@
+use std::path::PathBuf;
+
+use crate::config::RunArgs;
+use crate::evict::EvictionPolicy;
+use crate::fsck::FsckMode;
+use crate::gc::GcMode;
+
+pub const USAGE: &str = "Usage: fitm [OPTIONS] <COMMAND>
+
+Commands:
+  fuzz <config>             Fuzz the targets in <config>, resuming from fitm-state.json if present
+  resume <config>           Like fuzz, but fail if there is no fitm-state.json to resume from
+  status                    Summarise fitm-state.json and saved-states
+  replay <state> [input]    Restore <state> and run it on [input] (file or dir, default: the state's `in` dir)
+  triage                    Replay all crashes of all saved states and group them by outcome
+  clean                     Remove fitm-state.json, active-state, saved-states, workers and cmin-tmp
+  export [dest]             Copy fitm-state.json and all crashes, hangs and queues to [dest]
+  fsck [repair|quarantine]  Check fitm-state.json against saved-states. `repair` fixes fitm-state.json,
+                            `quarantine` also moves broken and orphaned states to saved-states/.quarantine
+  flatten [state]           Make <state> (default: all saved states) independent of the snapshots
+                            it is stored as a diff against
+  gc [all|prune|retire|compact] [archive|delete]
+                            Prune fuzz results, retire snapshots covered by others (archived to
+                            archived-states by default) and hard link identical CRIU page images
+  <config>                  Same as `fuzz <config>`
+
+Options:
+  -t, --run-time <secs>     Override `run_time` from the config
+      --server-only         Override `server_only` from the config with true
+      --no-server-only      Override `server_only` from the config with false
+  -C, --workdir <dir>       Change into <dir> before doing anything
+  -c, --config <config>     Use the `tools` of <config> for `replay` and `triage`
+  -s, --seed <seed>         Seed for the scheduler's RNG
+  -j, --workers <n>         Fuzz n snapshots at once (overrides `workers`)
+      --secondaries <n>     Run n AFL++ secondaries per snapshot (overrides `secondaries`)
+      --max-time <secs>     Stop after fuzzing this long in total (overrides `max_time`)
+      --max-execs <n>       Stop after n execs in total (overrides `max_execs`)
+      --max-generations <n> Stop after fuzzing n generations (overrides `max_generations`)
+      --max-snapshots <n>   Stop once there are n snapshots (overrides `max_snapshots`)
+  -h, --help                Print this help";
+
+/// What the fitm binary should do
+#[derive(Clone, Debug, PartialEq)]
+pub enum Subcommand {
+    Fuzz {
+        config: PathBuf,
+    },
+    Resume {
+        config: PathBuf,
+    },
+    Status,
+    Replay {
+        state: String,
+        input: Option<PathBuf>,
+    },
+    Triage,
+    Clean,
+    Export {
+        dest: Option<PathBuf>,
+    },
+    Fsck {
+        mode: FsckMode,
+    },
+    Flatten {
+        state: Option<String>,
+    },
+    Gc {
+        mode: GcMode,
+        policy: EvictionPolicy,
+    },
+    Help,
+}
+
+/// The parsed command line. Options override their counterparts in the config file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cli {
+    pub command: Subcommand,
+    pub run_time: Option<u64>,
+    pub server_only: Option<bool>,
+    pub workdir: Option<PathBuf>,
+    /// The campaign config for commands that don't take one as argument
+    pub config: Option<PathBuf>,
+    pub seed: Option<u64>,
+    pub workers: Option<usize>,
+    pub secondaries: Option<usize>,
+    pub max_time: Option<u64>,
+    pub max_execs: Option<u64>,
+    pub max_generations: Option<u64>,
+    pub max_snapshots: Option<u64>,
+}
+
+impl Cli {
+    /// Overrides the values of `args` we got options for. Validate `args` afterwards.
+    pub fn override_args(&self, args: &mut RunArgs) {
+        if let Some(run_time) = self.run_time {
+            args.run_time = run_time;
+        }
+        if let Some(server_only) = self.server_only {
+            args.server_only = server_only;
+        }
+        args.max_time = self.max_time.or(args.max_time);
+        args.max_execs = self.max_execs.or(args.max_execs);
+        args.max_generations = self.max_generations.or(args.max_generations);
+        args.max_snapshots = self.max_snapshots.or(args.max_snapshots);
+        args.workers = self.workers.unwrap_or(args.workers);
+        args.secondaries = self.secondaries.unwrap_or(args.secondaries);
+    }
+}
+
+fn parse_num(flag: &str, val: Option<String>) -> Result<u64, String> {
+    let val = val.ok_or_else(|| format!("{} expects a value", flag))?;
+    val.parse()
+        .map_err(|_| format!("{} expects a number, got '{}'", flag, val))
+}
+
+/// Parses the arguments (without the binary name)
+pub fn parse<I>(args: I) -> Result<Cli, String>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut cli = Cli {
+        command: Subcommand::Help,
+        run_time: None,
+        server_only: None,
+        workdir: None,
+        config: None,
+        seed: None,
+        workers: None,
+        secondaries: None,
+        max_time: None,
+        max_execs: None,
+        max_generations: None,
+        max_snapshots: None,
+    };
+    let mut positional = vec![];
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        // Allow `--flag=value` as well as `--flag value`
+        let (flag, mut inline_val) = match arg.split_once('=') {
+            Some((flag, val)) if arg.starts_with("--") => (flag.to_string(), Some(val.to_string())),
+            _ => (arg.clone(), None),
+        };
+        let mut value = || inline_val.take().or_else(|| args.next());
+        match flag.as_str() {
+            "-h" | "--help" => return Ok(cli),
+            "-t" | "--run-time" => cli.run_time = Some(parse_num(&flag, value())?),
+            "-s" | "--seed" => cli.seed = Some(parse_num(&flag, value())?),
+            "-j" | "--workers" => cli.workers = Some(parse_num(&flag, value())? as usize),
+            "--secondaries" => cli.secondaries = Some(parse_num(&flag, value())? as usize),
+            "--max-time" => cli.max_time = Some(parse_num(&flag, value())?),
+            "--max-execs" => cli.max_execs = Some(parse_num(&flag, value())?),
+            "--max-generations" => cli.max_generations = Some(parse_num(&flag, value())?),
+            "--max-snapshots" => cli.max_snapshots = Some(parse_num(&flag, value())?),
+            "-C" | "--workdir" => {
+                cli.workdir = Some(value().ok_or("--workdir expects a directory")?.into())
+            }
+            "-c" | "--config" => {
+                cli.config = Some(value().ok_or("--config expects a config path")?.into())
+            }
+            "--server-only" => cli.server_only = Some(true),
+            "--no-server-only" => cli.server_only = Some(false),
+            _ if flag.starts_with('-') && flag.len() > 1 => {
+                return Err(format!("Unknown option '{}'", arg))
+            }
+            _ => positional.push(arg),
+        }
+        if inline_val.is_some() {
+            return Err(format!("{} does not take a value", flag));
+        }
+    }
+
+    let mut positional = positional.into_iter();
+    let name = match positional.next() {
+        Some(name) => name,
+        None => return Ok(cli),
+    };
+    let mut config = |cmd: &str| -> Result<PathBuf, String> {
+        positional
+            .next()
+            .map(PathBuf::from)
+            .ok_or(format!("`{}` expects a config path", cmd))
+    };
+    cli.command = match name.as_str() {
+        "fuzz" => Subcommand::Fuzz {
+            config: config("fuzz")?,
+        },
+        "resume" => Subcommand::Resume {
+            config: config("resume")?,
+        },
+        "status" => Subcommand::Status,
+        "replay" => Subcommand::Replay {
+            state: positional
+                .next()
+                .ok_or("`replay` expects a state, e.g. fitm-gen2-state0")?,
+            input: positional.next().map(PathBuf::from),
+        },
+        "triage" => Subcommand::Triage,
+        "clean" => Subcommand::Clean,
+        "export" => Subcommand::Export {
+            dest: positional.next().map(PathBuf::from),
+        },
+        "fsck" => Subcommand::Fsck {
+            mode: match positional.next().as_deref() {
+                None | Some("report") => FsckMode::Report,
+                Some("repair") => FsckMode::Repair,
+                Some("quarantine") => FsckMode::Quarantine,
+                Some(mode) => return Err(format!("Unknown fsck mode '{}'", mode)),
+            },
+        },
+        "flatten" => Subcommand::Flatten {
+            state: positional.next(),
+        },
+        "gc" => Subcommand::Gc {
+            mode: match positional.next().as_deref() {
+                None | Some("all") => GcMode::All,
+                Some("prune") => GcMode::Prune,
+                Some("retire") => GcMode::Retire,
+                Some("compact") => GcMode::Compact,
+                Some(mode) => return Err(format!("Unknown gc mode '{}'", mode)),
+            },
+            policy: match positional.next().as_deref() {
+                None | Some("archive") => EvictionPolicy::Archive,
+                Some("delete") => EvictionPolicy::Delete,
+                Some(policy) => return Err(format!("Unknown eviction policy '{}'", policy)),
+            },
+        },
+        "help" => Subcommand::Help,
+        // Old style invocation: `fitm <config>`
+        _ => Subcommand::Fuzz {
+            config: name.into(),
+        },
+    };
+
+    match positional.next() {
+        Some(extra) => Err(format!("Unexpected argument '{}'", extra)),
+        None => Ok(cli),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Result<Cli, String> {
+        parse(line.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn test_parse_subcommands() {
+        assert_eq!(
+            args("config/fitm-args.ftp.json").unwrap().command,
+            Subcommand::Fuzz {
+                config: "config/fitm-args.ftp.json".into()
+            }
+        );
+        assert_eq!(
+            args("replay fitm-gen2-state0").unwrap().command,
+            Subcommand::Replay {
+                state: "fitm-gen2-state0".into(),
+                input: None
+            }
+        );
+        assert_eq!(args("status").unwrap().command, Subcommand::Status);
+        assert_eq!(args("").unwrap().command, Subcommand::Help);
+        assert!(args("resume").is_err());
+        assert!(args("clean now").is_err());
+        assert_eq!(
+            args("fsck quarantine").unwrap().command,
+            Subcommand::Fsck {
+                mode: FsckMode::Quarantine
+            }
+        );
+        assert!(args("fsck harder").is_err());
+        assert_eq!(
+            args("gc").unwrap().command,
+            Subcommand::Gc {
+                mode: GcMode::All,
+                policy: EvictionPolicy::Archive
+            }
+        );
+        assert_eq!(
+            args("gc retire delete").unwrap().command,
+            Subcommand::Gc {
+                mode: GcMode::Retire,
+                policy: EvictionPolicy::Delete
+            }
+        );
+        assert!(args("gc everything").is_err());
+        assert_eq!(
+            args("flatten fitm-gen3-state1").unwrap().command,
+            Subcommand::Flatten {
+                state: Some("fitm-gen3-state1".into())
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_overrides() {
+        let cli = args("-C /tmp resume cfg.json --run-time=30 --server-only -s 1337").unwrap();
+        assert_eq!(
+            cli.command,
+            Subcommand::Resume {
+                config: "cfg.json".into()
+            }
+        );
+        assert_eq!(cli.run_time, Some(30));
+        assert_eq!(cli.server_only, Some(true));
+        assert_eq!(cli.workdir, Some("/tmp".into()));
+        assert_eq!(cli.seed, Some(1337));
+        assert_eq!(cli.max_time, None);
+
+        let cli =
+            args("fuzz cfg.json --max-time 3600 --max-execs=1000000 --max-snapshots 50").unwrap();
+        assert_eq!(cli.max_time, Some(3600));
+        assert_eq!(cli.max_execs, Some(1000000));
+        assert_eq!(cli.max_snapshots, Some(50));
+        assert_eq!(cli.max_generations, None);
+        assert_eq!(args("fuzz cfg.json -j 4").unwrap().workers, Some(4));
+        assert_eq!(
+            args("fuzz cfg.json --secondaries=2").unwrap().secondaries,
+            Some(2)
+        );
+
+        let cli = args("triage --config=cfg.json").unwrap();
+        assert_eq!(cli.command, Subcommand::Triage);
+        assert_eq!(cli.config, Some("cfg.json".into()));
+        assert!(args("replay fitm-gen1-state0 -c").is_err());
+
+        assert!(args("fuzz cfg.json --seed abc").is_err());
+        assert!(args("fuzz cfg.json --run-time").is_err());
+        assert!(args("fuzz cfg.json --server-only=1").is_err());
+        assert!(args("fuzz cfg.json --frobnicate").is_err());
+    }
+
+    #[test]
+    fn test_override_args() {
+        let json = r#"{"client": "client", "server": "server", "run_time": 60}"#;
+        let mut run_args = RunArgs::from_json(json, std::path::Path::new("cfg.json")).unwrap();
+        args("fuzz cfg.json -t 0 -j 4")
+            .unwrap()
+            .override_args(&mut run_args);
+        assert_eq!(run_args.run_time, 0);
+        assert_eq!(run_args.workers, 4);
+        // Overrides are validated like the config itself
+        let e = run_args
+            .validate(std::path::Path::new("/tmp"))
+            .unwrap_err()
+            .to_string();
+        assert!(e.contains("`run_time` must be at least 1 second"), "{}", e);
+    }
+}
@@ -0,0 +1,425 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use chrono::Local;
+use termion::{color, style};
+
+use crate::evict::{EvictionPolicy, ARCHIVED_STATES};
+use crate::fsck::{self, FsckMode};
+use crate::gc::{self, GcMode, GcReport};
+use crate::incremental;
+use crate::state::{self, CampaignState};
+use crate::toolchain::Toolchain;
+use crate::utils::{afl_node_prefix, afl_results_dirs, fuzzer_stat};
+use crate::workers::{Worker, WORKERS_DIR};
+use crate::workspace::Workspace;
+use crate::{FITMSnapshot, ACTIVE_STATE, CMIN_TMP, FITM_STATE, SAVED_STATES};
+
+/// Everything `make reset` used to remove, below the workspace root
+pub const CLEAN_PATHS: [&str; 6] = [
+    FITM_STATE,
+    ACTIVE_STATE,
+    SAVED_STATES,
+    ARCHIVED_STATES,
+    CMIN_TMP,
+    WORKERS_DIR,
+];
+
+/// Files in `dir`, sorted, without AFL's README.txt. Empty if `dir` does not exist.
+fn list_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_file() && !path.ends_with("README.txt"))
+            .collect(),
+        Err(_) => vec![],
+    };
+    files.sort();
+    files
+}
+
+/// Names of all state folders in `saved-states`
+fn saved_state_dirs(workspace: &Workspace) -> io::Result<Vec<String>> {
+    let mut dirs: Vec<String> = fs::read_dir(&workspace.saved_states)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .collect();
+    dirs.sort();
+    Ok(dirs)
+}
+
+fn find_snapshot(workspace: &Workspace, state: &str) -> io::Result<FITMSnapshot> {
+    CampaignState::load_from(&workspace.path(FITM_STATE))?
+        .generation_snaps
+        .into_iter()
+        .flatten()
+        .find(|snap| snap.state_path == state)
+        .ok_or_else(|| {
+            io::Error::new(
+                ErrorKind::NotFound,
+                format!("[!] {} is not part of {}", state, FITM_STATE),
+            )
+        })
+}
+
+/// Prints an overview of the current run
+pub fn status(workspace: &Workspace) -> io::Result<()> {
+    let state = match CampaignState::load_from(&workspace.path(FITM_STATE)) {
+        Ok(state) => {
+            println!(
+                "[*] Seed {:?}, at gen {}, round {}, fuzzed for {:?}",
+                state.seed, state.current_gen, state.round, state.elapsed
+            );
+            Some(state)
+        }
+        Err(e) => {
+            println!("[!] Could not load {}: {}", FITM_STATE, e);
+            None
+        }
+    };
+    let history = state
+        .as_ref()
+        .map(|state| state.history.clone())
+        .unwrap_or_default();
+    let generation_snaps = state
+        .map(|state| state.generation_snaps)
+        .unwrap_or_default();
+    let saved = saved_state_dirs(workspace).unwrap_or_default();
+
+    println!(
+        "==== [*] {} generations, {} snapshots, {} saved states ====",
+        generation_snaps.len(),
+        generation_snaps.iter().map(|gen| gen.len()).sum::<usize>(),
+        saved.len()
+    );
+    for (gen, snaps) in generation_snaps.iter().enumerate() {
+        if snaps.is_empty() {
+            continue;
+        }
+        let side = if snaps[0].server { "server" } else { "client" };
+        println!("Gen {} ({}):", gen, side);
+        for snap in snaps {
+            let crashes: usize = afl_results_dirs(&workspace.saved_states, &snap.state_path)
+                .iter()
+                .map(|node| list_files(&node.join("crashes")).len())
+                .sum();
+            let crash_color = if crashes > 0 {
+                format!("{}", color::Fg(color::Red))
+            } else {
+                String::new()
+            };
+            let snap_history = history.get(&snap.state_path).cloned().unwrap_or_default();
+            println!(
+                "    {:<24} fuzzed: {:>3}x  paths: {:>6}  new: {:>5}  dry: {:>2}  execs: {:>10}  {}crashes: {}{}{}",
+                snap.state_path,
+                snap_history.runs,
+                fuzzer_stat(&workspace.saved_states, &snap.state_path, "paths_total")
+                    .unwrap_or_else(|| "-".into()),
+                snap_history.new_paths,
+                snap_history.dry_runs,
+                snap_history.execs,
+                crash_color,
+                crashes,
+                style::Reset,
+                if saved.contains(&snap.state_path) {
+                    ""
+                } else {
+                    " (missing in saved-states)"
+                }
+            );
+        }
+    }
+
+    let untracked: Vec<&String> = saved
+        .iter()
+        .filter(|dir| {
+            !generation_snaps
+                .iter()
+                .flatten()
+                .any(|snap| &snap.state_path == *dir)
+        })
+        .collect();
+    if !untracked.is_empty() {
+        println!("Saved states not in {}: {:?}", FITM_STATE, untracked);
+    }
+    Ok(())
+}
+
+/// Removes all state of previous runs (and the backups of fitm-state.json), like `make reset` does
+pub fn clean(workspace: &Workspace) -> io::Result<()> {
+    for path in CLEAN_PATHS {
+        let path = workspace.path(path);
+        let res = if path.is_dir() {
+            fs::remove_dir_all(&path)
+        } else {
+            fs::remove_file(&path)
+        };
+        match res {
+            Ok(()) => println!("[*] Removed {:?}", path),
+            Err(e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+    }
+    for backup in state::backup_paths(&workspace.path(FITM_STATE)) {
+        if fs::remove_file(&backup).is_ok() {
+            println!("[*] Removed {:?}", backup);
+        }
+    }
+    Ok(())
+}
+
+/// Copies fitm-state.json and the crashes, hangs and queue of every saved state to `dest`.
+/// Defaults to `fitm-export-<timestamp>`.
+pub fn export(workspace: &Workspace, dest: Option<&Path>) -> io::Result<PathBuf> {
+    let dest = match dest {
+        Some(dest) => dest.to_path_buf(),
+        None => PathBuf::from(format!(
+            "fitm-export-{}",
+            Local::now().format("%Y%m%d-%H%M%S")
+        )),
+    };
+    fs::create_dir_all(&dest)?;
+    let state_file = workspace.path(FITM_STATE);
+    if state_file.exists() {
+        fs::copy(state_file, dest.join(FITM_STATE))?;
+    }
+
+    let mut exported = 0;
+    for state in saved_state_dirs(workspace)? {
+        for node in afl_results_dirs(&workspace.saved_states, &state) {
+            let prefix = afl_node_prefix(&node);
+            for dir in &["crashes", "hangs", "queue"] {
+                let files = list_files(&node.join(dir));
+                if files.is_empty() {
+                    continue;
+                }
+                let to = dest.join(&state).join(dir);
+                fs::create_dir_all(&to)?;
+                for file in files {
+                    let name = file.file_name().unwrap().to_string_lossy();
+                    fs::copy(&file, to.join(format!("{}{}", prefix, name)))?;
+                    exported += 1;
+                }
+            }
+        }
+    }
+    println!("[*] Exported {} files to {:?}", exported, dest);
+    Ok(dest)
+}
+
+/// Checks fitm-state.json against saved-states, and repairs or quarantines what's broken.
+/// Returns the number of issues found.
+pub fn fsck(workspace: &Workspace, mode: FsckMode) -> io::Result<usize> {
+    let state_file = workspace.path(FITM_STATE);
+    let mut state = CampaignState::load_from(&state_file)?;
+    let issues = fsck::reconcile(&mut state, &workspace.saved_states, mode)?;
+    if issues.is_empty() {
+        println!(
+            "[*] {:?} and {:?} are consistent",
+            state_file, workspace.saved_states
+        );
+    } else if mode == FsckMode::Report {
+        println!(
+            "[*] Found {} issues, run `fitm fsck repair` or `fitm fsck quarantine` to fix them",
+            issues.len()
+        );
+    } else {
+        state.save_to(&state_file)?;
+        println!("[*] Fixed {} issues", issues.len());
+    }
+    Ok(issues.len())
+}
+
+/// Flattens the snapshot of `state` (default: of all saved states), see `incremental::flatten`.
+/// Returns the number of snapshots that were stored as diffs.
+pub fn flatten(workspace: &Workspace, state: Option<&str>) -> io::Result<usize> {
+    let states = match state {
+        Some(state) => vec![state.to_string()],
+        None => saved_state_dirs(workspace)?,
+    };
+    let mut flattened = 0;
+    for state in states {
+        let snapshot = workspace.saved_state(&state).join("snapshot");
+        if let Some(base) = incremental::parent_state(&workspace.saved_states, &state) {
+            let added = incremental::flatten(&snapshot)?;
+            println!(
+                "[*] Flattened {} (was a diff against {}), {} bytes added",
+                state, base, added
+            );
+            flattened += 1;
+        }
+    }
+    println!("[*] Flattened {} snapshots", flattened);
+    Ok(flattened)
+}
+
+/// Garbage collects saved-states, see `gc::collect`, and updates fitm-state.json to match
+pub fn gc(workspace: &Workspace, mode: GcMode, policy: EvictionPolicy) -> io::Result<GcReport> {
+    let state_file = workspace.path(FITM_STATE);
+    let mut state = CampaignState::load_from(&state_file)?;
+    let report = gc::collect(
+        &mut state,
+        &workspace.saved_states,
+        &workspace.path(ARCHIVED_STATES),
+        mode,
+        policy,
+    )?;
+    if !report.retired.is_empty() {
+        state.save_to(&state_file)?;
+    }
+    Ok(report)
+}
+
+/// Restores `state` for every file in `input` (default: the state's `in` dir).
+/// Outputs end up in `saved-states/<state>/replay`.
+pub fn replay(
+    workspace: &Workspace,
+    tools: &Toolchain,
+    state: &str,
+    input: Option<&Path>,
+) -> io::Result<Vec<(PathBuf, i32)>> {
+    let snap = find_snapshot(workspace, state)?;
+    let input = match input {
+        Some(input) => input.to_path_buf(),
+        None => workspace.saved_state(state).join("in"),
+    };
+    let inputs = if input.is_dir() {
+        list_files(&input)
+    } else {
+        vec![input]
+    };
+    let output_dir = workspace.saved_state(state).join("replay");
+    fs::create_dir_all(&output_dir)?;
+    let output_dir = fs::canonicalize(output_dir)?;
+    let worker = Worker::new(workspace, 0, 1);
+
+    let mut results = vec![];
+    for input in inputs {
+        let input = fs::canonicalize(input)?;
+        let code = snap.replay_file(tools, &worker, &input, output_dir.to_str().unwrap())?;
+        println!(
+            "[*] {:?}: {}",
+            input.file_name().unwrap(),
+            describe_exit(code)
+        );
+        results.push((input, code));
+    }
+    Ok(results)
+}
+
+fn describe_exit(code: i32) -> String {
+    if code > 128 {
+        format!("killed by signal {}", code - 128)
+    } else {
+        format!("exited with {}", code)
+    }
+}
+
+/// Replays every crash found so far and groups them by how the target died
+pub fn triage(workspace: &Workspace, tools: &Toolchain) -> io::Result<()> {
+    let generation_snaps = CampaignState::load_from(&workspace.path(FITM_STATE))?.generation_snaps;
+    let mut outcomes: BTreeMap<String, Vec<String>> = BTreeMap::new();
+
+    for snap in generation_snaps.iter().flatten() {
+        for node in afl_results_dirs(&workspace.saved_states, &snap.state_path) {
+            let crashes = node.join("crashes");
+            if list_files(&crashes).is_empty() {
+                continue;
+            }
+            println!(
+                "==== [*] Triaging crashes of {} ({:?}) ====",
+                snap.state_path,
+                node.file_name().unwrap()
+            );
+            let prefix = afl_node_prefix(&node);
+            for (input, code) in replay(workspace, tools, &snap.state_path, Some(&crashes))? {
+                outcomes
+                    .entry(describe_exit(code))
+                    .or_default()
+                    .push(format!(
+                        "{}/{}{}",
+                        snap.state_path,
+                        prefix,
+                        input.file_name().unwrap().to_string_lossy()
+                    ));
+            }
+        }
+    }
+
+    if outcomes.is_empty() {
+        println!("[*] No crashes to triage");
+    }
+    for (outcome, inputs) in outcomes {
+        println!(
+            "{}{} ({} inputs){}",
+            color::Fg(color::Green),
+            outcome,
+            inputs.len(),
+            style::Reset
+        );
+        for input in inputs {
+            println!("    {}", input);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::afl_results_dir;
+
+    #[test]
+    fn test_list_files_and_stats() {
+        let root = Path::new("/tmp/fitm_commands_unittest");
+        let _ = fs::remove_dir_all(root);
+        fs::create_dir_all(root.join("crashes")).unwrap();
+        fs::write(root.join("crashes/README.txt"), "afl").unwrap();
+        fs::write(root.join("crashes/id:000001"), "b").unwrap();
+        fs::write(root.join("crashes/id:000000"), "a").unwrap();
+
+        assert_eq!(
+            list_files(&root.join("crashes")),
+            vec![
+                root.join("crashes/id:000000"),
+                root.join("crashes/id:000001")
+            ]
+        );
+        assert!(list_files(&root.join("hangs")).is_empty());
+        assert_eq!(describe_exit(139), "killed by signal 11");
+        assert_eq!(describe_exit(1), "exited with 1");
+
+        // Everything stays within the workspace
+        let workspace = Workspace::new(root.join("campaign"));
+        let stats = afl_results_dir(&workspace.saved_states, "fitm-gen1-state0");
+        fs::create_dir_all(stats.join("crashes")).unwrap();
+        fs::write(stats.join("crashes/id:000000"), "a").unwrap();
+        fs::write(stats.join("fuzzer_stats"), "paths_total : 7\n").unwrap();
+        let secondary = stats.with_file_name("sec1");
+        fs::create_dir_all(secondary.join("crashes")).unwrap();
+        fs::write(secondary.join("crashes/id:000000"), "b").unwrap();
+        assert_eq!(
+            afl_results_dirs(&workspace.saved_states, "fitm-gen1-state0"),
+            vec![stats.clone(), secondary]
+        );
+        fs::create_dir_all(&workspace.active_state).unwrap();
+        assert_eq!(
+            fuzzer_stat(&workspace.saved_states, "fitm-gen1-state0", "paths_total"),
+            Some("7".to_string())
+        );
+        let dest = export(&workspace, Some(&root.join("export"))).unwrap();
+        assert!(dest.join("fitm-gen1-state0/crashes/id:000000").is_file());
+        assert!(dest
+            .join("fitm-gen1-state0/crashes/sec1-id:000000")
+            .is_file());
+        clean(&workspace).unwrap();
+        assert!(!workspace.saved_states.exists());
+        assert!(!workspace.active_state.exists());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,513 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt, fs,
+    io::{self, ErrorKind},
+    path::{Component, Path, PathBuf},
+    time::Duration,
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::budget::Budget;
+use crate::evict::{EvictionPolicy, Limits};
+use crate::gc::GcMode;
+use crate::history::RunTime;
+use crate::quarantine::MAX_CONSECUTIVE_FAILURES;
+use crate::scheduler::{SchedulerKind, SNAPSHOTS_PER_STAGE};
+use crate::toolchain::ToolPaths;
+use crate::ACTIVE_STATE;
+
+/// The config version this build writes and understands.
+/// Bump it and add a step to `migrate` whenever the format changes.
+pub const CONFIG_VERSION: u64 = 1;
+
+/// Used if `run_time` is not given
+pub const DEFAULT_RUN_TIME: u64 = 60;
+
+/// The contents of a `fitm-args.json`
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RunArgs {
+    /// Version of the config format, see `CONFIG_VERSION`
+    pub version: u64,
+    /// The client target binary
+    pub client: String,
+    pub client_args: Vec<String>,
+    pub client_envs: HashMap<String, String>,
+    /// Files and folders copied into the active state before the client's init run
+    pub client_files: Vec<String>,
+    /// The server target binary
+    pub server: String,
+    pub server_args: Vec<String>,
+    pub server_envs: HashMap<String, String>,
+    /// Files and folders copied into the active state before the server's init run
+    pub server_files: Vec<String>,
+    /// run time in secs, for snapshots without a fuzzing history
+    pub run_time: u64,
+    /// Lower bound of the adaptive run time in secs, defaults to `run_time / 4`
+    pub min_run_time: Option<u64>,
+    /// Upper bound of the adaptive run time in secs, defaults to `run_time * 4`
+    pub max_run_time: Option<u64>,
+    // Still needs an echo binary or a binary producing a short output, as client
+    // Just fuzzes the client for 100 millis.
+    /// Enable protocol discovery (server_only)
+    pub server_only: bool,
+    /// Paths to AFL++, fitm-qemu-trace and criu, see `Toolchain`
+    pub tools: ToolPaths,
+    /// Seed for the scheduler, random if not set
+    pub seed: Option<u64>,
+    /// Which `Scheduler` decides what to fuzz next
+    pub scheduler: SchedulerKind,
+    /// How many snapshots of a generation are fuzzed per stage
+    pub snapshots_per_gen: usize,
+    /// Stop after this many secs of fuzzing (over all resumed runs)
+    pub max_time: Option<u64>,
+    /// Stop after this many execs
+    pub max_execs: Option<u64>,
+    /// Stop after fuzzing this many generations
+    pub max_generations: Option<u64>,
+    /// Stop once there are this many snapshots
+    pub max_snapshots: Option<u64>,
+    /// Don't create snapshots for generations above this
+    pub max_depth: Option<usize>,
+    /// Evict the least useful snapshots of a generation above this many
+    pub max_snapshots_per_gen: Option<usize>,
+    /// Evict the least useful snapshots while `saved-states` is larger than this many MiB
+    pub max_saved_states_mb: Option<u64>,
+    /// Whether evicted snapshots are archived or deleted
+    pub eviction: EvictionPolicy,
+    /// Garbage collect saved-states after each stage
+    pub gc: Option<GcMode>,
+    /// Store snapshots as diffs against their base state
+    pub incremental_snapshots: bool,
+    /// How many snapshots of a stage are fuzzed at once, see `workers`
+    pub workers: usize,
+    /// AFL++ secondaries (`-S`) fuzzing each snapshot next to the main node
+    pub secondaries: usize,
+    /// Give up once this many snapshots in a row were quarantined
+    pub max_consecutive_failures: usize,
+}
+
+/// Errors while loading a config, always naming the offending file or key
+#[derive(Debug)]
+pub enum ConfigError {
+    /// Could not read the config file
+    Io(PathBuf, io::Error),
+    /// The file is not valid json
+    Syntax { path: PathBuf, reason: String },
+    /// A key is missing, unknown or has the wrong type
+    Key { key: String, reason: String },
+    /// The config was written for a newer FitM
+    Version { found: u64 },
+    /// The config parsed fine, but points to things that don't exist
+    Validation(Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "could not read config {:?}: {}", path, e),
+            ConfigError::Syntax { path, reason } => {
+                write!(f, "config {:?} is not valid json: {}", path, reason)
+            }
+            ConfigError::Key { key, reason } => write!(f, "config key `{}`: {}", key, reason),
+            ConfigError::Version { found } => write!(
+                f,
+                "config version {} is newer than the supported version {}",
+                found, CONFIG_VERSION
+            ),
+            ConfigError::Validation(problems) => {
+                write!(f, "invalid config:")?;
+                for problem in problems {
+                    write!(f, "\n  - {}", problem)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+impl From<ConfigError> for io::Error {
+    fn from(e: ConfigError) -> Self {
+        let kind = match &e {
+            ConfigError::Io(_, io_err) => io_err.kind(),
+            _ => ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, e)
+    }
+}
+
+fn key_error(key: &str, reason: impl ToString) -> ConfigError {
+    ConfigError::Key {
+        key: key.to_string(),
+        reason: reason.to_string(),
+    }
+}
+
+/// Upgrades an older config to `CONFIG_VERSION`, step by step.
+/// Returns the version the config had.
+pub fn migrate(config: &mut Map<String, Value>) -> Result<u64, ConfigError> {
+    let found = match config.get("version") {
+        None => 0,
+        Some(version) => version
+            .as_u64()
+            .ok_or_else(|| key_error("version", "expected a positive number"))?,
+    };
+    if found > CONFIG_VERSION {
+        return Err(ConfigError::Version { found });
+    }
+
+    if found < 1 {
+        // Version 0 configs sometimes had envs as a list of `[key, value]` pairs
+        for key in &["client_envs", "server_envs"] {
+            if let Some(Value::Array(pairs)) = config.get(*key) {
+                let mut envs = Map::new();
+                for pair in pairs {
+                    match pair.as_array().map(|pair| pair.as_slice()) {
+                        Some([Value::String(k), v]) => {
+                            envs.insert(k.clone(), v.clone());
+                        }
+                        _ => return Err(key_error(key, "expected [key, value] pairs")),
+                    }
+                }
+                config.insert(key.to_string(), Value::Object(envs));
+            }
+        }
+    }
+
+    config.insert("version".to_string(), CONFIG_VERSION.into());
+    Ok(found)
+}
+
+/// Removes `key` from the config and deserializes it
+fn take<T: DeserializeOwned>(
+    config: &mut Map<String, Value>,
+    key: &str,
+) -> Result<Option<T>, ConfigError> {
+    match config.remove(key) {
+        Some(value) => serde_json::from_value(value)
+            .map(Some)
+            .map_err(|e| key_error(key, e)),
+        None => Ok(None),
+    }
+}
+
+fn required<T: DeserializeOwned>(
+    config: &mut Map<String, Value>,
+    key: &str,
+) -> Result<T, ConfigError> {
+    take(config, key)?.ok_or_else(|| key_error(key, "missing"))
+}
+
+impl RunArgs {
+    /// Parses a config, migrating it from older versions and filling in defaults
+    pub fn from_json(json: &str, path: &Path) -> Result<Self, ConfigError> {
+        let mut config = match serde_json::from_str(json) {
+            Ok(Value::Object(config)) => config,
+            Ok(_) => {
+                return Err(ConfigError::Syntax {
+                    path: path.to_path_buf(),
+                    reason: "expected a json object".to_string(),
+                })
+            }
+            Err(e) => {
+                return Err(ConfigError::Syntax {
+                    path: path.to_path_buf(),
+                    reason: e.to_string(),
+                })
+            }
+        };
+
+        let found = migrate(&mut config)?;
+        if found != CONFIG_VERSION {
+            println!(
+                "[*] Migrated config {:?} from version {} to {}, consider updating the file",
+                path, found, CONFIG_VERSION
+            );
+        }
+
+        let args = RunArgs {
+            version: required(&mut config, "version")?,
+            client: required(&mut config, "client")?,
+            client_args: take(&mut config, "client_args")?.unwrap_or_default(),
+            client_envs: take(&mut config, "client_envs")?.unwrap_or_default(),
+            client_files: take(&mut config, "client_files")?.unwrap_or_default(),
+            server: required(&mut config, "server")?,
+            server_args: take(&mut config, "server_args")?.unwrap_or_default(),
+            server_envs: take(&mut config, "server_envs")?.unwrap_or_default(),
+            server_files: take(&mut config, "server_files")?.unwrap_or_default(),
+            run_time: take(&mut config, "run_time")?.unwrap_or(DEFAULT_RUN_TIME),
+            min_run_time: take(&mut config, "min_run_time")?,
+            max_run_time: take(&mut config, "max_run_time")?,
+            server_only: take(&mut config, "server_only")?.unwrap_or(false),
+            tools: take(&mut config, "tools")?.unwrap_or_default(),
+            seed: take(&mut config, "seed")?,
+            scheduler: take(&mut config, "scheduler")?.unwrap_or_default(),
+            snapshots_per_gen: take(&mut config, "snapshots_per_gen")?
+                .unwrap_or(SNAPSHOTS_PER_STAGE),
+            max_time: take(&mut config, "max_time")?,
+            max_execs: take(&mut config, "max_execs")?,
+            max_generations: take(&mut config, "max_generations")?,
+            max_snapshots: take(&mut config, "max_snapshots")?,
+            max_depth: take(&mut config, "max_depth")?,
+            max_snapshots_per_gen: take(&mut config, "max_snapshots_per_gen")?,
+            max_saved_states_mb: take(&mut config, "max_saved_states_mb")?,
+            eviction: take(&mut config, "eviction")?.unwrap_or_default(),
+            gc: take(&mut config, "gc")?,
+            incremental_snapshots: take(&mut config, "incremental_snapshots")?.unwrap_or(false),
+            workers: take(&mut config, "workers")?.unwrap_or(1),
+            secondaries: take(&mut config, "secondaries")?.unwrap_or(0),
+            max_consecutive_failures: take(&mut config, "max_consecutive_failures")?
+                .unwrap_or(MAX_CONSECUTIVE_FAILURES),
+        };
+
+        // Anything left over is most likely a typo
+        if let Some(key) = config.keys().next() {
+            return Err(key_error(key, "unknown key"));
+        }
+        Ok(args)
+    }
+
+    /// Reads and parses the config at `path`
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let json = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+        Self::from_json(&json, path)
+    }
+
+    /// Checks that the targets and `*_files` the config points to exist, relative to `workdir`.
+    /// Target binaries are relative to the active state dir, or copied there via `*_files`.
+    /// Tools are checked when resolving the `Toolchain`.
+    pub fn validate(&self, workdir: &Path) -> Result<(), ConfigError> {
+        let mut problems = vec![];
+
+        if self.run_time == 0 {
+            problems.push("`run_time` must be at least 1 second".to_string());
+        }
+        if self.snapshots_per_gen == 0 {
+            problems.push("`snapshots_per_gen` must be at least 1".to_string());
+        }
+        if self.max_depth.is_some_and(|depth| depth < 2) {
+            problems.push(
+                "`max_depth` must be at least 2, the initial snapshots are gen 1 and 2".to_string(),
+            );
+        }
+        if self.max_snapshots_per_gen == Some(0) {
+            problems.push("`max_snapshots_per_gen` must be at least 1".to_string());
+        }
+        if self.workers == 0 {
+            problems.push("`workers` must be at least 1".to_string());
+        }
+        if self.max_consecutive_failures == 0 {
+            problems.push("`max_consecutive_failures` must be at least 1".to_string());
+        }
+        let run_time = self.run_time_bounds();
+        if run_time.min.is_zero() {
+            problems.push("`min_run_time` must be at least 1 second".to_string());
+        }
+        if !(run_time.min..=run_time.max).contains(&run_time.base) {
+            problems.push(format!(
+                "`run_time` ({:?}) must be between `min_run_time` ({:?}) and `max_run_time` ({:?})",
+                run_time.base, run_time.min, run_time.max
+            ));
+        }
+
+        for (key, bin, files) in &[
+            ("client", &self.client, &self.client_files),
+            ("server", &self.server, &self.server_files),
+        ] {
+            for file in files.iter() {
+                if !workdir.join(file).exists() {
+                    problems.push(format!("`{}_files`: {:?} does not exist", key, file));
+                }
+            }
+
+            let resolved = workdir.join(resolve_from_active(bin));
+            let copied = files
+                .iter()
+                .any(|file| resolved.file_name() == Path::new(file).file_name());
+            if !resolved.exists() && !copied {
+                problems.push(format!(
+                    "`{}`: {:?} (resolved to {:?}) does not exist and is not in `{}_files`",
+                    key, bin, resolved, key
+                ));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigError::Validation(problems))
+        }
+    }
+}
+
+impl RunArgs {
+    /// When to stop the campaign
+    pub fn budget(&self) -> Budget {
+        Budget {
+            wall_time: self.max_time.map(Duration::from_secs),
+            execs: self.max_execs,
+            generations: self.max_generations,
+            snapshots: self.max_snapshots,
+        }
+    }
+
+    /// How far and wide the snapshot tree may grow
+    pub fn limits(&self) -> Limits {
+        Limits {
+            max_depth: self.max_depth,
+            max_snapshots_per_gen: self.max_snapshots_per_gen,
+            max_saved_states_size: self.max_saved_states_mb.map(|mb| mb * 1024 * 1024),
+            eviction: self.eviction,
+            gc: self.gc,
+            incremental: self.incremental_snapshots,
+        }
+    }
+
+    /// How long to fuzz each snapshot, see `RunTime`
+    pub fn run_time_bounds(&self) -> RunTime {
+        let max = self.max_run_time.unwrap_or(self.run_time * 4);
+        let min = self.min_run_time.unwrap_or((self.run_time / 4).max(1));
+        RunTime {
+            base: Duration::from_secs(self.run_time),
+            // A derived default never exceeds an explicit bound
+            min: Duration::from_secs(min.min(max)),
+            max: Duration::from_secs(max),
+        }
+    }
+}
+
+/// Target binaries are given relative to the active state dir.
+/// Returns the path relative to the working dir (or the absolute path), without touching the fs.
+pub fn resolve_from_active(bin: &str) -> PathBuf {
+    let mut resolved = PathBuf::new();
+    for component in Path::new(ACTIVE_STATE).join(bin).components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir if resolved.file_name().is_some() => {
+                resolved.pop();
+            }
+            component => resolved.push(component),
+        }
+    }
+    resolved
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(json: &str) -> Result<RunArgs, ConfigError> {
+        RunArgs::from_json(json, Path::new("test.json"))
+    }
+
+    #[test]
+    fn test_defaults_and_migration() {
+        let args = parse(
+            r#"{"client": "../client", "server": "../server",
+                "server_envs": [["QEMU_STRACE", "1"]]}"#,
+        )
+        .unwrap();
+        assert_eq!(args.version, CONFIG_VERSION);
+        assert_eq!(args.run_time, DEFAULT_RUN_TIME);
+        assert!(!args.server_only);
+        assert!(args.client_files.is_empty());
+        assert_eq!(args.seed, None);
+        assert_eq!(args.scheduler, SchedulerKind::Threshold);
+        assert_eq!(args.snapshots_per_gen, SNAPSHOTS_PER_STAGE);
+        assert_eq!(args.budget(), Budget::default());
+        assert!(!args.incremental_snapshots);
+        assert_eq!(args.workers, 1);
+        assert_eq!(args.secondaries, 0);
+        assert_eq!(args.max_consecutive_failures, MAX_CONSECUTIVE_FAILURES);
+        assert_eq!(args.limits(), Limits::default());
+        let run_time = args.run_time_bounds();
+        assert_eq!(run_time.min, Duration::from_secs(DEFAULT_RUN_TIME / 4));
+        assert_eq!(run_time.max, Duration::from_secs(DEFAULT_RUN_TIME * 4));
+        assert_eq!(args.server_envs["QEMU_STRACE"], "1");
+    }
+
+    #[test]
+    fn test_key_errors() {
+        let err = parse(r#"{"version": 1, "client": "a", "server": "b", "run_time": "10"}"#)
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with("config key `run_time`"), "{}", err);
+
+        let err = parse(r#"{"version": 1, "client": "a"}"#).unwrap_err();
+        assert!(err.to_string().contains("`server`: missing"), "{}", err);
+
+        let err =
+            parse(r#"{"version": 1, "client": "a", "server": "b", "run_tme": 10}"#).unwrap_err();
+        assert!(
+            err.to_string().contains("`run_tme`: unknown key"),
+            "{}",
+            err
+        );
+
+        let err = parse(r#"{"version": 1, "client": "a", "server": "b", "tools": {"afl": "x"}}"#)
+            .unwrap_err();
+        assert!(err.to_string().starts_with("config key `tools`"), "{}", err);
+
+        let err = parse(r#"{"version": 1, "client": "a", "server": "b", "scheduler": "fast"}"#)
+            .unwrap_err();
+        assert!(
+            err.to_string().starts_with("config key `scheduler`"),
+            "{}",
+            err
+        );
+
+        assert!(matches!(
+            parse(r#"{"version": 99, "client": "a", "server": "b"}"#),
+            Err(ConfigError::Version { found: 99 })
+        ));
+        assert!(matches!(parse("{"), Err(ConfigError::Syntax { .. })));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(resolve_from_active("../tests/bin"), Path::new("tests/bin"));
+        assert_eq!(
+            resolve_from_active("./ts3server"),
+            Path::new("active-state/ts3server")
+        );
+        assert_eq!(
+            resolve_from_active("/usr/bin/ftp"),
+            Path::new("/usr/bin/ftp")
+        );
+
+        let root = Path::new("/tmp/fitm_config_unittest");
+        let _ = fs::remove_dir_all(root);
+        for path in &["client", "data/server"] {
+            fs::create_dir_all(root.join(path).parent().unwrap()).unwrap();
+            fs::write(root.join(path), "").unwrap();
+        }
+
+        let mut args = parse(
+            r#"{"version": 1, "client": "../client", "server": "./server",
+                "server_files": ["data/server"]}"#,
+        )
+        .unwrap();
+        args.validate(root).unwrap();
+
+        args.max_run_time = Some(10);
+        assert!(args.validate(root).is_err());
+        args.max_run_time = None;
+
+        args.client = "../nope".to_string();
+        args.server_files.push("data/missing".to_string());
+        match args.validate(root) {
+            Err(ConfigError::Validation(problems)) => {
+                assert_eq!(problems.len(), 2, "{:?}", problems);
+                assert!(problems[0].starts_with("`client`"));
+                assert!(problems[1].starts_with("`server_files`"));
+            }
+            res => panic!("Expected validation errors, got {:?}", res),
+        }
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,568 @@
+use std::{
+    error::Error,
+    fmt, fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+use crate::pb::{Fields, Writer};
+
+// See criu/include/magic.h
+pub const IMG_COMMON_MAGIC: u32 = 0x5456_4319;
+pub const IMG_SERVICE_MAGIC: u32 = 0x5510_5940;
+pub const PSTREE_MAGIC: u32 = 0x5027_3030;
+pub const FDINFO_MAGIC: u32 = 0x5621_3732;
+pub const FILES_MAGIC: u32 = 0x5630_3138;
+pub const PIPES_MAGIC: u32 = 0x5651_3555;
+pub const CORE_MAGIC: u32 = 0x5505_3847;
+pub const PAGEMAP_MAGIC: u32 = 0x5608_4025;
+
+/// `pagemap_entry` flags, see criu/include/pagemap.h
+pub const PE_PARENT: u32 = 1 << 0;
+pub const PE_LAZY: u32 = 1 << 1;
+pub const PE_PRESENT: u32 = 1 << 2;
+
+/// `fd_types` from fdinfo.proto, only the ones we care about
+pub const FD_TYPE_REG: u32 = 1;
+pub const FD_TYPE_PIPE: u32 = 2;
+
+/// Errors while reading criu images, always carrying the offending image
+#[derive(Debug)]
+pub enum ImageError {
+    /// Could not read the image file at all
+    Io(PathBuf, io::Error),
+    /// The image is not the kind of image we expected
+    BadMagic {
+        path: PathBuf,
+        expected: u32,
+        found: u32,
+    },
+    /// The image ended in the middle of a header or entry
+    Truncated { path: PathBuf, offset: usize },
+    /// An entry could not be decoded
+    Decode {
+        path: PathBuf,
+        entry: usize,
+        reason: String,
+    },
+    /// The image was read fine, but did not contain what we need
+    Missing { path: PathBuf, what: String },
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageError::Io(path, e) => write!(f, "could not read {:?}: {}", path, e),
+            ImageError::BadMagic {
+                path,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{:?} has magic {:#x}, expected {:#x}",
+                path, found, expected
+            ),
+            ImageError::Truncated { path, offset } => {
+                write!(f, "{:?} is truncated at offset {}", path, offset)
+            }
+            ImageError::Decode {
+                path,
+                entry,
+                reason,
+            } => write!(f, "entry {} of {:?} is malformed: {}", entry, path, reason),
+            ImageError::Missing { path, what } => write!(f, "{:?} contains no {}", path, what),
+        }
+    }
+}
+
+impl Error for ImageError {}
+
+impl From<ImageError> for io::Error {
+    fn from(e: ImageError) -> Self {
+        let kind = match &e {
+            ImageError::Io(_, io_err) => io_err.kind(),
+            _ => ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, e)
+    }
+}
+
+/// A protobuf message stored in a criu image
+pub trait Entry: Sized {
+    /// The magic of the image holding this kind of entry
+    const MAGIC: u32;
+
+    fn decode(buf: &[u8]) -> io::Result<Self>;
+}
+
+/// pstree.proto: `pstree_entry`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PstreeEntry {
+    pub pid: u32,
+    pub ppid: u32,
+    pub pgid: u32,
+    pub sid: u32,
+    pub threads: Vec<u32>,
+}
+
+impl Entry for PstreeEntry {
+    const MAGIC: u32 = PSTREE_MAGIC;
+
+    fn decode(buf: &[u8]) -> io::Result<Self> {
+        let mut entry = PstreeEntry::default();
+        for field in Fields::new(buf) {
+            match field? {
+                (1, v) => entry.pid = v.as_u32()?,
+                (2, v) => entry.ppid = v.as_u32()?,
+                (3, v) => entry.pgid = v.as_u32()?,
+                (4, v) => entry.sid = v.as_u32()?,
+                (5, v) => entry.threads.push(v.as_u32()?),
+                _ => (),
+            }
+        }
+        Ok(entry)
+    }
+}
+
+/// fdinfo.proto: `fdinfo_entry`, maps an fd of a task to a file id in files.img
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FdinfoEntry {
+    pub id: u32,
+    pub flags: u32,
+    pub fd_type: u32,
+    pub fd: u32,
+}
+
+impl Entry for FdinfoEntry {
+    const MAGIC: u32 = FDINFO_MAGIC;
+
+    fn decode(buf: &[u8]) -> io::Result<Self> {
+        let mut entry = FdinfoEntry::default();
+        for field in Fields::new(buf) {
+            match field? {
+                (1, v) => entry.id = v.as_u32()?,
+                (2, v) => entry.flags = v.as_u32()?,
+                (3, v) => entry.fd_type = v.as_u32()?,
+                (4, v) => entry.fd = v.as_u32()?,
+                _ => (),
+            }
+        }
+        Ok(entry)
+    }
+}
+
+/// regfile.proto: `reg_file_entry`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RegFileEntry {
+    pub id: u32,
+    pub flags: u32,
+    pub pos: u64,
+    pub name: String,
+    pub size: Option<u64>,
+}
+
+impl RegFileEntry {
+    fn decode(buf: &[u8]) -> io::Result<Self> {
+        let mut entry = RegFileEntry::default();
+        for field in Fields::new(buf) {
+            match field? {
+                (1, v) => entry.id = v.as_u32()?,
+                (2, v) => entry.flags = v.as_u32()?,
+                (3, v) => entry.pos = v.as_u64()?,
+                (6, v) => entry.name = v.as_string()?,
+                (8, v) => entry.size = Some(v.as_u64()?),
+                _ => (),
+            }
+        }
+        Ok(entry)
+    }
+}
+
+/// pipe.proto: `pipe_entry`. `pipe_id` is the inode, as in `pipe:[pipe_id]`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PipeEntry {
+    pub id: u32,
+    pub pipe_id: u32,
+    pub flags: u32,
+}
+
+impl Entry for PipeEntry {
+    const MAGIC: u32 = PIPES_MAGIC;
+
+    fn decode(buf: &[u8]) -> io::Result<Self> {
+        let mut entry = PipeEntry::default();
+        for field in Fields::new(buf) {
+            match field? {
+                (1, v) => entry.id = v.as_u32()?,
+                (2, v) => entry.pipe_id = v.as_u32()?,
+                (3, v) => entry.flags = v.as_u32()?,
+                _ => (),
+            }
+        }
+        Ok(entry)
+    }
+}
+
+impl PipeEntry {
+    /// The name of this pipe in /proc/$pid/fd, which is also what `--inherit-fd` expects
+    pub fn key(&self) -> String {
+        format!("pipe:[{}]", self.pipe_id)
+    }
+}
+
+/// fdinfo.proto: `file_entry`. Only regular files and pipes are decoded, other kinds only carry their type.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FileEntry {
+    pub fd_type: u32,
+    pub id: u32,
+    pub reg: Option<RegFileEntry>,
+    pub pipe: Option<PipeEntry>,
+}
+
+impl Entry for FileEntry {
+    const MAGIC: u32 = FILES_MAGIC;
+
+    fn decode(buf: &[u8]) -> io::Result<Self> {
+        let mut entry = FileEntry::default();
+        for field in Fields::new(buf) {
+            match field? {
+                (1, v) => entry.fd_type = v.as_u32()?,
+                (2, v) => entry.id = v.as_u32()?,
+                (3, v) => entry.reg = Some(RegFileEntry::decode(v.as_bytes()?)?),
+                (18, v) => entry.pipe = Some(PipeEntry::decode(v.as_bytes()?)?),
+                _ => (),
+            }
+        }
+        Ok(entry)
+    }
+}
+
+/// core.proto: the parts of `core_entry` (and its `task_core_entry`) we use
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CoreEntry {
+    /// Machine type, 1 is x86_64
+    pub mtype: u32,
+    pub task_state: Option<u32>,
+    pub exit_code: Option<u32>,
+    pub comm: Option<String>,
+}
+
+impl Entry for CoreEntry {
+    const MAGIC: u32 = CORE_MAGIC;
+
+    fn decode(buf: &[u8]) -> io::Result<Self> {
+        let mut entry = CoreEntry::default();
+        for field in Fields::new(buf) {
+            match field? {
+                (1, v) => entry.mtype = v.as_u32()?,
+                (3, tc) => {
+                    for tc_field in Fields::new(tc.as_bytes()?) {
+                        match tc_field? {
+                            (1, v) => entry.task_state = Some(v.as_u32()?),
+                            (2, v) => entry.exit_code = Some(v.as_u32()?),
+                            (6, v) => entry.comm = Some(v.as_string()?),
+                            _ => (),
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+        Ok(entry)
+    }
+}
+
+/// pagemap.proto: `pagemap_entry`, a run of pages of a task's memory
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PagemapEntry {
+    pub vaddr: u64,
+    pub nr_pages: u32,
+    /// `PE_*` flags. Old images only have `in_parent`, which we map to `PE_PARENT` (or `PE_PRESENT`)
+    pub flags: u32,
+}
+
+impl Entry for PagemapEntry {
+    const MAGIC: u32 = PAGEMAP_MAGIC;
+
+    fn decode(buf: &[u8]) -> io::Result<Self> {
+        let mut entry = PagemapEntry::default();
+        let mut in_parent = false;
+        let mut flags = None;
+        for field in Fields::new(buf) {
+            match field? {
+                (1, v) => entry.vaddr = v.as_u64()?,
+                (2, v) => entry.nr_pages = v.as_u32()?,
+                (3, v) => in_parent = v.as_u64()? != 0,
+                (4, v) => flags = Some(v.as_u32()?),
+                _ => (),
+            }
+        }
+        entry.flags = flags.unwrap_or(if in_parent { PE_PARENT } else { PE_PRESENT });
+        Ok(entry)
+    }
+}
+
+impl PagemapEntry {
+    pub fn encode(&self) -> Vec<u8> {
+        Writer::new()
+            .uint(1, self.vaddr)
+            .uint(2, self.nr_pages as u64)
+            .uint(4, self.flags as u64)
+            .finish()
+    }
+}
+
+/// Splits a criu image into its raw entries, checking the magic.
+/// Each entry is a little endian u32 size followed by the encoded message.
+pub fn read_raw_entries(path: &Path, magic: u32) -> Result<Vec<Vec<u8>>, ImageError> {
+    let data = fs::read(path).map_err(|e| ImageError::Io(path.to_path_buf(), e))?;
+    let read_u32 = |offset: usize| -> Result<u32, ImageError> {
+        data.get(offset..offset + 4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .ok_or_else(|| ImageError::Truncated {
+                path: path.to_path_buf(),
+                offset,
+            })
+    };
+
+    let mut offset = 0;
+    let mut found = read_u32(offset)?;
+    offset += 4;
+    if found == IMG_COMMON_MAGIC || found == IMG_SERVICE_MAGIC {
+        // The actual image type follows the common magic
+        found = read_u32(offset)?;
+        offset += 4;
+    }
+    if found != magic {
+        return Err(ImageError::BadMagic {
+            path: path.to_path_buf(),
+            expected: magic,
+            found,
+        });
+    }
+
+    let mut entries = vec![];
+    while offset < data.len() {
+        let len = read_u32(offset)? as usize;
+        let entry =
+            data.get(offset + 4..offset + 4 + len)
+                .ok_or_else(|| ImageError::Truncated {
+                    path: path.to_path_buf(),
+                    offset,
+                })?;
+        entries.push(entry.to_vec());
+        offset += 4 + len;
+    }
+    Ok(entries)
+}
+
+/// Reads and decodes all entries of a criu image
+pub fn read_image<E: Entry>(path: &Path) -> Result<Vec<E>, ImageError> {
+    read_raw_entries(path, E::MAGIC)?
+        .iter()
+        .enumerate()
+        .map(|(i, raw)| {
+            E::decode(raw).map_err(|e| ImageError::Decode {
+                path: path.to_path_buf(),
+                entry: i,
+                reason: e.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Writes a criu image with the given raw entries, see `read_raw_entries`
+pub fn write_raw_entries(path: &Path, magic: u32, entries: &[Vec<u8>]) -> io::Result<()> {
+    let mut data = vec![];
+    data.extend_from_slice(&IMG_COMMON_MAGIC.to_le_bytes());
+    data.extend_from_slice(&magic.to_le_bytes());
+    for entry in entries {
+        data.extend_from_slice(&(entry.len() as u32).to_le_bytes());
+        data.extend_from_slice(entry);
+    }
+    fs::write(path, data)
+}
+
+/// Reads a `pagemap-*.img`: the id of its `pages-<id>.img` (from the `pagemap_head`) and its entries
+pub fn pagemap(path: &Path) -> Result<(u32, Vec<PagemapEntry>), ImageError> {
+    let raw = read_raw_entries(path, PAGEMAP_MAGIC)?;
+    let (head, entries) = raw.split_first().ok_or_else(|| ImageError::Missing {
+        path: path.to_path_buf(),
+        what: "pagemap head".to_string(),
+    })?;
+    let decode_err = |entry: usize, e: io::Error| ImageError::Decode {
+        path: path.to_path_buf(),
+        entry,
+        reason: e.to_string(),
+    };
+    let mut pages_id = 0;
+    for field in Fields::new(head) {
+        if let (1, v) = field.map_err(|e| decode_err(0, e))? {
+            pages_id = v.as_u32().map_err(|e| decode_err(0, e))?;
+        }
+    }
+    let entries = entries
+        .iter()
+        .enumerate()
+        .map(|(i, raw)| PagemapEntry::decode(raw).map_err(|e| decode_err(i + 1, e)))
+        .collect::<Result<_, _>>()?;
+    Ok((pages_id, entries))
+}
+
+/// Writes a `pagemap-*.img` referring to `pages-<pages_id>.img`
+pub fn write_pagemap(path: &Path, pages_id: u32, entries: &[PagemapEntry]) -> io::Result<()> {
+    let mut raw = vec![Writer::new().uint(1, pages_id as u64).finish()];
+    raw.extend(entries.iter().map(PagemapEntry::encode));
+    write_raw_entries(path, PAGEMAP_MAGIC, &raw)
+}
+
+/// The process tree of a snapshot, the first entry is the root task
+pub fn pstree(snapshot_dir: &Path) -> Result<Vec<PstreeEntry>, ImageError> {
+    read_image(&snapshot_dir.join("pstree.img"))
+}
+
+/// Pid of the snapshotted root task
+pub fn root_pid(snapshot_dir: &Path) -> Result<u32, ImageError> {
+    pstree(snapshot_dir)?
+        .first()
+        .map(|entry| entry.pid)
+        .ok_or_else(|| ImageError::Missing {
+            path: snapshot_dir.join("pstree.img"),
+            what: "tasks".to_string(),
+        })
+}
+
+/// All files the snapshotted tasks had open
+pub fn files(snapshot_dir: &Path) -> Result<Vec<FileEntry>, ImageError> {
+    read_image(&snapshot_dir.join("files.img"))
+}
+
+/// The fds of all tasks, read from every `fdinfo-*.img` in the snapshot
+pub fn fdinfos(snapshot_dir: &Path) -> Result<Vec<FdinfoEntry>, ImageError> {
+    let mut images: Vec<PathBuf> = fs::read_dir(snapshot_dir)
+        .map_err(|e| ImageError::Io(snapshot_dir.to_path_buf(), e))?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .map(|name| name.starts_with("fdinfo-") && name.ends_with(".img"))
+                .unwrap_or(false)
+        })
+        .collect();
+    images.sort();
+
+    let mut fdinfos = vec![];
+    for image in images {
+        fdinfos.append(&mut read_image(&image)?);
+    }
+    Ok(fdinfos)
+}
+
+/// All pipes of the snapshot. Older criu versions keep them in pipes.img, newer ones in files.img
+pub fn pipes(snapshot_dir: &Path) -> Result<Vec<PipeEntry>, ImageError> {
+    let pipes_img = snapshot_dir.join("pipes.img");
+    let mut pipes = if pipes_img.exists() {
+        read_image(&pipes_img)?
+    } else {
+        vec![]
+    };
+    pipes.extend(
+        files(snapshot_dir)?
+            .into_iter()
+            .filter_map(|file| file.pipe),
+    );
+    Ok(pipes)
+}
+
+/// The core image of the given task
+pub fn core(snapshot_dir: &Path, pid: u32) -> Result<CoreEntry, ImageError> {
+    let path = snapshot_dir.join(format!("core-{}.img", pid));
+    read_image(&path)?
+        .into_iter()
+        .next()
+        .ok_or(ImageError::Missing {
+            path,
+            what: "core entry".to_string(),
+        })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::pb::Writer;
+
+    pub fn write_image(path: &Path, magic: u32, entries: &[Vec<u8>]) {
+        write_raw_entries(path, magic, entries).unwrap();
+    }
+
+    pub fn reg_file_entry(id: u64, name: &str) -> Vec<u8> {
+        let mut reg = Writer::new();
+        reg.uint(1, id).string(6, name);
+        Writer::new()
+            .uint(1, FD_TYPE_REG as u64)
+            .uint(2, id)
+            .message(3, &reg)
+            .finish()
+    }
+
+    pub fn fdinfo_entry(id: u64, fd: u64) -> Vec<u8> {
+        Writer::new()
+            .uint(1, id)
+            .uint(2, 0)
+            .uint(3, FD_TYPE_REG as u64)
+            .uint(4, fd)
+            .finish()
+    }
+
+    #[test]
+    fn test_pstree_and_core() {
+        let dir = Path::new("/tmp/fitm_criu_images_unittest");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+
+        let task = Writer::new()
+            .uint(1, 16384)
+            .uint(2, 1)
+            .uint(5, 16384)
+            .finish();
+        write_image(&dir.join("pstree.img"), PSTREE_MAGIC, &[task]);
+        assert_eq!(root_pid(dir).unwrap(), 16384);
+
+        let mut tc = Writer::new();
+        tc.uint(1, 1).string(6, "pseudoserver");
+        let core_entry = Writer::new().uint(1, 1).message(3, &tc).finish();
+        write_image(&dir.join("core-16384.img"), CORE_MAGIC, &[core_entry]);
+        let core = core(dir, 16384).unwrap();
+        assert_eq!(core.comm.as_deref(), Some("pseudoserver"));
+        assert_eq!(core.task_state, Some(1));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_bad_images() {
+        let dir = Path::new("/tmp/fitm_criu_images_unittest_bad");
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+
+        // files.img where pstree.img is expected
+        write_image(&dir.join("pstree.img"), FILES_MAGIC, &[]);
+        assert!(matches!(pstree(dir), Err(ImageError::BadMagic { .. })));
+
+        // entry claims more bytes than there are
+        let mut data = vec![];
+        data.extend_from_slice(&PSTREE_MAGIC.to_le_bytes());
+        data.extend_from_slice(&10_u32.to_le_bytes());
+        data.push(0x08);
+        fs::write(dir.join("pstree.img"), data).unwrap();
+        assert!(matches!(pstree(dir), Err(ImageError::Truncated { .. })));
+
+        assert_eq!(
+            io::Error::from(pstree(Path::new("/nonexistent")).unwrap_err()).kind(),
+            ErrorKind::NotFound
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
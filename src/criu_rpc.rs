@@ -0,0 +1,411 @@
+use std::{
+    fs,
+    io::{self, ErrorKind},
+    mem,
+    os::unix::{
+        ffi::OsStrExt,
+        io::{AsRawFd, FromRawFd, OwnedFd},
+    },
+    path::{Path, PathBuf},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use crate::criu_images;
+use crate::pb::{Fields, Writer};
+
+/// Where `spawn_criu` starts the criu service
+pub const CRIU_SERVICE_SOCKET: &str = "/tmp/criu_service.socket";
+
+/// Responses are small, criu itself uses a few KB
+const MAX_RESPONSE_LEN: usize = 1 << 16;
+
+/// `criu_req_type` from rpc.proto
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReqType {
+    Empty = 0,
+    Dump = 1,
+    Restore = 2,
+    Check = 3,
+    PreDump = 4,
+    Version = 10,
+}
+
+impl ReqType {
+    fn from_u32(val: u32) -> Option<Self> {
+        Some(match val {
+            0 => ReqType::Empty,
+            1 => ReqType::Dump,
+            2 => ReqType::Restore,
+            3 => ReqType::Check,
+            4 => ReqType::PreDump,
+            10 => ReqType::Version,
+            _ => return None,
+        })
+    }
+}
+
+/// `criu_version` from rpc.proto
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CriuVersion {
+    pub major: i32,
+    pub minor: i32,
+    pub sublevel: Option<i32>,
+    pub gitid: Option<String>,
+}
+
+/// `criu_resp` from rpc.proto
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CriuResponse {
+    pub req_type: Option<ReqType>,
+    pub success: bool,
+    pub cr_errno: Option<i32>,
+    pub cr_errmsg: Option<String>,
+    pub version: Option<CriuVersion>,
+}
+
+impl CriuResponse {
+    fn decode(buf: &[u8]) -> io::Result<Self> {
+        let mut resp = CriuResponse::default();
+        for field in Fields::new(buf) {
+            match field? {
+                (1, v) => resp.req_type = ReqType::from_u32(v.as_u32()?),
+                (2, v) => resp.success = v.as_u64()? != 0,
+                (7, v) => resp.cr_errno = Some(v.as_u64()? as i32),
+                (9, v) => resp.cr_errmsg = Some(v.as_string()?),
+                (10, version) => {
+                    let mut ver = CriuVersion::default();
+                    for ver_field in Fields::new(version.as_bytes()?) {
+                        match ver_field? {
+                            (1, v) => ver.major = v.as_u64()? as i32,
+                            (2, v) => ver.minor = v.as_u64()? as i32,
+                            (3, v) => ver.gitid = Some(v.as_string()?),
+                            (4, v) => ver.sublevel = Some(v.as_u64()? as i32),
+                            _ => (),
+                        }
+                    }
+                    resp.version = Some(ver);
+                }
+                _ => (),
+            }
+        }
+        Ok(resp)
+    }
+
+    /// Turns an unsuccessful response into an error with whatever criu told us
+    pub fn into_result(self) -> io::Result<Self> {
+        if self.success {
+            return Ok(self);
+        }
+        Err(io::Error::other(format!(
+            "criu {:?} request failed (errno {:?}): {}",
+            self.req_type,
+            self.cr_errno,
+            self.cr_errmsg
+                .as_deref()
+                .unwrap_or("no message, check the criu log")
+        )))
+    }
+}
+
+/// Client for criu's RPC interface (`criu service`), see images/rpc.proto.
+/// FitM only asks the service for its version, to know it is up. Dumps are requested by the target itself
+/// (fitm-qemu dumps itself on the socket in `CRIU_SERVICE_SOCKET`), so FitM never gets their response
+/// and checks the service log and images instead, see `DumpStatus`. Restores run through criu's
+/// command line from `restore.sh`, see `RestorePlan`.
+#[derive(Clone, Debug)]
+pub struct CriuClient {
+    socket_path: PathBuf,
+}
+
+/// Requests without options are all FitM sends
+fn encode_request(req_type: ReqType) -> Vec<u8> {
+    Writer::new().uint(1, req_type as u64).finish()
+}
+
+fn connect_seqpacket(path: &Path) -> io::Result<OwnedFd> {
+    let fd = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_SEQPACKET, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    let path_bytes = path.as_os_str().as_bytes();
+    if path_bytes.len() >= addr.sun_path.len() {
+        return Err(io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("criu socket path {:?} is too long", path),
+        ));
+    }
+    for (dst, src) in addr.sun_path.iter_mut().zip(path_bytes) {
+        *dst = *src as libc::c_char;
+    }
+
+    let ret = unsafe {
+        libc::connect(
+            socket.as_raw_fd(),
+            &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_un>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(socket)
+}
+
+impl CriuClient {
+    pub fn new<P: AsRef<Path>>(socket_path: P) -> Self {
+        CriuClient {
+            socket_path: socket_path.as_ref().to_path_buf(),
+        }
+    }
+
+    pub fn socket_path(&self) -> &Path {
+        &self.socket_path
+    }
+
+    /// Sends a single request and waits for criu's answer
+    fn request(&self, req_type: ReqType) -> io::Result<CriuResponse> {
+        let req = encode_request(req_type);
+
+        let socket = connect_seqpacket(&self.socket_path)?;
+        let sent = unsafe {
+            libc::send(
+                socket.as_raw_fd(),
+                req.as_ptr() as *const libc::c_void,
+                req.len(),
+                0,
+            )
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut buf = vec![0_u8; MAX_RESPONSE_LEN];
+        let received = loop {
+            let ret = unsafe {
+                libc::recv(
+                    socket.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    0,
+                )
+            };
+            if ret >= 0 {
+                break ret as usize;
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() != ErrorKind::Interrupted {
+                return Err(e);
+            }
+        };
+        if received == 0 {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "criu closed the connection without answering",
+            ));
+        }
+        CriuResponse::decode(&buf[..received])
+    }
+
+    pub fn version(&self) -> io::Result<CriuVersion> {
+        self.request(ReqType::Version)?
+            .into_result()?
+            .version
+            .ok_or_else(|| io::Error::other("criu did not send a version"))
+    }
+
+    /// Waits until the service accepts requests, so targets don't try to dump before criu is listening
+    pub fn wait_until_ready(&self, timeout: Duration) -> io::Result<CriuVersion> {
+        let start = Instant::now();
+        loop {
+            match self.version() {
+                Ok(version) => return Ok(version),
+                Err(e) if start.elapsed() > timeout => {
+                    return Err(io::Error::new(
+                        e.kind(),
+                        format!(
+                            "criu service at {:?} not ready after {:?}: {}",
+                            self.socket_path, timeout, e
+                        ),
+                    ))
+                }
+                Err(_) => sleep(Duration::from_millis(20)),
+            }
+        }
+    }
+}
+
+/// Exit codes of all criu service workers, in order, parsed from the service log.
+/// Relevant lines look like this: "(00.055739) Worker(pid 43750) exited with 0"
+pub fn worker_exit_codes(service_log: &str) -> Vec<i32> {
+    service_log
+        .lines()
+        .filter_map(|line| {
+            let rest = &line[line.find("Worker(pid ")?..];
+            rest.rsplit(' ').next()?.parse().ok()
+        })
+        .collect()
+}
+
+/// Where `service_log` ends right now. Pass it to `DumpStatus::check`, so the workers of earlier
+/// requests (e.g. `wait_until_ready`) or dumps don't count.
+pub fn log_offset(service_log: &Path) -> u64 {
+    fs::metadata(service_log).map_or(0, |meta| meta.len())
+}
+
+/// Outcome of a dump that was requested by the target itself (fitm-qemu talks to the criu service directly).
+/// criu answers the target, not us, so this is how FitM learns whether a snapshot worked.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DumpStatus {
+    /// criu finished and the images are readable, holds the pid of the dumped task
+    Dumped(u32),
+    /// criu never got a dump request
+    NotRequested,
+    /// criu tried and failed, holds the exit code of the criu worker
+    WorkerFailed(i32),
+    /// criu claims success, but the images are broken
+    BadImages(String),
+}
+
+impl DumpStatus {
+    /// Figures out what happened to a dump into `images_dir`, based on what the criu service logged
+    /// after `since` (see `log_offset`) and the images.
+    pub fn check(service_log: &Path, since: u64, images_dir: &Path) -> DumpStatus {
+        let log = fs::read(service_log).unwrap_or_default();
+        // A shorter log was started over, all of it is new
+        let new = log.get(since as usize..).unwrap_or(&log);
+        match worker_exit_codes(&String::from_utf8_lossy(new)).last() {
+            None => DumpStatus::NotRequested,
+            Some(code) if *code != 0 => DumpStatus::WorkerFailed(*code),
+            Some(_) => match criu_images::root_pid(images_dir) {
+                Ok(pid) => DumpStatus::Dumped(pid),
+                Err(e) => DumpStatus::BadImages(e.to_string()),
+            },
+        }
+    }
+
+    /// Like `check`, but gives the criu service some time to notice its worker exited
+    pub fn wait_for(
+        service_log: &Path,
+        since: u64,
+        images_dir: &Path,
+        timeout: Duration,
+    ) -> DumpStatus {
+        let start = Instant::now();
+        loop {
+            let status = DumpStatus::check(service_log, since, images_dir);
+            if status != DumpStatus::NotRequested || start.elapsed() > timeout {
+                return status;
+            }
+            sleep(Duration::from_millis(20));
+        }
+    }
+
+    pub fn into_result(self) -> io::Result<u32> {
+        match self {
+            DumpStatus::Dumped(pid) => Ok(pid),
+            DumpStatus::NotRequested => Err(io::Error::other(
+                "[!] criu did not receive a dump request. Target exited early or something is broken.",
+            )),
+            DumpStatus::WorkerFailed(code) => Err(io::Error::other(format!(
+                "[!] criu dump failed with exit code {}, check the criu log",
+                code
+            ))),
+            DumpStatus::BadImages(reason) => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("[!] criu dump produced broken images: {}", reason),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_request() {
+        let req = encode_request(ReqType::Version);
+        let fields: Vec<_> = Fields::new(&req).map(|f| f.unwrap()).collect();
+        assert_eq!(fields.len(), 1);
+        assert_eq!(fields[0].0, 1);
+        assert_eq!(fields[0].1.as_u32().unwrap(), ReqType::Version as u32);
+    }
+
+    #[test]
+    fn test_decode_response() {
+        let mut version = Writer::new();
+        version.int32(1, 3).int32(2, 15);
+        let resp = Writer::new()
+            .uint(1, ReqType::Version as u64)
+            .bool(2, true)
+            .message(10, &version)
+            .finish();
+        let resp = CriuResponse::decode(&resp).unwrap();
+        assert_eq!(resp.req_type, Some(ReqType::Version));
+        assert_eq!(resp.version.unwrap().minor, 15);
+
+        let failed = Writer::new()
+            .uint(1, ReqType::Dump as u64)
+            .bool(2, false)
+            .int32(7, 1)
+            .string(9, "dump failed")
+            .finish();
+        let err = CriuResponse::decode(&failed)
+            .unwrap()
+            .into_result()
+            .unwrap_err();
+        assert!(err.to_string().contains("dump failed"));
+    }
+
+    #[test]
+    fn test_worker_exit_codes() {
+        let log = "(00.001) Starting\n(00.055739) Worker(pid 43750) exited with 0\n(01.2) Worker(pid 43751) exited with 1\n";
+        assert_eq!(worker_exit_codes(log), vec![0, 1]);
+        assert_eq!(worker_exit_codes("nothing here"), Vec::<i32>::new());
+    }
+
+    #[test]
+    fn test_dump_status() {
+        let root = Path::new("/tmp/fitm_criu_rpc_unittest");
+        let _ = fs::remove_dir_all(root);
+        fs::create_dir_all(root).unwrap();
+        let service_log = root.join("criu_stderr");
+        let images_dir = root.join("snapshot");
+
+        // The worker of `wait_until_ready` is no dump
+        fs::write(&service_log, "(00.01) Worker(pid 43750) exited with 0\n").unwrap();
+        let since = log_offset(&service_log);
+        assert_eq!(
+            DumpStatus::check(&service_log, since, &images_dir),
+            DumpStatus::NotRequested
+        );
+        assert!(matches!(
+            DumpStatus::check(&service_log, 0, &images_dir),
+            DumpStatus::BadImages(_)
+        ));
+
+        fs::write(
+            &service_log,
+            "(00.01) Worker(pid 43750) exited with 0\n(02.5) Worker(pid 43751) exited with 1\n",
+        )
+        .unwrap();
+        assert_eq!(
+            DumpStatus::wait_for(&service_log, since, &images_dir, Duration::ZERO),
+            DumpStatus::WorkerFailed(1)
+        );
+        assert_eq!(log_offset(&root.join("missing")), 0);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_missing_socket() {
+        let client = CriuClient::new("/tmp/fitm_no_such_criu.socket");
+        assert!(client.version().is_err());
+    }
+}
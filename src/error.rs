@@ -0,0 +1,149 @@
+use std::{
+    error::Error,
+    fmt,
+    io::{self, ErrorKind},
+    path::PathBuf,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::ConfigError;
+
+/// Errors of the fuzz pipeline, naming the snapshot (`state`) or file they are about.
+/// They only carry strings and paths, so failed jobs of forked workers can report them, see `run_jobs`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum FitmError {
+    /// No snapshot came out of a run of `state`
+    CriuDump { state: String, reason: String },
+    /// `state` could not be restored (or the restored target misbehaved), see `log`
+    CriuRestore {
+        state: String,
+        reason: String,
+        log: PathBuf,
+    },
+    /// afl-fuzz node `node` exited with `code` while fuzzing `state`
+    AflFuzz {
+        state: String,
+        node: String,
+        code: i32,
+    },
+    /// afl-cmin failed on the inputs of `state`
+    Cmin { state: String, reason: String },
+    /// A namespace could not be created or did not exit on its own
+    Namespace(String),
+    /// Reading or writing the files of the campaign failed
+    StateIo { context: String, reason: String },
+    /// The config or the campaign state don't fit this run
+    Config(String),
+    /// `failures` snapshots in a row were quarantined, see `report`
+    Quarantine { failures: usize, report: PathBuf },
+}
+
+impl FitmError {
+    /// A `StateIo` error, `context` says what we were doing
+    pub fn state_io(context: impl Into<String>, e: io::Error) -> Self {
+        FitmError::StateIo {
+            context: context.into(),
+            reason: e.to_string(),
+        }
+    }
+
+    /// The snapshot this error is about, if any
+    pub fn state(&self) -> Option<&str> {
+        match self {
+            FitmError::CriuDump { state, .. }
+            | FitmError::CriuRestore { state, .. }
+            | FitmError::AflFuzz { state, .. }
+            | FitmError::Cmin { state, .. } => Some(state),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for FitmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FitmError::CriuDump { state, reason } => {
+                write!(f, "criu dump of {} failed: {}", state, reason)
+            }
+            FitmError::CriuRestore { state, reason, log } => {
+                write!(f, "restoring {} failed: {} (see {:?})", state, reason, log)
+            }
+            FitmError::AflFuzz { state, node, code } => write!(
+                f,
+                "afl-fuzz node {} exited with {} while fuzzing {}",
+                node, code, state
+            ),
+            FitmError::Cmin { state, reason } => {
+                write!(f, "afl-cmin failed for {}: {}", state, reason)
+            }
+            FitmError::Namespace(reason) => write!(f, "namespace error: {}", reason),
+            FitmError::StateIo { context, reason } if context.is_empty() => {
+                write!(f, "{}", reason)
+            }
+            FitmError::StateIo { context, reason } => write!(f, "{}: {}", context, reason),
+            FitmError::Config(reason) => write!(f, "{}", reason),
+            FitmError::Quarantine { failures, report } => write!(
+                f,
+                "{} snapshots in a row failed to restore or fuzz, see {:?}",
+                failures, report
+            ),
+        }
+    }
+}
+
+impl Error for FitmError {}
+
+impl From<io::Error> for FitmError {
+    fn from(e: io::Error) -> Self {
+        FitmError::state_io("", e)
+    }
+}
+
+impl From<ConfigError> for FitmError {
+    fn from(e: ConfigError) -> Self {
+        FitmError::Config(e.to_string())
+    }
+}
+
+impl From<FitmError> for io::Error {
+    fn from(e: FitmError) -> Self {
+        let kind = match &e {
+            FitmError::Config(_) => ErrorKind::InvalidInput,
+            _ => ErrorKind::Other,
+        };
+        io::Error::new(kind, e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fitm_error() {
+        let e = FitmError::AflFuzz {
+            state: "fitm-gen3-state1".to_string(),
+            node: "sec1".to_string(),
+            code: 1,
+        };
+        assert_eq!(e.state(), Some("fitm-gen3-state1"));
+        assert_eq!(
+            e.to_string(),
+            "afl-fuzz node sec1 exited with 1 while fuzzing fitm-gen3-state1"
+        );
+        // Failed jobs of workers come back in one piece
+        let json = serde_json::to_string(&e).unwrap();
+        assert_eq!(serde_json::from_str::<FitmError>(&json).unwrap(), e);
+
+        let e = FitmError::from(io::Error::new(ErrorKind::NotFound, "gone"));
+        assert_eq!(e.state(), None);
+        assert_eq!(e.to_string(), "gone");
+        let e = FitmError::state_io("reading fitm-state.json", io::Error::other("gone"));
+        assert_eq!(e.to_string(), "reading fitm-state.json: gone");
+        assert_eq!(
+            io::Error::from(FitmError::Config("bad".to_string())).kind(),
+            ErrorKind::InvalidInput
+        );
+    }
+}
@@ -0,0 +1,407 @@
+use std::{
+    collections::HashSet,
+    fs,
+    io::{self, ErrorKind},
+    os::unix::fs::MetadataExt,
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::fsck::{parse_state_path, QUARANTINE_DIR};
+use crate::gc::GcMode;
+use crate::incremental;
+use crate::state::CampaignState;
+use crate::utils::{snapshot_weights, SnapshotWeight};
+
+/// `EvictionPolicy::Archive` moves retired snapshots here, next to `saved-states`
+pub const ARCHIVED_STATES: &str = "archived-states";
+
+/// What happens to the folder of a retired snapshot
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EvictionPolicy {
+    /// Move it to `archived-states`, it no longer counts towards the size limit
+    #[default]
+    Archive,
+    /// Remove it for good
+    Delete,
+}
+
+/// How `saved-states` grows: limits on how far and how wide the snapshot tree may grow
+/// (`None` means unlimited), how snapshots are stored and cleaned up.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Limits {
+    /// The highest generation we create snapshots for
+    pub max_depth: Option<usize>,
+    /// Snapshots per generation, the least useful ones above it get evicted
+    pub max_snapshots_per_gen: Option<usize>,
+    /// Bytes in `saved-states`, the least useful snapshots above it get evicted
+    pub max_saved_states_size: Option<u64>,
+    pub eviction: EvictionPolicy,
+    /// Garbage collection to run after each stage, see `gc::collect`
+    pub gc: Option<GcMode>,
+    /// Store new snapshots as diffs against their `base_state`, see `incremental`
+    pub incremental: bool,
+}
+
+/// The initial snapshots of gen 1 and 2 are where everything starts, they are never evicted
+pub(crate) fn evictable(generation: u32) -> bool {
+    generation > 2
+}
+
+/// Total size of all files below `path`, 0 if it does not exist.
+/// Files hard linked by `gc::compact` only take their space once, so they are counted once.
+pub fn dir_size(path: &Path) -> io::Result<u64> {
+    disk_usage(path, &mut HashSet::new())
+}
+
+fn disk_usage(path: &Path, seen: &mut HashSet<(u64, u64)>) -> io::Result<u64> {
+    let meta = match fs::symlink_metadata(path) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    if !seen.insert((meta.dev(), meta.ino())) {
+        return Ok(0);
+    }
+    if !meta.is_dir() {
+        return Ok(meta.len());
+    }
+    let mut size = 0;
+    for entry in fs::read_dir(path)? {
+        size += disk_usage(&entry?.path(), seen)?;
+    }
+    Ok(size)
+}
+
+/// The next free state id in `generation`: above every listed, saved, archived and quarantined state,
+/// so a new snapshot never takes the name of an evicted or broken one.
+pub fn next_state_id(
+    state: &CampaignState,
+    generation: usize,
+    saved_states: &Path,
+    archive: &Path,
+) -> usize {
+    let listed = state
+        .generation_snaps
+        .get(generation)
+        .into_iter()
+        .flatten()
+        .map(|snap| snap.state_id);
+    let quarantined = saved_states.join(QUARANTINE_DIR);
+    let dirs = [saved_states, archive, &quarantined];
+    let on_disk = dirs
+        .iter()
+        .filter_map(|dir| fs::read_dir(dir).ok())
+        .flatten()
+        .filter_map(|entry| parse_state_path(entry.ok()?.file_name().to_str()?))
+        .filter(|(gen, _)| *gen as usize == generation)
+        .map(|(_, id)| id);
+    listed.chain(on_disk).max().map_or(0, |id| id + 1)
+}
+
+/// The evictable snapshot with the lowest `SnapshotWeight`, in `generation` or in all of them.
+/// Bases of listed snapshots are skipped, their children could not be restored without them.
+fn least_useful(
+    state: &CampaignState,
+    generation: Option<usize>,
+    saved_states: &Path,
+) -> Option<String> {
+    let mut victim: Option<(f64, &str)> = None;
+    for (gen, snaps) in state.generation_snaps.iter().enumerate() {
+        if generation.is_some_and(|generation| generation != gen) {
+            continue;
+        }
+        let weights = snapshot_weights(saved_states, snaps, &state.history);
+        for (snap, weight) in snaps.iter().zip(weights.iter().map(SnapshotWeight::weight)) {
+            if evictable(snap.generation)
+                && victim.is_none_or(|(lowest, _)| weight < lowest)
+                && !state.is_base(&snap.state_path)
+            {
+                victim = Some((weight, &snap.state_path));
+            }
+        }
+    }
+    victim.map(|(_, state_path)| state_path.to_string())
+}
+
+/// Drops `state_path` (and its history) from `state`, then archives or deletes its folder.
+/// Snapshots stored as diffs against it are flattened first.
+pub(crate) fn evict(
+    state: &mut CampaignState,
+    state_path: &str,
+    policy: EvictionPolicy,
+    saved_states: &Path,
+    archive: &Path,
+) -> io::Result<()> {
+    for snaps in state.generation_snaps.iter_mut() {
+        snaps.retain(|snap| snap.state_path != state_path);
+    }
+    state.history.remove(state_path);
+
+    for dependent in incremental::dependents(saved_states, state_path)? {
+        incremental::flatten(&saved_states.join(&dependent).join("snapshot"))?;
+    }
+
+    let dir = saved_states.join(state_path);
+    let res = match policy {
+        EvictionPolicy::Archive => {
+            // The parent link would not resolve in the archive
+            if dir.is_dir() {
+                incremental::flatten(&dir.join("snapshot"))?;
+            }
+            fs::create_dir_all(archive)?;
+            let dest = archive.join(state_path);
+            if dest.exists() {
+                fs::remove_dir_all(&dest)?;
+            }
+            fs::rename(&dir, &dest)
+        }
+        EvictionPolicy::Delete => fs::remove_dir_all(&dir),
+    };
+    match res {
+        Ok(()) => (),
+        Err(e) if e.kind() == ErrorKind::NotFound => (),
+        Err(e) => return Err(e),
+    }
+    println!("[*] Evicted {} ({:?})", state_path, policy);
+    Ok(())
+}
+
+impl Limits {
+    /// Whether we may create snapshots for `generation`
+    pub fn allows_gen(&self, generation: usize) -> bool {
+        self.max_depth.is_none_or(|max| generation <= max)
+    }
+
+    /// Evicts the least useful snapshots until every generation and `saved_states` are within the limits.
+    /// Returns the evicted state paths.
+    pub fn enforce(
+        &self,
+        state: &mut CampaignState,
+        saved_states: &Path,
+        archive: &Path,
+    ) -> io::Result<Vec<String>> {
+        let mut evicted = vec![];
+
+        if let Some(max) = self.max_snapshots_per_gen {
+            for gen in 0..state.generation_snaps.len() {
+                while state.generation_snaps[gen].len() > max {
+                    match least_useful(state, Some(gen), saved_states) {
+                        Some(victim) => {
+                            evict(state, &victim, self.eviction, saved_states, archive)?;
+                            evicted.push(victim);
+                        }
+                        None => break,
+                    }
+                }
+            }
+        }
+
+        if let Some(max) = self.max_saved_states_size {
+            let mut size = dir_size(saved_states)?;
+            while size > max {
+                let victim = match least_useful(state, None, saved_states) {
+                    Some(victim) => victim,
+                    None => {
+                        println!(
+                            "[!] {:?} takes {} bytes, more than the limit of {}, but nothing is left to evict",
+                            saved_states, size, max
+                        );
+                        break;
+                    }
+                };
+                evict(state, &victim, self.eviction, saved_states, archive)?;
+                evicted.push(victim);
+                // Flattening its diff children takes space, too
+                size = dir_size(saved_states)?;
+            }
+        }
+
+        Ok(evicted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::criu_images::tests::{fdinfo_entry, reg_file_entry, write_image};
+    use crate::criu_images::{FDINFO_MAGIC, FILES_MAGIC};
+    use crate::restore::RestorePlan;
+    use crate::toolchain::Toolchain;
+    use crate::workspace::Workspace;
+    use crate::{test_snapshot, FITMSnapshot};
+
+    #[test]
+    fn test_enforce_limits() {
+        let root = Path::new("/tmp/fitm_evict_unittest");
+        let _ = fs::remove_dir_all(root);
+        let saved_states = root.join("saved-states");
+        let archive = root.join("archived-states");
+
+        let snaps = vec![
+            vec![],
+            vec![test_snapshot(1, 0)],
+            vec![test_snapshot(2, 0)],
+            vec![
+                test_snapshot(3, 0),
+                test_snapshot(3, 1),
+                test_snapshot(3, 2),
+            ],
+        ];
+        for snap in snaps.iter().flatten() {
+            let dir = saved_states.join(&snap.state_path);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("snapshot"), vec![0u8; 1000]).unwrap();
+        }
+        let mut state = CampaignState::new(1, snaps);
+        // Often fuzzed snapshots are the least useful, crashes make them more useful
+        for (state_path, runs) in [("fitm-gen3-state0", 4), ("fitm-gen3-state1", 8)] {
+            for _ in 0..runs {
+                state
+                    .history
+                    .entry(state_path.to_string())
+                    .or_default()
+                    .add_run(Duration::from_secs(1), 10, 0, 0, 0);
+            }
+        }
+        assert_eq!(dir_size(&saved_states).unwrap(), 5000);
+        // Hard links take no extra space
+        let linked = saved_states.join("fitm-gen3-state0");
+        fs::hard_link(linked.join("snapshot"), linked.join("snapshot-link")).unwrap();
+        assert_eq!(dir_size(&saved_states).unwrap(), 5000);
+        assert_eq!(next_state_id(&state, 3, &saved_states, &archive), 3);
+
+        let limits = Limits {
+            max_depth: Some(3),
+            max_snapshots_per_gen: Some(2),
+            ..Limits::default()
+        };
+        assert!(limits.allows_gen(3));
+        assert!(!limits.allows_gen(4));
+        assert_eq!(
+            limits.enforce(&mut state, &saved_states, &archive).unwrap(),
+            vec!["fitm-gen3-state1"]
+        );
+        assert!(!state.history.contains_key("fitm-gen3-state1"));
+        assert!(archive.join("fitm-gen3-state1/snapshot").is_file());
+        // The archived id is not reused
+        assert_eq!(next_state_id(&state, 3, &saved_states, &archive), 3);
+
+        let limits = Limits {
+            max_saved_states_size: Some(2500),
+            eviction: EvictionPolicy::Delete,
+            ..Limits::default()
+        };
+        assert_eq!(
+            limits.enforce(&mut state, &saved_states, &archive).unwrap(),
+            vec!["fitm-gen3-state0", "fitm-gen3-state2"]
+        );
+        assert!(!saved_states.join("fitm-gen3-state0").exists());
+        assert!(!archive.join("fitm-gen3-state0").exists());
+        // The initial snapshots stay, even above the limit
+        let limits = Limits {
+            max_saved_states_size: Some(0),
+            ..limits
+        };
+        assert!(limits
+            .enforce(&mut state, &saved_states, &archive)
+            .unwrap()
+            .is_empty());
+        assert_eq!(state.generation_snaps[1].len(), 1);
+        assert_eq!(state.generation_snaps[2].len(), 1);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_keep_bases() {
+        let root = Path::new("/tmp/fitm_evict_unittest_bases");
+        let _ = fs::remove_dir_all(root);
+        let workspace = Workspace::new(root);
+        let archive = root.join(ARCHIVED_STATES);
+
+        let child = FITMSnapshot {
+            base_state: "fitm-gen3-state0".to_string(),
+            ..test_snapshot(5, 0)
+        };
+        let snaps = vec![
+            vec![],
+            vec![test_snapshot(1, 0)],
+            vec![test_snapshot(2, 0)],
+            vec![test_snapshot(3, 0), test_snapshot(3, 1)],
+            vec![],
+            vec![child.clone()],
+        ];
+        for snap in snaps.iter().flatten() {
+            fs::create_dir_all(workspace.saved_state(&snap.state_path).join("snapshot")).unwrap();
+        }
+        // The child's restore reads the fds its base had open
+        let base_snapshot = workspace.saved_state("fitm-gen3-state0").join("snapshot");
+        write_image(
+            &base_snapshot.join("files.img"),
+            FILES_MAGIC,
+            &[reg_file_entry(1, "/work/active-state/fd/0")],
+        );
+        write_image(
+            &base_snapshot.join("fdinfo-2.img"),
+            FDINFO_MAGIC,
+            &[fdinfo_entry(1, 3)],
+        );
+        let mut state = CampaignState::new(1, snaps);
+        // The base is the least useful snapshot of its generation
+        for _ in 0..8 {
+            state
+                .history
+                .entry("fitm-gen3-state0".to_string())
+                .or_default()
+                .add_run(Duration::from_secs(1), 10, 0, 0, 0);
+        }
+        assert!(state.is_base("fitm-gen3-state0"));
+        assert!(!state.is_base("fitm-gen3-state1"));
+
+        let limits = Limits {
+            max_snapshots_per_gen: Some(1),
+            ..Limits::default()
+        };
+        assert_eq!(
+            limits
+                .enforce(&mut state, &workspace.saved_states, &archive)
+                .unwrap(),
+            vec!["fitm-gen3-state1"]
+        );
+
+        fs::create_dir_all(&workspace.active_state).unwrap();
+        fs::write(
+            workspace.active_state.join("pipes"),
+            "lrwx------ 198 -> pipe:[1337]\nl-wx------ 199 -> pipe:[1338]\n",
+        )
+        .unwrap();
+        let tools = Toolchain {
+            afl_fuzz: "afl-fuzz".into(),
+            afl_cmin: "afl-cmin".into(),
+            qemu_trace: "fitm-qemu-trace".into(),
+            criu: "criu".into(),
+        };
+        let plan = RestorePlan::for_snapshot(&child, &tools, &workspace).unwrap();
+        assert!(plan.inherit_fds.iter().any(|fd| fd.fd == 3));
+
+        // Once the child is gone, its base may go, too
+        let limits = Limits {
+            max_saved_states_size: Some(0),
+            eviction: EvictionPolicy::Delete,
+            ..Limits::default()
+        };
+        assert_eq!(
+            limits
+                .enforce(&mut state, &workspace.saved_states, &archive)
+                .unwrap(),
+            vec!["fitm-gen5-state0", "fitm-gen3-state0"]
+        );
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
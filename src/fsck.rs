@@ -0,0 +1,503 @@
+use std::{
+    collections::HashSet,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use regex::Regex;
+use termion::{color, style};
+
+use crate::incremental;
+use crate::state::CampaignState;
+use crate::state_path_for;
+
+/// Broken states are moved here (inside `saved-states`) instead of being deleted
+pub const QUARANTINE_DIR: &str = ".quarantine";
+
+/// Everything a saved state needs to be restored and fuzzed
+pub const REQUIRED_ENTRIES: [&str; 4] = ["snapshot", "pipes", "fd", "outputs"];
+
+/// What to do about the issues we find
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FsckMode {
+    /// Only print the issues
+    Report,
+    /// Fix `fitm-state.json`: drop broken entries, fix their ids. Never touches the folders.
+    Repair,
+    /// Like `Repair`, but also move orphaned and broken folders to `saved-states/.quarantine`
+    Quarantine,
+}
+
+/// One inconsistency between `fitm-state.json` and `saved-states`
+#[derive(Clone, Debug, PartialEq)]
+pub enum Issue {
+    /// A state folder no generation refers to
+    Orphan(String),
+    /// An entry without a state folder
+    MissingFolder(String),
+    /// An entry whose folder lacks some of `REQUIRED_ENTRIES`
+    Incomplete {
+        state_path: String,
+        missing: Vec<&'static str>,
+    },
+    /// `generation`/`state_id` (or the generation it's listed in) don't match `state_path`
+    Mismatch {
+        state_path: String,
+        generation: u32,
+        state_id: usize,
+        listed_in: usize,
+    },
+    /// The same `state_path` is listed more than once
+    Duplicate(String),
+    /// An entry whose `base_state` has no state folder, it can't be restored without it
+    MissingBase {
+        state_path: String,
+        base_state: String,
+    },
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Issue::Orphan(dir) => write!(f, "{} is not referenced by any generation", dir),
+            Issue::MissingFolder(state_path) => write!(f, "{} has no state folder", state_path),
+            Issue::Incomplete {
+                state_path,
+                missing,
+            } => write!(f, "{} lacks {}", state_path, missing.join(", ")),
+            Issue::Mismatch {
+                state_path,
+                generation,
+                state_id,
+                listed_in,
+            } => write!(
+                f,
+                "{} is listed in gen {} as generation {}, state {}",
+                state_path, listed_in, generation, state_id
+            ),
+            Issue::Duplicate(state_path) => write!(f, "{} is listed more than once", state_path),
+            Issue::MissingBase {
+                state_path,
+                base_state,
+            } => write!(
+                f,
+                "{} was created from {}, which has no state folder",
+                state_path, base_state
+            ),
+        }
+    }
+}
+
+/// Parses `fitm-gen{generation}-state{state_id}`
+pub fn parse_state_path(state_path: &str) -> Option<(u32, usize)> {
+    let re = Regex::new(r"^fitm-gen(\d+)-state(\d+)$").unwrap();
+    let caps = re.captures(state_path)?;
+    Some((caps[1].parse().ok()?, caps[2].parse().ok()?))
+}
+
+/// Finds all inconsistencies between `state` and the folders in `saved_states`
+pub fn check(state: &CampaignState, saved_states: &Path) -> io::Result<Vec<Issue>> {
+    let mut issues = vec![];
+    let mut seen = HashSet::new();
+
+    for (listed_in, snaps) in state.generation_snaps.iter().enumerate() {
+        for snap in snaps {
+            if snap.state_path != state_path_for(snap.generation, snap.state_id)
+                || snap.generation as usize != listed_in
+            {
+                issues.push(Issue::Mismatch {
+                    state_path: snap.state_path.clone(),
+                    generation: snap.generation,
+                    state_id: snap.state_id,
+                    listed_in,
+                });
+            }
+            // Duplicates can be misplaced, too, report that before skipping them
+            if !seen.insert(snap.state_path.as_str()) {
+                issues.push(Issue::Duplicate(snap.state_path.clone()));
+                continue;
+            }
+            let dir = saved_states.join(&snap.state_path);
+            if !dir.is_dir() {
+                issues.push(Issue::MissingFolder(snap.state_path.clone()));
+                continue;
+            }
+            let missing: Vec<&'static str> = REQUIRED_ENTRIES
+                .iter()
+                .filter(|entry| !dir.join(entry).exists())
+                .copied()
+                .collect();
+            if !missing.is_empty() {
+                issues.push(Issue::Incomplete {
+                    state_path: snap.state_path.clone(),
+                    missing,
+                });
+            }
+            if !snap.base_state.is_empty() && !saved_states.join(&snap.base_state).is_dir() {
+                issues.push(Issue::MissingBase {
+                    state_path: snap.state_path.clone(),
+                    base_state: snap.base_state.clone(),
+                });
+            }
+        }
+    }
+
+    let mut dirs: Vec<String> = fs::read_dir(saved_states)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        // Skip the quarantine and other hidden folders
+        .filter(|name| !name.starts_with('.'))
+        .collect();
+    dirs.sort();
+    for dir in dirs {
+        // Gen 0 only holds the initial client outputs, it never has snapshots
+        let is_gen0 = matches!(parse_state_path(&dir), Some((0, _)));
+        if !is_gen0 && !seen.contains(dir.as_str()) {
+            issues.push(Issue::Orphan(dir));
+        }
+    }
+
+    Ok(issues)
+}
+
+/// Moves the folder `name` of `saved_states` to `QUARANTINE_DIR`, replacing an older one of that name.
+/// Like `evict`, snapshots stored as diffs against it are flattened first.
+pub(crate) fn quarantine(saved_states: &Path, name: &str) -> io::Result<PathBuf> {
+    for dependent in incremental::dependents(saved_states, name)? {
+        incremental::flatten(&saved_states.join(&dependent).join("snapshot"))?;
+    }
+    // The relative parent link would not resolve in the quarantine, but its pages may be broken, too
+    let snapshot = saved_states.join(name).join("snapshot");
+    if snapshot.is_dir() {
+        if let Err(e) = incremental::flatten(&snapshot) {
+            println!(
+                "[!] Could not flatten {} before quarantining it: {}",
+                name, e
+            );
+        }
+    }
+    let quarantine_dir = saved_states.join(QUARANTINE_DIR);
+    fs::create_dir_all(&quarantine_dir)?;
+    let to = quarantine_dir.join(name);
+    let _ = fs::remove_dir_all(&to);
+    fs::rename(saved_states.join(name), &to)?;
+    Ok(to)
+}
+
+/// Fixes the given issues according to `mode`. Returns true if `state` was changed.
+pub fn fix(
+    state: &mut CampaignState,
+    saved_states: &Path,
+    issues: &[Issue],
+    mode: FsckMode,
+) -> io::Result<bool> {
+    if mode == FsckMode::Report {
+        return Ok(false);
+    }
+    let mut changed = false;
+    let mut drop = HashSet::new();
+
+    for issue in issues {
+        match issue {
+            Issue::Orphan(dir) if mode == FsckMode::Quarantine => {
+                println!(
+                    "[*] Quarantined {} to {:?}",
+                    dir,
+                    quarantine(saved_states, dir)?
+                );
+            }
+            Issue::Orphan(_) => (),
+            Issue::MissingFolder(state_path) => {
+                drop.insert(state_path.clone());
+            }
+            Issue::Incomplete { state_path, .. } | Issue::MissingBase { state_path, .. } => {
+                drop.insert(state_path.clone());
+                if mode == FsckMode::Quarantine {
+                    println!(
+                        "[*] Quarantined {} to {:?}",
+                        state_path,
+                        quarantine(saved_states, state_path)?
+                    );
+                }
+            }
+            Issue::Duplicate(state_path) => {
+                // Keep the first entry only
+                let mut first = true;
+                for snaps in state.generation_snaps.iter_mut() {
+                    snaps.retain(|snap| {
+                        let keep = snap.state_path != *state_path || first;
+                        first &= snap.state_path != *state_path;
+                        keep
+                    });
+                }
+                changed = true;
+            }
+            Issue::Mismatch { state_path, .. } => match parse_state_path(state_path) {
+                // The folder name is what counts, the entry follows it
+                Some((generation, state_id)) => {
+                    let mut moved = vec![];
+                    for snaps in state.generation_snaps.iter_mut() {
+                        let (mismatched, rest) = snaps
+                            .drain(..)
+                            .partition(|snap| snap.state_path == *state_path);
+                        *snaps = rest;
+                        moved.extend(mismatched);
+                    }
+                    let gen = generation as usize;
+                    if state.generation_snaps.len() <= gen {
+                        state.generation_snaps.resize(gen + 1, vec![]);
+                    }
+                    for mut snap in moved {
+                        snap.generation = generation;
+                        snap.state_id = state_id;
+                        state.generation_snaps[gen].push(snap);
+                    }
+                    changed = true;
+                }
+                None => {
+                    drop.insert(state_path.clone());
+                }
+            },
+        }
+    }
+
+    // Snapshots created from dropped ones can't be restored anymore
+    for state_path in drop.clone() {
+        for child in state.descendants(&state_path) {
+            if drop.insert(child.clone())
+                && mode == FsckMode::Quarantine
+                && saved_states.join(&child).is_dir()
+            {
+                println!(
+                    "[*] Quarantined {} to {:?}, it descends from {}",
+                    child,
+                    quarantine(saved_states, &child)?,
+                    state_path
+                );
+            }
+        }
+    }
+
+    if !drop.is_empty() {
+        for snaps in state.generation_snaps.iter_mut() {
+            snaps.retain(|snap| !drop.contains(&snap.state_path));
+        }
+        for state_path in &drop {
+            state.history.remove(state_path);
+        }
+        changed = true;
+    }
+    Ok(changed)
+}
+
+/// Checks `state` against `saved_states`, prints all issues and fixes them according to `mode`.
+/// Returns the issues found.
+pub fn reconcile(
+    state: &mut CampaignState,
+    saved_states: &Path,
+    mode: FsckMode,
+) -> io::Result<Vec<Issue>> {
+    let issues = check(state, saved_states)?;
+    for issue in &issues {
+        println!(
+            "{}[!] fsck: {}{}",
+            color::Fg(color::Yellow),
+            issue,
+            style::Reset
+        );
+    }
+    fix(state, saved_states, &issues, mode)?;
+    Ok(issues)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_snapshot, FITMSnapshot};
+
+    fn state_dir(root: &Path, name: &str, entries: &[&str]) {
+        fs::create_dir_all(root.join(name)).unwrap();
+        for entry in entries {
+            match *entry {
+                "pipes" => fs::write(root.join(name).join(entry), "").unwrap(),
+                _ => fs::create_dir_all(root.join(name).join(entry)).unwrap(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_check_and_fix() {
+        let root = Path::new("/tmp/fitm_fsck_unittest");
+        let _ = fs::remove_dir_all(root);
+        state_dir(root, "fitm-gen0-state0", &["outputs"]);
+        state_dir(root, "fitm-gen1-state0", &REQUIRED_ENTRIES);
+        state_dir(root, "fitm-gen3-state0", &REQUIRED_ENTRIES);
+        state_dir(root, "fitm-gen3-state1", &["snapshot", "fd"]);
+        state_dir(root, "fitm-gen5-state0", &REQUIRED_ENTRIES);
+        state_dir(root, "fitm-gen7-state0", &REQUIRED_ENTRIES);
+
+        let mut state = CampaignState::new(
+            1,
+            vec![
+                vec![],
+                vec![test_snapshot(1, 0)],
+                vec![test_snapshot(2, 0)],
+                vec![
+                    test_snapshot(3, 0),
+                    test_snapshot(3, 1),
+                    test_snapshot(1, 0),
+                ],
+                // Wrong state_id
+                vec![FITMSnapshot {
+                    state_path: "fitm-gen3-state0".into(),
+                    ..test_snapshot(3, 7)
+                }],
+                vec![],
+                vec![],
+                // Created from the incomplete gen3-state1
+                vec![FITMSnapshot {
+                    base_state: "fitm-gen3-state1".into(),
+                    ..test_snapshot(7, 0)
+                }],
+            ],
+        );
+
+        let issues = check(&state, root).unwrap();
+        assert_eq!(
+            issues,
+            vec![
+                Issue::MissingFolder("fitm-gen2-state0".into()),
+                Issue::Incomplete {
+                    state_path: "fitm-gen3-state1".into(),
+                    missing: vec!["pipes", "outputs"]
+                },
+                Issue::Mismatch {
+                    state_path: "fitm-gen1-state0".into(),
+                    generation: 1,
+                    state_id: 0,
+                    listed_in: 3
+                },
+                Issue::Duplicate("fitm-gen1-state0".into()),
+                Issue::Mismatch {
+                    state_path: "fitm-gen3-state0".into(),
+                    generation: 3,
+                    state_id: 7,
+                    listed_in: 4
+                },
+                Issue::Duplicate("fitm-gen3-state0".into()),
+                Issue::Orphan("fitm-gen5-state0".into()),
+            ]
+        );
+
+        let mut report = state.clone();
+        assert!(!fix(&mut report, root, &issues, FsckMode::Report).unwrap());
+
+        assert!(fix(&mut state, root, &issues, FsckMode::Quarantine).unwrap());
+        assert!(root.join(QUARANTINE_DIR).join("fitm-gen5-state0").is_dir());
+        assert!(root.join(QUARANTINE_DIR).join("fitm-gen3-state1").is_dir());
+        assert!(root.join(QUARANTINE_DIR).join("fitm-gen7-state0").is_dir());
+        assert!(check(&state, root).unwrap().is_empty());
+        let paths: Vec<Vec<&str>> = state
+            .generation_snaps
+            .iter()
+            .map(|snaps| snaps.iter().map(|s| s.state_path.as_str()).collect())
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                vec![],
+                vec!["fitm-gen1-state0"],
+                vec![],
+                vec!["fitm-gen3-state0"],
+                vec![],
+                vec![],
+                vec![],
+                vec![]
+            ]
+        );
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_fix_mismatch() {
+        let root = Path::new("/tmp/fitm_fsck_unittest_mismatch");
+        let _ = fs::remove_dir_all(root);
+        state_dir(root, "fitm-gen3-state2", &REQUIRED_ENTRIES);
+
+        let mut state = CampaignState::new(
+            1,
+            vec![
+                vec![],
+                vec![FITMSnapshot {
+                    state_path: "fitm-gen3-state2".into(),
+                    ..test_snapshot(3, 0)
+                }],
+            ],
+        );
+        let issues = check(&state, root).unwrap();
+        assert_eq!(
+            issues,
+            vec![Issue::Mismatch {
+                state_path: "fitm-gen3-state2".into(),
+                generation: 3,
+                state_id: 0,
+                listed_in: 1
+            }]
+        );
+        assert!(fix(&mut state, root, &issues, FsckMode::Repair).unwrap());
+        assert!(state.generation_snaps[1].is_empty());
+        assert_eq!(state.generation_snaps[3][0].state_id, 2);
+        assert!(check(&state, root).unwrap().is_empty());
+        assert_eq!(parse_state_path("fitm-gen12-state3"), Some((12, 3)));
+        assert_eq!(parse_state_path("something-else"), None);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_missing_base() {
+        let root = Path::new("/tmp/fitm_fsck_unittest_base");
+        let _ = fs::remove_dir_all(root);
+        state_dir(root, "fitm-gen5-state0", &REQUIRED_ENTRIES);
+        state_dir(root, "fitm-gen7-state0", &REQUIRED_ENTRIES);
+
+        let mut state = CampaignState::new(
+            1,
+            vec![
+                vec![],
+                vec![],
+                vec![],
+                vec![],
+                vec![],
+                vec![FITMSnapshot {
+                    base_state: "fitm-gen3-state0".into(),
+                    ..test_snapshot(5, 0)
+                }],
+                vec![],
+                vec![FITMSnapshot {
+                    base_state: "fitm-gen5-state0".into(),
+                    ..test_snapshot(7, 0)
+                }],
+            ],
+        );
+        let issues = check(&state, root).unwrap();
+        assert_eq!(
+            issues,
+            vec![Issue::MissingBase {
+                state_path: "fitm-gen5-state0".into(),
+                base_state: "fitm-gen3-state0".into()
+            }]
+        );
+
+        // Its child goes with it
+        assert!(fix(&mut state, root, &issues, FsckMode::Quarantine).unwrap());
+        assert!(state.generation_snaps.iter().all(Vec::is_empty));
+        assert!(root.join(QUARANTINE_DIR).join("fitm-gen5-state0").is_dir());
+        assert!(root.join(QUARANTINE_DIR).join("fitm-gen7-state0").is_dir());
+        assert!(check(&state, root).unwrap().is_empty());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
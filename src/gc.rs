@@ -0,0 +1,422 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    fs::{self, File},
+    hash::Hasher,
+    io::{self, BufRead, BufReader},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::evict::{dir_size, evict, evictable, EvictionPolicy};
+use crate::state::CampaignState;
+use crate::trace_window;
+use crate::utils::{afl_nodes, read_map_edges};
+
+/// AFL output dirs in a state folder whose nodes' queues are no longer needed once the outputs of
+/// a finished fuzz run have been made. Crashes, hangs and `fuzzer_stats` are kept.
+pub const PRUNABLE: [&str; 2] = ["out_postrun", "out"];
+/// The cminned corpus the next fuzz run of a snapshot starts from, it's never pruned
+pub const CORPUS: &str = "out/main/queue";
+
+/// Which steps `collect` runs
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GcMode {
+    /// Prune, retire and compact
+    All,
+    /// Remove the queues in the `PRUNABLE` dirs of all fuzzed snapshots, but their `CORPUS`
+    Prune,
+    /// Evict snapshots whose trace is covered by another snapshot
+    Retire,
+    /// Hard link identical CRIU page images across snapshots
+    Compact,
+}
+
+impl GcMode {
+    fn runs(&self, step: GcMode) -> bool {
+        *self == GcMode::All || *self == step
+    }
+}
+
+/// What a garbage collection freed
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GcReport {
+    /// Bytes of pruned fuzz results
+    pub pruned: u64,
+    /// Snapshots retired because their traces are covered by others
+    pub retired: Vec<String>,
+    /// Bytes saved by hard linking identical page images
+    pub compacted: u64,
+}
+
+/// Removes the queues of every node in the `PRUNABLE` dirs of `state_path`, except for the `CORPUS`.
+/// Returns the bytes freed.
+pub fn prune(saved_states: &Path, state_path: &str) -> io::Result<u64> {
+    let state_dir = saved_states.join(state_path);
+    let mut freed = 0;
+    for out in PRUNABLE {
+        for node in afl_nodes(&state_dir.join(out)) {
+            let queue = node.join("queue");
+            if queue.is_dir() && queue != state_dir.join(CORPUS) {
+                freed += dir_size(&queue)?;
+                fs::remove_dir_all(&queue)?;
+            }
+        }
+    }
+    Ok(freed)
+}
+
+/// Snapshots whose `snapshot_map` edges are all hit by another snapshot in the `get_traces` window.
+/// Of two snapshots with the same edges, the older one is kept. Initial snapshots and bases of
+/// listed snapshots (see `CampaignState::is_base`) are never covered.
+pub fn covered_snapshots(state: &CampaignState, saved_states: &Path) -> Vec<String> {
+    let maps: Vec<(u32, &str, HashSet<String>)> = state
+        .generation_snaps
+        .iter()
+        .flatten()
+        .filter_map(|snap| {
+            let edges = read_map_edges(&saved_states.join(&snap.state_path).join("snapshot_map"))?;
+            Some((snap.generation, snap.state_path.as_str(), edges))
+        })
+        .collect();
+
+    let mut covered: Vec<String> = vec![];
+    for (i, (generation, state_path, edges)) in maps.iter().enumerate() {
+        if !evictable(*generation) || state.is_base(state_path) {
+            continue;
+        }
+        let window = trace_window(*generation);
+        let is_covered = maps
+            .iter()
+            .enumerate()
+            .any(|(j, (other_gen, other, other_edges))| {
+                i != j
+                && window.contains(other_gen)
+                && !covered.iter().any(|retired| retired == other)
+                && edges.is_subset(other_edges)
+                // Equal traces: only the later snapshot is covered
+                && (edges.len() < other_edges.len() || j < i)
+            });
+        if is_covered {
+            covered.push(state_path.to_string());
+        }
+    }
+    covered
+}
+
+/// `compact` remembers the page image hashes of earlier runs here, in `saved-states`
+pub const PAGE_HASHES: &str = ".page-hashes";
+
+/// Content hash of a file, to find candidates for `compact`
+fn file_hash(path: &Path) -> io::Result<u64> {
+    let mut hasher = DefaultHasher::new();
+    let mut reader = BufReader::new(File::open(path)?);
+    loop {
+        let chunk = reader.fill_buf()?;
+        if chunk.is_empty() {
+            return Ok(hasher.finish());
+        }
+        hasher.write(chunk);
+        let len = chunk.len();
+        reader.consume(len);
+    }
+}
+
+/// Whether the files `a` and `b` have the same content, without reading either of them in full
+fn same_content(a: &Path, b: &Path) -> io::Result<bool> {
+    let mut a = BufReader::new(File::open(a)?);
+    let mut b = BufReader::new(File::open(b)?);
+    loop {
+        let (chunk_a, chunk_b) = (a.fill_buf()?, b.fill_buf()?);
+        if chunk_a.is_empty() || chunk_b.is_empty() {
+            return Ok(chunk_a.is_empty() && chunk_b.is_empty());
+        }
+        let len = chunk_a.len().min(chunk_b.len());
+        if chunk_a[..len] != chunk_b[..len] {
+            return Ok(false);
+        }
+        a.consume(len);
+        b.consume(len);
+    }
+}
+
+/// Hashes of the page images `compact` has seen, by inode and modification. Images are only ever
+/// written once, so an image with a known key does not need to be read again.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct PageHashes(HashMap<String, u64>);
+
+impl PageHashes {
+    /// The hashes saved in `saved_states`, none if there are none or they're unreadable
+    fn load(saved_states: &Path) -> Self {
+        fs::read(saved_states.join(PAGE_HASHES))
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self, saved_states: &Path) -> io::Result<()> {
+        fs::write(saved_states.join(PAGE_HASHES), serde_json::to_vec(self)?)
+    }
+
+    fn key(meta: &fs::Metadata) -> String {
+        format!(
+            "{}:{}:{}.{}:{}",
+            meta.dev(),
+            meta.ino(),
+            meta.mtime(),
+            meta.mtime_nsec(),
+            meta.len()
+        )
+    }
+}
+
+/// Replaces CRIU page images (`snapshot/pages-*.img`) that are identical across snapshots
+/// by hard links to a single copy. Nothing writes to saved images, so sharing them is safe.
+/// Only images that are new since the last run are hashed, see `PAGE_HASHES`.
+/// Returns the bytes saved.
+pub fn compact(state: &CampaignState, saved_states: &Path) -> io::Result<u64> {
+    let known = PageHashes::load(saved_states);
+    let mut hashes = PageHashes::default();
+    let mut seen: HashMap<(u64, u64), Vec<PathBuf>> = HashMap::new();
+    let mut saved = 0;
+
+    for snap in state.generation_snaps.iter().flatten() {
+        let snapshot_dir = saved_states.join(&snap.state_path).join("snapshot");
+        let mut pages: Vec<PathBuf> = match fs::read_dir(&snapshot_dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| {
+                    path.is_file()
+                        && path
+                            .file_name()
+                            .and_then(|name| name.to_str())
+                            .is_some_and(|name| {
+                                name.starts_with("pages-") && name.ends_with(".img")
+                            })
+                })
+                .collect(),
+            Err(_) => continue,
+        };
+        pages.sort();
+
+        for page in pages {
+            let meta = fs::metadata(&page)?;
+            if meta.len() == 0 {
+                continue;
+            }
+            let key = PageHashes::key(&meta);
+            let hash = match known.0.get(&key).or_else(|| hashes.0.get(&key)) {
+                Some(hash) => *hash,
+                None => file_hash(&page)?,
+            };
+            let candidates = seen.entry((meta.len(), hash)).or_default();
+            let mut linked = false;
+            for candidate in candidates.iter() {
+                let candidate_meta = fs::metadata(candidate)?;
+                if candidate_meta.dev() == meta.dev() && candidate_meta.ino() == meta.ino() {
+                    // Already shared
+                    linked = true;
+                    break;
+                }
+                if same_content(candidate, &page)? {
+                    let tmp = page.with_extension("img.gc");
+                    let _ = fs::remove_file(&tmp);
+                    fs::hard_link(candidate, &tmp)?;
+                    fs::rename(&tmp, &page)?;
+                    saved += meta.len();
+                    linked = true;
+                    break;
+                }
+            }
+            if !linked {
+                candidates.push(page);
+                hashes.0.insert(key, hash);
+            }
+        }
+    }
+    // Images of snapshots that are gone are forgotten
+    hashes.save(saved_states)?;
+    Ok(saved)
+}
+
+/// Runs the steps of `mode` on all snapshots in `state`. Retired snapshots are archived or deleted
+/// according to `policy` and dropped from `state`, which the caller has to save.
+pub fn collect(
+    state: &mut CampaignState,
+    saved_states: &Path,
+    archive: &Path,
+    mode: GcMode,
+    policy: EvictionPolicy,
+) -> io::Result<GcReport> {
+    let mut report = GcReport::default();
+
+    if mode.runs(GcMode::Retire) {
+        for state_path in covered_snapshots(state, saved_states) {
+            println!(
+                "[*] Retiring {}, its trace is covered by other snapshots",
+                state_path
+            );
+            evict(state, &state_path, policy, saved_states, archive)?;
+            report.retired.push(state_path);
+        }
+    }
+
+    if mode.runs(GcMode::Prune) {
+        let fuzzed: Vec<String> = state
+            .generation_snaps
+            .iter()
+            .flatten()
+            .filter(|snap| {
+                state
+                    .history
+                    .get(&snap.state_path)
+                    .is_some_and(|h| h.runs > 0)
+            })
+            .map(|snap| snap.state_path.clone())
+            .collect();
+        for state_path in fuzzed {
+            report.pruned += prune(saved_states, &state_path)?;
+        }
+    }
+
+    if mode.runs(GcMode::Compact) {
+        report.compacted = compact(state, saved_states)?;
+    }
+
+    println!(
+        "[*] GC: pruned {} bytes, retired {} snapshots, compacted {} bytes",
+        report.pruned,
+        report.retired.len(),
+        report.compacted
+    );
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{test_snapshot, FITMSnapshot};
+
+    #[test]
+    fn test_collect() {
+        let root = Path::new("/tmp/fitm_gc_unittest");
+        let _ = fs::remove_dir_all(root);
+        let saved_states = root.join("saved-states");
+        let archive = root.join("archived-states");
+
+        let snaps = vec![
+            vec![],
+            vec![test_snapshot(1, 0)],
+            vec![test_snapshot(2, 0)],
+            vec![test_snapshot(3, 0), test_snapshot(3, 1)],
+            vec![
+                test_snapshot(4, 0),
+                test_snapshot(4, 1),
+                test_snapshot(4, 2),
+            ],
+        ];
+        let maps = [
+            ("fitm-gen1-state0", "1:1\n"),
+            ("fitm-gen2-state0", "2:1\n"),
+            ("fitm-gen3-state0", "1:1\n3:1\n"),
+            // Covered by gen3-state0
+            ("fitm-gen3-state1", "3:1\n"),
+            ("fitm-gen4-state0", "2:1\n4:1\n"),
+            // Same trace as gen4-state0, but younger
+            ("fitm-gen4-state1", "2:1\n4:1\n"),
+            ("fitm-gen4-state2", "5:1\n"),
+        ];
+        for (state_path, map) in maps {
+            let dir = saved_states.join(state_path);
+            fs::create_dir_all(dir.join("snapshot")).unwrap();
+            fs::create_dir_all(dir.join("out/main/queue")).unwrap();
+            fs::write(dir.join("out/main/queue/id:000000"), "789").unwrap();
+            fs::create_dir_all(dir.join("out/sec1/queue")).unwrap();
+            fs::write(dir.join("out/sec1/queue/id:000000"), "0").unwrap();
+            fs::create_dir_all(dir.join("out_postrun/main/crashes")).unwrap();
+            fs::create_dir_all(dir.join("out_postrun/main/queue")).unwrap();
+            fs::write(dir.join("out_postrun/main/queue/id:000000"), "1234").unwrap();
+            fs::create_dir_all(dir.join("out_postrun/sec1/queue")).unwrap();
+            fs::write(dir.join("out_postrun/sec1/queue/id:000000"), "56").unwrap();
+            fs::write(dir.join("snapshot_map"), map).unwrap();
+            fs::write(dir.join("snapshot/pages-1.img"), vec![7u8; 4096]).unwrap();
+        }
+        let mut state = CampaignState::new(1, snaps);
+        // A child keeps its base from being retired
+        state.generation_snaps.push(vec![FITMSnapshot {
+            base_state: "fitm-gen3-state1".to_string(),
+            ..test_snapshot(5, 0)
+        }]);
+        assert_eq!(
+            covered_snapshots(&state, &saved_states),
+            vec!["fitm-gen4-state1"]
+        );
+        state.generation_snaps.pop();
+        state
+            .history
+            .entry("fitm-gen1-state0".to_string())
+            .or_default()
+            .add_run(Duration::from_secs(1), 10, 0, 0, 0);
+
+        assert_eq!(
+            covered_snapshots(&state, &saved_states),
+            vec!["fitm-gen3-state1", "fitm-gen4-state1"]
+        );
+
+        let report = collect(
+            &mut state,
+            &saved_states,
+            &archive,
+            GcMode::All,
+            EvictionPolicy::Delete,
+        )
+        .unwrap();
+        assert_eq!(report.retired, vec!["fitm-gen3-state1", "fitm-gen4-state1"]);
+        assert_eq!(state.generation_snaps[3].len(), 1);
+        assert!(!saved_states.join("fitm-gen3-state1").exists());
+
+        // Only fuzzed snapshots are pruned, crashes and the corpus for the next run stay
+        assert_eq!(report.pruned, 7);
+        let fuzzed = saved_states.join("fitm-gen1-state0");
+        assert!(!fuzzed.join("out_postrun/main/queue").exists());
+        assert!(!fuzzed.join("out_postrun/sec1/queue").exists());
+        assert!(!fuzzed.join("out/sec1/queue").exists());
+        assert!(fuzzed.join(CORPUS).join("id:000000").is_file());
+        assert!(fuzzed.join("out_postrun/main/crashes").is_dir());
+        assert!(saved_states
+            .join("fitm-gen2-state0/out_postrun/main/queue")
+            .is_dir());
+
+        // 5 snapshots left, 4 of their page images are now links to the first
+        assert_eq!(report.compacted, 4 * 4096);
+        let first = fs::metadata(fuzzed.join("snapshot/pages-1.img")).unwrap();
+        assert_eq!(first.nlink(), 5);
+        // All of them are one inode, so there is one hash to remember
+        assert_eq!(PageHashes::load(&saved_states).0.len(), 1);
+        let other = saved_states.join("fitm-gen2-state0/snapshot/pages-2.img");
+        fs::write(&other, vec![7u8; 4095]).unwrap();
+        assert!(!same_content(&fuzzed.join("snapshot/pages-1.img"), &other).unwrap());
+        fs::write(&other, vec![7u8; 4096]).unwrap();
+        assert!(same_content(&fuzzed.join("snapshot/pages-1.img"), &other).unwrap());
+        fs::remove_file(&other).unwrap();
+        assert_eq!(
+            collect(
+                &mut state,
+                &saved_states,
+                &archive,
+                GcMode::Compact,
+                EvictionPolicy::Delete,
+            )
+            .unwrap()
+            .compacted,
+            0
+        );
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,242 @@
+use std::path::Path;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::utils::{afl_results_dirs, current_millis, node_stat};
+
+/// The exec timeout is this many times the measured restore + exec latency
+pub const TIMEOUT_MULTIPLIER: u32 = 5;
+/// Bounds for calibrated exec timeouts
+pub const MIN_EXEC_TIMEOUT: Duration = Duration::from_millis(100);
+pub const MAX_EXEC_TIMEOUT: Duration = Duration::from_secs(60);
+/// If more than this share of a run's execs time out, we recalibrate
+pub const HANG_RATE_THRESHOLD: f64 = 0.02;
+
+/// Everything we remember about the fuzz runs of one snapshot, kept in `fitm-state.json`
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FuzzHistory {
+    /// How often the snapshot was fuzzed
+    pub runs: u64,
+    /// Execs over all runs
+    pub execs: u64,
+    /// New paths over all runs
+    pub new_paths: u64,
+    /// Unique crashes over all runs
+    pub crashes: u64,
+    /// New paths found by the last run
+    pub last_new_paths: u64,
+    /// Unique crashes found by the last run
+    pub last_crashes: u64,
+    /// Runs in a row that found neither new paths nor crashes
+    pub dry_runs: u32,
+    /// When (millis since epoch) a run last found new paths or crashes
+    pub last_find: Option<u64>,
+    /// How long the last run was
+    pub last_run_time: Duration,
+    /// Execs of the last run
+    pub last_execs: u64,
+    /// Execs of the last run that timed out
+    pub last_timeouts: u64,
+    /// The calibrated exec timeout, `None` until `calibrate_timeout` ran
+    pub timeout: Option<Duration>,
+}
+
+/// Reads a numeric stat of the last run, trying older and newer AFL++ names, summed over all nodes
+fn stat(saved_states: &Path, state_path: &str, keys: &[&str]) -> u64 {
+    afl_results_dirs(saved_states, state_path)
+        .iter()
+        .map(|node| {
+            keys.iter()
+                .find_map(|key| node_stat(node, key)?.parse::<u64>().ok())
+                .unwrap_or(0)
+        })
+        .sum()
+}
+
+impl FuzzHistory {
+    /// Adds a finished run, given the number of execs, new paths, crashes and timeouts
+    pub fn add_run(
+        &mut self,
+        run_time: Duration,
+        execs: u64,
+        new_paths: u64,
+        crashes: u64,
+        timeouts: u64,
+    ) {
+        self.runs += 1;
+        self.last_execs = execs;
+        self.last_timeouts = timeouts;
+        self.execs += execs;
+        self.new_paths += new_paths;
+        self.crashes += crashes;
+        self.last_new_paths = new_paths;
+        self.last_crashes = crashes;
+        self.last_run_time = run_time;
+        if new_paths > 0 || crashes > 0 {
+            self.dry_runs = 0;
+            self.last_find = Some(current_millis());
+        } else {
+            self.dry_runs += 1;
+        }
+    }
+
+    /// Adds the run that just finished for `state_path`, from AFL's `fuzzer_stats` in its saved state
+    pub fn record(&mut self, saved_states: &Path, state_path: &str, run_time: Duration) {
+        let stat = |keys: &[&str]| stat(saved_states, state_path, keys);
+        self.add_run(
+            run_time,
+            stat(&["execs_done"]),
+            stat(&["paths_found", "corpus_found"]),
+            stat(&["unique_crashes", "saved_crashes"]),
+            stat(&["total_tmout", "unique_hangs", "saved_hangs"]),
+        );
+    }
+
+    /// Share of the last run's execs that timed out
+    pub fn hang_rate(&self) -> f64 {
+        if self.last_execs == 0 {
+            return 0.0;
+        }
+        self.last_timeouts as f64 / self.last_execs as f64
+    }
+
+    /// Not calibrated yet, or too many timeouts in the last run
+    pub fn needs_calibration(&self) -> bool {
+        self.timeout.is_none() || self.hang_rate() > HANG_RATE_THRESHOLD
+    }
+}
+
+/// The exec timeout for a measured restore + exec `latency`.
+/// If the old timeout made too many execs hang, the new one is at least twice as long.
+pub fn calibrated_timeout(
+    latency: Duration,
+    previous: Option<Duration>,
+    hanging: bool,
+) -> Duration {
+    let mut timeout = latency * TIMEOUT_MULTIPLIER;
+    if let (true, Some(previous)) = (hanging, previous) {
+        timeout = timeout.max(previous * 2);
+    }
+    timeout.clamp(MIN_EXEC_TIMEOUT, MAX_EXEC_TIMEOUT)
+}
+
+/// How long to fuzz a snapshot: `base` for snapshots we know nothing about,
+/// longer for productive and shorter for dead ones, always within `min` and `max`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RunTime {
+    pub base: Duration,
+    pub min: Duration,
+    pub max: Duration,
+}
+
+impl RunTime {
+    /// Always fuzz for exactly `run_time`
+    pub fn fixed(run_time: Duration) -> Self {
+        RunTime {
+            base: run_time,
+            min: run_time,
+            max: run_time,
+        }
+    }
+
+    /// The run time for a snapshot with the given history
+    pub fn for_history(&self, history: Option<&FuzzHistory>) -> Duration {
+        let factor = match history {
+            None => 1.0,
+            Some(history) if history.runs == 0 => 1.0,
+            // Finds are worth more the more there were, but with diminishing returns
+            Some(history) if history.dry_runs == 0 => {
+                let finds = history.last_new_paths + 4 * history.last_crashes;
+                1.0 + (finds as f64).log2().max(0.0) / 2.0
+            }
+            // Halve the time for every dry run
+            Some(history) => 0.5f64.powi(history.dry_runs.min(32) as i32),
+        };
+        self.base.mul_f64(factor).clamp(self.min, self.max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_calibrated_timeout() {
+        let latency = Duration::from_millis(300);
+        assert_eq!(
+            calibrated_timeout(latency, None, false),
+            Duration::from_millis(1500)
+        );
+        assert_eq!(
+            calibrated_timeout(Duration::from_millis(1), None, false),
+            MIN_EXEC_TIMEOUT
+        );
+        assert_eq!(
+            calibrated_timeout(latency, Some(Duration::from_secs(3)), true),
+            Duration::from_secs(6)
+        );
+        assert_eq!(
+            calibrated_timeout(latency, Some(Duration::from_secs(3)), false),
+            Duration::from_millis(1500)
+        );
+        assert_eq!(
+            calibrated_timeout(latency, Some(Duration::from_secs(40)), true),
+            MAX_EXEC_TIMEOUT
+        );
+    }
+
+    #[test]
+    fn test_adaptive_run_time() {
+        let run_time = RunTime {
+            base: Duration::from_secs(60),
+            min: Duration::from_secs(10),
+            max: Duration::from_secs(240),
+        };
+        assert_eq!(run_time.for_history(None), Duration::from_secs(60));
+
+        let mut history = FuzzHistory::default();
+        history.add_run(Duration::from_secs(60), 1000, 64, 0, 0);
+        assert!(history.last_find.is_some());
+        // log2(64) / 2 = 3 -> 4x
+        assert_eq!(
+            run_time.for_history(Some(&history)),
+            Duration::from_secs(240)
+        );
+
+        history.add_run(Duration::from_secs(240), 1000, 0, 0, 0);
+        assert_eq!(
+            run_time.for_history(Some(&history)),
+            Duration::from_secs(30)
+        );
+        history.add_run(Duration::from_secs(30), 1000, 0, 0, 0);
+        history.add_run(Duration::from_secs(15), 1000, 0, 0, 0);
+        assert_eq!(history.dry_runs, 3);
+        assert_eq!(
+            run_time.for_history(Some(&history)),
+            Duration::from_secs(10)
+        );
+
+        history.add_run(Duration::from_secs(10), 1000, 0, 1, 0);
+        assert_eq!(history.dry_runs, 0);
+        assert_eq!((history.runs, history.execs), (5, 5000));
+        assert_eq!((history.new_paths, history.crashes), (64, 1));
+        assert_eq!(
+            run_time.for_history(Some(&history)),
+            Duration::from_secs(120)
+        );
+
+        assert!(history.needs_calibration());
+        history.timeout = Some(Duration::from_secs(1));
+        assert!(!history.needs_calibration());
+        history.add_run(Duration::from_secs(10), 1000, 0, 0, 50);
+        assert!(history.needs_calibration());
+
+        let fixed = RunTime::fixed(Duration::from_millis(100));
+        assert_eq!(
+            fixed.for_history(Some(&history)),
+            Duration::from_millis(100)
+        );
+    }
+}
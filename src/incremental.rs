@@ -0,0 +1,395 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write},
+    os::unix::fs::symlink,
+    path::{Path, PathBuf},
+};
+
+use crate::criu_images::{pagemap, write_pagemap, PagemapEntry, PE_LAZY, PE_PARENT, PE_PRESENT};
+
+/// CRIU looks for the images of the previous dump behind this symlink in the images dir
+pub const PARENT_LINK: &str = "parent";
+
+pub const PAGE_SIZE: u64 = 4096;
+
+/// The `parent` link of a saved snapshot pointing to the saved snapshot of `base_state`.
+/// Relative, so it stays valid inside `saved-states`, see `link_parent`.
+pub fn parent_target(base_state: &str) -> PathBuf {
+    Path::new("..").join("..").join(base_state).join("snapshot")
+}
+
+/// `pagemap-*.img` files in a snapshot dir, sorted
+fn pagemaps(snapshot_dir: &Path) -> io::Result<Vec<String>> {
+    let mut names: Vec<String> = fs::read_dir(snapshot_dir)?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|name| name.starts_with("pagemap-") && name.ends_with(".img"))
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+fn pages_path(snapshot_dir: &Path, pages_id: u32) -> PathBuf {
+    snapshot_dir.join(format!("pages-{}.img", pages_id))
+}
+
+/// The pages of one pagemap image, following the `parent` links for pages that are not present
+struct Pages {
+    /// Id of the `pages-<id>.img` holding the data
+    pages_id: u32,
+    /// Entries with the offset of their data in `pages`
+    entries: Vec<(PagemapEntry, u64)>,
+    pages: Option<File>,
+    parent: Option<Box<Pages>>,
+}
+
+impl Pages {
+    /// `None` if the snapshot has no such pagemap
+    fn open(snapshot_dir: &Path, pagemap_name: &str) -> io::Result<Option<Pages>> {
+        let path = snapshot_dir.join(pagemap_name);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let (pages_id, raw_entries) = pagemap(&path)?;
+        let mut offset = 0;
+        let mut entries = vec![];
+        for entry in raw_entries {
+            entries.push((entry, offset));
+            if entry.flags & PE_PRESENT != 0 {
+                offset += entry.nr_pages as u64 * PAGE_SIZE;
+            }
+        }
+        let pages = match File::open(pages_path(snapshot_dir, pages_id)) {
+            Ok(file) => Some(file),
+            Err(e) if e.kind() == ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        let parent_dir = snapshot_dir.join(PARENT_LINK);
+        let parent = if parent_dir.is_dir() {
+            Pages::open(&parent_dir, pagemap_name)?.map(Box::new)
+        } else {
+            None
+        };
+        Ok(Some(Pages {
+            pages_id,
+            entries,
+            pages,
+            parent,
+        }))
+    }
+
+    /// The contents of the page at `vaddr`, `None` if no snapshot in the chain has it
+    fn read_page(&mut self, vaddr: u64) -> io::Result<Option<Vec<u8>>> {
+        let found = self.entries.iter().find(|(entry, _)| {
+            vaddr >= entry.vaddr && vaddr < entry.vaddr + entry.nr_pages as u64 * PAGE_SIZE
+        });
+        match found {
+            Some((entry, offset)) if entry.flags & PE_PRESENT != 0 => {
+                let offset = offset + (vaddr - entry.vaddr);
+                let pages = match self.pages.as_mut() {
+                    Some(pages) => pages,
+                    None => return Ok(None),
+                };
+                let mut page = vec![0; PAGE_SIZE as usize];
+                pages.seek(SeekFrom::Start(offset))?;
+                pages.read_exact(&mut page)?;
+                Ok(Some(page))
+            }
+            Some((entry, _)) if entry.flags & PE_PARENT != 0 => match self.parent.as_mut() {
+                Some(parent) => parent.read_page(vaddr),
+                None => Ok(None),
+            },
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Appends a run of pages to `entries`, merging it with the last entry if it continues it
+fn push_run(entries: &mut Vec<PagemapEntry>, vaddr: u64, nr_pages: u32, flags: u32) {
+    if let Some(last) = entries.last_mut() {
+        if last.flags == flags && last.vaddr + last.nr_pages as u64 * PAGE_SIZE == vaddr {
+            last.nr_pages += nr_pages;
+            return;
+        }
+    }
+    entries.push(PagemapEntry {
+        vaddr,
+        nr_pages,
+        flags,
+    });
+}
+
+/// Rewrites every pagemap of `snapshot_dir` page by page. `keep(vaddr, page, chain)` decides
+/// whether a page is stored here (`Some(data)`) or left to the parent chain (`None`).
+/// Returns by how many bytes the page images shrank (negative if they grew).
+fn rewrite_pagemaps<F>(snapshot_dir: &Path, parent_dir: &Path, mut keep: F) -> io::Result<i64>
+where
+    F: FnMut(u64, Option<Vec<u8>>, Option<&mut Pages>) -> io::Result<Option<Vec<u8>>>,
+{
+    let mut shrunk: i64 = 0;
+    for name in pagemaps(snapshot_dir)? {
+        let mut own = match Pages::open(snapshot_dir, &name)? {
+            Some(own) => own,
+            None => continue,
+        };
+        let mut chain = if parent_dir.is_dir() {
+            Pages::open(parent_dir, &name)?
+        } else {
+            None
+        };
+        let pages_id = own.pages_id;
+        let pages = pages_path(snapshot_dir, pages_id);
+        let tmp_pages = pages.with_extension("img.tmp");
+        let mut out = BufWriter::new(File::create(&tmp_pages)?);
+        let mut entries = vec![];
+        let old_size = fs::metadata(&pages).map(|meta| meta.len()).unwrap_or(0);
+        let mut new_size = 0;
+
+        for (entry, _) in own.entries.clone() {
+            // Lazy pages and holes are kept as they are
+            if entry.flags & PE_LAZY != 0 || entry.flags & (PE_PRESENT | PE_PARENT) == 0 {
+                if entry.flags & PE_PRESENT != 0 {
+                    for i in 0..entry.nr_pages as u64 {
+                        let page = own
+                            .read_page(entry.vaddr + i * PAGE_SIZE)?
+                            .unwrap_or_default();
+                        out.write_all(&page)?;
+                        new_size += PAGE_SIZE;
+                    }
+                }
+                entries.push(entry);
+                continue;
+            }
+            for i in 0..entry.nr_pages as u64 {
+                let vaddr = entry.vaddr + i * PAGE_SIZE;
+                let page = own.read_page(vaddr)?;
+                match keep(vaddr, page, chain.as_mut())? {
+                    Some(data) => {
+                        out.write_all(&data)?;
+                        new_size += PAGE_SIZE;
+                        push_run(&mut entries, vaddr, 1, PE_PRESENT);
+                    }
+                    None => push_run(&mut entries, vaddr, 1, PE_PARENT),
+                }
+            }
+        }
+
+        out.flush()?;
+        drop(out);
+        let tmp_pagemap = snapshot_dir.join(format!("{}.tmp", name));
+        write_pagemap(&tmp_pagemap, pages_id, &entries)?;
+        fs::rename(&tmp_pages, &pages)?;
+        fs::rename(&tmp_pagemap, snapshot_dir.join(&name))?;
+        shrunk += old_size as i64 - new_size as i64;
+    }
+    Ok(shrunk)
+}
+
+/// Turns the full dump in `snapshot_dir` into a diff against `base_dir` (and its chain):
+/// pages that are the same in the base are dropped and marked `PE_PARENT`, and a `parent` link
+/// to `parent_target` is created. Returns the bytes saved.
+pub fn diff(snapshot_dir: &Path, base_dir: &Path, parent_target: &Path) -> io::Result<u64> {
+    let link = snapshot_dir.join(PARENT_LINK);
+    let _ = fs::remove_file(&link);
+    // The full images are still valid with the link, so we can't break anything if we stop halfway
+    symlink(parent_target, &link)?;
+    let shrunk = rewrite_pagemaps(snapshot_dir, base_dir, |vaddr, page, chain| {
+        let base_page = match chain {
+            Some(chain) => chain.read_page(vaddr)?,
+            None => None,
+        };
+        Ok(match page {
+            Some(page) if Some(&page) == base_page.as_ref() => None,
+            Some(page) => Some(page),
+            None => base_page,
+        })
+    })?;
+    Ok(shrunk.max(0) as u64)
+}
+
+/// Writes every page of the chain into `snapshot_dir` itself and removes its `parent` link,
+/// so it no longer depends on other snapshots. Returns the bytes added.
+pub fn flatten(snapshot_dir: &Path) -> io::Result<u64> {
+    let link = snapshot_dir.join(PARENT_LINK);
+    if fs::symlink_metadata(&link).is_err() {
+        return Ok(0);
+    }
+    if !link.is_dir() {
+        return Err(io::Error::new(
+            ErrorKind::NotFound,
+            format!("{:?} points nowhere, can't flatten", link),
+        ));
+    }
+    let shrunk = rewrite_pagemaps(snapshot_dir, &link, |vaddr, page, chain| match page {
+        Some(page) => Ok(Some(page)),
+        None => match chain {
+            Some(chain) => chain.read_page(vaddr)?.map(Some).ok_or_else(|| {
+                io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("page {:#x} is missing in the parent chain", vaddr),
+                )
+            }),
+            None => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("page {:#x} has no parent to read it from", vaddr),
+            )),
+        },
+    })?;
+    fs::remove_file(&link)?;
+    Ok((-shrunk).max(0) as u64)
+}
+
+/// The state whose snapshot the `parent` link of `state_path` in `saved_states` points to
+pub fn parent_state(saved_states: &Path, state_path: &str) -> Option<String> {
+    let target = fs::read_link(
+        saved_states
+            .join(state_path)
+            .join("snapshot")
+            .join(PARENT_LINK),
+    )
+    .ok()?;
+    // `../../<base_state>/snapshot`
+    let base = target.parent()?.file_name()?;
+    Some(base.to_str()?.to_string())
+}
+
+/// Saved states whose snapshot is a diff against `state_path`
+pub fn dependents(saved_states: &Path, state_path: &str) -> io::Result<Vec<String>> {
+    let mut dependents: Vec<String> = fs::read_dir(saved_states)?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|name| parent_state(saved_states, name).as_deref() == Some(state_path))
+        .collect();
+    dependents.sort();
+    Ok(dependents)
+}
+
+/// `cp -r` keeps the relative `parent` link, which is dangling outside of `saved-states`.
+/// Points the link of the copied `snapshot_dir` to the absolute path of the original's parent.
+pub fn link_parent(snapshot_dir: &Path, saved_snapshot_dir: &Path) -> io::Result<()> {
+    let saved_link = saved_snapshot_dir.join(PARENT_LINK);
+    if fs::symlink_metadata(&saved_link).is_err() {
+        return Ok(());
+    }
+    let target = fs::canonicalize(&saved_link)?;
+    let link = snapshot_dir.join(PARENT_LINK);
+    let _ = fs::remove_file(&link);
+    symlink(target, link)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: u64 = 0x10000;
+
+    fn page(byte: u8) -> Vec<u8> {
+        vec![byte; PAGE_SIZE as usize]
+    }
+
+    /// A snapshot dir with a single pagemap of consecutive present pages at `BASE`
+    fn dump(dir: &Path, pages: &[u8]) {
+        fs::create_dir_all(dir).unwrap();
+        let entry = PagemapEntry {
+            vaddr: BASE,
+            nr_pages: pages.len() as u32,
+            flags: PE_PRESENT,
+        };
+        write_pagemap(&dir.join("pagemap-1.img"), 7, &[entry]).unwrap();
+        let data: Vec<u8> = pages.iter().flat_map(|byte| page(*byte)).collect();
+        fs::write(dir.join("pages-7.img"), data).unwrap();
+    }
+
+    fn read_all(dir: &Path, count: u64) -> Vec<Option<Vec<u8>>> {
+        let mut pages = Pages::open(dir, "pagemap-1.img").unwrap().unwrap();
+        (0..count)
+            .map(|i| pages.read_page(BASE + i * PAGE_SIZE).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_diff_and_flatten() {
+        let root = Path::new("/tmp/fitm_incremental_unittest");
+        let _ = fs::remove_dir_all(root);
+        let saved_states = root.join("saved-states");
+        let base = saved_states.join("fitm-gen1-state0/snapshot");
+        let child = saved_states.join("fitm-gen3-state0/snapshot");
+        let grandchild = saved_states.join("fitm-gen5-state0/snapshot");
+        dump(&base, &[1, 2, 3, 4]);
+        dump(&child, &[1, 9, 3, 4]);
+        dump(&grandchild, &[1, 9, 3, 5]);
+
+        let saved = diff(&child, &base, &parent_target("fitm-gen1-state0")).unwrap();
+        assert_eq!(saved, 3 * PAGE_SIZE);
+        let (_, entries) = pagemap(&child.join("pagemap-1.img")).unwrap();
+        assert_eq!(
+            entries
+                .iter()
+                .map(|e| (e.nr_pages, e.flags))
+                .collect::<Vec<_>>(),
+            vec![(1, PE_PARENT), (1, PE_PRESENT), (2, PE_PARENT)]
+        );
+        assert_eq!(
+            diff(&grandchild, &child, &parent_target("fitm-gen3-state0")).unwrap(),
+            3 * PAGE_SIZE
+        );
+        let expected: Vec<Option<Vec<u8>>> =
+            [1, 9, 3, 5].iter().map(|byte| Some(page(*byte))).collect();
+        assert_eq!(read_all(&grandchild, 4), expected);
+
+        assert_eq!(
+            parent_state(&saved_states, "fitm-gen5-state0").as_deref(),
+            Some("fitm-gen3-state0")
+        );
+        assert_eq!(
+            dependents(&saved_states, "fitm-gen3-state0").unwrap(),
+            vec!["fitm-gen5-state0"]
+        );
+
+        // A copy outside of the saved states only works with an absolute link
+        let active = root.join("active-state/snapshot");
+        fs::create_dir_all(&active).unwrap();
+        for file in ["pagemap-1.img", "pages-7.img"] {
+            fs::copy(grandchild.join(file), active.join(file)).unwrap();
+        }
+        link_parent(&active, &grandchild).unwrap();
+        assert_eq!(read_all(&active, 4), expected);
+
+        assert_eq!(flatten(&grandchild).unwrap(), 3 * PAGE_SIZE);
+        assert!(fs::symlink_metadata(grandchild.join(PARENT_LINK)).is_err());
+        assert!(dependents(&saved_states, "fitm-gen3-state0")
+            .unwrap()
+            .is_empty());
+        fs::remove_dir_all(saved_states.join("fitm-gen3-state0")).unwrap();
+        assert_eq!(read_all(&grandchild, 4), expected);
+        assert_eq!(flatten(&grandchild).unwrap(), 0);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_quarantine_base() {
+        let root = Path::new("/tmp/fitm_incremental_unittest_quarantine");
+        let _ = fs::remove_dir_all(root);
+        let saved_states = root.join("saved-states");
+        let base = saved_states.join("fitm-gen1-state0/snapshot");
+        let child = saved_states.join("fitm-gen3-state0/snapshot");
+        let grandchild = saved_states.join("fitm-gen5-state0/snapshot");
+        dump(&base, &[1, 2, 3, 4]);
+        dump(&child, &[1, 9, 3, 4]);
+        dump(&grandchild, &[1, 9, 3, 5]);
+        diff(&child, &base, &parent_target("fitm-gen1-state0")).unwrap();
+        diff(&grandchild, &child, &parent_target("fitm-gen3-state0")).unwrap();
+
+        // Quarantining the middle of the chain keeps both ends restorable
+        let quarantined = crate::fsck::quarantine(&saved_states, "fitm-gen3-state0").unwrap();
+        assert!(!saved_states.join("fitm-gen3-state0").exists());
+        assert!(fs::symlink_metadata(grandchild.join(PARENT_LINK)).is_err());
+        let expected: Vec<Option<Vec<u8>>> =
+            [1, 9, 3, 5].iter().map(|byte| Some(page(*byte))).collect();
+        assert_eq!(read_all(&grandchild, 4), expected);
+        let expected: Vec<Option<Vec<u8>>> =
+            [1, 9, 3, 4].iter().map(|byte| Some(page(*byte))).collect();
+        assert_eq!(read_all(&quarantined.join("snapshot"), 4), expected);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
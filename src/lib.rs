@@ -1,28 +1,62 @@
-use std::collections::HashMap;
-use std::ffi::OsString;
-use std::fs::{self, remove_dir_all, DirEntry, File};
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, remove_dir_all, File};
 use std::io::{self, ErrorKind, Write};
+use std::os::unix::process::ExitStatusExt;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::{env, fmt};
 
-use crate::namespacing::NamespaceContext;
+use crate::budget::Budget;
+use crate::criu_rpc::{CriuClient, DumpStatus};
+pub use crate::error::FitmError;
+use crate::evict::{Limits, ARCHIVED_STATES};
+use crate::fsck::FsckMode;
+use crate::history::{calibrated_timeout, FuzzHistory, RunTime, HANG_RATE_THRESHOLD};
+use crate::namespacing::{Namespace, NamespaceContext};
+use crate::quarantine::Quarantine;
+use crate::restore::{RestorePlan, RESTORE_LOG};
+use crate::scheduler::Scheduler;
+use crate::state::CampaignState;
+use crate::toolchain::Toolchain;
 use crate::utils::RomuRand;
-use crate::utils::{advance_pid, cp_recursive, get_filesize, pick_random, spawn_criu};
+use crate::utils::{advance_pid, cp_recursive, get_filesize, spawn_criu};
+use crate::workers::{Worker, WORKERS_DIR};
+use crate::workspace::Workspace;
 use chrono::Local;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 
 use termion::{color, style};
 
+pub mod budget;
+pub mod cli;
+pub mod commands;
+pub mod config;
+pub mod criu_images;
+pub mod criu_rpc;
+pub mod error;
+pub mod evict;
+pub mod fsck;
+pub mod gc;
+pub mod history;
+pub mod incremental;
 pub mod namespacing;
+mod pb;
+pub mod quarantine;
+pub mod restore;
+pub mod scheduler;
+pub mod state;
+pub mod toolchain;
 pub mod utils;
+pub mod workers;
+pub mod workspace;
 
-/// If randomness is higher than this theshold, we continue with the next round
+/// If randomness is higher than this theshold, we continue with the next round (see `ThresholdScheduler`)
 pub const ABORT_THRESHOLD: f64 = 0.98;
-/// If randomness is higher than this theshold, we skip a step
+/// If randomness is higher than this theshold, we skip a step (see `ThresholdScheduler`)
 pub const SKIP_STEP_THRESHOLD: f64 = 0.93;
 /// Theshold, when an output should be considered too similar to all others for snapshot creation.
 /// 1.0 means exact match, 0.0 means no similarity
@@ -33,11 +67,26 @@ pub const JARO_DISTANCE_THRESHOLD: f64 = 0.98;
 // server_set: set of afl-showmap on server outputs that are relevant for us
 pub const ORIGIN_STATE_CLIENT: &str = "fitm-gen2-state0";
 pub const ORIGIN_STATE_SERVER: &str = "fitm-gen1-state0";
+/// The state that is restored or fuzzed, below the `Workspace` root
 pub const ACTIVE_STATE: &str = "active-state";
+/// One folder per snapshot, below the `Workspace` root
 pub const SAVED_STATES: &str = "saved-states";
-
+/// Inputs for afl-cmin are collected here
+pub const CMIN_TMP: &str = "cmin-tmp";
+/// Serialized `CampaignState`, used to resume a run
+pub const FITM_STATE: &str = "fitm-state.json";
+/// Written to the active state by `restore_with_input`: how long the restore and exec took, in micros
+pub const EXEC_TIME_FILE: &str = "exec_time";
+/// Exec timeout of the initial snapshots. Each snapshot calibrates its own before it's fuzzed.
+pub const INITIAL_EXEC_TIMEOUT: Duration = Duration::from_secs(3);
+/// How many inputs of the `in` dir `measure_exec_latency` restores
+pub const CALIBRATION_INPUTS: usize = 3;
+
+/// Output of the criu service, below the `Workspace` root
 pub const CRIU_STDOUT: &str = "criu_stdout";
 pub const CRIU_STDERR: &str = "criu_stderr";
+/// How long we wait for the criu service to come up, or to report a dump
+pub const CRIU_STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
 
 /// FITMSnapshot contains all the information for one specific snapshot and fuzz run.
 #[derive(Clone, Serialize, Deserialize)]
@@ -50,9 +99,7 @@ pub struct FITMSnapshot {
     pub state_path: String,
     /// Binary that is being fuzzed
     pub target_bin: String,
-    /// Timeout for this run
-    /// TODO: probably should be dynamic based on how interesting this state
-    /// is.
+    /// Timeout for each exec (afl -t). Calibrated before fuzzing, see `FuzzHistory::timeout`
     pub timeout: Duration,
     // All the states that came out of the current state
     // child_states: Vec<(u32, u32)>
@@ -70,19 +117,26 @@ pub struct FITMSnapshot {
     pub pid: Option<i32>,
     /// A list of files related to the process-snapshot
     pub files: Vec<String>,
+    /// Why this snapshot was quarantined, see `quarantine`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failure: Option<FitmError>,
 }
 
 impl fmt::Debug for FITMSnapshot {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("FITMSnapshot")
+        let mut debug = f.debug_struct("FITMSnapshot");
+        debug
             .field("state_path", &self.state_path)
             .field("base_state", &self.base_state)
             .field("target_bin", &self.target_bin)
             .field("timeout", &self.timeout)
             .field("server", &self.server)
             .field("initial", &self.initial)
-            .field("origin_state", &self.origin_state)
-            .finish()
+            .field("origin_state", &self.origin_state);
+        if let Some(failure) = &self.failure {
+            debug.field("failure", failure);
+        }
+        debug.finish()
     }
 }
 
@@ -95,16 +149,36 @@ pub fn origin_state(is_server: bool) -> &'static str {
     }
 }
 
-fn state_path_for(gen: u32, state_id: usize) -> String {
+pub(crate) fn state_path_for(gen: u32, state_id: usize) -> String {
     format!("fitm-gen{}-state{}", gen, state_id)
 }
 
+/// A snapshot named like `FITMSnapshot::new` would, without creating anything on disk
+#[cfg(test)]
+pub(crate) fn test_snapshot(gen: u32, state_id: usize) -> FITMSnapshot {
+    FITMSnapshot {
+        generation: gen,
+        state_id,
+        state_path: state_path_for(gen, state_id),
+        target_bin: "bin".to_string(),
+        timeout: Duration::from_secs(1),
+        server: gen % 2 == 1,
+        base_state: String::new(),
+        initial: false,
+        origin_state: String::new(),
+        pid: None,
+        files: vec![],
+        failure: None,
+    }
+}
+
 /// Implementation of functions for an afl run
 /// Createing a new FITMSnapshot will create the necessary directory in active-state
 impl FITMSnapshot {
-    /// Create a new afl run instance
+    /// Create a new afl run instance, in the active state of `workspace`
     #[allow(clippy::too_many_arguments)]
     pub fn new(
+        workspace: &Workspace,
         generation: u32,
         state_id: usize,
         target_bin: String,
@@ -113,41 +187,38 @@ impl FITMSnapshot {
         server: bool,
         from_snapshot: bool,
         pid: Option<i32>,
-    ) -> FITMSnapshot {
+    ) -> Result<FITMSnapshot, FitmError> {
         let origin_state = origin_state(server).to_string();
 
         let state_path = state_path_for(generation, state_id);
+        let active_state = &workspace.active_state;
 
         // Make sure there is no old active_state folder
-        match std::fs::remove_dir_all(ACTIVE_STATE) {
+        match std::fs::remove_dir_all(active_state) {
             Ok(_) => (),
             Err(e) if e.kind() == ErrorKind::NotFound => (),
-            Err(e) => println!("[!] Error while removing {}: {:?}", ACTIVE_STATE, e),
+            Err(e) => println!("[!] Error while removing {:?}: {:?}", active_state, e),
         };
 
         // Create the new directories and files to make afl feel at home
-        fs::create_dir(ACTIVE_STATE).expect("[-] Could not create state dir!");
-
-        fs::create_dir(format!("{}/in", ACTIVE_STATE)).expect("[-] Could not create in dir!");
-
-        fs::create_dir(format!("{}/out", ACTIVE_STATE)).expect("[-] Could not create out dir!");
-
-        fs::create_dir(format!("{}/outputs", ACTIVE_STATE))
-            .expect("[-] Could not create outputs dir!");
-
-        fs::create_dir(format!("{}/out/maps", ACTIVE_STATE))
-            .expect("[-] Could not create out/maps dir!");
-
-        let fd_path = format!("{}/fd", ACTIVE_STATE);
-        fs::create_dir(fd_path).expect("[-] Could not create fd dir!");
-
-        if from_snapshot {
-            // Grab old snapshot from which we want to create a new one here
-
-            if base_state != *"" {
-                utils::copy_snapshot_base(&base_state);
+        let setup = || -> io::Result<()> {
+            fs::create_dir_all(active_state)?;
+            for dir in ["in", "out", "outputs", "out/maps", "fd"] {
+                fs::create_dir(active_state.join(dir))?;
             }
+
+            if from_snapshot && base_state != *"" {
+                // Grab old snapshot from which we want to create a new one here
+                utils::copy_snapshot_base(workspace, &base_state)?;
+            };
+            Ok(())
         };
+        setup().map_err(|e| {
+            FitmError::state_io(
+                format!("setting up {:?} for {}", active_state, state_path),
+                e,
+            )
+        })?;
 
         let new_run = FITMSnapshot {
             generation,
@@ -161,16 +232,16 @@ impl FITMSnapshot {
             origin_state,
             pid,
             files: Vec::new(),
+            failure: None,
         };
 
         // We can write a tool in the future to parse this info
         // and print a visualization of the state order
-        let path = format!("{}/run-info", ACTIVE_STATE);
-        let mut file = fs::File::create(path).expect("[!] Could not create FITMSnapshot file");
-        file.write_all(format!("{:?}", new_run).as_bytes())
-            .expect("[!] Could not write to FITMSnapshot file");
+        let path = active_state.join("run-info");
+        fs::write(&path, format!("{:?}", new_run))
+            .map_err(|e| FitmError::state_io(format!("writing {:?}", path), e))?;
 
-        new_run
+        Ok(new_run)
     }
 
     pub fn attach_files(mut self, file_list: &[String]) -> Self {
@@ -180,107 +251,126 @@ impl FITMSnapshot {
 
     /// Copies everything in ./fd to ./outputs/ of a specified state path.
     /// this is used on the initial client state to generate intitial inputs for the first server run
-    fn copy_fds_to_output_for(&self, gen: u32, state: usize) {
-        let state_path = state_path_for(gen, state);
-        // Make sure state dir outputs exists
-        let _ = fs::create_dir_all(&format!("./saved-states/{}/outputs", state_path));
-        for (i, entry) in fs::read_dir(&format!("./{}/fd", ACTIVE_STATE))
-            .expect("[!] Could not find fd folder in copy_fds_to_output_for")
-            .enumerate()
-        {
-            let path = entry
-                .expect("[!] Could not find entry in fd folder in copy_fds_to_output_for")
-                .path();
-            if path.is_file() {
-                let to = &format!("./saved-states/{}/outputs/initial{}", &state_path, i);
-                std::fs::copy(&path, &to).unwrap_or_else(|_| {
-                    panic!(
-                        "[!] Could not copy {:?} to {} in copy_fds_to_output_for",
-                        path,
-                        to.as_str()
-                    )
-                });
+    fn copy_fds_to_output_for(
+        &self,
+        workspace: &Workspace,
+        gen: u32,
+        state: usize,
+    ) -> Result<(), FitmError> {
+        let outputs = workspace
+            .saved_state(&state_path_for(gen, state))
+            .join("outputs");
+        let fd = workspace.active_state.join("fd");
+        let copy = || -> io::Result<()> {
+            // Make sure state dir outputs exists
+            fs::create_dir_all(&outputs)?;
+            for (i, entry) in fs::read_dir(&fd)?.enumerate() {
+                let path = entry?.path();
+                if path.is_file() {
+                    std::fs::copy(&path, outputs.join(format!("initial{}", i)))?;
+                }
             }
-        }
+            Ok(())
+        };
+        copy().map_err(|e| FitmError::state_io(format!("copying {:?} to {:?}", fd, outputs), e))
     }
 
-    /// Copies everything in ./fd to ./outputs/
-    /// this is used on the initial client state to generate intitial inputs for the first server run
-    fn copy_queue_to(&self, dst: &Path, active: bool) -> Result<(), io::Error> {
+    /// Copies the queues of all AFL nodes in `state_dir` (our saved or active state) to `dst`.
+    /// Files of secondaries are prefixed with their node, so they don't overwrite each other.
+    fn copy_queue_to(&self, dst: &Path, state_dir: &Path) -> Result<(), io::Error> {
+        let queues: Vec<PathBuf> = utils::afl_nodes(&state_dir.join("out"))
+            .into_iter()
+            .map(|node| node.join("queue"))
+            .filter(|queue| queue.is_dir())
+            .collect();
+        if queues.is_empty() {
+            return Err(io::Error::new(
+                ErrorKind::NotFound,
+                format!("no AFL queue in {:?}", state_dir),
+            ));
+        }
+
         fs::create_dir_all(dst)?;
-        // used string as type because format!().as_str offended the borrow checker,
-        // even if the string is saved in a tmp variable first
-        let from = if active {
-            "active-state/out/main/queue".to_string()
-        } else {
-            format!("saved-states/{}/out/main/queue", self.state_path)
-        };
-        for (_, entry) in fs::read_dir(from)?.enumerate() {
-            let path = entry?.path();
-            let name = path.file_name().unwrap();
-            if path.is_file() {
-                std::fs::copy(&path, dst.join(&name))?;
+        for queue in queues {
+            let prefix = utils::afl_node_prefix(queue.parent().unwrap());
+            for entry in fs::read_dir(&queue)? {
+                let path = entry?.path();
+                let name = path.file_name().unwrap().to_string_lossy();
+                if path.is_file() {
+                    std::fs::copy(&path, dst.join(format!("{}{}", prefix, name)))?;
+                }
             }
         }
         Ok(())
     }
 
-    fn save_fuzz_results(&self) -> Result<(), io::Error> {
+    fn save_fuzz_results(&self, worker: &Worker) -> Result<(), FitmError> {
         let postrun = "out_postrun";
-        let out = format!("{}/out", ACTIVE_STATE);
-        let out_postrun = format!("{}/{}", ACTIVE_STATE, postrun);
+        let active_state = worker.workspace.active_state.to_str().unwrap();
+        let out = format!("{}/out", active_state);
+        let out_postrun = format!("{}/{}", active_state, postrun);
 
         // cp will copy out into out_postrun on the second and third copy because the destination already exists
         // thus we need src and dst to be the same name
+        let context = format!("saving the fuzz results of {}", self.state_path);
         if PathBuf::from(out_postrun.as_str()).is_dir() {
-            remove_dir_all(out_postrun.as_str())
-                .expect("[!] Error while removing out_postrun in save_fuzz_results");
+            remove_dir_all(out_postrun.as_str()).map_err(|e| FitmError::state_io(&context, e))?;
         }
-        cp_recursive(out.as_str(), out_postrun.as_str());
+        cp_recursive(out.as_str(), out_postrun.as_str())
+            .map_err(|e| FitmError::state_io(&context, e))?;
 
         // Don't copy INTO out_postrun, if you do the folders won't get merged by cp
-        let to = format!("./saved-states/{}", self.state_path);
-        cp_recursive(out_postrun.as_str(), to.as_str());
-        Ok(())
+        let to = worker.workspace.saved_state(&self.state_path);
+        cp_recursive(out_postrun.as_str(), to.to_str().unwrap())
+            .map_err(|e| FitmError::state_io(&context, e))
     }
 
     /// Needed for the two initial snapshots created based on the target
     /// binaries
+    #[allow(clippy::too_many_arguments)]
     pub fn init_run(
         &self,
+        tools: &Toolchain,
+        worker: &Worker,
         rand: &mut RomuRand,
         create_outputs: bool,
         create_snapshot: bool,
         cli_args: &[String],
         extra_envs: &HashMap<String, String>,
-    ) -> Result<Option<i32>, io::Error> {
-        ensure_dir_exists(ACTIVE_STATE);
+    ) -> Result<Option<i32>, FitmError> {
+        let active_state = worker.workspace.active_state.to_str().unwrap();
+        ensure_dir_exists(active_state)
+            .map_err(|e| FitmError::state_io(format!("creating {}", active_state), e))?;
 
         for file in &self.files {
-            if !cp_recursive(file, ACTIVE_STATE).success() {
-                panic!("Could not copy {}", file);
-            }
+            cp_recursive(file, active_state).map_err(|e| {
+                FitmError::state_io(format!("copying {} to {}", file, active_state), e)
+            })?;
         }
 
         // Start the initial snapshot run. We use our patched qemu to emulate
         // until the first recv of the target is hit. We have to use setsid to
         // circumvent the --shell-job problem of criu and stdbuf to have the
         // correct stdin, stdout and stderr file descriptors.
-        let closure_exit = NamespaceContext::new()
-            .execute(|| -> io::Result<i32> {
-                spawn_criu("./criu/criu/criu", "/tmp/criu_service.socket")
-                    .expect("[!] Could not spawn criuserver");
+        let closure_exit = NamespaceContext::new().run(
+            &format!("the init run of {}", self.state_path),
+            || -> io::Result<i32> {
+                // The criu service lives as long as this namespace and is reaped with it
+                #[allow(clippy::zombie_processes)]
+                let _criu_srv = spawn_criu(&tools.criu, worker)?;
+                let criu_log = env::current_dir()?.join(&worker.workspace.criu_stderr);
+                CriuClient::new(&worker.criu_socket).wait_until_ready(CRIU_STARTUP_TIMEOUT)?;
 
                 // Change into our state directory and generate the afl maps there
-                env::set_current_dir(ACTIVE_STATE)
-                    .expect("[!] Could not change into active_state during init_run");
+                worker.mount_active_state()?;
+                env::set_current_dir(&worker.mount_point)?;
 
-                let snapshot_dir = format!("{}/snapshot", env::current_dir().unwrap().display());
-                fs::create_dir(&snapshot_dir).expect("[-] Could not create snapshot dir!");
+                let snapshot_dir = format!("{}/snapshot", env::current_dir()?.display());
+                fs::create_dir(&snapshot_dir)?;
 
                 // Force the target PID to be in the Order of ~16k (high, but not hither than a normal pid_max)
-                // Also use a small range of random PIDs to allow for running multiple FITM instances
-                advance_pid((1 << 14) + rand.below(9001));
+                // Also use a small range of random PIDs to allow for running multiple FITM instances (and workers)
+                advance_pid(worker.pick_pid(rand))?;
 
                 // Open a file for stdout and stderr to log to
                 let (stdout, stderr) = (fs::File::create("stdout")?, fs::File::create("stderr")?);
@@ -288,16 +378,19 @@ impl FITMSnapshot {
                 // create the .cur_input so that criu snapshots a fd connected to
                 // .cur_input
                 let dev_null = "/dev/null";
-                let stdin = fs::File::open(dev_null).unwrap();
+                let stdin = fs::File::open(dev_null)?;
 
                 let mut command = Command::new("setsid");
                 command
-                    .args(&["stdbuf", "-oL", "../fitm-qemu-trace", &self.target_bin])
+                    .args(["stdbuf", "-oL"])
+                    .arg(&tools.qemu_trace)
+                    .arg(&self.target_bin)
                     .args(cli_args)
                     .stdin(Stdio::from(stdin))
                     .stdout(Stdio::from(stdout))
                     .stderr(Stdio::from(stderr))
                     .env("CRIU_SNAPSHOT_OUT_DIR", &snapshot_dir)
+                    .env("CRIU_SERVICE_SOCKET", &worker.criu_socket)
                     .env("AFL_NO_UI", "1")
                     // Let's just assume nothing tries to execute 0x16, so we never hit this entrypoint.
                     .env("AFL_ENTRYPOINT", "0x16");
@@ -313,192 +406,299 @@ impl FITMSnapshot {
                     command.env("LETS_DO_THE_TIMEWARP_AGAIN", "1");
                 }
 
+                // Only what criu logs from here on is about our dump
+                let since = criu_rpc::log_offset(&criu_log);
                 // Once exit_ok() is not nightly anymore we should use it here. It reports any possible exit error a process might have.
                 // wait() only reports the exit status and panics if the process stop by other means than calling exit().
                 // ref: https://doc.rust-lang.org/std/process/struct.ExitStatus.html#method.exit_ok
-                let exit_status = command
-                    .spawn()
-                    .expect("[!] Could not spawn snapshot run")
-                    .wait()
-                    .unwrap_or_else(|_| panic!("[!] Snapshot run did not end with call to exit(): {}", &self.target_bin));
+                let exit_status = command.spawn()?.wait()?;
 
                 println!(
                     "[*] Init run finished with exit code {:?}",
                     exit_status.code()
                 );
 
-                // TODO: Handle errors properly.
                 match exit_status.code() {
-                    None => {
-                        // https://doc.rust-lang.org/std/process/struct.ExitStatus.html#method.code, grep "on unix"
-                        // This is expected for a snapshot.
-                        println!("[*] Target was killed by signal. Assuming dump success.");
-                        Ok(0)
+                    // https://doc.rust-lang.org/std/process/struct.ExitStatus.html#method.code, grep "on unix"
+                    // This is expected for a snapshot, criu kills the target once the dump is done.
+                    None if create_snapshot => {
+                        let status = DumpStatus::wait_for(
+                            &criu_log,
+                            since,
+                            Path::new(&snapshot_dir),
+                            CRIU_STARTUP_TIMEOUT,
+                        );
+                        println!("[*] Target was killed by signal. Dump status: {:?}", status);
+                        status.into_result().map(|_| 0)
                     }
+                    None => Ok(0),
                     Some(n) => {
                         println!("[!] Unexpected exit status '{}' from snapshot creation.", n);
-                        Err(io::Error::new(ErrorKind::Other, "[!] criu dump failed, check active-state dir."))
-                    },
+                        Err(io::Error::other(
+                            "[!] criu dump failed, check active-state dir.",
+                        ))
+                    }
                 }
-            })
-            .expect("[!] Namespace creation failed") // This panic is never triggered as no branch returns Err()
-            .wait()
-            .expect("[!] Namespace wait failed")
-            .code()
-            .unwrap();
+            },
+        )?;
 
         let mut pid = None;
         if create_snapshot {
-            if closure_exit == 0 {
-                // With snapshot_run we move the state folder instead of copying it,
-                // but in this initial case we need to use
-                // the state folder shortly after running this function
-                // TODO: don't call unwrap on Err. It's alright for now as this cases is unrecoverable.
-                pid = Some(utils::parse_pid().unwrap());
-                utils::mv_rename(ACTIVE_STATE, &format!("./saved-states/{}", self.state_path));
-            } else {
-                panic!(
-                    "[!] Snapshot in init_run failed. Check latest active-state folder for clues."
-                );
+            if closure_exit != 0 {
+                return Err(FitmError::CriuDump {
+                    state: self.state_path.clone(),
+                    reason: format!(
+                        "the init run exited with {}, check {:?} for clues",
+                        closure_exit, worker.workspace.active_state
+                    ),
+                });
             }
+            // With snapshot_run we move the state folder instead of copying it,
+            // but in this initial case we need to use
+            // the state folder shortly after running this function
+            pid = Some(utils::parse_pid(&worker.workspace).map_err(|e| {
+                FitmError::state_io(format!("reading the pid of {}", self.state_path), e)
+            })?);
+            utils::mv(
+                active_state,
+                worker
+                    .workspace
+                    .saved_state(&self.state_path)
+                    .to_str()
+                    .unwrap(),
+            )
+            .map_err(|e| FitmError::state_io(format!("saving {}", self.state_path), e))?;
         }
 
         if create_outputs {
-            self.copy_fds_to_output_for(0, 0);
+            self.copy_fds_to_output_for(&worker.workspace, 0, 0)?;
 
-            remove_dir_all(ACTIVE_STATE)
-                .expect("[!] Could not remove active_state during init_run");
+            remove_dir_all(active_state)
+                .map_err(|e| FitmError::state_io(format!("removing {}", active_state), e))?;
         }
 
         Ok(pid)
     }
 
-    fn found_crashes(&self) -> bool {
-        let iter: Vec<DirEntry> = fs::read_dir(format!("{}/out/main/crashes", ACTIVE_STATE))
-            .expect("[!]")
-            .map(|entry| entry.unwrap())
-            .collect();
-
-        !iter.is_empty()
+    fn found_crashes(&self, worker: &Worker) -> bool {
+        utils::afl_nodes(&worker.workspace.active_state.join("out"))
+            .iter()
+            .any(|node| {
+                fs::read_dir(node.join("crashes"))
+                    .map(|mut entries| entries.next().is_some())
+                    .unwrap_or(false)
+            })
     }
 
-    /// Start a single fuzz run in afl which gets restored from an earlier
-    /// snapshot. Because we use sh and the restore script we have to skip the
-    /// bin check
-    fn fuzz_run(&self, run_duration: &Duration) -> Result<(), io::Error> {
+    /// Runs AFL++ node `node` of a fuzz run on `worker` (see `Worker::node`), syncing with the other
+    /// nodes in `sync_dir`. Only call this in a `NamespaceContext`.
+    fn afl_fuzz_node(
+        &self,
+        tools: &Toolchain,
+        worker: &Worker,
+        node: usize,
+        sync_dir: &Path,
+        run_duration: &Duration,
+    ) -> Result<i32, io::Error> {
         // If not currently needed, all states should reside in `saved-state`.
         // Thus they need to be copied to be fuzzed
         // stdout is mutable so it can be read later
-        let exit_status = NamespaceContext::new()
-            .execute(|| -> io::Result<i32> {
-                let (stdout, stderr) = self.to_active()?;
-                println!(
-                    "==== [*] Start fuzzing {} ({:?}) ====",
-                    self.state_path,
-                    PathBuf::from(&self.target_bin).file_name().unwrap()
-                );
-                // Spawn the afl run in a command. This run is relative to the state dir
-                // meaning we already are inside the directory. This prevents us from
-                // accidentally using different resources than we expect.
+        let (stdout, stderr, _) = self.to_active(tools, &worker.node(node))?;
+        // Spawn the afl run in a command. This run is relative to the state dir
+        // meaning we already are inside the directory. This prevents us from
+        // accidentally using different resources than we expect.
 
-                utils::clear_out();
+        utils::clear_out()?;
 
-                let exit_status = Command::new("../AFLplusplus/afl-fuzz")
-                    .args(&[
-                        "-i",
-                        "./in",
-                        "-o",
-                        "./out",
-                        // No mem limit
-                        "-m",
-                        "none",
-                        // Fuzzing as main node
-                        "-M",
-                        "main",
-                        "-d",
-                        // At what time to stop this afl run
-                        "-V",
-                        &format!("{}", run_duration.as_secs()),
-                        // Timeout per individual execution
-                        "-t",
-                        &format!("{}", self.timeout.as_millis()),
-                        "--",
-                        "bash",
-                        // Our restore script
-                        "./restore.sh",
-                        // The fuzzer input file
-                        "@@",
-                    ])
-                    .stdout(Stdio::from(stdout))
-                    .stderr(Stdio::from(stderr))
-                    // In case we already started the fuzz run earlier, resume it here.
-                    .env("AFL_AUTORESUME", "1")
-                    .env("CRIU_SNAPSHOT_DIR", "./snapshot")
-                    // We launch sh first, which is (hopefully) not instrumented
-                    .env("AFL_SKIP_BIN_CHECK", "1")
-                    .env("AFL_NO_UI", "1")
-                    // Give criu forkserver up to a minute to spawn
-                    .env("AFL_FORKSRV_INIT_TMOUT", "60000")
-                    .env("FITM_CREATE_OUTPUTS", "1")
-                    // this will split up multi-byte compares.
-                    // The map gets denser, but we also not get stuck as easily
-                    .env("AFL_COMPCOV_LEVEL", "2")
-                    // We don't want afl to shorten our inputs, ever.
-                    .env("AFL_DISABLE_TRIM", "1")
-                    .spawn()?
-                    .wait()?;
+        let name = utils::afl_node_name(node);
+        let role: &[&str] = if node == 0 {
+            // Fuzzing as main node, without deterministic stages
+            &["-M", &name, "-d"]
+        } else {
+            &["-S", &name]
+        };
+        let exit_status = Command::new(&tools.afl_fuzz)
+            .args(["-i", "./in", "-o"])
+            .arg(sync_dir)
+            // No mem limit
+            .args(["-m", "none"])
+            .args(role)
+            .args([
+                // At what time to stop this afl run
+                "-V",
+                &format!("{}", run_duration.as_secs()),
+                // Timeout per individual execution
+                "-t",
+                &format!("{}", self.timeout.as_millis()),
+                "--",
+                "bash",
+                // Our restore script
+                "./restore.sh",
+                // The fuzzer input file
+                "@@",
+            ])
+            .stdout(Stdio::from(stdout))
+            .stderr(Stdio::from(stderr))
+            // In case we already started the fuzz run earlier, resume it here.
+            .env("AFL_AUTORESUME", "1")
+            .env("CRIU_SNAPSHOT_DIR", "./snapshot")
+            // We launch sh first, which is (hopefully) not instrumented
+            .env("AFL_SKIP_BIN_CHECK", "1")
+            .env("AFL_NO_UI", "1")
+            // Give criu forkserver up to a minute to spawn
+            .env("AFL_FORKSRV_INIT_TMOUT", "60000")
+            .env("FITM_CREATE_OUTPUTS", "1")
+            // this will split up multi-byte compares.
+            // The map gets denser, but we also not get stuck as easily
+            .env("AFL_COMPCOV_LEVEL", "2")
+            // We don't want afl to shorten our inputs, ever.
+            .env("AFL_DISABLE_TRIM", "1")
+            .spawn()?
+            .wait()?;
+
+        // Killed (e.g. on shutdown, see `budget::kill_children`), report it like `restore_with_input`
+        Ok(exit_status
+            .code()
+            .unwrap_or_else(|| 128 + exit_status.signal().unwrap_or(0)))
+    }
 
-                Ok(exit_status.code().unwrap())
+    /// Start a single fuzz run in afl which gets restored from an earlier
+    /// snapshot: the main node and `worker.secondaries` secondaries at once, each in a namespace
+    /// and active state of its own. Because we use sh and the restore script we have to skip the
+    /// bin check. With secondaries, the nodes sync in `Worker::sync_dir` while fuzzing, their
+    /// outputs end up in main's `out` afterwards.
+    fn fuzz_run(
+        &self,
+        tools: &Toolchain,
+        worker: &Worker,
+        run_duration: &Duration,
+    ) -> Result<(), FitmError> {
+        println!(
+            "==== [*] Start fuzzing {} ({:?}) ====",
+            self.state_path,
+            PathBuf::from(&self.target_bin).file_name().unwrap()
+        );
+
+        // The nodes change into their active states, so the sync dir needs an absolute path
+        let sync_dir = if worker.secondaries == 0 {
+            PathBuf::from("./out")
+        } else {
+            let sync_dir = worker.sync_dir();
+            let _ = remove_dir_all(&sync_dir);
+            fs::create_dir_all(&sync_dir)
+                .and_then(|_| fs::canonicalize(&sync_dir))
+                .map_err(|e| FitmError::state_io(format!("creating {:?}", sync_dir), e))?
+        };
+        let namespaces: Vec<io::Result<Namespace>> = (0..=worker.secondaries)
+            .map(|node| {
+                NamespaceContext::new()
+                    .execute(|| self.afl_fuzz_node(tools, worker, node, &sync_dir, run_duration))
             })
-            .expect("[!] Namespace creation failed")
-            .wait()
-            .expect("[!] Namespace wait failed")
-            .code()
-            .unwrap();
+            .collect();
+
+        // Wait for every node that started, then report the first that failed
+        let mut failed = None;
+        for (node, namespace) in namespaces.into_iter().enumerate() {
+            let name = utils::afl_node_name(node);
+            let what = format!("afl-fuzz node {} of {}", name, self.state_path);
+            let res = namespace
+                .map_err(|e| {
+                    FitmError::Namespace(format!(
+                        "could not create a namespace for {}: {}",
+                        what, e
+                    ))
+                })
+                .and_then(|mut namespace| namespace.exit_code(&what));
+            let restore_log = worker.node(node).workspace.active_state.join(RESTORE_LOG);
+            let e = match res {
+                Ok(0) => continue,
+                // afl-fuzz gives up if the forkserver never comes up, most likely the restore failed
+                Ok(code) if !restore::restore_succeeded(&restore_log) => FitmError::CriuRestore {
+                    state: self.state_path.clone(),
+                    reason: format!(
+                        "afl-fuzz node {} exited with {} before the restore finished",
+                        name, code
+                    ),
+                    log: restore_log,
+                },
+                Ok(code) => FitmError::AflFuzz {
+                    state: self.state_path.clone(),
+                    node: name,
+                    code,
+                },
+                Err(e) => e,
+            };
+            println!(
+                "[!] Error during afl-fuzz execution ({}). Please check latest statefolder for output",
+                e
+            );
+            failed.get_or_insert(e);
+        }
+        if let Some(e) = failed {
+            return Err(e);
+        }
 
         if self.state_path == "fitm-gen4-state0" {
             sleep(Duration::from_millis(0));
         }
 
-        if exit_status != 0 {
-            let info =
-                "[!] Error during afl-fuzz execution. Please check latest statefolder for output";
-            println!("{}", info);
-            std::process::exit(1);
+        // Collect the outputs of all nodes in main's `out`, the secondaries don't need their copies anymore
+        let out = worker.workspace.active_state.join("out");
+        if worker.secondaries > 0 {
+            for node in 0..=worker.secondaries {
+                let name = utils::afl_node_name(node);
+                let context = format!("collecting the results of {} of {}", name, self.state_path);
+                let _ = remove_dir_all(out.join(&name));
+                utils::mv(
+                    sync_dir.join(&name).to_str().unwrap(),
+                    out.join(&name).to_str().unwrap(),
+                )
+                .map_err(|e| FitmError::state_io(&context, e))?;
+                if node != 0 {
+                    remove_dir_all(worker.node(node).workspace.active_state)
+                        .map_err(|e| FitmError::state_io(&context, e))?;
+                }
+            }
+            let _ = remove_dir_all(&sync_dir);
         }
 
         println!("         Fuzzer Stats:");
-        match fs::read_to_string("./active-state/out/main/fuzzer_stats") {
-            Ok(stats) => {
-                for line in stats.split('\n') {
-                    if line.starts_with("execs_done")
-                        || line.starts_with("execs_per_sec")
-                        || line.starts_with("paths_total")
-                        || line.starts_with("max_depth")
-                        || line.starts_with("stability")
-                        || line.starts_with("unique_crashes")
-                        || line.starts_with("unique_hangs")
-                        || line.starts_with("cycles_done")
-                    {
-                        println!(
-                            "         {}- {}{}",
-                            color::Fg(color::Green),
-                            line,
-                            style::Reset
-                        );
+        for node in utils::afl_nodes(&out) {
+            if worker.secondaries > 0 {
+                println!("         {:?}:", node.file_name().unwrap());
+            }
+            match fs::read_to_string(node.join("fuzzer_stats")) {
+                Ok(stats) => {
+                    for line in stats.split('\n') {
+                        if line.starts_with("execs_done")
+                            || line.starts_with("execs_per_sec")
+                            || line.starts_with("paths_total")
+                            || line.starts_with("max_depth")
+                            || line.starts_with("stability")
+                            || line.starts_with("unique_crashes")
+                            || line.starts_with("unique_hangs")
+                            || line.starts_with("cycles_done")
+                        {
+                            println!(
+                                "         {}- {}{}",
+                                color::Fg(color::Green),
+                                line,
+                                style::Reset
+                            );
+                        }
                     }
                 }
+                Err(_) => println!(
+                    "{}Failed to read fuzzer-stats. Skipping.{}",
+                    color::Fg(color::Red),
+                    style::Reset
+                ),
             }
-            Err(_) => println!(
-                "{}Failed to read fuzzer-stats. Skipping.{}",
-                color::Fg(color::Red),
-                style::Reset
-            ),
         }
 
         println!("==== [*] Finished fuzzing {} ====", self.state_path);
 
-        if self.found_crashes() {
+        if self.found_crashes(worker) {
             println!(
                 "{}{}==== [*] Crashes present after fuzzing {} ===={}",
                 color::Fg(color::Green),
@@ -512,96 +712,186 @@ impl FITMSnapshot {
         // let mut stdout_content = String::new();
         // stdout.read_to_string(&mut stdout_content).unwrap();
         // println!("==== [*] AFL++ stdout: \n{}", stdout_content);
-        self.save_fuzz_results()?;
+        self.save_fuzz_results(worker)?;
 
         Ok(())
     }
 
-    pub fn create_outputs_file(
+    /// Restores this snapshot and feeds it `entry_path`.
+    /// Returns the exit code of the restored target, or 128 + signal if it was killed.
+    fn restore_with_input(
         &self,
-        entry_path: PathBuf,
-        output_path: &str,
-    ) -> Result<(), io::Error> {
-        let exit_status = NamespaceContext::new()
-            .execute(|| -> io::Result<i32> {
-                let (stdout, stderr) = self.to_active()?;
-
-                let entry_file =
-                    fs::File::open(entry_path.clone()).expect("[!] Could not open queue file");
+        tools: &Toolchain,
+        worker: &Worker,
+        entry_path: &Path,
+    ) -> Result<i32, FitmError> {
+        let code = NamespaceContext::new().run(
+            &format!("the restore of {}", self.state_path),
+            || -> io::Result<i32> {
+                let (stdout, stderr, plan) = self.to_active(tools, worker)?;
+
+                let entry_file = fs::File::open(entry_path)?;
                 println!("==== [*] Using input: {:?} ====", entry_path);
-
-                let _restore_status = Command::new("setsid")
-                    .args(&[
-                        "stdbuf",
-                        "-oL",
-                        "bash",
-                        "./restore.sh",
-                        entry_path.clone().to_str().unwrap(),
-                    ])
+                let start = Instant::now();
+
+                let _restore_status = plan
+                    .command(
+                        Path::new("."),
+                        entry_path,
+                        &[
+                            ("FITM_CREATE_OUTPUTS", OsStr::new("1")),
+                            ("AFL_NO_UI", OsStr::new("1")),
+                        ],
+                    )?
                     .stdin(Stdio::from(entry_file))
-                    .stdout(Stdio::from(stdout.try_clone().unwrap()))
-                    .stderr(Stdio::from(stderr.try_clone().unwrap()))
-                    .env("FITM_CREATE_OUTPUTS", "1")
-                    .env("AFL_NO_UI", "1")
-                    .spawn()
-                    .expect("[!] Could not spawn snapshot run")
-                    .wait()
-                    .expect("[!] Snapshot restore failed");
-
-                let exit_status =
-                    utils::waitpid(self.pid.unwrap()).expect("[!] create_outputs_file(): Snapshot run failed");
-                Ok(exit_status.code().unwrap())
-            })
-            .expect("[!] Namespace creation failed")
-            .wait()
-            .expect("[!] Namespace wait failed")
-            .code()
-            .unwrap();
+                    .stdout(Stdio::from(stdout.try_clone()?))
+                    .stderr(Stdio::from(stderr.try_clone()?))
+                    .spawn()?
+                    .wait()?;
 
-        if self.state_path == "fitm-gen2-state0" {
-            sleep(Duration::from_millis(0));
+                let pid = self.pid.ok_or_else(|| {
+                    io::Error::other(format!("the pid of {} is unknown", self.state_path))
+                })?;
+                let exit_status = utils::waitpid(pid)?;
+                // We're in the active state dir, the parent reads this in `measure_exec_latency`
+                fs::write(EXEC_TIME_FILE, start.elapsed().as_micros().to_string())?;
+                Ok(exit_status
+                    .code()
+                    .unwrap_or_else(|| 128 + exit_status.signal().unwrap_or(0)))
+            },
+        )?;
+        let restore_log = worker.workspace.active_state.join(RESTORE_LOG);
+        if !restore::restore_succeeded(&restore_log) {
+            return Err(self.restore_error(worker, "criu did not finish the restore"));
         }
+        Ok(code)
+    }
 
-        if exit_status != 0 {
-            let info =
-                "[!] Error during create_outputs execution. Please check latest statefolder for output";
-            println!("{}", info);
-            std::process::exit(1);
+    /// Restores the snapshot for up to `CALIBRATION_INPUTS` inputs in `input_dir`
+    /// and returns the slowest restore + exec time. `None` if there are no inputs.
+    pub fn measure_exec_latency(
+        &self,
+        tools: &Toolchain,
+        worker: &Worker,
+        input_dir: &Path,
+    ) -> Result<Option<Duration>, FitmError> {
+        let mut latency = None;
+        let entries = utils::read_dir_sorted(input_dir)
+            .map_err(|e| FitmError::state_io(format!("reading {:?}", input_dir), e))?;
+        for entry in entries
+            .iter()
+            .filter(|entry| entry.path().is_file())
+            .take(CALIBRATION_INPUTS)
+        {
+            let exec_time = worker.workspace.active_state.join(EXEC_TIME_FILE);
+            let _ = fs::remove_file(&exec_time);
+            let input = fs::canonicalize(entry.path())
+                .map_err(|e| FitmError::state_io(format!("reading {:?}", entry.path()), e))?;
+            self.restore_with_input(tools, worker, &input)?;
+            // Only written once the restored target is done
+            let micros: u64 = fs::read_to_string(&exec_time)
+                .ok()
+                .and_then(|micros| micros.trim().parse().ok())
+                .ok_or_else(|| self.restore_error(worker, "no exec time was measured"))?;
+            latency = latency.max(Some(Duration::from_micros(micros)));
+        }
+        Ok(latency)
+    }
+
+    /// A `CriuRestore` error for this snapshot, restored by `worker`
+    fn restore_error(&self, worker: &Worker, reason: impl Into<String>) -> FitmError {
+        FitmError::CriuRestore {
+            state: self.state_path.clone(),
+            reason: reason.into(),
+            log: worker.workspace.active_state.join(RESTORE_LOG),
         }
+    }
 
-        env::set_current_dir(ACTIVE_STATE)?;
+    /// Moves the outputs the last restored run left in `active-state/fd` to `output_path`,
+    /// named after the input that created them.
+    fn collect_outputs(
+        &self,
+        worker: &Worker,
+        entry_path: &Path,
+        output_path: &str,
+    ) -> Result<(), FitmError> {
         // Move created outputs to a given folder
         // Probably saved states, as current active-state folder will be deleted with next to_active()
-        for entry in fs::read_dir("./fd").expect("[!] Could not read populated fd folder") {
-            let dir_entry = entry.unwrap();
-            let file_name = &dir_entry.file_name();
+        let fd = worker.workspace.active_state.join("fd");
+        let collect = || -> io::Result<()> {
+            for entry in fs::read_dir(&fd)? {
+                let dir_entry = entry?;
+
+                // skip empty outputs --> easier debugging
+                if get_filesize(&dir_entry.path())? == 0 {
+                    continue;
+                }
 
-            // skip empty outputs --> easier debugging
-            if get_filesize(&dir_entry.path()) == 0 {
-                continue;
+                let destination_path = Path::new(output_path).join(entry_path.file_name().unwrap());
+                fs::copy(dir_entry.path(), destination_path)?;
             }
+            Ok(())
+        };
+        collect().map_err(|e| {
+            FitmError::state_io(
+                format!("collecting the outputs of {} in {:?}", self.state_path, fd),
+                e,
+            )
+        })
+    }
 
-            let from = format!("./fd/{}", &file_name.to_str().unwrap());
-            let destination_path =
-                Path::new(output_path).join(entry_path.file_name().unwrap().to_str().unwrap());
-            let to = destination_path
-                .to_str()
-                .expect("[!] Couldn't convert destination_path to str");
-            fs::copy(from, to).expect("[!] Could not copy output file to outputs folder");
+    pub fn create_outputs_file(
+        &self,
+        tools: &Toolchain,
+        worker: &Worker,
+        entry_path: PathBuf,
+        output_path: &str,
+    ) -> Result<(), FitmError> {
+        let exit_status = self.restore_with_input(tools, worker, &entry_path)?;
+
+        if self.state_path == "fitm-gen2-state0" {
+            sleep(Duration::from_millis(0));
+        }
+
+        if exit_status != 0 {
+            println!(
+                "[!] Error during create_outputs execution. Please check latest statefolder for output"
+            );
+            return Err(self.restore_error(
+                worker,
+                format!("the target exited with {} on {:?}", exit_status, entry_path),
+            ));
         }
 
-        // After creating the outputs we go back into the base directory
-        env::set_current_dir(&Path::new("../")).unwrap();
+        self.collect_outputs(worker, &entry_path, output_path)
+    }
 
-        Ok(())
+    /// Like `create_outputs_file`, but a misbehaving target is not fatal.
+    /// Returns the exit code of the target, or 128 + signal if it crashed.
+    pub fn replay_file(
+        &self,
+        tools: &Toolchain,
+        worker: &Worker,
+        entry_path: &Path,
+        output_path: &str,
+    ) -> Result<i32, FitmError> {
+        let exit_status = self.restore_with_input(tools, worker, entry_path)?;
+        if worker.workspace.active_state.join("fd").is_dir() {
+            self.collect_outputs(worker, entry_path, output_path)?;
+        }
+        Ok(exit_status)
     }
 
-    pub fn create_outputs(&self, input_path: &str, output_path: &str) -> Result<(), io::Error> {
+    pub fn create_outputs(
+        &self,
+        tools: &Toolchain,
+        worker: &Worker,
+        input_path: &str,
+        output_path: &str,
+    ) -> Result<(), FitmError> {
         // Work with absolute paths
-        let input_path = build_create_absolute_path(input_path)
-            .expect("[!] Error while constructing absolute input_dir path");
-        let output_path = build_create_absolute_path(output_path)
-            .expect("[!] Error while constructing absolute output_dir path");
+        let input_path = build_create_absolute_path(input_path)?;
+        let output_path = build_create_absolute_path(output_path)?;
 
         println!(
             "==== [*] Creating outputs for state: {} ====",
@@ -610,26 +900,33 @@ impl FITMSnapshot {
         let _ = io::stdout().flush();
 
         // Iterate through all entries of given folder and create output for each
-        for (_, entry) in fs::read_dir(input_path)
-            .unwrap_or_else(|_| panic!("[!] Could not read queue of state: {}", self.state_path))
-            .enumerate()
-        {
-            let entry_unwrapped = entry.unwrap();
-            if entry_unwrapped.file_type().unwrap().is_dir() {
+        let entries = utils::read_dir_sorted(&input_path).map_err(|e| {
+            FitmError::state_io(format!("reading the queue of {}", self.state_path), e)
+        })?;
+        for entry in entries {
+            let entry_path = entry.path();
+            if entry_path.is_dir() {
                 continue;
             }
-            let entry_path = entry_unwrapped.path();
 
-            self.create_outputs_file(entry_path, output_path.as_str())?;
+            self.create_outputs_file(tools, worker, entry_path, output_path.as_str())?;
         }
 
         Ok(())
     }
 
-    pub fn create_environment(&self) -> Result<(File, File), io::Error> {
-        utils::create_restore_sh(self);
+    /// Mounts the worker's active state, writes `restore.sh` and changes into it.
+    /// Only call this in a `NamespaceContext`.
+    pub fn create_environment(
+        &self,
+        tools: &Toolchain,
+        worker: &Worker,
+    ) -> Result<(File, File, RestorePlan), io::Error> {
+        worker.mount_active_state()?;
+        let workspace = worker.mounted();
+        let plan = utils::create_restore_sh(self, tools, &workspace)?;
         // Change into our state directory and generate the afl maps there
-        env::set_current_dir(ACTIVE_STATE)?;
+        env::set_current_dir(&workspace.active_state)?;
 
         // Open a file for stdout and stderr to log to
         let (stdout, stderr) = (
@@ -637,37 +934,63 @@ impl FITMSnapshot {
             fs::File::create("stderr-afl")?,
         );
 
-        Ok((stdout, stderr))
+        Ok((stdout, stderr, plan))
     }
 
     /// Copies the state from saved-states to active-state
-    /// Returns a tuple of (stdout, stderr)
+    /// Returns a tuple of (stdout, stderr, the restore plan of the state)
     /// We have to copy to an active state, because each state can only be restored once in CRIU
     /// Initial indicates which file handles (stdout, stderr) are returned
-    pub fn to_active(&self) -> Result<(File, File), io::Error> {
+    pub fn to_active(
+        &self,
+        tools: &Toolchain,
+        worker: &Worker,
+    ) -> Result<(File, File, RestorePlan), io::Error> {
         // If not currently needed, all states should reside in `saved-state`.
         // Thus they need to be copied to be fuzzed
         // clear active-state first to make sure fuzzed state folder ends up
         // as "active-state" and not within "active-state"
-        match std::fs::remove_dir_all(ACTIVE_STATE) {
+        let active_state = &worker.workspace.active_state;
+        match std::fs::remove_dir_all(active_state) {
             Ok(_) => (),
             Err(e) if e.kind() == ErrorKind::NotFound => (),
-            Err(e) => println!("[!] Error while removing {}: {:?}", ACTIVE_STATE, e),
+            Err(e) => println!("[!] Error while removing {:?}: {:?}", active_state, e),
         };
+        if let Some(parent) = active_state.parent() {
+            fs::create_dir_all(parent)?;
+        }
 
-        utils::cp_recursive(&format!("./saved-states/{}", self.state_path), ACTIVE_STATE);
-
-        let (stdout, stderr) = self.create_environment()?;
+        let saved_state = worker.workspace.saved_state(&self.state_path);
+        utils::cp_recursive(
+            saved_state.to_str().unwrap(),
+            active_state.to_str().unwrap(),
+        )?;
+        incremental::link_parent(
+            &active_state.join("snapshot"),
+            &saved_state.join("snapshot"),
+        )?;
+        // A log of an earlier restore would hide a failed one, see `restore_succeeded`
+        match fs::remove_file(active_state.join(RESTORE_LOG)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
 
-        Ok((stdout, stderr))
+        self.create_environment(tools, worker)
     }
 
+    /// Restores this snapshot with `input_path` and saves the snapshot the target dumps on its next recv
+    /// as `state_id` of the next own generation, in `dest`
     pub fn create_next_snapshot(
         &self,
+        tools: &Toolchain,
+        worker: &Worker,
         state_id: usize,
         input_path: &str,
-    ) -> Result<Option<FITMSnapshot>, io::Error> {
+        dest: &Path,
+    ) -> Result<Option<FITMSnapshot>, FitmError> {
+        let active_state = worker.workspace.active_state.to_str().unwrap();
         let next_snapshot = FITMSnapshot::new(
+            &worker.workspace,
             self.generation + 2,
             state_id,
             self.target_bin.to_string(),
@@ -676,7 +999,7 @@ impl FITMSnapshot {
             self.server,
             true,
             self.pid,
-        );
+        )?;
 
         println!(
             "==== [*] Running snapshot run on {} for input: \"{}\" ====",
@@ -686,87 +1009,99 @@ impl FITMSnapshot {
 
         let _ = io::stdout().flush();
 
-        let exit_code = NamespaceContext::new()
-            .execute(|| -> io::Result<i32> {
-                spawn_criu("./criu/criu/criu", "/tmp/criu_service.socket")
-                    .expect("[!] Could not spawn criuserver");
+        let exit_code = NamespaceContext::new().run(
+            &format!("the snapshot run of {}", self.state_path),
+            || -> io::Result<i32> {
+                // The criu service lives as long as this namespace and is reaped with it
+                #[allow(clippy::zombie_processes)]
+                let _criu_srv = spawn_criu(&tools.criu, worker)?;
+                let criu_log = env::current_dir()?.join(&worker.workspace.criu_stderr);
+                CriuClient::new(&worker.criu_socket).wait_until_ready(CRIU_STARTUP_TIMEOUT)?;
 
-                let (stdout, stderr) = self.to_active()?;
+                let (stdout, stderr, plan) = self.to_active(tools, worker)?;
 
-                // let (stdout, stderr) = self.create_environment()?;
-                let stdin_file = fs::File::open(input_path).unwrap();
-                let snapshot_dir = format!("{}/snapshot", env::current_dir().unwrap().display());
+                let stdin_file = fs::File::open(input_path)?;
+                let snapshot_dir = format!("{}/snapshot", env::current_dir()?.display());
 
-                let next_snapshot_dir =
-                    format!("{}/next_snapshot", env::current_dir().unwrap().display());
+                let next_snapshot_dir = format!("{}/next_snapshot", env::current_dir()?.display());
 
                 let _ = fs::remove_dir_all(&next_snapshot_dir);
-                fs::create_dir(&next_snapshot_dir).expect("[-] Could not create snapshot dir!");
-
-                let _restore = Command::new("setsid")
-                    .args(&["stdbuf", "-oL", "./restore.sh", input_path])
+                fs::create_dir(&next_snapshot_dir)?;
+
+                // Only what criu logs from here on is about our dump
+                let since = criu_rpc::log_offset(&criu_log);
+                let _restore = plan
+                    .command(
+                        Path::new("."),
+                        Path::new(input_path),
+                        &[
+                            ("LETS_DO_THE_TIMEWARP_AGAIN", OsStr::new("1")),
+                            ("CRIU_SNAPSHOT_DIR", OsStr::new(&snapshot_dir)),
+                            ("CRIU_SNAPSHOT_OUT_DIR", OsStr::new(&next_snapshot_dir)),
+                            ("CRIU_SERVICE_SOCKET", worker.criu_socket.as_os_str()),
+                            ("AFL_NO_UI", OsStr::new("1")),
+                        ],
+                    )?
                     .stdin(Stdio::from(stdin_file))
                     .stdout(Stdio::from(stdout))
                     .stderr(Stdio::from(stderr))
-                    .env("LETS_DO_THE_TIMEWARP_AGAIN", "1")
-                    .env("CRIU_SNAPSHOT_DIR", &snapshot_dir)
-                    .env("CRIU_SNAPSHOT_OUT_DIR", &next_snapshot_dir)
-                    .env("AFL_NO_UI", "1")
-                    .spawn()
-                    .expect("[!] Could not spawn snapshot run")
-                    .wait()
-                    .expect("[!] Snapshot restore failed");
+                    .spawn()?
+                    .wait()?;
 
-                let exit_status =
-                    utils::waitpid(self.pid.unwrap()).expect("[!] create_next_snapshot(): Snapshot run failed");
+                let pid = self.pid.ok_or_else(|| {
+                    io::Error::other(format!("the pid of {} is unknown", self.state_path))
+                })?;
+                let exit_status = utils::waitpid(pid)?;
                 println!("[*] Snapshot run exited with code {:?}", exit_status.code());
-                
-                // TODO: Handle errors properly
+
                 match exit_status.code() {
+                    // https://doc.rust-lang.org/std/process/struct.ExitStatus.html#method.code, grep "on unix"
+                    // This is expected for a snapshot, criu kills the target once the dump is done.
                     None => {
-                        // https://doc.rust-lang.org/std/process/struct.ExitStatus.html#method.code, grep "on unix"
-                        // This is expected for a snapshot.
-                        println!("[*] Target was killed by signal. Assuming dump success.");
-                        Ok(0)
+                        let status = DumpStatus::wait_for(
+                            &criu_log,
+                            since,
+                            Path::new(&next_snapshot_dir),
+                            CRIU_STARTUP_TIMEOUT,
+                        );
+                        println!("[*] Target was killed by signal. Dump status: {:?}", status);
+                        status.into_result().map(|_| 0)
                     }
                     Some(n) => {
                         println!("[!] Unexpected exit status '{}' from snapshot creation.", n);
-                        Err(io::Error::new(ErrorKind::Other, "[!] criu dump failed. Target exited early or something is broken. Check active-state dir."))
+                        Err(io::Error::other("[!] criu dump failed. Target exited early or something is broken. Check active-state dir."))
                     },
                 }
-            })
-            .expect("[!] Namespace creation failed")
-            .wait()
-            .expect("[!] Namespace wait failed")
-            .code()
-            .unwrap();
-
-        let _next_snapshot_path = format!(
-            "{}/{}/next_snapshot",
-            env::current_dir().unwrap().display(),
-            ACTIVE_STATE
-        );
+            },
+        )?;
 
         let success = exit_code == 0;
         if success {
-            fs::remove_dir_all(&format!("./{}/snapshot", ACTIVE_STATE))
-                .expect("Failed to remove old snapshot");
-            fs::rename(
-                &format!("./{}/next_snapshot", ACTIVE_STATE),
-                &format!("./{}/snapshot", ACTIVE_STATE),
-            )
-            .expect("Failed to move folder");
-            // We need to store the prev input, as it may get deleted from the prev generation through minimization.
-            fs::copy(input_path, &format!("./{}/prev_input", ACTIVE_STATE))
-                .expect("Could not copy file :(");
-            fs::write(&format!("./{}/prev_input_path", ACTIVE_STATE), input_path)
-                .expect("Could not store prev_input_path");
-            fs::create_dir(&format!("./{}/next_snapshot", ACTIVE_STATE))
-                .expect("Failed to reinitialize ./next_snapshot");
-            utils::mv_rename(
-                ACTIVE_STATE,
-                &format!("./saved-states/{}", next_snapshot.state_path),
-            );
+            let save = || -> io::Result<()> {
+                fs::remove_dir_all(format!("./{}/snapshot", active_state))?;
+                fs::rename(
+                    format!("./{}/next_snapshot", active_state),
+                    format!("./{}/snapshot", active_state),
+                )?;
+                // We need to store the prev input, as it may get deleted from the prev generation through minimization.
+                fs::copy(input_path, format!("./{}/prev_input", active_state))?;
+                fs::write(format!("./{}/prev_input_path", active_state), input_path)?;
+                fs::create_dir(format!("./{}/next_snapshot", active_state))?;
+                fs::create_dir_all(dest)?;
+                utils::mv(
+                    active_state,
+                    dest.join(&next_snapshot.state_path).to_str().unwrap(),
+                )
+            };
+            save().map_err(|e| {
+                FitmError::state_io(
+                    format!(
+                        "saving the snapshot {} of {}",
+                        next_snapshot.state_path, self.state_path
+                    ),
+                    e,
+                )
+            })?;
             println!(
                 "         ^-> finished after {} millis",
                 utils::current_millis() - start_millis
@@ -797,19 +1132,19 @@ impl FITMSnapshot {
     /// bin check
     fn afl_cmin(
         &self,
+        tools: &Toolchain,
+        worker: &Worker,
         input_dir: &str,
         output_dir: &str,
         keep_traces: bool,
-    ) -> Result<(), io::Error> {
-        let input_dir = build_create_absolute_path(input_dir)
-            .expect("[!] Error while constructing absolute input_dir path");
-        let output_dir = build_create_absolute_path(output_dir)
-            .expect("[!] Error while constructing absolute output_dir path");
+    ) -> Result<(), FitmError> {
+        let input_dir = build_create_absolute_path(input_dir)?;
+        let output_dir = build_create_absolute_path(output_dir)?;
 
         // Make sure we always have at least a single input (even if the other side finished)
-        if fs::read_dir(&input_dir).unwrap().next().is_none() {
+        if fs::read_dir(&input_dir)?.next().is_none() {
             println!("     [!] We did not receive any input from prior runs. Placing nop.");
-            let mut dummy_file = File::create(&format!("{}/nop_input", &input_dir))?;
+            let mut dummy_file = File::create(format!("{}/nop_input", &input_dir))?;
             dummy_file.write_all(b"nop")?;
         }
 
@@ -817,15 +1152,22 @@ impl FITMSnapshot {
         // meaning we already are inside the directory. This prevents us from
         // accidentally using different resources than we expect.
 
-        let exit_status = NamespaceContext::new()
-            .execute(|| -> io::Result<i32> {
-                let (stdout, stderr) = self.to_active()?;
+        let exit_status = NamespaceContext::new().run(
+            &format!("afl-cmin of {}", self.state_path),
+            || -> io::Result<i32> {
+                let (stdout, stderr, _) = self.to_active(tools, worker)?;
                 // state has to be activated at this point
-                assert!(env::current_dir().unwrap().ends_with(ACTIVE_STATE));
+                let cwd = env::current_dir()?;
+                if !cwd.ends_with(&worker.mount_point) {
+                    return Err(io::Error::other(format!(
+                        "afl-cmin has to run in {:?}, not in {:?}",
+                        worker.mount_point, cwd
+                    )));
+                }
 
-                let mut command = Command::new("../AFLplusplus/afl-cmin");
+                let mut command = Command::new(&tools.afl_cmin);
                 command
-                    .args(&[
+                    .args([
                         "-i",
                         &input_dir,
                         "-o",
@@ -865,29 +1207,31 @@ impl FITMSnapshot {
 
                 let mut child = command.spawn()?;
                 let exit_status = child.wait()?;
-                Ok(exit_status.code().unwrap())
-            })
-            .expect("[!] Namespace creation failed - missing sudo or capabilities?")
-            .wait()
-            .expect("[!] Namespace wait failed")
-            .code()
-            .unwrap();
+                Ok(exit_status.code().unwrap_or(-1))
+            },
+        )?;
 
         // We want to quit if cmin breaks (0) but not if it found a crash in the target (2)
         if exit_status != 0 && exit_status != 2 {
-            let info =
-                "[!] Error during afl-cmin execution. Please check latest statefolder for output";
-            println!("{}", info);
-            std::process::exit(1);
+            println!(
+                "[!] Error during afl-cmin execution. Please check latest statefolder for output"
+            );
+            return Err(FitmError::Cmin {
+                state: self.state_path.clone(),
+                reason: format!("exited with {} on {}", exit_status, input_dir),
+            });
         }
 
-        if fs::read_dir(&output_dir).unwrap().next().is_none() {
+        if fs::read_dir(&output_dir)?.next().is_none() {
             println!("Cmin minimized to 0 testcases. Bug in cmin? Check active-dir.");
-            std::process::exit(-1);
+            return Err(FitmError::Cmin {
+                state: self.state_path.clone(),
+                reason: format!("minimized {} to 0 testcases", input_dir),
+            });
         }
 
         // On a larger server, we had issues with cmin files getting lost in the subsequent copy...
-        Command::new("sync").status().unwrap();
+        utils::sync_tree(Path::new(&output_dir))?;
         sleep(Duration::from_millis(200));
 
         println!(
@@ -898,221 +1242,390 @@ impl FITMSnapshot {
     }
 }
 
-fn cpy_trace(trace_file: &str, state_path: &str) -> Result<(), io::Error> {
+fn cpy_trace(trace_file: &str, state_dir: &Path) -> Result<(), FitmError> {
     // Copy the .trace to the new snapshot dir
-    let to = format!("./saved-states/{}/snapshot_map", state_path);
-    println!("saving trace_file: {} to: {}", &trace_file, &to);
-    fs::copy(&trace_file, &to).unwrap_or_else(|_| {
-        panic!(
-            "[!] cpy_trace failed to copy trace_file: {} to: {}",
-            trace_file,
-            to.as_str()
-        )
-    });
+    let to = state_dir.join("snapshot_map");
+    println!("saving trace_file: {} to: {:?}", &trace_file, &to);
+    fs::copy(trace_file, &to)
+        .map_err(|e| FitmError::state_io(format!("copying {} to {:?}", trace_file, to), e))?;
 
     Ok(())
 }
 
-/// Run afl_fuzz for each snapshot with all inputs for the current gen
-/// @param current_snaps: list of snapshots for this stage
-/// @param current_inputs: path to inputs for this stage
-/// @return: upcoming snaps for the next generation based on current snaps (client->client, server->server)
-pub fn process_stage(
-    rand: &mut RomuRand,
-    current_snaps: &[FITMSnapshot],
-    current_inputs: &[PathBuf],
-    next_gen_id_start: usize,
-    run_time: &Duration,
-) -> Result<Vec<FITMSnapshot>, io::Error> {
-    let mut next_own_snaps: Vec<FITMSnapshot> = vec![];
+/// What fuzzing a single snapshot in `process_stage` left us with
+#[derive(Serialize, Deserialize)]
+struct SnapshotRun {
+    /// The fuzzed snapshot, with its calibrated timeout
+    snap: FITMSnapshot,
+    history: FuzzHistory,
+    /// Snapshots for the next own gen, numbered from 0 until `process_stage` gives them their ids
+    new_snaps: Vec<FITMSnapshot>,
+    /// Where `new_snaps` wait for their ids
+    staged_in: PathBuf,
+}
 
+/// Fuzz a single snapshot with all inputs for the current gen on `worker`, and create snapshots of
+/// the next own gen for new traces (if `create_snapshots`). `job` is its index in the stage.
+#[allow(clippy::too_many_arguments)]
+fn fuzz_snapshot(
+    tools: &Toolchain,
+    worker: &Worker,
+    job: usize,
+    mut snap: FITMSnapshot,
+    mut snap_history: FuzzHistory,
+    current_inputs: &[PathBuf],
+    create_snapshots: bool,
+    run_time: &RunTime,
+) -> Result<SnapshotRun, FitmError> {
     println!(
-        "     -> Processing stage with {} inputs.", //: {:?}",
-        current_inputs.len(),
+        "==== [*] Time start process_stage loop step {} (worker {}): {:?} ====",
+        snap.state_path,
+        worker.id,
+        Local::now().format("%Y-%m-%d %H:%M:%S").to_string()
     );
 
-    for snap in pick_random(rand, current_snaps, 5) {
-        println!(
-            "==== [*] Time start process_stage loop step {}: {:?} ====",
-            snap.state_path,
-            Local::now().format("%Y-%m-%d %H:%M:%S").to_string()
-        );
+    let staged_in = worker.new_states(job);
+    let _ = fs::remove_dir_all(&staged_in);
+    let mut new_snaps: Vec<FITMSnapshot> = vec![];
 
-        let cmin_tmp_dir = "cmin-tmp";
+    let cmin_tmp_dir = &*worker.cmin_tmp.to_string_lossy();
 
-        // remove old tmp if it exists, then recreate
-        let _ = std::fs::remove_dir_all(&cmin_tmp_dir);
-        std::fs::create_dir_all(&cmin_tmp_dir)?;
+    // remove old tmp if it exists, then recreate
+    let _ = std::fs::remove_dir_all(cmin_tmp_dir);
+    std::fs::create_dir_all(cmin_tmp_dir)?;
 
-        // Copy all current_inputs to cmin dir
-        for (i, input) in current_inputs.iter().enumerate() {
-            std::fs::copy(&input, &format!("{}/imported{}", &cmin_tmp_dir, i))?;
-        }
+    // Copy all current_inputs to cmin dir
+    for (i, input) in current_inputs.iter().enumerate() {
+        std::fs::copy(input, format!("{}/imported{}", &cmin_tmp_dir, i))?;
+    }
+
+    // Copy all queue items to cmin dir (doesn't necessarily exist yet)
+    let saved_state = worker.workspace.saved_state(&snap.state_path);
+    let _ = snap.copy_queue_to(Path::new(&cmin_tmp_dir), &saved_state);
 
-        // Copy all queue items to cmin dir (doesn't necessarily exist yet)
-        let _ = snap.copy_queue_to(&Path::new(&cmin_tmp_dir), false);
+    // Use the calibrated timeout, if we have one
+    if let Some(timeout) = snap_history.timeout {
+        snap.timeout = timeout;
+    }
 
-        // cmin all files to the in dir
-        let saved_state_dir = &format!("saved-states/{}/in", snap.state_path);
-        let _ = std::fs::remove_dir_all(&saved_state_dir);
+    // cmin all files to the in dir
+    let saved_state_dir = &saved_state.join("in").to_string_lossy().into_owned();
+    let _ = std::fs::remove_dir_all(saved_state_dir);
 
-        // don't keep traces here
-        snap.afl_cmin(&cmin_tmp_dir, &saved_state_dir, false)?;
+    // don't keep traces here
+    snap.afl_cmin(tools, worker, cmin_tmp_dir, saved_state_dir, false)?;
 
-        // afl_cmin exports minimized input to saved-states/$state/in
-        // fuzz_run activates saved-states/$state and uses ./in as input
-        snap.fuzz_run(&run_time)?;
+    // afl_cmin exports minimized input to saved-states/$state/in
+    // fuzz_run activates saved-states/$state and uses ./in as input
+    if snap_history.needs_calibration() {
+        let hanging = snap_history.hang_rate() > HANG_RATE_THRESHOLD;
+        if let Some(latency) =
+            snap.measure_exec_latency(tools, worker, Path::new(saved_state_dir))?
+        {
+            let timeout = calibrated_timeout(latency, snap_history.timeout, hanging);
+            println!(
+                "==== [*] Calibrated exec timeout of {}: {:?} (was {:?}, latency {:?}, hang rate {:.1}%) ====",
+                snap.state_path,
+                timeout,
+                snap.timeout,
+                latency,
+                100.0 * snap_history.hang_rate()
+            );
+            snap_history.timeout = Some(timeout);
+            snap.timeout = timeout;
+        }
+    }
+    let snap_run_time = run_time.for_history(Some(&snap_history));
+    println!(
+        "==== [*] Fuzzing {} for {:?} (runs: {}, new paths: {}, crashes: {}, dry runs: {}) ====",
+        snap.state_path,
+        snap_run_time,
+        snap_history.runs,
+        snap_history.new_paths,
+        snap_history.crashes,
+        snap_history.dry_runs
+    );
+    snap.fuzz_run(tools, worker, &snap_run_time)?;
+    snap_history.record(
+        &worker.workspace.saved_states,
+        &snap.state_path,
+        snap_run_time,
+    );
 
-        // current output to cmin-tmp
-        let _ = std::fs::remove_dir_all(&cmin_tmp_dir);
-        snap.copy_queue_to(&Path::new(&cmin_tmp_dir), true)
-            .unwrap_or_else(|_| panic!("[!] copy_queue_to failed for snap: {}", snap.state_path));
+    // current output to cmin-tmp
+    let _ = std::fs::remove_dir_all(cmin_tmp_dir);
+    snap.copy_queue_to(Path::new(&cmin_tmp_dir), &worker.workspace.active_state)
+        .map_err(|e| {
+            FitmError::state_io(format!("collecting the queue of {}", snap.state_path), e)
+        })?;
 
-        // Replace the old stored queue with the new, cminned queue
-        let cmin_post_exec = format!("saved-states/{}/out/main/queue", snap.state_path);
-        let _ = std::fs::remove_dir_all(&cmin_post_exec);
+    // Replace the old stored queue with the new, cminned queue
+    let cmin_post_exec = &saved_state
+        .join("out/main/queue")
+        .to_string_lossy()
+        .into_owned();
+    let _ = std::fs::remove_dir_all(cmin_post_exec);
 
-        // keep traces for snapshot creation
-        snap.afl_cmin(&cmin_tmp_dir, &cmin_post_exec, true)?;
+    // keep traces for snapshot creation
+    snap.afl_cmin(tools, worker, cmin_tmp_dir, cmin_post_exec, true)?;
 
-        // TODO: Make sure the same bitmap never creates a new snapshop for this state (may exist from last round already)
+    // TODO: Make sure the same bitmap never creates a new snapshop for this state (may exist from last round already)
 
-        let outputs = format!("saved-states/{}/outputs", snap.state_path);
-        snap.create_outputs(&cmin_post_exec, &outputs)?;
+    let outputs = &saved_state.join("outputs").to_string_lossy().into_owned();
+    snap.create_outputs(tools, worker, cmin_post_exec, outputs)?;
 
-        // we pass false for next gens for input_file list as we don't want to compare againt the current gen
-        let mut other_outputs: Vec<Vec<u8>> =
-            input_file_list_for_gen((snap.generation - 1) as usize, false)?
-                .iter()
-                .map(|x| fs::read(x).expect("[!] Failed to map input_list"))
-                .collect();
-        let mut ignored_outputs: Vec<OsString> = vec![];
+    // we pass false for next gens for input_file list as we don't want to compare againt the current gen
+    let mut other_outputs: Vec<Vec<u8>> =
+        input_file_list_for_gen(&worker.workspace, (snap.generation - 1) as usize, false)?
+            .iter()
+            .map(|x| fs::read(x).map_err(|e| FitmError::state_io(format!("reading {:?}", x), e)))
+            .collect::<Result<_, _>>()?;
+    let mut ignored_outputs: Vec<OsString> = vec![];
 
-        println!(
-            "==== [*] Time start output_minimization {}: {:?} ====",
-            snap.state_path,
-            Local::now().format("%Y-%m-%d %H:%M:%S").to_string()
-        );
-        for entry in fs::read_dir(&outputs)? {
-            let entry = entry.unwrap();
-            let entry_path = entry.path();
-            let entry_file_name = entry.file_name();
-            let own_output = {
-                fs::read(entry_path)
-                    .expect("[!] Could not read own_output while calculating jaro distance")
-            };
+    println!(
+        "==== [*] Time start output_minimization {}: {:?} ====",
+        snap.state_path,
+        Local::now().format("%Y-%m-%d %H:%M:%S").to_string()
+    );
+    for entry in utils::read_dir_sorted(outputs)? {
+        let entry_path = entry.path();
+        let entry_file_name = entry.file_name();
+        let own_output = fs::read(&entry_path)
+            .map_err(|e| FitmError::state_io(format!("reading {:?}", entry_path), e))?;
+
+        // read all outputs of gen, gen -2, gen -4
+        // one man's input is the other man's output
+        // So we read at +1, -1, -3
+        if other_outputs
+            .iter()
+            .any(|x| utils::output_similarity(x, &own_output) > JARO_DISTANCE_THRESHOLD)
+        {
+            ignored_outputs.push(entry_file_name);
+        } else {
+            // The output path always starts with uuid`-`, then continues with the input filename
+            other_outputs.push(own_output);
+        }
+    }
+    println!(
+        "==== [*] Time end output_minimization {}: {:?} ====",
+        snap.state_path,
+        Local::now().format("%Y-%m-%d %H:%M:%S").to_string()
+    );
 
-            // read all outputs of gen, gen -2, gen -4
-            // one man's input is the other man's output
-            // So we read at +1, -1, -3
-            if other_outputs
-                .iter()
-                .any(|x| utils::output_similarity(x, &own_output) > JARO_DISTANCE_THRESHOLD)
-            {
-                ignored_outputs.push(entry_file_name);
-            } else {
-                // The output path always starts with uuid`-`, then continues with the input filename
-                other_outputs.push(own_output);
+    let absolut_cmin_post_exec = build_create_absolute_path(cmin_post_exec)?;
+    let entries = if create_snapshots {
+        utils::read_dir_sorted(&absolut_cmin_post_exec)?
+    } else {
+        println!("==== [*] Not creating snapshots, the next gen is beyond max_depth ====");
+        vec![]
+    };
+    for entry in entries {
+        if ignored_outputs.contains(&entry.file_name()) {
+            println!(
+                "==== [*] Skipping output {:?} on snapshot creation (Output too similar, JARO says no.) ====",
+                &entry.file_name(),
+            );
+            continue;
+        }
+        if entry.path().is_file() {
+            // A temporary id, `process_stage` numbers the snapshots of all jobs in order
+            let state_id = new_snaps.len();
+
+            let trace_file = format!(
+                "{}/.traces/{}",
+                &absolut_cmin_post_exec,
+                entry.file_name().into_string().unwrap()
+            );
+
+            if let Some(traces) = get_traces(&worker.workspace, snap.generation)? {
+                // If we have seen the current trace before we don't want to create a new snapshot for this input
+                let cur_trace = fs::read_to_string(&trace_file)
+                    .map_err(|e| FitmError::state_io(format!("reading {}", trace_file), e))?;
+                if traces.iter().any(|trace| trace == cur_trace.as_str()) {
+                    println!(
+                        "==== [*] Skipping snapshot run for input (duplicate trace): {:?} ====",
+                        entry.path()
+                    );
+                    continue;
+                }
+            }
+            let snap_option = snap.create_next_snapshot(
+                tools,
+                worker,
+                state_id,
+                entry.path().as_os_str().to_str().unwrap(),
+                &staged_in,
+            )?;
+            if let Some(new_snap) = snap_option {
+                cpy_trace(trace_file.as_str(), &staged_in.join(&new_snap.state_path))?;
+
+                // Commit this fresly-baked snapshot to our vec.
+                new_snaps.push(new_snap);
             }
         }
-        println!(
-            "==== [*] Time end output_minimization {}: {:?} ====",
-            snap.state_path,
-            Local::now().format("%Y-%m-%d %H:%M:%S").to_string()
-        );
+    }
 
-        let absolut_cmin_post_exec = build_create_absolute_path(&cmin_post_exec)
-            .expect("[!] Error while constructing absolute input_dir path");
-        for entry in fs::read_dir(&absolut_cmin_post_exec)? {
-            let entry = entry?;
-            if ignored_outputs.contains(&entry.file_name()) {
+    println!(
+        "==== [*] Time end snapshot creation (all) {}: {:?} ====",
+        snap.state_path,
+        Local::now().format("%Y-%m-%d %H:%M:%S").to_string()
+    );
+
+    let traces = format!("{}/.traces", &absolut_cmin_post_exec);
+    fs::remove_dir_all(&traces)
+        .map_err(|e| FitmError::state_io(format!("removing {}", traces), e))?;
+
+    Ok(SnapshotRun {
+        snap,
+        history: snap_history,
+        new_snaps,
+        staged_in,
+    })
+}
+
+/// Run afl_fuzz for each snapshot with all inputs for the current gen, spread over `workers`
+/// @param workspace: where the snapshots are saved
+/// @param current_snaps: list of snapshots for this stage
+/// @param current_inputs: path to inputs for this stage
+/// @param next_gen_id_start: first state id for new snapshots, `None` if the next gen is beyond `max_depth`
+/// @param quarantine: snapshots that failed to restore or fuzz end up here, the others are merged
+/// @return: upcoming snaps for the next generation based on current snaps (client->client, server->server),
+/// and the error of a job that failed. The runs that finished are merged anyway, save them before giving up.
+#[allow(clippy::too_many_arguments)]
+pub fn process_stage(
+    workspace: &Workspace,
+    tools: &Toolchain,
+    rand: &mut RomuRand,
+    scheduler: &mut dyn Scheduler,
+    history: &mut BTreeMap<String, FuzzHistory>,
+    current_snaps: &[FITMSnapshot],
+    current_inputs: &[PathBuf],
+    next_gen_id_start: Option<usize>,
+    run_time: &RunTime,
+    workers: &[Worker],
+    quarantine: &mut Quarantine,
+) -> Result<(Vec<FITMSnapshot>, Option<FitmError>), FitmError> {
+    let mut next_own_snaps: Vec<FITMSnapshot> = vec![];
+
+    println!(
+        "     -> Processing stage with {} inputs.", //: {:?}",
+        current_inputs.len(),
+    );
+
+    let jobs: Vec<(FITMSnapshot, FuzzHistory)> = scheduler
+        .pick_snapshots(rand, current_snaps, history)
+        .into_iter()
+        .map(|snap| {
+            let snap_history = history.get(&snap.state_path).cloned().unwrap_or_default();
+            (snap, snap_history)
+        })
+        .collect();
+    let (runs, failed) = workers::run_jobs(workers, jobs, |worker, job, (snap, snap_history)| {
+        let mut failed = snap.clone();
+        match fuzz_snapshot(
+            tools,
+            worker,
+            job,
+            snap,
+            snap_history,
+            current_inputs,
+            next_gen_id_start.is_some(),
+            run_time,
+        ) {
+            Ok(run) => Ok(Ok(run)),
+            // A broken snapshot should not take the campaign down with it.
+            // Nodes killed while shutting down say nothing about the snapshot though.
+            Err(e) if !budget::stop_requested() && quarantine::is_snapshot_failure(&failed, &e) => {
+                println!("[!] Quarantining {}: {}", failed.state_path, e);
+                let _ = fs::remove_dir_all(worker.new_states(job));
+                let dir = quarantine::quarantine_snapshot(worker, &mut failed, e).map_err(|e| {
+                    FitmError::state_io(format!("quarantining {}", failed.state_path), e)
+                })?;
                 println!(
-                    "==== [*] Skipping output {:?} on snapshot creation (Output too similar, JARO says no.) ====",
-                    &entry.file_name(),
+                    "{}==== [!] Moved {} to {:?} ===={}",
+                    color::Fg(color::Red),
+                    failed.state_path,
+                    dir,
+                    style::Reset,
                 );
-                continue;
+                Ok(Err(failed))
             }
-            if entry.path().is_file() {
-                // get the next id: current start + amount of snapshots we created in the meantime
-                let state_id = next_gen_id_start + next_own_snaps.len();
-
-                let trace_file = format!(
-                    "{}/.traces/{}",
-                    &absolut_cmin_post_exec,
-                    entry.file_name().into_string().unwrap()
-                );
-
-                if let Some(traces) = get_traces(snap.generation).unwrap() {
-                    // If we have seen the current trace before we don't want to create a new snapshot for this input
-                    let cur_trace = fs::read_to_string(&trace_file)
-                        .expect("[!] Could not read current trace_file in process_stage");
-                    if traces.iter().any(|trace| trace == cur_trace.as_str()) {
-                        println!(
-                            "==== [*] Skipping snapshot run for input (duplicate trace): {:?} ====",
-                            entry.path()
-                        );
-                        continue;
-                    }
-                }
-                let snap_option = snap
-                    .create_next_snapshot(state_id, entry.path().as_os_str().to_str().unwrap())?;
-                if let Some(new_snap) = snap_option {
-                    cpy_trace(trace_file.as_str(), &new_snap.state_path)?;
-
-                    // Commit this fresly-baked snapshot to our vec.
-                    next_own_snaps.push(new_snap);
-                }
+            Err(e) => {
+                let _ = fs::remove_dir_all(worker.new_states(job));
+                Err(e)
             }
         }
+    });
 
-        println!(
-            "==== [*] Time end snapshot creation (all) {}: {:?} ====",
-            snap.state_path,
-            Local::now().format("%Y-%m-%d %H:%M:%S").to_string()
-        );
-
-        fs::remove_dir_all(format!("{}/.traces", &absolut_cmin_post_exec))
-            .expect("[!] Could not remove .traces after saving program maps");
+    // Merge in the order the snapshots were picked, so ids don't depend on which worker was faster
+    for run in runs {
+        let run = match run {
+            Ok(run) => {
+                quarantine.succeeded();
+                run
+            }
+            Err(failed) => {
+                quarantine.failed(failed);
+                continue;
+            }
+        };
+        history.insert(run.snap.state_path.clone(), run.history);
+        scheduler.fuzzed(&run.snap);
+
+        for mut new_snap in run.new_snaps {
+            let staged = run.staged_in.join(&new_snap.state_path);
+            // the next id: current start + amount of snapshots we committed in the meantime
+            new_snap.state_id = next_gen_id_start.unwrap_or_default() + next_own_snaps.len();
+            new_snap.state_path = state_path_for(new_snap.generation, new_snap.state_id);
+            let saved = workspace.saved_state(&new_snap.state_path);
+            utils::mv(staged.to_str().unwrap(), saved.to_str().unwrap())
+                .and_then(|_| fs::write(saved.join("run-info"), format!("{:?}", new_snap)))
+                .map_err(|e| {
+                    FitmError::state_io(format!("saving {:?} as {:?}", staged, saved), e)
+                })?;
+            println!(
+                "{}==== [*] Saved new snapshot {:?} as {} ===={}",
+                color::Fg(color::Blue),
+                staged,
+                new_snap.state_path,
+                style::Reset,
+            );
+            next_own_snaps.push(new_snap);
+        }
+        let _ = fs::remove_dir_all(&run.staged_in);
     }
 
-    Ok(next_own_snaps)
+    Ok((next_own_snaps, failed))
 }
 
 /// Originally proposed return value of process_stage()
 /// @return: False, if we didn't advance to the next generation (no more output)
-pub fn check_stage_advanced(next_inputs: &mut Vec<String>) -> bool {
+pub fn check_stage_advanced(next_inputs: &mut [String]) -> bool {
     !next_inputs.is_empty()
 }
 
 // Get the (non-minimized) input dir to the generation with id gen_id
-fn generation_input_dir(gen_id: usize) -> String {
-    format!("./generation_inputs/{}", gen_id)
+fn generation_input_dir(workspace: &Workspace, gen_id: usize) -> PathBuf {
+    workspace.path("generation_inputs").join(gen_id.to_string())
 }
 
 // Make sure the given folder exists
-fn ensure_dir_exists(dir: &str) {
-    fs_extra::dir::create_all(dir, false).expect("Could not create dir");
+fn ensure_dir_exists(dir: &str) -> io::Result<()> {
+    fs::create_dir_all(dir)
 }
 
 // Constructs an absolute path from a relative one. Creates the directory if it doesn't exist yet
-fn build_create_absolute_path(relative: &str) -> Result<String, io::Error> {
-    let canonicalized_string = PathBuf::from(relative).canonicalize();
-    let os_string = match canonicalized_string {
-        Ok(val) => val.into_os_string(),
-        Err(_e) => {
-            ensure_dir_exists(relative);
-            PathBuf::from(relative)
-                .canonicalize()
-                .unwrap()
-                .into_os_string()
-        }
+fn build_create_absolute_path(relative: &str) -> Result<String, FitmError> {
+    let absolute = match PathBuf::from(relative).canonicalize() {
+        Ok(val) => val,
+        Err(_e) => ensure_dir_exists(relative)
+            .and_then(|_| PathBuf::from(relative).canonicalize())
+            .map_err(|e| FitmError::state_io(format!("creating {}", relative), e))?,
     };
-    let absolute_str = String::from(
-        os_string
-            .to_str()
-            .expect("[!] Could not convert os_string to str"),
-    );
-    Ok(absolute_str)
+    absolute
+        .into_os_string()
+        .into_string()
+        .map_err(|path| FitmError::Config(format!("{:?} is not a valid UTF-8 path", path)))
 }
 
 /// Naming scheme:
@@ -1122,7 +1635,11 @@ fn build_create_absolute_path(relative: &str) -> Result<String, io::Error> {
 /// the snapshots of this generation. Starts with 0 for each X
 /// @param gen_id: The generation for which to get all inputs
 /// @return: List of paths, one path per output per state
-fn input_file_list_for_gen(gen_id: usize, use_future_gen: bool) -> Result<Vec<PathBuf>, io::Error> {
+fn input_file_list_for_gen(
+    workspace: &Workspace,
+    gen_id: usize,
+    use_future_gen: bool,
+) -> Result<Vec<PathBuf>, io::Error> {
     // should match above naming scheme
     // Look for the last and last -2 state's output to get the input.
     let gen_path = if use_future_gen {
@@ -1146,8 +1663,7 @@ fn input_file_list_for_gen(gen_id: usize, use_future_gen: bool) -> Result<Vec<Pa
     };
 
     // Using shell like globs would make this much easier: https://docs.rs/globset/0.4.6/globset/
-    Ok(fs::read_dir("./saved-states/")?
-        .into_iter()
+    let mut inputs: Vec<PathBuf> = fs::read_dir(&workspace.saved_states)?
         // Ignore errors
         .filter_map(|x| x.ok())
         // First, find all legit gen{gen_id}-state dirs
@@ -1155,38 +1671,54 @@ fn input_file_list_for_gen(gen_id: usize, use_future_gen: bool) -> Result<Vec<Pa
             entry.path().is_dir() && gen_path.find(entry.path().to_str().unwrap()).is_some()
         })
         // return all files in outputs
-        .map(|entry| entry.path().join("outputs").read_dir().unwrap())
         // We now have an iterator of directories of files, flatten to iterator of files
-        .flatten()
+        .flat_map(|entry| {
+            entry
+                .path()
+                .join("outputs")
+                .read_dir()
+                .into_iter()
+                .flatten()
+        })
         // Ignore more errors
         .filter_map(|x| x.ok())
         // read all files, return the strings
         .filter(|x| x.path().is_file())
         .map(|x| x.path())
-        .collect())
+        .collect();
+    // Sorted, so seeded runs don't depend on the order of the fs
+    inputs.sort();
+    Ok(inputs)
+}
+
+/// The generations whose traces `get_traces` compares against for `gen_id`:
+/// the gen itself and up to 3 of the same side into the past
+pub(crate) fn trace_window(gen_id: u32) -> [u32; 4] {
+    [
+        gen_id,
+        if gen_id >= 2 { gen_id - 2 } else { gen_id },
+        if gen_id >= 4 { gen_id - 4 } else { gen_id },
+        if gen_id >= 6 { gen_id - 6 } else { gen_id },
+    ]
 }
 
 // We are currently not sure if checking only current gen or all gens for duplicate traces is better
 // Problem: Server & Client may indefinitely bounce "passwd" and "wrong passwd" back and forth
 // without realizing that no new path has been found.
-pub fn get_traces(gen_id: u32) -> io::Result<Option<Vec<String>>> {
+pub fn get_traces(workspace: &Workspace, gen_id: u32) -> Result<Option<Vec<String>>, FitmError> {
     // should match naming scheme explained at `input_file_list_for_gen`
 
     // TODO: Cache this :)
-    // We look up to 3 into the past
+    let [gen0, gen1, gen2, gen3] = trace_window(gen_id);
     let gen_path = Regex::new(&format!(
         "fitm-gen({}|{}|{}|{})-state\\d+",
-        gen_id,
-        if gen_id >= 2 { gen_id - 2 } else { gen_id },
-        if gen_id >= 4 { gen_id - 4 } else { gen_id },
-        if gen_id >= 6 { gen_id - 6 } else { gen_id },
+        gen0, gen1, gen2, gen3
     ));
 
     let snapshot_regex = gen_path.unwrap();
     // Collect all snapshot folders in saved-states
-    let states_iter = fs::read_dir(SAVED_STATES)
-        .unwrap_or_else(|_| panic!("[!] Could not read_dir {} in get_traces.", SAVED_STATES))
-        .into_iter()
+    let states_iter = fs::read_dir(&workspace.saved_states)
+        .map_err(|e| FitmError::state_io(format!("reading {:?}", workspace.saved_states), e))?
         .filter_map(|dir| dir.ok())
         .filter(|dir_entry| {
             dir_entry.path().is_dir()
@@ -1203,10 +1735,10 @@ pub fn get_traces(gen_id: u32) -> io::Result<Option<Vec<String>>> {
     // Return content of each file as vec
     let traces_vec: Vec<String> = traces_iter
         .map(|path| {
-            fs::read_to_string(path)
-                .expect("[!] Error while reading snapshot_map files in get_traces")
+            fs::read_to_string(&path)
+                .map_err(|e| FitmError::state_io(format!("reading {:?}", path), e))
         })
-        .collect();
+        .collect::<Result<_, _>>()?;
     if traces_vec.is_empty() {
         println!(
             "{}No other traces found!{}",
@@ -1219,18 +1751,11 @@ pub fn get_traces(gen_id: u32) -> io::Result<Option<Vec<String>>> {
     }
 }
 
-/// We try to restore, let's see how it goes.
-pub fn save_restore_generation_state(
-    generation_snaps: &[Vec<FITMSnapshot>],
-) -> Result<(), io::Error> {
-    let mut file = File::create("fitm-state.json")?;
-    file.write_all(serde_json::to_string(generation_snaps)?.as_bytes())
-}
-
-/// Run fitm
+/// Run fitm in `workspace`
 /// runtime indicates the time, after which the fuzzer switches to the next entry
-#[allow(clippy::clippy::too_many_arguments)]
+#[allow(clippy::too_many_arguments)]
 pub fn run(
+    workspace: &Workspace,
     client_bin: &str,
     client_args: &[String],
     client_envs: &HashMap<String, String>,
@@ -1239,86 +1764,144 @@ pub fn run(
     server_args: &[String],
     server_envs: &HashMap<String, String>,
     server_files: &[String],
-    run_time: &Duration,
+    run_time: &RunTime,
     // Still needs an echo binary or a binary producing a short output, as client
     // Just fuzzes the client for 100 millis.
     server_only: bool,
-) -> Result<(), io::Error> {
+    // Seed for the scheduler, random if None
+    seed: Option<u64>,
+    tools: &Toolchain,
+    // Decides which gens and snapshots to fuzz, see `scheduler`
+    scheduler: &mut dyn Scheduler,
+    // When to stop, see `budget`
+    budget: &Budget,
+    // How far and wide the snapshot tree may grow, see `evict`
+    limits: &Limits,
+    // How many snapshots are fuzzed at once, see `workers`
+    workers: usize,
+    // AFL++ secondaries per snapshot, see `Worker::node`
+    secondaries: usize,
+    // Give up once this many snapshots in a row failed to restore or fuzz, see `quarantine`
+    max_consecutive_failures: usize,
+) -> Result<(), FitmError> {
     println!(
         "{}
     __________________  ___
    / ____/  _/_  __/  |/  /
-  / /_   / /  / / / /|_/ / 
- / __/ _/ /  / / / /  / /  
-/_/   /___/ /_/ /_/  /_/   
+  / /_   / /  / / / /|_/ /
+ / __/ _/ /  / / / /  / /
+/_/   /___/ /_/ /_/  /_/
 
 {}",
         color::Fg(color::Cyan),
         style::Reset
     );
 
-    // A lot of timeout for now
-    let run_timeout = Duration::from_secs(3);
-    let server_only_client_runtime = Duration::from_millis(100);
+    // A lot of timeout, until the snapshots are calibrated
+    let run_timeout = INITIAL_EXEC_TIMEOUT;
+    let server_only_client_runtime = RunTime::fixed(Duration::from_millis(100));
 
-    let mut rand = RomuRand::preseeded();
+    // Finish the current stage on SIGINT/SIGTERM, then save and shut down
+    budget::install_signal_handlers();
 
     // clean up last runs
-    let _ = remove_dir_all(ACTIVE_STATE);
-    let _ = remove_dir_all("cmin-tmp");
+    let _ = remove_dir_all(&workspace.active_state);
+    let _ = remove_dir_all(workspace.path(CMIN_TMP));
+    let _ = remove_dir_all(workspace.path(WORKERS_DIR));
+
+    let workers = Worker::pool(workspace, workers, secondaries);
+    if workers.len() > 1 {
+        println!("[*] Fuzzing {} snapshots at once", workers.len());
+    }
+    if secondaries > 0 {
+        println!("[*] Fuzzing each snapshot with {} secondaries", secondaries);
+    }
 
     // the folder contains inputs for each generation
-    ensure_dir_exists(&generation_input_dir(0));
-    ensure_dir_exists(&generation_input_dir(1));
+    fs::create_dir_all(generation_input_dir(workspace, 0))?;
+    fs::create_dir_all(generation_input_dir(workspace, 1))?;
 
     // Try to restore the last state.
-    let restored_state: Option<Vec<Vec<FITMSnapshot>>> = match fs::read_to_string("fitm-state.json")
-    {
-        Ok(fitm_json) => match serde_json::from_str(&fitm_json) {
-            Ok(state) => {
-                let state: Vec<Vec<FITMSnapshot>> = state;
-                // some basic sanity checks for fitm-state.json.
-                if state.len() <= 2
-                    || state[1].is_empty()
-                    || state[2].is_empty()
-                    || state[1][0].target_bin != server_bin
-                    || state[2][0].target_bin != client_bin
-                {
-                    panic!("Saved_state was not created for the current binaries or is corrupt, please remove (or fix) `fitm-state.json` manually. Bailing out.");
-                } else {
-                    Some(state)
-                }
+    let state_file = workspace.path(FITM_STATE);
+    let restored_state: Option<CampaignState> = match CampaignState::load_from(&state_file) {
+        Ok(mut state) => {
+            // Entries without a (complete) folder can't be restored, they'd only make us crash later on.
+            if !fsck::reconcile(&mut state, &workspace.saved_states, FsckMode::Repair)?.is_empty() {
+                println!("[*] Repaired {}, see `fitm fsck` for details", FITM_STATE);
             }
-            Err(e) => {
-                println!("No fitm-state.json ({})", e);
-                None
+            let snaps = &state.generation_snaps;
+            // some basic sanity checks for fitm-state.json.
+            if snaps.len() <= 2 || snaps[1].is_empty() || snaps[2].is_empty() {
+                return Err(FitmError::Config(
+                    "fitm-state.json lacks the initial snapshots (or their saved-states), run `fitm clean` to start over".to_string(),
+                ));
+            } else if snaps[1][0].target_bin != server_bin || snaps[2][0].target_bin != client_bin {
+                return Err(FitmError::Config(
+                    "fitm-state.json was not created for the current binaries, run `fitm clean` or use another working dir".to_string(),
+                ));
+            } else {
+                Some(state)
             }
-        },
-        Err(_) => {
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {
             println!("File fitm-state.json not found. Restarting from scratch.");
             None
         }
+        Err(e) => {
+            println!("No fitm-state.json ({})", e);
+            None
+        }
+    };
+
+    // An explicit seed wins, else we continue with the seed of the restored campaign
+    let restored_seed = restored_state.as_ref().and_then(|state| state.seed);
+    let seed = match (seed, restored_seed) {
+        (Some(seed), Some(restored)) if seed != restored => {
+            println!(
+                "[!] Overriding seed {} of the restored run with {}",
+                restored, seed
+            );
+            seed
+        }
+        (Some(seed), _) | (None, Some(seed)) => seed,
+        (None, None) => utils::current_nanos(),
+    };
+    println!(
+        "{}[*] Using seed {}{}",
+        color::Fg(color::Green),
+        seed,
+        style::Reset
+    );
+    // Continue with the exact RNG state of the restored run, unless the seed changed
+    let mut rand = match &restored_state {
+        Some(CampaignState { rng: Some(rng), .. }) if restored_seed == Some(seed) => *rng,
+        _ => RomuRand::new(seed),
     };
 
-    let mut generation_snaps: Vec<Vec<FITMSnapshot>> = match restored_state {
-        Some(snaps) => {
+    let mut state = match restored_state {
+        Some(mut state) => {
             println!(
-                "{}Resuming run with with {} generations{}",
+                "{}Resuming run with with {} generations at gen {}, round {} (fuzzed for {:?} so far){}",
                 color::Fg(color::Green),
-                snaps.len() - 1,
+                state.generation_snaps.len() - 1,
+                state.current_gen,
+                state.round,
+                state.elapsed,
                 style::Reset
             );
-            snaps
+            state.seed = Some(seed);
+            state
         }
         None => {
             println!("No valid state to resume. Starting fresh :)");
 
             println!(
                 "==== [*] Time start init_run: {} ====",
-                Local::now().format("%Y-%m-%d %H:%M:%S").to_string()
+                Local::now().format("%Y-%m-%d %H:%M:%S")
             );
             // Snapshot for gen2 (first client gen that's fuzzed) is created from this obj.
             let mut afl_client_snap: FITMSnapshot = FITMSnapshot::new(
+                &workers[0].workspace,
                 2,
                 0,
                 client_bin.to_string(),
@@ -1327,17 +1910,25 @@ pub fn run(
                 false,
                 false,
                 None,
-            )
+            )?
             .attach_files(client_files);
 
             // first create a snapshot, without outputs
-            afl_client_snap.pid =
-                afl_client_snap.init_run(&mut rand, false, true, client_args, client_envs)?;
+            afl_client_snap.pid = afl_client_snap.init_run(
+                tools,
+                &workers[0],
+                &mut rand,
+                false,
+                true,
+                client_args,
+                client_envs,
+            )?;
             // Move ./fd files (hopefully just one) to ./outputs folder for gen 0, state 0
             // (to gen0-state0/outputs)
             // we just need tmp to create outputs
             // something fails if we don't use this tmp object
             let tmp = FITMSnapshot::new(
+                &workers[0].workspace,
                 2,
                 0,
                 client_bin.to_string(),
@@ -1346,11 +1937,20 @@ pub fn run(
                 false,
                 false,
                 None,
-            )
+            )?
             .attach_files(client_files);
-            tmp.init_run(&mut rand, true, false, client_args, client_envs)?;
+            tmp.init_run(
+                tools,
+                &workers[0],
+                &mut rand,
+                true,
+                false,
+                client_args,
+                client_envs,
+            )?;
 
             let mut afl_server: FITMSnapshot = FITMSnapshot::new(
+                &workers[0].workspace,
                 1,
                 0,
                 server_bin.to_string(),
@@ -1359,10 +1959,17 @@ pub fn run(
                 true,
                 false,
                 None,
-            )
+            )?
             .attach_files(server_files);
-            afl_server.pid =
-                afl_server.init_run(&mut rand, false, true, server_args, server_envs)?;
+            afl_server.pid = afl_server.init_run(
+                tools,
+                &workers[0],
+                &mut rand,
+                false,
+                true,
+                server_args,
+                server_envs,
+            )?;
 
             println!(
                 "==== [*] Time end init_run: {:?} ====",
@@ -1370,54 +1977,47 @@ pub fn run(
             );
 
             // We need initial outputs from the client, else something went wrong
-            assert_ne!(input_file_list_for_gen(1, true)?.len(), 0);
+            if input_file_list_for_gen(workspace, 1, true)?.is_empty() {
+                return Err(FitmError::Config(
+                    "the client produced no output before its first recv, check `client_args` and `client_envs`".to_string(),
+                ));
+            }
 
             // Create the generation snaps vec
-            vec![
-                // Gen 0 client doesn't need a snapshot (it's the run from binary start to initial recv)
-                vec![],
-                // Gen 1 server is the initial server snapshot at recv, awaiting gen 0's output as input
-                vec![afl_server],
-                // Gen 2 client is the initial client snapshot, awaiting gen 1's output (server response) as input
-                vec![afl_client_snap],
-            ]
+            CampaignState::new(
+                seed,
+                vec![
+                    // Gen 0 client doesn't need a snapshot (it's the run from binary start to initial recv)
+                    vec![],
+                    // Gen 1 server is the initial server snapshot at recv, awaiting gen 0's output as input
+                    vec![afl_server],
+                    // Gen 2 client is the initial client snapshot, awaiting gen 1's output (server response) as input
+                    vec![afl_client_snap],
+                ],
+            )
         }
     };
 
-    let mut current_gen = 0;
-    let mut round = 0;
+    let mut quarantine = Quarantine::new(max_consecutive_failures);
+    let start_time = Instant::now();
+    let elapsed_before = state.elapsed;
+    let mut current_gen = state.current_gen;
+    let mut round = state.round;
 
-    loop {
-        current_gen += 1;
-        if generation_snaps[current_gen].is_empty() {
-            println!(
-                "No snapshots (yet) for gen {}, restarting with gen 1 (initial request)",
-                current_gen
-            );
-            // Restart with gen 1 -> the client at gen 0 does not accept input.
-            current_gen = 1;
+    let reason = loop {
+        state.elapsed = elapsed_before + start_time.elapsed();
+        if let Some(reason) = budget.stop_reason(&state) {
+            break reason;
         }
 
-        // occasionally, stop going deeper, and go back to 0
-        if current_gen != 1 && (rand.below(1000) as f64 / 1000.0) > ABORT_THRESHOLD {
-            println!(
-                "Restarting fuzzing from gen 1 because of randomness (threshold {})",
-                ABORT_THRESHOLD
-            );
-            current_gen = 1;
-        }
+        current_gen = scheduler.next_gen(&mut rand, &state.generation_snaps, current_gen);
 
         // We wrapped around (or started fresh) -> next round
         if current_gen == 1 {
             round += 1;
         }
 
-        // occasionally, skip a step (unless we're in the first run)
-        if round != 1 && (rand.below(1000) as f64 / 1000.0) > SKIP_STEP_THRESHOLD {
-            println!(
-                "Skiping gen {} by random chance (threshold {})",
-                current_gen, SKIP_STEP_THRESHOLD
-            );
+        if scheduler.skip_gen(&mut rand, &state.generation_snaps, current_gen, round) {
             continue;
         }
 
@@ -1434,16 +2034,17 @@ pub fn run(
         // snapshots based on current_gen (i.e. client) --> snaps[current_gen+2] (client)
         let next_own_gen = current_gen + 2;
         // Make sure we have vecs for the next client and server generations
-        if next_other_gen == generation_snaps.len() {
-            generation_snaps.push(vec![])
+        if next_other_gen == state.generation_snaps.len() {
+            state.generation_snaps.push(vec![])
         }
-        if next_own_gen == generation_snaps.len() {
-            generation_snaps.push(vec![])
+        if next_own_gen == state.generation_snaps.len() {
+            state.generation_snaps.push(vec![])
         }
 
         println!(
             "==== [*] Queue before process_stage contains: {:?} ====",
-            generation_snaps
+            state
+                .generation_snaps
                 .iter()
                 .map(|x| x.iter().map(|y| y.state_path.as_str()).collect::<Vec<_>>())
                 .collect::<Vec<Vec<_>>>()
@@ -1454,35 +2055,91 @@ pub fn run(
             current_gen,
             Local::now().format("%Y-%m-%d %H:%M:%S").to_string()
         );
-        let next_gen_id_start = generation_snaps[next_own_gen].len();
-        let mut next_snaps = process_stage(
+        let next_gen_id_start = limits.allows_gen(next_own_gen).then(|| {
+            evict::next_state_id(
+                &state,
+                next_own_gen,
+                &workspace.saved_states,
+                &workspace.path(ARCHIVED_STATES),
+            )
+        });
+        let (mut next_snaps, failed) = process_stage(
+            workspace,
+            tools,
             &mut rand,
-            &generation_snaps[current_gen],
-            &input_file_list_for_gen(current_gen, true)?,
+            scheduler,
+            &mut state.history,
+            &state.generation_snaps[current_gen],
+            &input_file_list_for_gen(workspace, current_gen, true)?,
             next_gen_id_start,
             if server_only && current_gen % 2 == 0 {
                 println!("==== [+] Fuzzing gen {} for 100 millis, we're not interested in this side (server_only mode set) ===", current_gen);
                 &server_only_client_runtime
             } else {
-                &run_time
+                run_time
             },
+            &workers,
+            &mut quarantine,
         )?;
+        quarantine
+            .remove_from(&mut state, &workspace.saved_states)
+            .map_err(|e| FitmError::state_io("quarantining the children of failed snapshots", e))?;
         println!(
             "==== [*] Time end process_stage gen {}: {:?} ====",
             current_gen,
             Local::now().format("%Y-%m-%d %H:%M:%S").to_string()
         );
 
-        generation_snaps[next_own_gen].append(&mut next_snaps);
+        if limits.incremental {
+            for snap in &next_snaps {
+                let saved = incremental::diff(
+                    &workspace.saved_state(&snap.state_path).join("snapshot"),
+                    &workspace.saved_state(&snap.base_state).join("snapshot"),
+                    &incremental::parent_target(&snap.base_state),
+                )?;
+                println!(
+                    "[*] Stored {} as diff against {}, saved {} bytes",
+                    snap.state_path, snap.base_state, saved
+                );
+            }
+        }
+        state.generation_snaps[next_own_gen].append(&mut next_snaps);
+        let evicted = limits.enforce(
+            &mut state,
+            &workspace.saved_states,
+            &workspace.path(ARCHIVED_STATES),
+        )?;
+        if !evicted.is_empty() {
+            println!(
+                "==== [*] Evicted {} snapshots to stay within the limits ====",
+                evicted.len()
+            );
+        }
+        if let Some(mode) = limits.gc {
+            gc::collect(
+                &mut state,
+                &workspace.saved_states,
+                &workspace.path(ARCHIVED_STATES),
+                mode,
+                limits.eviction,
+            )?;
+        }
         println!(
             "Queue after process_stage contains: {:?}",
-            generation_snaps
+            state
+                .generation_snaps
                 .iter()
                 .map(|x| x.iter().map(|y| y.state_path.as_str()).collect::<Vec<_>>())
                 .collect::<Vec<Vec<_>>>()
         );
 
-        match save_restore_generation_state(&generation_snaps) {
+        // Remember exactly where we are, so a resume continues with the next gen
+        state.stages += 1;
+        state.current_gen = current_gen;
+        state.round = round;
+        state.rng = Some(rand);
+        state.elapsed = elapsed_before + start_time.elapsed();
+        match state.save_to(&state_file) {
             Ok(()) => (),
             Err(e) => println!(
                 "{}==== [!] Could not save state :( ({:?}){}",
@@ -1491,5 +2148,42 @@ pub fn run(
                 style::Reset
             ),
         };
+
+        if let Some(e) = failed {
+            println!(
+                "{}==== [!] Fuzzing gen {} failed, saved what finished ===={}",
+                color::Fg(color::Red),
+                current_gen,
+                style::Reset
+            );
+            budget::kill_children();
+            return Err(e);
+        }
+        if quarantine.exceeded() {
+            println!(
+                "{}==== [!] Too many snapshots failed in a row, giving up ====\n{}{}",
+                color::Fg(color::Red),
+                quarantine.report(&workspace.saved_states),
+                style::Reset
+            );
+            budget::kill_children();
+            return Err(quarantine
+                .abort(&workspace.saved_states)
+                .unwrap_or_else(|e| FitmError::state_io("writing the quarantine report", e)));
+        }
+    };
+
+    // The last stage is done, wrap up
+    state.elapsed = elapsed_before + start_time.elapsed();
+    if let Err(e) = state.save_to(&state_file) {
+        println!(
+            "{}==== [!] Could not save state :( ({:?}){}",
+            color::Fg(color::Red),
+            e,
+            style::Reset
+        );
     }
+    budget::kill_children();
+    budget::print_summary(&state, &reason);
+    Ok(())
 }
@@ -1,29 +1,13 @@
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::env;
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::process;
-use std::{env, time::Duration};
-
-#[derive(Serialize, Deserialize)]
-struct RunArgs {
-    /// The client target binary
-    client: String,
-    client_args: Vec<String>,
-    client_envs: HashMap<String, String>,
-    client_files: Vec<String>,
-    /// The client target binary
-    server: String,
-    server_args: Vec<String>,
-    server_envs: HashMap<String, String>,
-    server_files: Vec<String>,
-    /// run time in secs
-    run_time: u64,
-    // Still needs an echo binary or a binary producing a short output, as client
-    // Just fuzzes the client for 100 millis.
-    /// Enable protocol discovery (server_only)
-    server_only: bool,
-}
+
+use fitm::cli::{self, Cli, Subcommand};
+use fitm::commands;
+use fitm::config::{ConfigError, RunArgs};
+use fitm::toolchain::Toolchain;
+use fitm::workspace::Workspace;
 
 fn is_root() {
     match env::var("SUDO_USER") {
@@ -50,42 +34,78 @@ fn setup_env() {
     env::set_var(debug, "1");
 }
 
-#[allow(dead_code)]
-fn load_args(path: PathBuf) -> RunArgs {
-    match fs::read_to_string(path) {
-        Ok(args_json) => match serde_json::from_str(&args_json) {
-            Ok(run_args) => run_args,
-            Err(e) => panic!("[!] Error parsing fitm-args.json: {:?}", e),
-        },
-        Err(e) => panic!("[!] Error reading fitm-args.json: {:?}", e),
+/// Loads the config at `path`, with the overrides of `cli`, and validates the result
+fn load_args(path: &Path, cli: &Cli) -> RunArgs {
+    let args = RunArgs::load(path).and_then(|mut args| {
+        cli.override_args(&mut args);
+        args.validate(&env::current_dir().unwrap())?;
+        Ok(args)
+    });
+    match args {
+        Ok(args) => args,
+        Err(e) => {
+            println!("[!] {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+fn toolchain(tools: Result<Toolchain, ConfigError>) -> Toolchain {
+    match tools {
+        Ok(tools) => {
+            tools.print();
+            tools
+        }
+        Err(e) => {
+            println!("[!] {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+/// The tools of the config at `path`, or those in the working dir without one
+fn config_tools(path: Option<&Path>) -> Toolchain {
+    let cwd = env::current_dir().unwrap();
+    match path.map(RunArgs::load) {
+        None => toolchain(Toolchain::from_cwd()),
+        Some(Ok(args)) => toolchain(Toolchain::resolve(&args.tools, &cwd)),
+        Some(Err(e)) => {
+            println!("[!] {}", e);
+            process::exit(1);
+        }
     }
 }
 
-fn ensure_saved_states() {
-    if !Path::new("saved-states").exists() && fs::create_dir("saved-states").is_err() {
+fn ensure_saved_states(workspace: &Workspace) {
+    let saved_states = &workspace.saved_states;
+    if !saved_states.exists() && fs::create_dir(saved_states).is_err() {
         println!("Could not create saved-states dir, aborting!");
         process::exit(0);
     };
 }
 
-fn main() {
+fn fuzz(workspace: &Workspace, cli: &Cli, config_path: PathBuf, resume: bool) {
     is_root();
 
     setup_env();
 
-    ensure_saved_states();
+    ensure_saved_states(workspace);
 
-    println!("cwd: {:?}", std::env::current_dir().unwrap());
+    if resume && !workspace.path(fitm::FITM_STATE).exists() {
+        println!("[!] Nothing to resume, {} not found", fitm::FITM_STATE);
+        process::exit(1);
+    }
+
+    let args = load_args(&config_path, cli);
 
-    let config_path: PathBuf = std::env::args()
-        .nth(1)
-        .expect("No config path given")
-        .into();
-    let args = load_args(config_path);
+    let tools = toolchain(Toolchain::resolve(
+        &args.tools,
+        &env::current_dir().unwrap(),
+    ));
 
-    // TODO: use argv to fill these
     // Paths are relative to ACTIVE_DIR
     if let Err(e) = fitm::run(
+        workspace,
         &args.client,
         &args.client_args,
         &args.client_envs,
@@ -94,9 +114,92 @@ fn main() {
         &args.server_args,
         &args.server_envs,
         &args.server_files,
-        &Duration::from_secs(args.run_time),
+        &args.run_time_bounds(),
         args.server_only,
+        cli.seed.or(args.seed),
+        &tools,
+        args.scheduler
+            .build(args.snapshots_per_gen, workspace)
+            .as_mut(),
+        &args.budget(),
+        &args.limits(),
+        args.workers,
+        args.secondaries,
+        args.max_consecutive_failures,
     ) {
-        println!("Error {:?}", e);
+        println!("[!] Fuzzing failed: {}", e);
+        process::exit(1);
+    };
+}
+
+fn main() {
+    let cli = match cli::parse(env::args().skip(1)) {
+        Ok(cli) => cli,
+        Err(e) => {
+            println!("[!] {}\n\n{}", e, cli::USAGE);
+            process::exit(1);
+        }
+    };
+
+    // Resolve the config before changing the working dir, it's given relative to where we were started
+    let absolute = |path: &PathBuf| env::current_dir().unwrap().join(path);
+    let command = match &cli.command {
+        Subcommand::Fuzz { config } => Subcommand::Fuzz {
+            config: absolute(config),
+        },
+        Subcommand::Resume { config } => Subcommand::Resume {
+            config: absolute(config),
+        },
+        command => command.clone(),
+    };
+    let config = cli.config.as_ref().map(absolute);
+
+    if let Some(workdir) = &cli.workdir {
+        if let Err(e) = env::set_current_dir(workdir) {
+            println!("[!] Could not change into {:?}: {}", workdir, e);
+            process::exit(1);
+        }
+    }
+
+    println!("cwd: {:?}", std::env::current_dir().unwrap());
+    let workspace = Workspace::default();
+
+    let res = match command {
+        Subcommand::Fuzz { config } => {
+            fuzz(&workspace, &cli, config, false);
+            Ok(())
+        }
+        Subcommand::Resume { config } => {
+            fuzz(&workspace, &cli, config, true);
+            Ok(())
+        }
+        Subcommand::Status => commands::status(&workspace),
+        Subcommand::Replay { state, input } => {
+            is_root();
+            setup_env();
+            let tools = config_tools(config.as_deref());
+            commands::replay(&workspace, &tools, &state, input.as_deref()).map(|_| ())
+        }
+        Subcommand::Triage => {
+            is_root();
+            setup_env();
+            commands::triage(&workspace, &config_tools(config.as_deref()))
+        }
+        Subcommand::Clean => commands::clean(&workspace),
+        Subcommand::Export { dest } => commands::export(&workspace, dest.as_deref()).map(|_| ()),
+        Subcommand::Fsck { mode } => commands::fsck(&workspace, mode).map(|_| ()),
+        Subcommand::Flatten { state } => {
+            commands::flatten(&workspace, state.as_deref()).map(|_| ())
+        }
+        Subcommand::Gc { mode, policy } => commands::gc(&workspace, mode, policy).map(|_| ()),
+        Subcommand::Help => {
+            println!("{}", cli::USAGE);
+            Ok(())
+        }
     };
+
+    if let Err(e) = res {
+        println!("Error {:?}", e);
+        process::exit(1);
+    }
 }
@@ -4,8 +4,11 @@ use std::{
     ffi::CString,
     fmt::Debug,
     io::{self, Write},
+    path::Path,
 };
 
+use crate::error::FitmError;
+
 fn mount(
     src: &str,
     target: &str,
@@ -51,6 +54,24 @@ fn mount(
     }
 }
 
+/// Bind mounts `src` over `target` in the current mount namespace.
+/// Our mounts are made slaves first, so the bind mount doesn't propagate back to the parent namespace.
+pub fn bind_mount(src: &Path, target: &Path) -> io::Result<()> {
+    let path_str = |path: &Path| {
+        path.to_str()
+            .map(str::to_string)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{:?}", path)))
+    };
+    mount("none", "/", None, libc::MS_REC | libc::MS_SLAVE, None)?;
+    mount(
+        &path_str(src)?,
+        &path_str(target)?,
+        None,
+        libc::MS_BIND,
+        None,
+    )
+}
+
 unsafe fn sys_clone(flags: libc::c_int) -> io::Result<Option<libc::pid_t>> {
     let ret: pid_t = libc::syscall(libc::SYS_clone, flags as libc::c_int, 0, 0, 0, 0) as _;
 
@@ -65,7 +86,7 @@ unsafe fn sys_clone(flags: libc::c_int) -> io::Result<Option<libc::pid_t>> {
 }
 
 pub struct NamespaceContext {
-    pub init_fn: Box<dyn FnOnce()>,
+    pub init_fn: Box<dyn FnOnce() -> io::Result<()>>,
 }
 
 impl NamespaceContext {
@@ -73,22 +94,21 @@ impl NamespaceContext {
         NamespaceContext {
             init_fn: Box::new(|| {
                 // mount("none","/", None, libc::MS_REC | libc::MS_PRIVATE, None); // Make / private (meaning changes wont propagate to the default namespace)
-                mount("none", "/proc", None, libc::MS_REC | libc::MS_PRIVATE, None)
-                    .expect("mounting proc private failed");
+                mount("none", "/proc", None, libc::MS_REC | libc::MS_PRIVATE, None)?;
                 mount(
                     "proc",
                     "/proc",
                     Some("proc"),
                     libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
                     None,
-                )
-                .expect("proc remounting failed");
+                )?;
 
                 unsafe { libc::setsid() };
 
                 let _ = io::stdout().flush();
                 // std::process::Command::new("stat").arg("/proc/self/ns/pid").status().unwrap();
                 // std::process::Command::new("ps").arg("-aux").status().unwrap();
+                Ok(())
             }),
         }
     }
@@ -122,23 +142,44 @@ impl NamespaceContext {
                 status: None,
             },
             None => {
-                (self.init_fn)();
+                if let Err(e) = (self.init_fn)() {
+                    println!("[!] Namespace setup failed with error {:?}", e);
+                    let _ = io::stdout().flush();
+                    std::process::exit(1)
+                }
                 let res = f();
                 std::thread::sleep(std::time::Duration::from_millis(5));
-                std::process::Command::new("sync")
-                    .arg("-f")
-                    .status()
-                    .unwrap();
+                let _ = std::process::Command::new("sync").arg("-f").status();
                 let _ = io::stdout().flush();
                 match res {
                     Ok(val) => std::process::exit(val),
-                    Err(e) => panic!("Namespace call failed with error {:?}", e),
+                    Err(e) => {
+                        println!("[!] Namespace call failed with error {:?}", e);
+                        let _ = io::stdout().flush();
+                        std::process::exit(1)
+                    }
                 }
             }
         })
     }
 }
 
+impl NamespaceContext {
+    /// Runs `f` in a new namespace and waits for it, see `Namespace::exit_code`.
+    /// `what` names the job in errors.
+    pub fn run<T, E>(self, what: &str, f: T) -> Result<i32, FitmError>
+    where
+        T: FnOnce() -> Result<i32, E>,
+        E: Debug,
+    {
+        self.execute(f)
+            .map_err(|e| {
+                FitmError::Namespace(format!("could not create a namespace for {}: {}", what, e))
+            })?
+            .exit_code(what)
+    }
+}
+
 impl Default for NamespaceContext {
     fn default() -> Self {
         Self::new()
@@ -151,6 +192,16 @@ pub struct Namespace {
 }
 
 impl Namespace {
+    /// Waits for the namespace and returns the exit code of its init, `what` names the job in errors
+    pub fn exit_code(&mut self, what: &str) -> Result<i32, FitmError> {
+        let status = self
+            .wait()
+            .map_err(|e| FitmError::Namespace(format!("could not wait for {}: {}", what, e)))?;
+        status
+            .code()
+            .ok_or_else(|| FitmError::Namespace(format!("{} was killed: {}", what, status)))
+    }
+
     pub fn wait(&mut self) -> io::Result<ExitStatus> {
         if let Some(status) = self.status {
             return Ok(status);
@@ -292,7 +343,7 @@ mod tests {
                 println!("UID: {}", unsafe { libc::getuid() });
 
                 let _criu_srv = Command::new("criu")
-                    .args(&[
+                    .args([
                         "service",
                         "-v4",
                         "--address",
@@ -307,6 +358,7 @@ mod tests {
                     .read(true)
                     .write(true)
                     .create(true)
+                    .truncate(false)
                     .mode(0o644)
                     .open("/proc/sys/kernel/ns_last_pid")
                     .expect("Failed to open ns_last_pid");
@@ -329,15 +381,15 @@ mod tests {
 
                 file.seek(SeekFrom::Start(0)).unwrap();
                 unsafe { libc::ftruncate(file.as_raw_fd(), 0) };
-                file.write(311336.to_string().as_bytes())?;
+                file.write_all(311336.to_string().as_bytes())?;
 
                 let mut child = Command::new("setsid")
                     .args(&[
-                        format!("stdbuf"),
-                        format!("-oL"),
-                        format!("./fitm-qemu-trace"),
-                        format!("{}", "tests/targets/pseudoserver_simple"),
-                        format!("{}", "/dev/null"),
+                        "stdbuf".to_string(),
+                        "-oL".to_string(),
+                        "./fitm-qemu-trace".to_string(),
+                        "tests/targets/pseudoserver_simple".to_string(),
+                        "/dev/null".to_string(),
                     ])
                     .stdin(Stdio::from(stdin_file))
                     .stdout(Stdio::from(stdout))
@@ -374,7 +426,9 @@ mod tests {
             std::process::exit(0);
         }
 
+        let workspace = crate::workspace::Workspace::default();
         let afl_server_snap: FITMSnapshot = FITMSnapshot::new(
+            &workspace,
             1,
             0,
             "tests/targets/pseudoserver_simple".to_string(),
@@ -383,10 +437,21 @@ mod tests {
             true,
             false,
             None,
-        );
+        )
+        .unwrap();
 
+        let tools = crate::toolchain::Toolchain::from_cwd().unwrap();
+        let worker = crate::workers::Worker::new(&workspace, 0, 1);
         afl_server_snap
-            .init_run(false, true, &[], &[], &[])
+            .init_run(
+                &tools,
+                &worker,
+                &mut crate::utils::RomuRand::preseeded(),
+                false,
+                true,
+                &[],
+                &std::collections::HashMap::new(),
+            )
             .unwrap();
 
         std::fs::write("./saved-states/fitm-gen1-state0/in/testinp", "ulullulul")
@@ -394,6 +459,8 @@ mod tests {
 
         afl_server_snap
             .create_outputs(
+                &tools,
+                &worker,
                 "./saved-states/fitm-gen1-state0/in",
                 "./saved-states/fitm-gen1-state0/outputs",
             )
@@ -0,0 +1,220 @@
+use std::io::{self, ErrorKind};
+
+/// A single decoded protobuf field value.
+/// We only need the wire types CRIU actually uses in its images.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Value<'a> {
+    Varint(u64),
+    Fixed64(u64),
+    Bytes(&'a [u8]),
+    Fixed32(u32),
+}
+
+impl<'a> Value<'a> {
+    pub fn as_u64(&self) -> io::Result<u64> {
+        match *self {
+            Value::Varint(v) | Value::Fixed64(v) => Ok(v),
+            Value::Fixed32(v) => Ok(v as u64),
+            Value::Bytes(_) => Err(invalid("expected a numeric field, got bytes")),
+        }
+    }
+
+    pub fn as_u32(&self) -> io::Result<u32> {
+        Ok(self.as_u64()? as u32)
+    }
+
+    pub fn as_bytes(&self) -> io::Result<&'a [u8]> {
+        match *self {
+            Value::Bytes(b) => Ok(b),
+            _ => Err(invalid("expected a length-delimited field")),
+        }
+    }
+
+    pub fn as_string(&self) -> io::Result<String> {
+        String::from_utf8(self.as_bytes()?.to_vec())
+            .map_err(|_| invalid("string field is not valid utf-8"))
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Reads a base 128 varint at `pos`, advancing it.
+pub(crate) fn read_varint(buf: &[u8], pos: &mut usize) -> io::Result<u64> {
+    let mut result = 0_u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *buf.get(*pos).ok_or_else(|| invalid("truncated varint"))?;
+        *pos += 1;
+        result |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(result);
+        }
+    }
+    Err(invalid("varint longer than 64 bits"))
+}
+
+/// Iterator over the `(field number, value)` pairs of one encoded message
+pub(crate) struct Fields<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Fields<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Fields { buf, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.buf.len())
+            .ok_or_else(|| invalid("field runs past the end of the message"))?;
+        let slice = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn next_field(&mut self) -> io::Result<(u32, Value<'a>)> {
+        let key = read_varint(self.buf, &mut self.pos)?;
+        let field = (key >> 3) as u32;
+        let value = match key & 0x7 {
+            0 => Value::Varint(read_varint(self.buf, &mut self.pos)?),
+            1 => {
+                let mut bytes = [0_u8; 8];
+                bytes.copy_from_slice(self.take(8)?);
+                Value::Fixed64(u64::from_le_bytes(bytes))
+            }
+            2 => {
+                let len = read_varint(self.buf, &mut self.pos)? as usize;
+                Value::Bytes(self.take(len)?)
+            }
+            5 => {
+                let mut bytes = [0_u8; 4];
+                bytes.copy_from_slice(self.take(4)?);
+                Value::Fixed32(u32::from_le_bytes(bytes))
+            }
+            wire_type => {
+                return Err(invalid(&format!(
+                    "unsupported wire type {} for field {}",
+                    wire_type, field
+                )))
+            }
+        };
+        Ok((field, value))
+    }
+}
+
+impl<'a> Iterator for Fields<'a> {
+    type Item = io::Result<(u32, Value<'a>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos >= self.buf.len() {
+            return None;
+        }
+        let ret = self.next_field();
+        if ret.is_err() {
+            // Don't loop on garbage
+            self.pos = self.buf.len();
+        }
+        Some(ret)
+    }
+}
+
+/// Minimal protobuf encoder, fields are written in the order they are added
+#[derive(Default)]
+pub(crate) struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn varint(&mut self, mut val: u64) {
+        while val >= 0x80 {
+            self.buf.push((val as u8) | 0x80);
+            val >>= 7;
+        }
+        self.buf.push(val as u8);
+    }
+
+    fn key(&mut self, field: u32, wire_type: u64) {
+        self.varint(((field as u64) << 3) | wire_type);
+    }
+
+    pub fn uint(&mut self, field: u32, val: u64) -> &mut Self {
+        self.key(field, 0);
+        self.varint(val);
+        self
+    }
+
+    pub fn finish(&self) -> Vec<u8> {
+        self.buf.clone()
+    }
+}
+
+/// FitM itself only writes varints, the tests build whole criu images and responses
+#[cfg(test)]
+impl Writer {
+    /// int32 fields are sign extended to 64 bit, so negative values take 10 bytes
+    pub fn int32(&mut self, field: u32, val: i32) -> &mut Self {
+        self.uint(field, val as i64 as u64)
+    }
+
+    pub fn bool(&mut self, field: u32, val: bool) -> &mut Self {
+        self.uint(field, val as u64)
+    }
+
+    pub fn bytes(&mut self, field: u32, val: &[u8]) -> &mut Self {
+        self.key(field, 2);
+        self.varint(val.len() as u64);
+        self.buf.extend_from_slice(val);
+        self
+    }
+
+    pub fn string(&mut self, field: u32, val: &str) -> &mut Self {
+        self.bytes(field, val.as_bytes())
+    }
+
+    pub fn message(&mut self, field: u32, msg: &Writer) -> &mut Self {
+        self.bytes(field, &msg.buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Fields, Value, Writer};
+
+    #[test]
+    fn test_decode_fields() {
+        // field 1: varint 150, field 2: "hi"
+        let msg = [0x08, 0x96, 0x01, 0x12, 0x02, b'h', b'i'];
+        let fields: Vec<_> = Fields::new(&msg).map(|f| f.unwrap()).collect();
+        assert_eq!(fields[0], (1, Value::Varint(150)));
+        assert_eq!(fields[1].1.as_string().unwrap(), "hi");
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let mut inner = Writer::new();
+        inner.string(1, "key").int32(2, -1);
+        let msg = Writer::new().uint(1, 300).message(2, &inner).finish();
+
+        let fields: Vec<_> = Fields::new(&msg).map(|f| f.unwrap()).collect();
+        assert_eq!(fields[0], (1, Value::Varint(300)));
+        let inner: Vec<_> = Fields::new(fields[1].1.as_bytes().unwrap())
+            .map(|f| f.unwrap())
+            .collect();
+        assert_eq!(inner[0].1.as_string().unwrap(), "key");
+        assert_eq!(inner[1].1.as_u64().unwrap() as i32, -1);
+    }
+
+    #[test]
+    fn test_decode_truncated() {
+        let msg = [0x12, 0x05, b'h'];
+        assert!(Fields::new(&msg).next().unwrap().is_err());
+    }
+}
@@ -0,0 +1,337 @@
+use std::{
+    fmt::Write as _,
+    fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+};
+
+use crate::error::FitmError;
+use crate::fsck::{self, QUARANTINE_DIR};
+use crate::restore::RESTORE_LOG;
+use crate::state::CampaignState;
+use crate::utils::{afl_node_name, afl_node_prefix};
+use crate::workers::Worker;
+use crate::FITMSnapshot;
+
+/// Default of the `max_consecutive_failures` config key
+pub const MAX_CONSECUTIVE_FAILURES: usize = 3;
+/// The diagnostic report of a run that gave up, in `saved-states/.quarantine`
+pub const QUARANTINE_REPORT: &str = "report";
+
+/// Logs of a fuzz run that are kept in `logs` of a quarantined folder, prefixed with their AFL node
+const NODE_LOGS: [&str; 3] = ["stdout-afl", "stderr-afl", RESTORE_LOG];
+
+/// Whether `e` means `snap` itself is broken: it could not be restored, or afl-fuzz gave up on it
+pub fn is_snapshot_failure(snap: &FITMSnapshot, e: &FitmError) -> bool {
+    matches!(e, FitmError::CriuRestore { .. } | FitmError::AflFuzz { .. })
+        && e.state() == Some(snap.state_path.as_str())
+}
+
+fn copy_log(from: &Path, to: &Path) -> io::Result<bool> {
+    match fs::copy(from, to) {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Moves the folder of `snap` to `saved-states/.quarantine`, together with the logs `worker` wrote
+/// while fuzzing it, and notes `failure` on it. Returns where the folder went.
+pub fn quarantine_snapshot(
+    worker: &Worker,
+    snap: &mut FITMSnapshot,
+    mut failure: FitmError,
+) -> io::Result<PathBuf> {
+    let dir = fsck::quarantine(&worker.workspace.saved_states, &snap.state_path)?;
+    let logs = dir.join("logs");
+    fs::create_dir_all(&logs)?;
+
+    for node in 0..=worker.secondaries {
+        let active_state = worker.node(node).workspace.active_state;
+        for log in NODE_LOGS {
+            let from = active_state.join(log);
+            let to = logs.join(format!(
+                "{}{}",
+                afl_node_prefix(Path::new(&afl_node_name(node))),
+                log
+            ));
+            if copy_log(&from, &to)? {
+                // The active state is gone with the next run, point to our copy instead
+                if let FitmError::CriuRestore { log, .. } = &mut failure {
+                    if *log == from {
+                        *log = to;
+                    }
+                }
+            }
+        }
+    }
+    for criu_log in [&worker.workspace.criu_stdout, &worker.workspace.criu_stderr] {
+        if let Some(name) = criu_log.file_name() {
+            copy_log(criu_log, &logs.join(name))?;
+        }
+    }
+
+    snap.failure = Some(failure);
+    fs::write(dir.join("run-info"), format!("{:?}", snap))?;
+    Ok(dir)
+}
+
+/// The snapshots quarantined in this run. Once `max_consecutive_failures` snapshots failed in a row,
+/// something is wrong with the setup rather than with single snapshots, and the run should give up.
+#[derive(Debug, Default)]
+pub struct Quarantine {
+    pub max_consecutive_failures: usize,
+    /// Failures since the last snapshot that was fuzzed fine
+    consecutive: usize,
+    /// In the order they failed, with their `failure`
+    pub snaps: Vec<FITMSnapshot>,
+    /// Snapshots quarantined along with the failed snapshot they descend from, as `(state_path, failed)`.
+    /// They never ran, so they don't count as failures.
+    pub children: Vec<(String, String)>,
+}
+
+impl Quarantine {
+    pub fn new(max_consecutive_failures: usize) -> Self {
+        Quarantine {
+            max_consecutive_failures,
+            ..Quarantine::default()
+        }
+    }
+
+    /// A snapshot was fuzzed fine, that ends the streak
+    pub fn succeeded(&mut self) {
+        self.consecutive = 0;
+    }
+
+    /// `snap` failed and was quarantined, see `quarantine_snapshot`
+    pub fn failed(&mut self, snap: FITMSnapshot) {
+        self.consecutive += 1;
+        self.snaps.push(snap);
+    }
+
+    /// Whether too many snapshots failed in a row
+    pub fn exceeded(&self) -> bool {
+        self.consecutive >= self.max_consecutive_failures
+    }
+
+    /// Drops the quarantined snapshots (and their history) from `state`, so they are not picked again.
+    /// Snapshots created from them can't be restored without them, their folders in `saved_states`
+    /// are quarantined, too.
+    pub fn remove_from(
+        &mut self,
+        state: &mut CampaignState,
+        saved_states: &Path,
+    ) -> io::Result<()> {
+        for snap in &self.snaps {
+            for child in state.descendants(&snap.state_path) {
+                if self.contains(&child) {
+                    continue;
+                }
+                let dir = fsck::quarantine(saved_states, &child)?;
+                println!(
+                    "[!] Quarantined {} to {:?}, it descends from {}",
+                    child, dir, snap.state_path
+                );
+                self.children.push((child, snap.state_path.clone()));
+            }
+        }
+        for snaps in state.generation_snaps.iter_mut() {
+            snaps.retain(|snap| !self.contains(&snap.state_path));
+        }
+        for state_path in self.state_paths() {
+            state.history.remove(state_path);
+        }
+        Ok(())
+    }
+
+    fn state_paths(&self) -> impl Iterator<Item = &str> {
+        self.snaps
+            .iter()
+            .map(|snap| snap.state_path.as_str())
+            .chain(self.children.iter().map(|(child, _)| child.as_str()))
+    }
+
+    fn contains(&self, state_path: &str) -> bool {
+        self.state_paths()
+            .any(|quarantined| quarantined == state_path)
+    }
+
+    /// What failed in this run and where to look, the last `consecutive` entries failed in a row
+    pub fn report(&self, saved_states: &Path) -> String {
+        let mut report = format!(
+            "{} of {} quarantined snapshots failed in a row (max_consecutive_failures: {}):\n",
+            self.consecutive,
+            self.snaps.len(),
+            self.max_consecutive_failures
+        );
+        for snap in &self.snaps {
+            let failure = snap
+                .failure
+                .as_ref()
+                .map_or_else(|| "unknown failure".to_string(), FitmError::to_string);
+            let _ = writeln!(
+                report,
+                "- {} ({}): {}",
+                snap.state_path, snap.target_bin, failure
+            );
+            let _ = writeln!(
+                report,
+                "  folder and logs: {:?}",
+                saved_states.join(QUARANTINE_DIR).join(&snap.state_path)
+            );
+        }
+        for (child, base) in &self.children {
+            let _ = writeln!(
+                report,
+                "- {}: descends from {}, quarantined with it",
+                child, base
+            );
+        }
+        report
+    }
+
+    /// Writes the `report` to `QUARANTINE_REPORT` and returns the error the run gives up with
+    pub fn abort(&self, saved_states: &Path) -> io::Result<FitmError> {
+        let report = saved_states.join(QUARANTINE_DIR).join(QUARANTINE_REPORT);
+        fs::create_dir_all(saved_states.join(QUARANTINE_DIR))?;
+        fs::write(&report, self.report(saved_states))?;
+        Ok(FitmError::Quarantine {
+            failures: self.consecutive,
+            report,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::restore::{restore_succeeded, RESTORE_SUCCESS};
+    use crate::test_snapshot;
+    use crate::workspace::Workspace;
+
+    #[test]
+    fn test_quarantine_snapshot() {
+        let root = Path::new("/tmp/fitm_quarantine_unittest");
+        let _ = fs::remove_dir_all(root);
+        let workspace = Workspace::new(root);
+        let worker = Worker::pool(&workspace, 1, 1).remove(0);
+
+        let saved = workspace.saved_state("fitm-gen3-state0");
+        fs::create_dir_all(saved.join("snapshot")).unwrap();
+        let main_log = worker.workspace.active_state.join(RESTORE_LOG);
+        let sec_log = worker.node(1).workspace.active_state.join(RESTORE_LOG);
+        fs::create_dir_all(sec_log.parent().unwrap()).unwrap();
+        fs::create_dir_all(main_log.parent().unwrap()).unwrap();
+        fs::write(&main_log, format!("(00.1) {}\n", RESTORE_SUCCESS)).unwrap();
+        fs::write(&sec_log, "Error (criu/cr-restore.c): Can't restore\n").unwrap();
+        fs::write(worker.node(1).workspace.active_state.join("stderr-afl"), "").unwrap();
+        fs::write(&worker.workspace.criu_stderr, "").unwrap();
+        assert!(restore_succeeded(&main_log));
+        assert!(!restore_succeeded(&sec_log));
+        assert!(!restore_succeeded(&root.join("missing.log")));
+
+        let mut failed = test_snapshot(3, 0);
+        let failure = FitmError::CriuRestore {
+            state: failed.state_path.clone(),
+            reason: "afl-fuzz node sec1 exited with 1 before the restore finished".to_string(),
+            log: sec_log,
+        };
+        assert!(is_snapshot_failure(&failed, &failure));
+        assert!(!is_snapshot_failure(&test_snapshot(3, 1), &failure));
+        assert!(!is_snapshot_failure(
+            &failed,
+            &FitmError::Config("broken".to_string())
+        ));
+
+        let dir = quarantine_snapshot(&worker, &mut failed, failure).unwrap();
+        let quarantined = workspace.saved_states.join(QUARANTINE_DIR);
+        assert_eq!(dir, quarantined.join("fitm-gen3-state0"));
+        assert!(!saved.exists());
+        assert!(dir.join("snapshot").is_dir());
+        assert!(dir.join("logs/restore.log").is_file());
+        assert!(dir.join("logs/sec1-restore.log").is_file());
+        assert!(dir.join("logs/sec1-stderr-afl").is_file());
+        assert!(dir.join("logs/criu_stderr").is_file());
+        // The failure points to the copy of the log
+        match &failed.failure {
+            Some(FitmError::CriuRestore { log, .. }) => {
+                assert_eq!(log, &dir.join("logs/sec1-restore.log"))
+            }
+            other => panic!("unexpected failure {:?}", other),
+        }
+        assert!(fs::read_to_string(dir.join("run-info"))
+            .unwrap()
+            .contains("failure"));
+
+        let mut state = CampaignState::new(
+            1,
+            vec![
+                vec![],
+                vec![],
+                vec![],
+                vec![failed.clone(), test_snapshot(3, 1)],
+                vec![],
+                vec![FITMSnapshot {
+                    base_state: "fitm-gen3-state0".to_string(),
+                    ..test_snapshot(5, 0)
+                }],
+                vec![],
+                vec![FITMSnapshot {
+                    base_state: "fitm-gen5-state0".to_string(),
+                    ..test_snapshot(7, 0)
+                }],
+            ],
+        );
+        // The children of a failed snapshot can't be restored without it
+        for child in ["fitm-gen5-state0", "fitm-gen7-state0"] {
+            fs::create_dir_all(workspace.saved_state(child)).unwrap();
+        }
+        assert_eq!(
+            state.descendants("fitm-gen3-state0"),
+            vec!["fitm-gen5-state0", "fitm-gen7-state0"]
+        );
+        state
+            .history
+            .entry("fitm-gen3-state0".to_string())
+            .or_default()
+            .add_run(Duration::from_secs(1), 10, 0, 0, 0);
+
+        let mut quarantine = Quarantine::new(2);
+        quarantine.failed(failed);
+        assert!(!quarantine.exceeded());
+        quarantine.succeeded();
+        quarantine.failed(test_snapshot(3, 2));
+        assert!(!quarantine.exceeded());
+        quarantine.failed(test_snapshot(3, 3));
+        assert!(quarantine.exceeded());
+
+        quarantine
+            .remove_from(&mut state, &workspace.saved_states)
+            .unwrap();
+        assert_eq!(state.generation_snaps[3].len(), 1);
+        assert!(state.generation_snaps[5].is_empty());
+        assert!(state.generation_snaps[7].is_empty());
+        assert!(state.history.is_empty());
+        assert!(quarantined.join("fitm-gen7-state0").is_dir());
+        assert!(!workspace.saved_state("fitm-gen5-state0").exists());
+        // They never ran, so they are no failures of their own
+        assert_eq!(quarantine.snaps.len(), 3);
+
+        let e = quarantine.abort(&workspace.saved_states).unwrap();
+        assert_eq!(
+            e,
+            FitmError::Quarantine {
+                failures: 2,
+                report: quarantined.join(QUARANTINE_REPORT)
+            }
+        );
+        let report = fs::read_to_string(quarantined.join(QUARANTINE_REPORT)).unwrap();
+        assert!(report.starts_with("2 of 3 quarantined snapshots failed in a row"));
+        assert!(report.contains("- fitm-gen3-state0 (bin): restoring fitm-gen3-state0 failed"));
+        assert!(report.contains("- fitm-gen7-state0: descends from fitm-gen3-state0"));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,529 @@
+use std::{
+    collections::BTreeMap,
+    env,
+    ffi::{OsStr, OsString},
+    fs::{self, OpenOptions},
+    io::{self, ErrorKind},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::PermissionsExt,
+        io::{AsRawFd, FromRawFd, OwnedFd},
+        process::CommandExt,
+    },
+    path::Path,
+    process::Command,
+};
+
+use crate::criu_images::{self, FD_TYPE_REG};
+use crate::toolchain::Toolchain;
+use crate::workspace::Workspace;
+use crate::FITMSnapshot;
+
+/// Fds 198 and 199 are the AFL forkserver control and status pipes
+pub const FORKSRV_FD: u32 = 198;
+/// Where criu logs restores to, in the active state next to the images dir
+pub const RESTORE_LOG: &str = "restore.log";
+/// What criu logs to `RESTORE_LOG` once the restored process is running
+pub const RESTORE_SUCCESS: &str = "Restore finished successfully";
+/// fitm-qemu reads the env of the restored process from this file in the active state
+pub const ENVFILE: &str = "envfile";
+/// Restores outside of afl-fuzz force PIDs out of the usual range. 1<<15 is the largest that's legal on WSL2.
+const LAST_PID: u32 = 1 << 15;
+
+/// A regular file the snapshotted process held open, as found in `files.img`/`fdinfo-*.img`
+#[derive(Clone, Debug, PartialEq)]
+pub struct FdMapping {
+    pub fd: u32,
+    pub path: String,
+}
+
+/// One `--inherit-fd` argument for `criu restore`.
+/// `key` is what criu matches against the dumped fd (a path without leading `/` or `pipe:[ino]`).
+#[derive(Clone, Debug, PartialEq)]
+pub struct InheritFd {
+    pub fd: u32,
+    pub key: String,
+}
+
+impl InheritFd {
+    pub fn arg(&self) -> String {
+        format!("fd[{}]:{}", self.fd, self.key)
+    }
+}
+
+/// A file the restore script opens on a given fd before calling criu,
+/// so criu can hand it to the restored process.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReopenFd {
+    pub fd: u32,
+    pub path: String,
+    /// Open for appending (`>>`) instead of read/write (`<>`)
+    pub append: bool,
+}
+
+/// Everything needed to restore a snapshot: which fds to reopen and what to pass to `criu restore`.
+/// Replaces the old `create_restore.py` + `restore.sh.tmp` combo.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RestorePlan {
+    pub criu_bin: String,
+    /// Log file, relative to the images dir
+    pub log_file: String,
+    /// Images dir as passed to criu, the restore script defaults `$CRIU_SNAPSHOT_DIR` to `./snapshot`
+    pub images_dir: String,
+    pub reopen_fds: Vec<ReopenFd>,
+    pub inherit_fds: Vec<InheritFd>,
+}
+
+impl Default for RestorePlan {
+    fn default() -> Self {
+        RestorePlan {
+            criu_bin: "criu".to_string(),
+            log_file: format!("../{}", RESTORE_LOG),
+            images_dir: "$CRIU_SNAPSHOT_DIR".to_string(),
+            reopen_fds: vec![],
+            inherit_fds: vec![],
+        }
+    }
+}
+
+/// Finds all files in our `fd/` folder the snapshotted process had open, together with their fd number.
+/// These have to be reopened and handed to criu on restore.
+pub fn fd_mappings(snapshot_dir: &Path) -> io::Result<Vec<FdMapping>> {
+    let fdinfos = criu_images::fdinfos(snapshot_dir)?;
+    Ok(criu_images::files(snapshot_dir)?
+        .into_iter()
+        .filter(|file| file.fd_type == FD_TYPE_REG)
+        .filter_map(|file| Some((file.id, file.reg?.name)))
+        .filter(|(_, name)| name.contains("/fd/"))
+        .filter_map(|(id, path)| {
+            fdinfos
+                .iter()
+                .find(|fdinfo| fdinfo.id == id)
+                .map(|fdinfo| FdMapping {
+                    fd: fdinfo.fd,
+                    path,
+                })
+        })
+        .collect())
+}
+
+/// Whether the restore logged to `log` went through. No log means criu never got to restore.
+pub fn restore_succeeded(log: &Path) -> bool {
+    fs::read(log).is_ok_and(|log| String::from_utf8_lossy(&log).contains(RESTORE_SUCCESS))
+}
+
+/// Parses the `pipes` file written by fitm-qemu: the first two `pipe:[ino]` are the forkserver pipes.
+pub fn forkserver_pipes(pipes_file: &Path) -> io::Result<(String, String)> {
+    let content = fs::read_to_string(pipes_file)?;
+    let mut pipes = content
+        .split(|c: char| c.is_whitespace())
+        .filter_map(|word| {
+            let start = word.find("pipe:[")?;
+            let end = start + word[start..].find(']')?;
+            Some(word[start..=end].to_string())
+        });
+    match (pipes.next(), pipes.next()) {
+        (Some(first), Some(second)) => Ok((first, second)),
+        _ => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("{:?} does not contain two forkserver pipes", pipes_file),
+        )),
+    }
+}
+
+fn strip_root(path: &str) -> String {
+    path.trim_start_matches('/').to_string()
+}
+
+impl RestorePlan {
+    /// Build the restore plan for a state living in `active_dir`.
+    /// `base_snapshot` is the criu images dir of the state the snapshot was created from, if any.
+    pub fn build(active_dir: &Path, base_snapshot: Option<&Path>) -> io::Result<Self> {
+        let mut plan = RestorePlan::default();
+
+        // When running with afl-fuzz the script receives 198/199 as pipes for RPC with the forkserver.
+        // To forward these into the restored process we use --inherit-fd.
+        // The target for --inherit-fd within the restored process are the named pipes that are stored in the pipes file.
+        // `init_run` and `copy_snapshot_base` always put it there, a state without it can't be fuzzed.
+        let (first, second) = forkserver_pipes(&active_dir.join("pipes")).map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!("no forkserver pipes for {:?}: {}", active_dir, e),
+            )
+        })?;
+        plan.inherit_fds.push(InheritFd {
+            fd: FORKSRV_FD,
+            key: first,
+        });
+        plan.inherit_fds.push(InheritFd {
+            fd: FORKSRV_FD + 1,
+            key: second,
+        });
+
+        if let Some(base_snapshot) = base_snapshot {
+            let active = active_dir.to_string_lossy();
+            for (fd, name) in &[(1, "stdout"), (2, "stderr")] {
+                let path = format!("{}/{}", active, name);
+                plan.inherit_fds.push(InheritFd {
+                    fd: *fd,
+                    key: strip_root(&path),
+                });
+                plan.reopen_fds.push(ReopenFd {
+                    fd: *fd,
+                    path,
+                    append: true,
+                });
+            }
+
+            // create_restore.py also replaced `fitm-c<n>s<m>` state names in these paths with the current
+            // state. Our states are named `fitm-gen<n>-state<m>`, so that never matched, and the fds live in
+            // the active state, which is mounted at the same path for every restore. They are used as they are.
+            for mapping in fd_mappings(base_snapshot)? {
+                plan.inherit_fds.push(InheritFd {
+                    fd: mapping.fd,
+                    key: strip_root(&mapping.path),
+                });
+                plan.reopen_fds.push(ReopenFd {
+                    fd: mapping.fd,
+                    path: mapping.path,
+                    append: false,
+                });
+            }
+        }
+
+        Ok(plan)
+    }
+
+    /// The restore plan for the given snapshot, once it has been copied to the active state of `workspace`.
+    pub fn for_snapshot(
+        snap: &FITMSnapshot,
+        tools: &Toolchain,
+        workspace: &Workspace,
+    ) -> io::Result<Self> {
+        let cwd = env::current_dir()?;
+        let base_snapshot = if snap.base_state.is_empty() {
+            None
+        } else {
+            Some(cwd.join(workspace.saved_state(&snap.base_state).join("snapshot")))
+        };
+        let mut plan =
+            RestorePlan::build(&cwd.join(&workspace.active_state), base_snapshot.as_deref())?;
+        plan.criu_bin = tools.criu.to_string_lossy().into_owned();
+        Ok(plan)
+    }
+
+    /// Arguments for `criu`, without the binary itself
+    pub fn criu_args(&self) -> Vec<String> {
+        let mut args: Vec<String> = vec![
+            "restore".into(),
+            "-d".into(),
+            "-vvv".into(),
+            "-o".into(),
+            self.log_file.clone(),
+            "--images-dir".into(),
+            self.images_dir.clone(),
+        ];
+        for inherit_fd in &self.inherit_fds {
+            args.push("--inherit-fd".into());
+            args.push(inherit_fd.arg());
+        }
+        args
+    }
+
+    /// Renders the bash script afl-fuzz and afl-cmin use as target.
+    /// It dumps the env for fitm-qemu, reopens all fds and calls `criu restore`, like `command`.
+    pub fn script(&self) -> String {
+        let mut script = String::from(
+            r#"#!/bin/bash
+set -x
+# Generated by fitm, expects the input file as first argument
+
+export INPUT_FILENAME=$(realpath $1)
+
+# fitm-qemu reads the env of the restored process from this file
+ENVFILE="envfile"
+env | sort > $ENVFILE
+pwd
+
+echo -n "" > ./out/.cur_input
+
+if [[ -z "$CRIU_SNAPSHOT_DIR" ]]; then
+  CRIU_SNAPSHOT_DIR="./snapshot"
+fi
+
+"#,
+        );
+
+        for reopen in &self.reopen_fds {
+            let mode = if reopen.append { ">>" } else { "<>" };
+            script.push_str(&format!("exec {}{} {}\n", reopen.fd, mode, reopen.path));
+        }
+
+        script.push_str(&format!(
+            r#"
+if [[ -z "${{__AFL_SHM_ID}}" ]]; then
+  exec {}< /dev/null
+  exec {}> /dev/null
+  # We are in a snapshot run force PIDs out of the usual range.
+  # 1<<15 is the largest that's legal on WSL2.
+  echo "$((1<<15))" > /proc/sys/kernel/ns_last_pid
+else
+  echo "Running in AFL, no dummy FDs necessary"
+fi
+
+"#,
+            FORKSRV_FD,
+            FORKSRV_FD + 1
+        ));
+
+        script.push_str(&self.criu_bin);
+        for arg in self.criu_args() {
+            if arg.starts_with("fd[") {
+                script.push_str(&format!(" \\\n    \"{}\"", arg));
+            } else {
+                script.push_str(&format!(" \\\n    {}", arg));
+            }
+        }
+        script.push_str(" \\\n    && echo 'OK'\n");
+        script
+    }
+
+    /// `criu restore` for the runs FitM restores itself (`restore_with_input`, `create_next_snapshot`),
+    /// run in `active_dir` with `input` and `envs`. Does what `script` does outside of afl-fuzz: writes
+    /// `ENVFILE`, empties `out/.cur_input`, puts `reopen_fds` and dummy forkserver fds in place, moves PIDs
+    /// out of the usual range and starts criu in a session of its own.
+    /// afl-fuzz and afl-cmin still run the script, as they exec a single program with the input path.
+    pub fn command(
+        &self,
+        active_dir: &Path,
+        input: &Path,
+        envs: &[(&str, &OsStr)],
+    ) -> io::Result<Command> {
+        let mut vars: BTreeMap<OsString, OsString> = env::vars_os().collect();
+        for (key, val) in envs {
+            vars.insert(key.into(), val.into());
+        }
+        vars.insert("INPUT_FILENAME".into(), fs::canonicalize(input)?.into());
+        vars.insert("PWD".into(), fs::canonicalize(active_dir)?.into());
+        let mut envfile = vec![];
+        for (key, val) in &vars {
+            envfile.extend_from_slice(key.as_bytes());
+            envfile.push(b'=');
+            envfile.extend_from_slice(val.as_bytes());
+            envfile.push(b'\n');
+        }
+        fs::write(active_dir.join(ENVFILE), envfile)?;
+        // Like the script, a state without an out folder is restored anyway
+        let _ = fs::write(active_dir.join("out/.cur_input"), "");
+
+        let images_dir = vars
+            .get(OsStr::new("CRIU_SNAPSHOT_DIR"))
+            .map_or("./snapshot".into(), |dir| {
+                dir.to_string_lossy().into_owned()
+            });
+        let plan = RestorePlan {
+            images_dir,
+            ..self.clone()
+        };
+
+        let dev_null = |fd, append| ReopenFd {
+            fd,
+            path: "/dev/null".to_string(),
+            append,
+        };
+        let mut reopen_fds = self.reopen_fds.clone();
+        reopen_fds.push(dev_null(FORKSRV_FD, false));
+        reopen_fds.push(dev_null(FORKSRV_FD + 1, true));
+        // Opened above all target fds, so no dup2 below overwrites a file before it is in place
+        let floor = reopen_fds.iter().map(|reopen| reopen.fd).max().unwrap_or(0) as i32 + 1;
+        let mut fds = vec![];
+        for reopen in &reopen_fds {
+            let file = OpenOptions::new()
+                .read(!reopen.append)
+                .write(true)
+                .append(reopen.append)
+                .create(true)
+                .open(active_dir.join(&reopen.path))
+                .map_err(|e| {
+                    io::Error::new(e.kind(), format!("reopening {:?}: {}", reopen.path, e))
+                })?;
+            let high = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_DUPFD_CLOEXEC, floor) };
+            if high < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            fds.push((unsafe { OwnedFd::from_raw_fd(high) }, reopen.fd as i32));
+        }
+
+        if let Err(e) = fs::write("/proc/sys/kernel/ns_last_pid", LAST_PID.to_string()) {
+            println!("[!] Could not move PIDs out of the usual range: {}", e);
+        }
+
+        let mut command = Command::new(&self.criu_bin);
+        command
+            .args(plan.criu_args())
+            .current_dir(active_dir)
+            .envs(vars);
+        unsafe {
+            command.pre_exec(move || {
+                if libc::setsid() < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                for (file, fd) in &fds {
+                    if libc::dup2(file.as_raw_fd(), *fd) < 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            });
+        }
+        Ok(command)
+    }
+
+    /// Writes the restore script to `path` and makes it executable
+    pub fn write_script(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.script())?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::criu_images::tests::{fdinfo_entry, reg_file_entry, write_image};
+    use crate::criu_images::{FDINFO_MAGIC, FILES_MAGIC};
+    use std::path::PathBuf;
+
+    /// Creates a fake state dir with a snapshot holding fd 3 -> fd/0 and fd 0 -> /dev/null
+    fn fixture(root: &Path) -> PathBuf {
+        let _ = fs::remove_dir_all(root);
+        let snapshot = root.join("snapshot");
+        fs::create_dir_all(&snapshot).unwrap();
+        write_image(
+            &snapshot.join("files.img"),
+            FILES_MAGIC,
+            &[
+                reg_file_entry(1, "/dev/null"),
+                reg_file_entry(2, "/work/active-state/fd/0"),
+            ],
+        );
+        write_image(
+            &snapshot.join("fdinfo-2.img"),
+            FDINFO_MAGIC,
+            &[fdinfo_entry(1, 0), fdinfo_entry(2, 3)],
+        );
+        fs::write(
+            root.join("pipes"),
+            "lrwx------ 198 -> pipe:[1337]\nl-wx------ 199 -> pipe:[1338]\n",
+        )
+        .unwrap();
+        snapshot
+    }
+
+    #[test]
+    fn test_fd_mappings() {
+        let root = Path::new("/tmp/fitm_restore_unittest_mappings");
+        let snapshot = fixture(root);
+        assert_eq!(
+            fd_mappings(&snapshot).unwrap(),
+            vec![FdMapping {
+                fd: 3,
+                path: "/work/active-state/fd/0".to_string()
+            }]
+        );
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_restore_plan() {
+        let root = Path::new("/tmp/fitm_restore_unittest_plan");
+        let snapshot = fixture(root);
+
+        let initial = RestorePlan::build(root, None).unwrap();
+        assert!(initial.reopen_fds.is_empty());
+        assert_eq!(
+            initial.criu_args()[7..],
+            [
+                "--inherit-fd",
+                "fd[198]:pipe:[1337]",
+                "--inherit-fd",
+                "fd[199]:pipe:[1338]"
+            ]
+        );
+
+        let plan = RestorePlan::build(root, Some(&snapshot)).unwrap();
+        let keys: Vec<String> = plan.inherit_fds.iter().map(|i| i.arg()).collect();
+        assert_eq!(
+            keys,
+            vec![
+                "fd[198]:pipe:[1337]",
+                "fd[199]:pipe:[1338]",
+                "fd[1]:tmp/fitm_restore_unittest_plan/stdout",
+                "fd[2]:tmp/fitm_restore_unittest_plan/stderr",
+                "fd[3]:work/active-state/fd/0",
+            ]
+        );
+        let script = plan.script();
+        assert!(script.contains("exec 3<> /work/active-state/fd/0\n"));
+        assert!(script.contains("exec 1>> /tmp/fitm_restore_unittest_plan/stdout\n"));
+        assert!(script.ends_with("&& echo 'OK'\n"));
+
+        // Without forkserver pipes afl-fuzz could never talk to the restored target
+        fs::remove_file(root.join("pipes")).unwrap();
+        assert!(RestorePlan::build(root, None).is_err());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_restore_command() {
+        let root = Path::new("/tmp/fitm_restore_unittest_command");
+        let _ = fs::remove_dir_all(root);
+        fs::create_dir_all(root.join("out")).unwrap();
+        fs::write(root.join("out/.cur_input"), "old input").unwrap();
+        fs::write(root.join("input"), "new input").unwrap();
+        fs::write(root.join("fd0"), "").unwrap();
+        // Stands in for criu, records what it got
+        let fake_criu = root.join("fake-criu");
+        fs::write(
+            &fake_criu,
+            "#!/bin/sh\necho \"$@\" > args\nreadlink /proc/$$/fd/3 /proc/$$/fd/198 > fds\n",
+        )
+        .unwrap();
+        fs::set_permissions(&fake_criu, fs::Permissions::from_mode(0o755)).unwrap();
+        let plan = RestorePlan {
+            criu_bin: fake_criu.to_string_lossy().into_owned(),
+            reopen_fds: vec![ReopenFd {
+                fd: 3,
+                path: "fd0".to_string(),
+                append: false,
+            }],
+            inherit_fds: vec![InheritFd {
+                fd: 3,
+                key: "fd0".to_string(),
+            }],
+            ..Default::default()
+        };
+
+        let status = plan
+            .command(
+                root,
+                &root.join("input"),
+                &[("CRIU_SNAPSHOT_DIR", OsStr::new("/snapshots/next"))],
+            )
+            .unwrap()
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let args = fs::read_to_string(root.join("args")).unwrap();
+        assert!(args.starts_with("restore -d -vvv -o ../restore.log --images-dir /snapshots/next"));
+        assert!(args.ends_with("--inherit-fd fd[3]:fd0\n"));
+        let fds = fs::read_to_string(root.join("fds")).unwrap();
+        assert_eq!(fds, "/tmp/fitm_restore_unittest_command/fd0\n/dev/null\n");
+        let envfile = fs::read_to_string(root.join(ENVFILE)).unwrap();
+        assert!(envfile.contains("INPUT_FILENAME=/tmp/fitm_restore_unittest_command/input\n"));
+        assert!(envfile.contains("CRIU_SNAPSHOT_DIR=/snapshots/next\n"));
+        assert_eq!(fs::read(root.join("out/.cur_input")).unwrap(), b"");
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,437 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::history::FuzzHistory;
+use crate::utils::{fuzzer_stat, pick_snapshots_weighted, pick_weighted, RomuRand};
+use crate::workspace::Workspace;
+use crate::{FITMSnapshot, ABORT_THRESHOLD, SKIP_STEP_THRESHOLD};
+
+/// How many snapshots of a generation we fuzz per stage, unless `snapshots_per_gen` is configured
+pub const SNAPSHOTS_PER_STAGE: usize = 5;
+/// Energy of snapshots the power schedule has not seen fuzzed yet, so new snapshots get a chance early on
+pub const FRESH_ENERGY: f64 = 8.0;
+/// How much of a snapshot's novelty is kept after each of its runs
+pub const NOVELTY_DECAY: f64 = 0.5;
+/// A level of depth is worth this many new paths
+pub const DEPTH_WEIGHT: f64 = 4.0;
+/// The power schedule never aborts or skips with a higher probability than this
+pub const MAX_SKIP_PROBABILITY: f64 = 0.75;
+
+/// Decides which generation, and which of its snapshots, to fuzz next
+pub trait Scheduler {
+    /// Picks the generation to fuzz after `last_gen`. Returning gen 1 starts a new round.
+    fn next_gen(
+        &mut self,
+        rand: &mut RomuRand,
+        generation_snaps: &[Vec<FITMSnapshot>],
+        last_gen: usize,
+    ) -> usize;
+
+    /// Whether to skip `gen` in this `round`
+    fn skip_gen(
+        &mut self,
+        rand: &mut RomuRand,
+        generation_snaps: &[Vec<FITMSnapshot>],
+        gen: usize,
+        round: usize,
+    ) -> bool;
+
+    /// The snapshots of one generation to fuzz in this stage
+    fn pick_snapshots(
+        &mut self,
+        rand: &mut RomuRand,
+        snaps: &[FITMSnapshot],
+        history: &BTreeMap<String, FuzzHistory>,
+    ) -> Vec<FITMSnapshot>;
+
+    /// Called after `snap` was fuzzed and its results were saved
+    fn fuzzed(&mut self, _snap: &FITMSnapshot) {}
+}
+
+/// The `scheduler` config key
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SchedulerKind {
+    /// Walk the generations in order, randomly restart or skip, see `ThresholdScheduler`
+    #[default]
+    Threshold,
+    /// Favour what recently found new coverage, see `PowerScheduler`
+    Power,
+}
+
+impl SchedulerKind {
+    /// A scheduler fuzzing up to `budget` snapshots per generation of `workspace`
+    pub fn build(self, budget: usize, workspace: &Workspace) -> Box<dyn Scheduler> {
+        let saved_states = workspace.saved_states.clone();
+        match self {
+            SchedulerKind::Threshold => Box::new(ThresholdScheduler {
+                budget,
+                saved_states,
+            }),
+            SchedulerKind::Power => Box::new(PowerScheduler {
+                budget,
+                saved_states,
+                ..Default::default()
+            }),
+        }
+    }
+}
+
+/// A random number in [0, 1)
+fn chance(rand: &mut RomuRand) -> f64 {
+    rand.below(1000) as f64 / 1000.0
+}
+
+/// The gen after `last_gen`, or gen 1 if there are no snapshots for it (yet)
+fn following_gen(generation_snaps: &[Vec<FITMSnapshot>], last_gen: usize) -> usize {
+    let gen = last_gen + 1;
+    if generation_snaps.get(gen).is_none_or(Vec::is_empty) {
+        println!(
+            "No snapshots (yet) for gen {}, restarting with gen 1 (initial request)",
+            gen
+        );
+        // Restart with gen 1 -> the client at gen 0 does not accept input.
+        1
+    } else {
+        gen
+    }
+}
+
+/// The original FitM schedule: go one generation deeper each step.
+/// Occasionally (`ABORT_THRESHOLD`) restart at gen 1, occasionally (`SKIP_STEP_THRESHOLD`) skip a gen.
+/// Snapshots are picked by `pick_snapshots_weighted`.
+#[derive(Clone, Debug)]
+pub struct ThresholdScheduler {
+    /// Max snapshots per generation
+    pub budget: usize,
+    /// Where the snapshot maps and `fuzzer_stats` the weights are based on are read from
+    pub saved_states: PathBuf,
+}
+
+impl Default for ThresholdScheduler {
+    fn default() -> Self {
+        ThresholdScheduler {
+            budget: SNAPSHOTS_PER_STAGE,
+            saved_states: Workspace::default().saved_states,
+        }
+    }
+}
+
+impl Scheduler for ThresholdScheduler {
+    fn next_gen(
+        &mut self,
+        rand: &mut RomuRand,
+        generation_snaps: &[Vec<FITMSnapshot>],
+        last_gen: usize,
+    ) -> usize {
+        let gen = following_gen(generation_snaps, last_gen);
+        // occasionally, stop going deeper, and go back to 0
+        if gen != 1 && chance(rand) > ABORT_THRESHOLD {
+            println!(
+                "Restarting fuzzing from gen 1 because of randomness (threshold {})",
+                ABORT_THRESHOLD
+            );
+            return 1;
+        }
+        gen
+    }
+
+    fn skip_gen(
+        &mut self,
+        rand: &mut RomuRand,
+        _generation_snaps: &[Vec<FITMSnapshot>],
+        gen: usize,
+        round: usize,
+    ) -> bool {
+        // occasionally, skip a step (unless we're in the first run)
+        if round != 1 && chance(rand) > SKIP_STEP_THRESHOLD {
+            println!(
+                "Skiping gen {} by random chance (threshold {})",
+                gen, SKIP_STEP_THRESHOLD
+            );
+            return true;
+        }
+        false
+    }
+
+    fn pick_snapshots(
+        &mut self,
+        rand: &mut RomuRand,
+        snaps: &[FITMSnapshot],
+        history: &BTreeMap<String, FuzzHistory>,
+    ) -> Vec<FITMSnapshot> {
+        pick_snapshots_weighted(rand, &self.saved_states, snaps, history, self.budget)
+    }
+}
+
+/// Coverage of the last AFL run of a snapshot, from its `fuzzer_stats`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Coverage {
+    pub paths_total: u64,
+    pub max_depth: u64,
+}
+
+impl Coverage {
+    pub fn read(saved_states: &Path, state_path: &str) -> Option<Self> {
+        let stat = |key| fuzzer_stat(saved_states, state_path, key)?.parse().ok();
+        Some(Coverage {
+            // Newer AFL++ versions call it corpus_count
+            paths_total: stat("paths_total").or_else(|| stat("corpus_count"))?,
+            max_depth: stat("max_depth").unwrap_or(0),
+        })
+    }
+}
+
+/// What the power schedule knows about one snapshot
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Power {
+    last: Coverage,
+    /// Decaying sum of new paths (and depth) found in the last runs
+    novelty: f64,
+    /// Runs in a row without new paths or depth
+    stale: u32,
+}
+
+impl Power {
+    fn energy(&self) -> f64 {
+        (1.0 + self.novelty) / (1 + self.stale) as f64
+    }
+}
+
+/// A power schedule: snapshots that recently found new paths (`paths_total`) or got deeper (`max_depth`)
+/// get more energy, stale ones less. Snapshots are picked with a probability proportional to their energy.
+/// Generations with less energy than average are restarted from or skipped more often, productive ones less.
+#[derive(Clone, Debug)]
+pub struct PowerScheduler {
+    /// Max snapshots per generation
+    pub budget: usize,
+    /// Where the `fuzzer_stats` of fuzzed snapshots are read from
+    pub saved_states: PathBuf,
+    snaps: BTreeMap<String, Power>,
+}
+
+impl Default for PowerScheduler {
+    fn default() -> Self {
+        PowerScheduler {
+            budget: SNAPSHOTS_PER_STAGE,
+            saved_states: Workspace::default().saved_states,
+            snaps: BTreeMap::new(),
+        }
+    }
+}
+
+impl PowerScheduler {
+    fn energy(&self, snap: &FITMSnapshot) -> f64 {
+        self.snaps
+            .get(&snap.state_path)
+            .map_or(FRESH_ENERGY, Power::energy)
+    }
+
+    fn gen_energy(&self, snaps: &[FITMSnapshot]) -> f64 {
+        snaps.iter().map(|snap| self.energy(snap)).sum::<f64>() / snaps.len().max(1) as f64
+    }
+
+    /// Energy of `gen` relative to the average of all (non-empty) gens
+    fn relative_energy(&self, generation_snaps: &[Vec<FITMSnapshot>], gen: usize) -> f64 {
+        let energies: Vec<f64> = generation_snaps
+            .iter()
+            .filter(|snaps| !snaps.is_empty())
+            .map(|snaps| self.gen_energy(snaps))
+            .collect();
+        let average = energies.iter().sum::<f64>() / energies.len().max(1) as f64;
+        if average <= 0.0 {
+            return 1.0;
+        }
+        generation_snaps
+            .get(gen)
+            .map_or(1.0, |snaps| self.gen_energy(snaps) / average)
+    }
+
+    /// The threshold's base probability, scaled down for productive gens and up for stale ones
+    fn probability(threshold: f64, relative_energy: f64) -> f64 {
+        ((1.0 - threshold) / relative_energy.max(f64::EPSILON)).min(MAX_SKIP_PROBABILITY)
+    }
+}
+
+impl Scheduler for PowerScheduler {
+    fn next_gen(
+        &mut self,
+        rand: &mut RomuRand,
+        generation_snaps: &[Vec<FITMSnapshot>],
+        last_gen: usize,
+    ) -> usize {
+        let gen = following_gen(generation_snaps, last_gen);
+        if gen == 1 {
+            return gen;
+        }
+        let relative = self.relative_energy(generation_snaps, gen);
+        let probability = Self::probability(ABORT_THRESHOLD, relative);
+        if chance(rand) < probability {
+            println!(
+                "Restarting fuzzing from gen 1, gen {} has {:.2}x the average energy (p={:.3})",
+                gen, relative, probability
+            );
+            return 1;
+        }
+        gen
+    }
+
+    fn skip_gen(
+        &mut self,
+        rand: &mut RomuRand,
+        generation_snaps: &[Vec<FITMSnapshot>],
+        gen: usize,
+        round: usize,
+    ) -> bool {
+        if round == 1 {
+            return false;
+        }
+        let relative = self.relative_energy(generation_snaps, gen);
+        let probability = Self::probability(SKIP_STEP_THRESHOLD, relative);
+        if chance(rand) < probability {
+            println!(
+                "Skiping gen {}, it has {:.2}x the average energy (p={:.3})",
+                gen, relative, probability
+            );
+            return true;
+        }
+        false
+    }
+
+    fn pick_snapshots(
+        &mut self,
+        rand: &mut RomuRand,
+        snaps: &[FITMSnapshot],
+        _history: &BTreeMap<String, FuzzHistory>,
+    ) -> Vec<FITMSnapshot> {
+        let energies: Vec<f64> = snaps.iter().map(|snap| self.energy(snap)).collect();
+        let total: f64 = energies.iter().sum();
+        let picked = pick_weighted(rand, &energies, self.budget);
+        println!(
+            "[*] Picked {} of {} snapshots (budget {})",
+            picked.len(),
+            snaps.len(),
+            self.budget
+        );
+        for &idx in &picked {
+            println!(
+                "    {:<24} energy {:.3} (share {:.1}%)",
+                snaps[idx].state_path,
+                energies[idx],
+                100.0 * energies[idx] / total
+            );
+        }
+        picked.into_iter().map(|idx| snaps[idx].clone()).collect()
+    }
+
+    fn fuzzed(&mut self, snap: &FITMSnapshot) {
+        let coverage = match Coverage::read(&self.saved_states, &snap.state_path) {
+            Some(coverage) => coverage,
+            None => return,
+        };
+        let power = match self.snaps.get_mut(&snap.state_path) {
+            Some(power) => power,
+            None => {
+                // Nothing to compare the first run with, start out as average
+                self.snaps.insert(
+                    snap.state_path.clone(),
+                    Power {
+                        last: coverage,
+                        novelty: 1.0,
+                        stale: 0,
+                    },
+                );
+                return;
+            }
+        };
+        let new_paths = coverage.paths_total.saturating_sub(power.last.paths_total);
+        let new_depth = coverage.max_depth.saturating_sub(power.last.max_depth);
+        let gain = new_paths as f64 + DEPTH_WEIGHT * new_depth as f64;
+        power.novelty = power.novelty * NOVELTY_DECAY + gain;
+        power.stale = if gain > 0.0 { 0 } else { power.stale + 1 };
+        power.last = coverage;
+        println!(
+            "[*] {}: {} new paths, {} deeper, energy now {:.2}",
+            snap.state_path,
+            new_paths,
+            new_depth,
+            power.energy()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_snapshot;
+
+    #[test]
+    fn test_threshold_scheduler() {
+        let gens = vec![
+            vec![],
+            vec![test_snapshot(1, 0)],
+            vec![test_snapshot(2, 0)],
+            vec![],
+        ];
+        let mut scheduler = ThresholdScheduler::default();
+        let mut rand = RomuRand::new(1);
+        assert_eq!(scheduler.next_gen(&mut rand, &gens, 0), 1);
+        assert_eq!(scheduler.next_gen(&mut rand, &gens, 2), 1);
+        assert_eq!(scheduler.next_gen(&mut rand, &gens, 7), 1);
+        // Never skip in the first round
+        assert!(!(0..100).any(|_| scheduler.skip_gen(&mut rand, &gens, 2, 1)));
+        assert!((0..1000).any(|_| scheduler.skip_gen(&mut rand, &gens, 2, 2)));
+    }
+
+    #[test]
+    fn test_power_schedule() {
+        let snaps: Vec<FITMSnapshot> = (0..10).map(|id| test_snapshot(3, id)).collect();
+        let mut scheduler = PowerScheduler::default();
+        let power = |novelty, stale| Power {
+            last: Coverage::default(),
+            novelty,
+            stale,
+        };
+        for snap in &snaps[1..] {
+            scheduler
+                .snaps
+                .insert(snap.state_path.clone(), power(0.0, 10));
+        }
+        scheduler
+            .snaps
+            .insert(snaps[0].state_path.clone(), power(100.0, 0));
+
+        // The productive snapshot is picked almost always, the stale ones rarely
+        let mut rand = RomuRand::new(1337);
+        let mut picked = BTreeMap::new();
+        for _ in 0..100 {
+            let picks = scheduler.pick_snapshots(&mut rand, &snaps, &BTreeMap::new());
+            assert_eq!(picks.len(), SNAPSHOTS_PER_STAGE);
+            for pick in picks {
+                *picked.entry(pick.state_id).or_insert(0) += 1;
+            }
+        }
+        assert!(picked[&0] > 95, "{:?}", picked);
+
+        // Stale gens are skipped more often than fresh ones
+        let gens = vec![
+            vec![],
+            vec![test_snapshot(1, 0)],
+            vec![],
+            snaps[1..].to_vec(),
+        ];
+        assert!(scheduler.relative_energy(&gens, 3) < 1.0);
+        assert!(scheduler.relative_energy(&gens, 1) > 1.0);
+        let skips = |gen| {
+            let mut rand = RomuRand::new(1);
+            let mut scheduler = scheduler.clone();
+            (0..1000)
+                .filter(|_| scheduler.skip_gen(&mut rand, &gens, gen, 2))
+                .count()
+        };
+        assert!(skips(3) > skips(1));
+        assert!(PowerScheduler::probability(SKIP_STEP_THRESHOLD, 0.0) <= MAX_SKIP_PROBABILITY);
+    }
+}
@@ -0,0 +1,346 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, ErrorKind, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::history::FuzzHistory;
+use crate::utils::RomuRand;
+use crate::{FITMSnapshot, FITM_STATE};
+
+/// Version of the `fitm-state.json` format.
+/// Version 0 was a bare `generation_snaps` array, version 1 had no scheduler state,
+/// version 2 only had `fuzz_counts` instead of the full `history`.
+pub const STATE_VERSION: u32 = 3;
+
+/// How many old states we keep next to `fitm-state.json`
+pub const STATE_BACKUPS: usize = 5;
+
+/// Everything we need to resume a campaign, serialized to `fitm-state.json`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CampaignState {
+    pub version: u32,
+    /// The seed the campaign was started with, `None` for old state files
+    pub seed: Option<u64>,
+    /// A list of generations, each generation being a list of snapshots
+    pub generation_snaps: Vec<Vec<FITMSnapshot>>,
+    /// The last generation that was processed, we continue with the next one
+    #[serde(default)]
+    pub current_gen: usize,
+    /// Number of times we wrapped around to gen 1
+    #[serde(default)]
+    pub round: usize,
+    /// Number of generations fuzzed (`process_stage` calls) over all runs
+    #[serde(default)]
+    pub stages: u64,
+    /// RNG state after the last processed generation, `None` to start from `seed`
+    #[serde(default)]
+    pub rng: Option<RomuRand>,
+    /// Fuzzing history of each snapshot (by `state_path`)
+    #[serde(default)]
+    pub history: BTreeMap<String, FuzzHistory>,
+    /// Version 2 only counted the runs per snapshot, moved to `history` on load
+    #[serde(default, skip_serializing)]
+    fuzz_counts: BTreeMap<String, u64>,
+    /// Time spent fuzzing over all (resumed) runs
+    #[serde(default)]
+    pub elapsed: Duration,
+}
+
+/// On disk, we also understand the old, bare format
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StateFile {
+    Current(CampaignState),
+    Legacy(Vec<Vec<FITMSnapshot>>),
+}
+
+impl CampaignState {
+    pub fn new(seed: u64, generation_snaps: Vec<Vec<FITMSnapshot>>) -> Self {
+        CampaignState {
+            version: STATE_VERSION,
+            seed: Some(seed),
+            generation_snaps,
+            current_gen: 0,
+            round: 0,
+            stages: 0,
+            rng: None,
+            history: BTreeMap::new(),
+            fuzz_counts: BTreeMap::new(),
+            elapsed: Duration::ZERO,
+        }
+    }
+
+    /// Parses a `fitm-state.json`, in the current or the legacy format
+    pub fn from_json(json: &str) -> io::Result<Self> {
+        match serde_json::from_str(json)? {
+            StateFile::Current(state) if state.version > STATE_VERSION => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "{} has version {}, we only understand up to {}",
+                    FITM_STATE, state.version, STATE_VERSION
+                ),
+            )),
+            StateFile::Current(mut state) => {
+                // Older versions are upgraded through the serde defaults
+                state.version = STATE_VERSION;
+                for (state_path, runs) in std::mem::take(&mut state.fuzz_counts) {
+                    state.history.entry(state_path).or_default().runs = runs;
+                }
+                Ok(state)
+            }
+            StateFile::Legacy(generation_snaps) => {
+                println!(
+                    "[*] {} is in the old format, it will be upgraded on the next save",
+                    FITM_STATE
+                );
+                Ok(CampaignState {
+                    seed: None,
+                    ..CampaignState::new(0, generation_snaps)
+                })
+            }
+        }
+    }
+
+    /// Loads `fitm-state.json` from the current dir, see `load_from`
+    pub fn load() -> io::Result<Self> {
+        Self::load_from(Path::new(FITM_STATE))
+    }
+
+    /// Loads the state at `path`. If it is corrupt, we fall back to the newest backup that isn't.
+    pub fn load_from(path: &Path) -> io::Result<Self> {
+        let err = match fs::read_to_string(path).and_then(|json| Self::from_json(&json)) {
+            Ok(state) => return Ok(state),
+            Err(e) if e.kind() == ErrorKind::NotFound => return Err(e),
+            Err(e) => e,
+        };
+        println!("[!] Could not load {:?} ({}), trying backups", path, err);
+        for backup in backup_paths(path) {
+            match fs::read_to_string(&backup).and_then(|json| Self::from_json(&json)) {
+                Ok(state) => {
+                    println!("[*] Restored state from backup {:?}", backup);
+                    return Ok(state);
+                }
+                Err(e) if e.kind() == ErrorKind::NotFound => break,
+                Err(e) => println!("[!] Backup {:?} is broken too ({})", backup, e),
+            }
+        }
+        Err(err)
+    }
+
+    /// Writes `fitm-state.json` to the current dir, see `save_to`
+    pub fn save(&self) -> io::Result<()> {
+        self.save_to(Path::new(FITM_STATE))
+    }
+
+    /// Atomically replaces the state at `path`: we write to a tmp file and rename it over the old state.
+    /// The previous `STATE_BACKUPS` states are kept as `<path>.1` (newest) to `<path>.N`.
+    pub fn save_to(&self, path: &Path) -> io::Result<()> {
+        let tmp = with_suffix(path, "tmp");
+        {
+            let mut file = File::create(&tmp)?;
+            file.write_all(serde_json::to_string(self)?.as_bytes())?;
+            file.sync_all()?;
+        }
+
+        if path.exists() {
+            let backups = backup_paths(path);
+            for i in (1..backups.len()).rev() {
+                match fs::rename(&backups[i - 1], &backups[i]) {
+                    Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                    _ => (),
+                }
+            }
+            // Link instead of move, so there is a valid state at `path` at all times
+            let _ = fs::remove_file(&backups[0]);
+            if fs::hard_link(path, &backups[0]).is_err() {
+                fs::copy(path, &backups[0])?;
+            }
+        }
+
+        fs::rename(&tmp, path)?;
+        // Make sure the rename itself hits the disk
+        let dir = match path.parent() {
+            Some(parent) if parent != Path::new("") => parent,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()
+    }
+
+    /// Whether a listed snapshot was created from `state_path`, see `FITMSnapshot::base_state`.
+    /// Restoring it reads the fd mappings of its base, so the base has to stay.
+    pub fn is_base(&self, state_path: &str) -> bool {
+        self.generation_snaps
+            .iter()
+            .flatten()
+            .any(|snap| snap.base_state == state_path)
+    }
+
+    /// The listed snapshots created from `state_path`, the ones created from them and so on.
+    /// None of them can be restored once `state_path` is gone.
+    pub fn descendants(&self, state_path: &str) -> Vec<String> {
+        let mut descendants: Vec<String> = vec![];
+        let mut bases = vec![state_path.to_string()];
+        while let Some(base) = bases.pop() {
+            for snap in self.generation_snaps.iter().flatten() {
+                if snap.base_state == base && !descendants.contains(&snap.state_path) {
+                    descendants.push(snap.state_path.clone());
+                    bases.push(snap.state_path.clone());
+                }
+            }
+        }
+        descendants
+    }
+
+    /// Removes all snapshots without a folder in `saved_states`.
+    /// Returns the `state_path`s of the dropped snapshots.
+    pub fn drop_missing(&mut self, saved_states: &Path) -> Vec<String> {
+        let mut dropped = vec![];
+        for snaps in self.generation_snaps.iter_mut() {
+            snaps.retain(|snap| {
+                let exists = saved_states.join(&snap.state_path).is_dir();
+                if !exists {
+                    dropped.push(snap.state_path.clone());
+                }
+                exists
+            });
+        }
+        for state_path in &dropped {
+            self.history.remove(state_path);
+        }
+        dropped
+    }
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/// `<path>.1` to `<path>.STATE_BACKUPS`, newest first
+pub fn backup_paths(path: &Path) -> Vec<PathBuf> {
+    (1..=STATE_BACKUPS)
+        .map(|i| with_suffix(path, &i.to_string()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_snapshot;
+
+    #[test]
+    fn test_load_legacy_and_current() {
+        let legacy = CampaignState::from_json("[[], [], []]").unwrap();
+        assert_eq!(legacy.seed, None);
+        assert_eq!(legacy.generation_snaps.len(), 3);
+
+        let state = CampaignState::new(1337, vec![vec![]]);
+        let json = serde_json::to_string(&state).unwrap();
+        let loaded = CampaignState::from_json(&json).unwrap();
+        assert_eq!(loaded.version, STATE_VERSION);
+        assert_eq!(loaded.seed, Some(1337));
+
+        let newer = json.replace("\"version\":3", "\"version\":99");
+        assert!(CampaignState::from_json(&newer).is_err());
+        assert!(CampaignState::from_json("{}").is_err());
+    }
+
+    #[test]
+    fn test_scheduler_state_roundtrip() {
+        // Version 1 files don't have any scheduler state yet
+        let v1 = CampaignState::from_json(r#"{"version": 1, "seed": 3, "generation_snaps": []}"#)
+            .unwrap();
+        assert_eq!((v1.current_gen, v1.round, v1.rng), (0, 0, None));
+        assert_eq!(v1.version, STATE_VERSION);
+
+        // Version 2 only counted runs
+        let v2 = CampaignState::from_json(
+            r#"{"version": 2, "seed": 3, "generation_snaps": [], "fuzz_counts": {"fitm-gen1-state0": 4}}"#,
+        )
+        .unwrap();
+        assert_eq!(v2.history["fitm-gen1-state0"].runs, 4);
+        assert!(!serde_json::to_string(&v2).unwrap().contains("fuzz_counts"));
+
+        let mut rand = RomuRand::new(42);
+        rand.below(100);
+        let mut state = CampaignState::new(42, vec![]);
+        state.current_gen = 3;
+        state.round = 7;
+        state.rng = Some(rand);
+        state
+            .history
+            .entry("fitm-gen1-state0".to_string())
+            .or_default()
+            .add_run(Duration::from_secs(60), 100, 2, 0, 0);
+        state.elapsed = Duration::from_secs(1234);
+
+        let loaded = CampaignState::from_json(&serde_json::to_string(&state).unwrap()).unwrap();
+        assert_eq!(loaded.current_gen, 3);
+        assert_eq!(loaded.round, 7);
+        assert_eq!(
+            loaded.history["fitm-gen1-state0"],
+            state.history["fitm-gen1-state0"]
+        );
+        assert_eq!(loaded.elapsed, Duration::from_secs(1234));
+        // The restored rng continues with the exact same sequence
+        assert_eq!(loaded.rng.unwrap().below(1 << 30), rand.below(1 << 30));
+    }
+
+    #[test]
+    fn test_atomic_save_and_backups() {
+        let root = Path::new("/tmp/fitm_state_unittest");
+        let _ = fs::remove_dir_all(root);
+        fs::create_dir_all(root).unwrap();
+        let path = root.join("fitm-state.json");
+
+        for round in 0..(STATE_BACKUPS + 2) {
+            let mut state = CampaignState::new(1, vec![]);
+            state.round = round;
+            state.save_to(&path).unwrap();
+        }
+        assert!(!with_suffix(&path, "tmp").exists());
+        assert!(!with_suffix(&path, &(STATE_BACKUPS + 1).to_string()).exists());
+        let newest_backup = fs::read_to_string(&backup_paths(&path)[0]).unwrap();
+        assert_eq!(
+            CampaignState::from_json(&newest_backup).unwrap().round,
+            STATE_BACKUPS
+        );
+
+        // A truncated state falls back to the newest backup
+        fs::write(&path, "{\"version\": 2, \"gener").unwrap();
+        assert_eq!(
+            CampaignState::load_from(&path).unwrap().round,
+            STATE_BACKUPS
+        );
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_drop_missing() {
+        let root = Path::new("/tmp/fitm_state_unittest_missing");
+        let _ = fs::remove_dir_all(root);
+        fs::create_dir_all(root.join("fitm-gen1-state0")).unwrap();
+
+        let mut state = CampaignState::new(
+            1,
+            vec![vec![], vec![test_snapshot(1, 0), test_snapshot(1, 1)]],
+        );
+        state
+            .history
+            .insert("fitm-gen1-state1".to_string(), Default::default());
+
+        assert_eq!(state.drop_missing(root), vec!["fitm-gen1-state1"]);
+        assert_eq!(state.generation_snaps[1].len(), 1);
+        assert!(state.history.is_empty());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
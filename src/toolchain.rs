@@ -0,0 +1,200 @@
+use std::{
+    env,
+    ffi::OsString,
+    fs,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::ConfigError;
+
+/// Tool paths as given in the `tools` section of the config, all optional
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ToolPaths {
+    pub afl_fuzz: Option<String>,
+    pub afl_cmin: Option<String>,
+    pub qemu_trace: Option<String>,
+    pub criu: Option<String>,
+}
+
+/// Absolute paths to all external tools FitM runs.
+/// Every call site uses these, so FitM works from any working dir and with system-installed tools.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Toolchain {
+    pub afl_fuzz: PathBuf,
+    pub afl_cmin: PathBuf,
+    pub qemu_trace: PathBuf,
+    pub criu: PathBuf,
+}
+
+/// How to find one tool: its config key, env var, location in a FitM checkout and name in `$PATH`
+struct ToolSpec {
+    key: &'static str,
+    env: &'static str,
+    local: &'static str,
+    name: &'static str,
+}
+
+const AFL_FUZZ: ToolSpec = ToolSpec {
+    key: "afl_fuzz",
+    env: "FITM_AFL_FUZZ",
+    local: "AFLplusplus/afl-fuzz",
+    name: "afl-fuzz",
+};
+const AFL_CMIN: ToolSpec = ToolSpec {
+    key: "afl_cmin",
+    env: "FITM_AFL_CMIN",
+    local: "AFLplusplus/afl-cmin",
+    name: "afl-cmin",
+};
+const QEMU_TRACE: ToolSpec = ToolSpec {
+    key: "qemu_trace",
+    env: "FITM_QEMU_TRACE",
+    local: "fitm-qemu-trace",
+    name: "fitm-qemu-trace",
+};
+const CRIU: ToolSpec = ToolSpec {
+    key: "criu",
+    env: "FITM_CRIU",
+    local: "criu/criu/criu",
+    name: "criu",
+};
+
+fn is_executable(path: &Path) -> bool {
+    fs::metadata(path)
+        .map(|meta| meta.is_file() && meta.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+/// Looks up `name` in the given `$PATH`
+fn find_in_path(name: &str, path_var: Option<OsString>) -> Option<PathBuf> {
+    env::split_paths(&path_var?)
+        .map(|dir| dir.join(name))
+        .find(|candidate| is_executable(candidate))
+}
+
+impl ToolSpec {
+    /// Config beats env beats the FitM checkout beats `$PATH`.
+    /// An explicitly configured tool has to exist, we don't silently fall back.
+    fn resolve(&self, configured: Option<&str>, workdir: &Path) -> Result<PathBuf, String> {
+        let explicit = match configured {
+            Some(path) => Some((format!("`tools.{}`", self.key), PathBuf::from(path))),
+            None => env::var_os(self.env).map(|path| (format!("${}", self.env), path.into())),
+        };
+        let found = match explicit {
+            Some((source, path)) => {
+                let path = workdir.join(path);
+                if !is_executable(&path) {
+                    return Err(format!("{} {:?} is not an executable file", source, path));
+                }
+                path
+            }
+            None => Some(workdir.join(self.local))
+                .filter(|local| is_executable(local))
+                .or_else(|| find_in_path(self.name, env::var_os("PATH")))
+                .ok_or_else(|| {
+                    format!(
+                        "{} not found in {:?} or $PATH, set `tools.{}` or ${} (or run make)",
+                        self.name,
+                        workdir.join(self.local),
+                        self.key,
+                        self.env
+                    )
+                })?,
+        };
+        fs::canonicalize(&found).map_err(|e| format!("{:?}: {}", found, e))
+    }
+}
+
+impl Toolchain {
+    /// Resolves all tools, paths in the config are relative to `workdir`.
+    /// Reports every missing tool at once.
+    pub fn resolve(paths: &ToolPaths, workdir: &Path) -> Result<Self, ConfigError> {
+        let mut problems = vec![];
+        let mut resolve = |spec: &ToolSpec, configured: &Option<String>| {
+            spec.resolve(configured.as_deref(), workdir)
+                .map_err(|e| problems.push(e))
+                .unwrap_or_default()
+        };
+        let tools = Toolchain {
+            afl_fuzz: resolve(&AFL_FUZZ, &paths.afl_fuzz),
+            afl_cmin: resolve(&AFL_CMIN, &paths.afl_cmin),
+            qemu_trace: resolve(&QEMU_TRACE, &paths.qemu_trace),
+            criu: resolve(&CRIU, &paths.criu),
+        };
+        if problems.is_empty() {
+            Ok(tools)
+        } else {
+            Err(ConfigError::Validation(problems))
+        }
+    }
+
+    /// The tools of a FitM checkout (or `$PATH`) in the current working dir
+    pub fn from_cwd() -> Result<Self, ConfigError> {
+        let cwd = env::current_dir().map_err(|e| ConfigError::Io(".".into(), e))?;
+        Self::resolve(&ToolPaths::default(), &cwd)
+    }
+
+    /// Logs where each tool was found
+    pub fn print(&self) {
+        println!("[*] afl-fuzz: {:?}", self.afl_fuzz);
+        println!("[*] afl-cmin: {:?}", self.afl_cmin);
+        println!("[*] fitm-qemu-trace: {:?}", self.qemu_trace);
+        println!("[*] criu: {:?}", self.criu);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn touch(path: &Path, mode: u32) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, "").unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    #[test]
+    fn test_resolve_tools() {
+        let root = Path::new("/tmp/fitm_toolchain_unittest");
+        let _ = fs::remove_dir_all(root);
+        for spec in &[AFL_FUZZ, AFL_CMIN, QEMU_TRACE] {
+            touch(&root.join(spec.local), 0o755);
+        }
+        touch(&root.join("bin/criu"), 0o755);
+        touch(&root.join("not-executable"), 0o644);
+
+        assert_eq!(
+            find_in_path("criu", Some(root.join("bin").into_os_string())),
+            Some(root.join("bin/criu"))
+        );
+        assert_eq!(find_in_path("criu", None), None);
+
+        let paths = ToolPaths {
+            criu: Some("bin/criu".to_string()),
+            ..Default::default()
+        };
+        let tools = Toolchain::resolve(&paths, root).unwrap();
+        assert_eq!(tools.afl_fuzz, root.join("AFLplusplus/afl-fuzz"));
+        assert_eq!(tools.criu, root.join("bin/criu"));
+
+        let paths = ToolPaths {
+            criu: Some("not-executable".to_string()),
+            afl_cmin: Some("missing".to_string()),
+            ..Default::default()
+        };
+        match Toolchain::resolve(&paths, root) {
+            Err(ConfigError::Validation(problems)) => {
+                assert_eq!(problems.len(), 2);
+                assert!(problems[0].starts_with("`tools.afl_cmin`"));
+                assert!(problems[1].starts_with("`tools.criu`"));
+            }
+            res => panic!("Expected validation errors, got {:?}", res),
+        }
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
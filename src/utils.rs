@@ -1,11 +1,25 @@
-use crate::{FITMSnapshot, ACTIVE_STATE, CRIU_STDERR, CRIU_STDOUT};
-
-use fs_extra::{self, dir::CopyOptions};
+use crate::criu_images;
+use crate::history::FuzzHistory;
+use crate::incremental;
+use crate::restore::RestorePlan;
+use crate::toolchain::Toolchain;
+use crate::workers::Worker;
+use crate::workspace::Workspace;
+use crate::FITMSnapshot;
+
+use serde::{Deserialize, Serialize};
 use std::{
     cmp::{max, min},
-    fs::{self, create_dir_all},
-    io::{self, Error, ErrorKind, Write},
-    path::Path,
+    collections::{BTreeMap, HashMap, HashSet},
+    ffi::CString,
+    fs::{self, create_dir_all, File, OpenOptions},
+    io::{self, ErrorKind, Write},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{lchown, symlink, MetadataExt},
+        io::AsRawFd,
+    },
+    path::{Path, PathBuf},
     process::{Child, Command, ExitStatus, Stdio},
     str::FromStr,
     time::{Duration, SystemTime, UNIX_EPOCH},
@@ -42,147 +56,578 @@ where
         .collect()
 }
 
-pub fn clear_out() {
-    std::fs::remove_dir_all("out")
-        .expect("[!] Could not remove old 'out' folder in utils::clear_out");
-    create_dir_all("out").expect("[!] Could not recreate out folder in utils::clear_out");
+/// Picks up to `count` distinct indices, each with a probability proportional to its weight.
+/// Weights <= 0 are never picked. The indices are returned sorted.
+pub fn pick_weighted(rand: &mut RomuRand, weights: &[f64], count: usize) -> Vec<usize> {
+    let mut weights: Vec<f64> = weights.iter().map(|w| w.max(0.0)).collect();
+    let mut picked = vec![];
+    while picked.len() < count {
+        let total: f64 = weights.iter().sum();
+        if total <= 0.0 {
+            break;
+        }
+        let mut target = rand.below(1 << 20) as f64 / (1 << 20) as f64 * total;
+        let idx = (0..weights.len())
+            .filter(|&idx| weights[idx] > 0.0)
+            .find(|&idx| {
+                target -= weights[idx];
+                target < 0.0
+            })
+            // Rounding, take the last one that's left
+            .unwrap_or_else(|| weights.iter().rposition(|w| *w > 0.0).unwrap());
+        weights[idx] = 0.0;
+        picked.push(idx);
+    }
+    picked.sort_unstable();
+    picked
+}
+
+/// What the weight of a snapshot in `pick_snapshots_weighted` is based on
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SnapshotWeight {
+    /// Share of the `snapshot_map` edges no other snapshot of the generation has, 0.5 if unknown
+    pub novelty: f64,
+    /// `max_depth` of the last AFL run
+    pub depth: u64,
+    /// How often the snapshot was fuzzed
+    pub fuzzed: u64,
+    /// Crashes over all runs
+    pub crashes: u64,
+}
+
+impl SnapshotWeight {
+    /// Novel, deep and crashing snapshots weigh more, often fuzzed ones less
+    pub fn weight(&self) -> f64 {
+        let novelty = 1.0 + 3.0 * self.novelty;
+        let depth = 1.0 + (1.0 + self.depth as f64).ln() / 4.0;
+        let crashes = if self.crashes > 0 { 1.5 } else { 1.0 };
+        novelty * depth * crashes / (1 + self.fuzzed) as f64
+    }
+}
+
+/// Edges in an afl-showmap file (`edge:count` per line)
+pub(crate) fn read_map_edges(path: &Path) -> Option<HashSet<String>> {
+    let map = fs::read_to_string(path).ok()?;
+    Some(
+        map.lines()
+            .filter_map(|line| line.split(':').next())
+            .filter(|edge| !edge.is_empty())
+            .map(String::from)
+            .collect(),
+    )
 }
 
-pub fn parse_pid() -> io::Result<i32> {
-    let pstree_path = format!("{}/snapshot/pstree.img", ACTIVE_STATE);
-    if !Path::new(&pstree_path).exists() {
-        return Err(Error::new(ErrorKind::NotFound, "pstree.img does not exist"));
+/// For each map, the share of its edges that no other map has. `None` (no map) counts as 0.5.
+pub fn map_novelty(maps: &[Option<HashSet<String>>]) -> Vec<f64> {
+    let mut edge_counts: HashMap<&str, usize> = HashMap::new();
+    for edge in maps.iter().flatten().flatten() {
+        *edge_counts.entry(edge).or_default() += 1;
     }
+    maps.iter()
+        .map(|map| match map {
+            None => 0.5,
+            Some(map) if map.is_empty() => 0.0,
+            Some(map) => {
+                map.iter()
+                    .filter(|edge| edge_counts[edge.as_str()] == 1)
+                    .count() as f64
+                    / map.len() as f64
+            }
+        })
+        .collect()
+}
 
-    let pstree = Command::new("./criu/crit/crit-python3")
-        .args(&[
-            "decode".to_string(),
-            "-i".to_string(),
-            pstree_path,
-        ])
-        .output()
-        .expect("[!] crit decode failed during utils::parse_pid");
-    let pstree_string = String::from_utf8(pstree.stdout)
-        .expect("[!] Failed to grab output from crit in utils::parse_pid");
-    let pstree_json = json::parse(pstree_string.as_str()).expect("[!] parse_pid failed to parse JSON in utils::parse_pid");
-    let pid = pstree_json["entries"][0]["pid"]
-        .as_i32()
-        .expect("[!] Could not transform json value into i32 in utils::parse_pid");
-    Ok(pid)
+/// Weighs the snapshots of one generation, see `SnapshotWeight`
+pub fn snapshot_weights(
+    saved_states: &Path,
+    snaps: &[FITMSnapshot],
+    history: &BTreeMap<String, FuzzHistory>,
+) -> Vec<SnapshotWeight> {
+    let maps: Vec<Option<HashSet<String>>> = snaps
+        .iter()
+        .map(|snap| read_map_edges(&saved_states.join(&snap.state_path).join("snapshot_map")))
+        .collect();
+    snaps
+        .iter()
+        .zip(map_novelty(&maps))
+        .map(|(snap, novelty)| {
+            let snap_history = history.get(&snap.state_path);
+            SnapshotWeight {
+                novelty,
+                depth: fuzzer_stat(saved_states, &snap.state_path, "max_depth")
+                    .and_then(|depth| depth.parse().ok())
+                    .unwrap_or(0),
+                fuzzed: snap_history.map_or(0, |h| h.runs),
+                crashes: snap_history.map_or(0, |h| h.crashes),
+            }
+        })
+        .collect()
+}
+
+/// Picks up to `count` snapshots of one generation, weighted by `snapshot_weights`, and logs each choice
+pub fn pick_snapshots_weighted(
+    rand: &mut RomuRand,
+    saved_states: &Path,
+    snaps: &[FITMSnapshot],
+    history: &BTreeMap<String, FuzzHistory>,
+    count: usize,
+) -> Vec<FITMSnapshot> {
+    let weights = snapshot_weights(saved_states, snaps, history);
+    let values: Vec<f64> = weights.iter().map(SnapshotWeight::weight).collect();
+    let total: f64 = values.iter().sum();
+    let picked = pick_weighted(rand, &values, count);
+    println!(
+        "[*] Picked {} of {} snapshots (budget {})",
+        picked.len(),
+        snaps.len(),
+        count
+    );
+    for &idx in &picked {
+        let weight = &weights[idx];
+        println!(
+            "    {:<24} weight {:.3} (share {:.1}%): novelty {:.2}, depth {}, fuzzed {}x, crashes {}",
+            snaps[idx].state_path,
+            values[idx],
+            100.0 * values[idx] / total,
+            weight.novelty,
+            weight.depth,
+            weight.fuzzed,
+            weight.crashes
+        );
+    }
+    picked.into_iter().map(|idx| snaps[idx].clone()).collect()
+}
+
+/// All entries of `dir`, sorted by name so iteration order doesn't depend on the fs
+pub fn read_dir_sorted<P: AsRef<Path>>(dir: P) -> io::Result<Vec<fs::DirEntry>> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    Ok(entries)
+}
+
+/// Empties AFL's `out` folder in the working dir
+pub fn clear_out() -> io::Result<()> {
+    match std::fs::remove_dir_all("out") {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => (),
+    }
+    create_dir_all("out")
+}
+
+/// Reads the pid of the snapshotted process from the criu images in the active state of `workspace`
+pub fn parse_pid(workspace: &Workspace) -> io::Result<i32> {
+    let snapshot_dir = workspace.active_state.join("snapshot");
+    Ok(criu_images::root_pid(&snapshot_dir)? as i32)
+}
+
+/// Copies `from` into the dir `to`, like `cp_recursive`, but fails if `to` already holds one of that name
+pub fn copy(from: &str, to: &str) -> io::Result<()> {
+    let dest = destination(Path::new(from), Path::new(to))?;
+    if fs::symlink_metadata(&dest).is_ok() {
+        return Err(io::Error::new(
+            ErrorKind::AlreadyExists,
+            format!("could not copy {} to {}: {:?} exists", from, to, dest),
+        ));
+    }
+    cp_recursive(from, to)
+}
+
+/// Copies `from` into `dst` with reflinks (FICLONE) or `copy_file_range`, falling back to a plain copy
+fn copy_file_contents(from: &File, dst: &File) -> io::Result<()> {
+    if unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE, from.as_raw_fd()) } == 0 {
+        return Ok(());
+    }
+    let mut left = from.metadata()?.len();
+    let mut copied = 0;
+    while left > 0 {
+        let ret = unsafe {
+            libc::copy_file_range(
+                from.as_raw_fd(),
+                std::ptr::null_mut(),
+                dst.as_raw_fd(),
+                std::ptr::null_mut(),
+                left as usize,
+                0,
+            )
+        };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            let unsupported = matches!(
+                err.raw_os_error(),
+                Some(libc::EXDEV | libc::ENOSYS | libc::EINVAL | libc::EOPNOTSUPP)
+            );
+            if unsupported && copied == 0 {
+                // e.g. procfs or old kernels
+                let mut from = from;
+                let mut dst = dst;
+                io::copy(&mut from, &mut dst)?;
+                return Ok(());
+            }
+            return Err(err);
+        }
+        if ret == 0 {
+            // The file shrank while we copied it
+            break;
+        }
+        left -= ret as u64;
+        copied += ret;
+    }
+    Ok(())
 }
 
-pub fn mv(from: &str, to: &str) {
-    let options = CopyOptions::new();
-    fs_extra::dir::move_dir(&from, &to, &options)
-        .unwrap_or_else(|_| panic!("utils::mv failed to move '{}' to '{}'", from, to));
+/// Sets owner, permissions and timestamps of `path` to those in `meta`, without following symlinks.
+/// Like cp, we silently keep our own uid/gid if we are not allowed to chown.
+fn preserve_metadata(path: &Path, meta: &fs::Metadata) -> io::Result<()> {
+    match lchown(path, Some(meta.uid()), Some(meta.gid())) {
+        Ok(()) => (),
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => (),
+        Err(e) => return Err(e),
+    }
+    if !meta.file_type().is_symlink() {
+        // After chown, which clears setuid bits
+        fs::set_permissions(path, meta.permissions())?;
+    }
+    let times = [
+        libc::timespec {
+            tv_sec: meta.atime() as libc::time_t,
+            tv_nsec: meta.atime_nsec() as _,
+        },
+        libc::timespec {
+            tv_sec: meta.mtime() as libc::time_t,
+            tv_nsec: meta.mtime_nsec() as _,
+        },
+    ];
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let ret = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            c_path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
 }
 
-pub fn mv_rename(from: &str, to: &str) {
-    cp_recursive(from, to);
+/// Copies the file, dir, symlink or fifo `from` to `to` (which must not be a dir), keeping metadata.
+/// Every file and dir written is fsynced.
+fn copy_tree(from: &Path, to: &Path) -> io::Result<()> {
+    let meta = fs::symlink_metadata(from)?;
+    let file_type = meta.file_type();
+    if file_type.is_dir() {
+        match fs::create_dir(to) {
+            Ok(()) => (),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists && to.is_dir() => (),
+            Err(e) => return Err(e),
+        }
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_tree(&entry.path(), &to.join(entry.file_name()))?;
+        }
+        // Timestamps last, adding the entries changed them
+        preserve_metadata(to, &meta)?;
+        File::open(to)?.sync_all()?;
+        return Ok(());
+    }
+
+    // Like cp, replace whatever is in the way
+    match fs::symlink_metadata(to) {
+        Ok(existing) if existing.is_dir() => {
+            return Err(io::Error::new(
+                ErrorKind::AlreadyExists,
+                format!("cannot overwrite dir {:?} with non-dir {:?}", to, from),
+            ))
+        }
+        Ok(existing) if !existing.is_file() || !file_type.is_file() => fs::remove_file(to)?,
+        _ => (),
+    }
+    if file_type.is_symlink() {
+        symlink(fs::read_link(from)?, to)?;
+    } else if file_type.is_file() {
+        let src = File::open(from)?;
+        let dst = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(to)?;
+        copy_file_contents(&src, &dst)?;
+        dst.sync_all()?;
+    } else {
+        // fifos, sockets and device nodes
+        let c_path = CString::new(to.as_os_str().as_bytes())?;
+        if unsafe { libc::mknod(c_path.as_ptr(), meta.mode(), meta.rdev()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    preserve_metadata(to, &meta)
+}
 
-    match std::fs::remove_dir_all(from) {
-        Ok(_) => (),
-        Err(e) if e.kind() == ErrorKind::Other => {
-            // retry since this usually is a problem within remove_dir_all
-            std::fs::remove_dir_all(from)
-                .expect("[!] Error while calling remove_dir_all() again in utils:mv_rename");
+/// fsyncs `path` and, if it's a dir, every file and dir below it
+pub fn sync_tree(path: &Path) -> io::Result<()> {
+    let file_type = fs::symlink_metadata(path)?.file_type();
+    if file_type.is_dir() {
+        for entry in fs::read_dir(path)? {
+            sync_tree(&entry?.path())?;
         }
-        Err(e) => println!(
-            "[!] Could not remove '{}' in utils::mv_rename: {:?}",
-            from, e
-        ),
+    } else if !file_type.is_file() {
+        // Opening a fifo would block, symlinks are synced with their dir
+        return Ok(());
+    }
+    File::open(path)?.sync_all()
+}
+
+/// Like `cp --preserve -r from to`: if `to` is a dir, `from` ends up inside it, else it becomes `to`.
+/// Keeps permissions, ownership and timestamps (as CRIU needs them) and symlinks as they are.
+/// Uses reflinks where the filesystem supports them, and fsyncs only what it wrote.
+pub fn cp_recursive(from: &str, to: &str) -> io::Result<()> {
+    let from = Path::new(from);
+    let to = destination(from, Path::new(to))?;
+    copy_tree(from, &to)?;
+    // Make the new entry itself durable
+    sync_parent(&to)
+}
+
+/// Where `cp`/`mv` put `from`: inside `to` if that's a dir, else `to` itself
+fn destination(from: &Path, to: &Path) -> io::Result<PathBuf> {
+    if !to.is_dir() {
+        return Ok(to.to_path_buf());
+    }
+    let name = from.file_name().ok_or_else(|| {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("cannot put {:?} into a dir, it has no name", from),
+        )
+    })?;
+    Ok(to.join(name))
+}
+
+/// fsyncs the dir holding `path`, so a new or renamed entry survives a crash
+fn sync_parent(path: &Path) -> io::Result<()> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
     };
+    File::open(parent)?.sync_all()
 }
 
-pub fn copy(from: &str, to: &str) {
-    let options = CopyOptions::new();
-    fs_extra::dir::copy(&from, &to, &options)
-        .unwrap_or_else(|_| panic!("utils::copy failed to copy '{}' to '{}'", from, to));
+/// Checks that `copy` has the same entries, types, file sizes and symlink targets as `orig`
+fn verify_copy(orig: &Path, copy: &Path) -> io::Result<()> {
+    let mismatch = |what: &str| {
+        Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("copy {:?} of {:?} is incomplete: {}", copy, orig, what),
+        ))
+    };
+    let orig_meta = fs::symlink_metadata(orig)?;
+    let copy_meta = match fs::symlink_metadata(copy) {
+        Ok(meta) => meta,
+        Err(_) => return mismatch("missing"),
+    };
+    if orig_meta.file_type() != copy_meta.file_type() {
+        return mismatch("different file type");
+    }
+    if orig_meta.is_file() && orig_meta.len() != copy_meta.len() {
+        return mismatch("different size");
+    }
+    if orig_meta.file_type().is_symlink() && fs::read_link(orig)? != fs::read_link(copy)? {
+        return mismatch("different symlink target");
+    }
+    if orig_meta.is_dir() {
+        let mut orig_names = fs::read_dir(orig)?
+            .map(|entry| Ok(entry?.file_name()))
+            .collect::<io::Result<Vec<_>>>()?;
+        let mut copy_names = fs::read_dir(copy)?
+            .map(|entry| Ok(entry?.file_name()))
+            .collect::<io::Result<Vec<_>>>()?;
+        orig_names.sort();
+        copy_names.sort();
+        if orig_names != copy_names {
+            return mismatch("different entries");
+        }
+        for name in orig_names {
+            verify_copy(&orig.join(&name), &copy.join(&name))?;
+        }
+    }
+    Ok(())
 }
 
-pub fn cp_recursive(from: &str, to: &str) -> ExitStatus {
-    // preserve is needed because otherwise file permissions change through copying
-    let ret = Command::new("cp")
-        .args(&["--preserve", "-r", from, to])
-        .status()
-        .expect("[!] Failed to wait for cp");
+/// Moves `from` to `to` across filesystems: copies it next to `to`, checks the copy is complete,
+/// renames it into place and only then deletes `from`. If we are interrupted, `from` is still intact.
+fn move_by_copy(from: &Path, to: &Path) -> io::Result<()> {
+    let name = to.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = to.with_file_name(format!(".{}.moving", name));
+    match fs::symlink_metadata(&tmp) {
+        Ok(meta) if meta.is_dir() => fs::remove_dir_all(&tmp)?,
+        Ok(_) => fs::remove_file(&tmp)?,
+        Err(_) => (),
+    }
+    copy_tree(from, &tmp)?;
+    verify_copy(from, &tmp)?;
+    fs::rename(&tmp, to)?;
+    sync_parent(to)?;
+
+    let removed = if fs::symlink_metadata(from)?.is_dir() {
+        fs::remove_dir_all(from)
+    } else {
+        fs::remove_file(from)
+    };
+    if let Err(e) = removed {
+        // The move itself is done, we only waste some space
+        println!(
+            "[!] Moved {:?} to {:?}, but could not remove it: {}",
+            from, to, e
+        );
+    }
+    Ok(())
+}
 
-    Command::new("sync").status().unwrap();
-    ret
+/// Like `mv from to`: if `to` is a dir, `from` ends up inside it, else it becomes `to`.
+/// An atomic rename(2) if both are on the same filesystem, else see `move_by_copy`.
+pub fn mv(from: &str, to: &str) -> io::Result<()> {
+    let from = Path::new(from);
+    let to = destination(from, Path::new(to))?;
+    match fs::rename(from, &to) {
+        Ok(()) => sync_parent(&to),
+        Err(e) if e.raw_os_error() == Some(libc::EXDEV) => move_by_copy(from, &to),
+        Err(e) => Err(e),
+    }
 }
 
-pub fn copy_overwrite(from: &str, to: &str) {
-    let mut options = CopyOptions::new();
-    options.overwrite = true;
-    fs_extra::dir::copy(&from, &to, &options)
-        .unwrap_or_else(|_| panic!("utils::copy failed to copy '{}' to '{}'", from, to));
+/// Copies `from` into the dir `to`, replacing what's there, see `cp_recursive`
+pub fn copy_overwrite(from: &str, to: &str) -> io::Result<()> {
+    cp_recursive(from, to).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("could not copy {} to {}: {}", from, to, e),
+        )
+    })
 }
 
+/// Like `copy`, but only logs errors
 pub fn copy_ignore(from: &str, to: &str) {
-    let options = CopyOptions::new();
-    if let Err(e) = fs_extra::dir::copy(&from, &to, &options) {
+    if let Err(e) = copy(from, to) {
         println!("Ignored error in copy: {:?}", e)
     }
 }
 
 //#[allow(dead_code)]
-pub fn rm(dir: &str) {
-    Command::new("rm")
-        .args(&["-rf", dir])
-        .spawn()
-        .expect("[!] Could not start removing dir/file")
-        .wait()
-        .unwrap_or_else(|_| panic!("[!] Removing dir/file {} failed.", dir));
+pub fn rm(dir: &str) -> io::Result<()> {
+    let status = Command::new("rm").args(["-rf", dir]).status()?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "removing {} failed with {}",
+            dir, status
+        )));
+    }
+    Ok(())
 }
 
-fn cp_stdfiles(base_state: &str) {
-    // stdout
-    fs::copy(
-        format!("./saved-states/{}/stdout", base_state),
-        format!("{}/stdout", ACTIVE_STATE),
-    )
-    .expect("[!] Could not copy old stdout file to active-state");
-
-    // stderr
-    fs::copy(
-        format!("./saved-states/{}/stderr", base_state),
-        format!("{}/stderr", ACTIVE_STATE),
-    )
-    .expect("[!] Could not copy old stdout file to active-state");
+fn cp_stdfiles(base: &Path, active_state: &Path) -> io::Result<()> {
+    for name in ["stdout", "stderr"] {
+        let to = active_state.join(name);
+        cp_recursive(base.join(name).to_str().unwrap(), to.to_str().unwrap())?;
+    }
+    Ok(())
 }
 
-pub fn copy_snapshot_base(base_state: &str) {
+/// Copies what the snapshot of `base_state` needs to be restored into the active state of `workspace`
+pub fn copy_snapshot_base(workspace: &Workspace, base_state: &str) -> io::Result<()> {
+    let base = workspace.saved_state(base_state);
+    let active_state = &workspace.active_state;
     // copy old snapshot folder for criu
-    let old_snapshot = format!("./saved-states/{}/snapshot", base_state);
-    let new_snapshot = ACTIVE_STATE.to_string();
+    let old_snapshot = base.join("snapshot");
+    let new_snapshot = active_state.to_str().unwrap();
 
-    cp_recursive(old_snapshot.as_str(), new_snapshot.as_str());
+    cp_recursive(old_snapshot.to_str().unwrap(), new_snapshot)?;
+    incremental::link_parent(&active_state.join("snapshot"), &old_snapshot)?;
 
     // copy old pipes file so restore.sh knows which pipes are open
-    let old_pipes = format!("./saved-states/{}/pipes", base_state);
-    let new_pipes = format!("{}/pipes", ACTIVE_STATE);
-    fs::copy(old_pipes, new_pipes).expect("[!] Could not copy old pipes file to new state-dir");
+    let old_pipes = base.join("pipes");
+    let new_pipes = active_state.join("pipes");
+    cp_recursive(old_pipes.to_str().unwrap(), new_pipes.to_str().unwrap())?;
 
-    // copy old fd folder for new state
-    let from = format!("./saved-states/{}/fd", base_state);
-    let to = ACTIVE_STATE.to_string();
-    copy(&from, &to);
+    // copy old fd folder for new state, CRIU checks the owners and timestamps of the files in it
+    cp_recursive(base.join("fd").to_str().unwrap(), new_snapshot)?;
 
     // copy old stdout/err since they are part of the process' state
-    cp_stdfiles(base_state);
+    cp_stdfiles(&base, active_state)
 }
 
-pub fn create_restore_sh(afl: &FITMSnapshot) {
-    Command::new("python3")
-        .args(&[
-            "create_restore.py".to_string(),
-            afl.base_state.to_string(),
-            afl.state_path.to_string(),
-        ])
-        .spawn()
-        .expect("[!] Could not spawn create_restore.py")
-        .wait()
-        .expect("[!] Could not create restore.sh with python");
+/// The AFL++ node a fuzz run starts as `-M`, the secondaries are `-S sec<n>`
+pub const AFL_MAIN_NODE: &str = "main";
+
+/// The name of AFL++ node `node` of a fuzz run, 0 is the main node
+pub fn afl_node_name(node: usize) -> String {
+    if node == 0 {
+        AFL_MAIN_NODE.to_string()
+    } else {
+        format!("sec{}", node)
+    }
+}
+
+/// The node folders in AFL's output dir `out`, main first, the secondaries sorted by name.
+/// Empty if `out` does not exist.
+pub fn afl_nodes(out: &Path) -> Vec<PathBuf> {
+    let mut nodes: Vec<PathBuf> = match fs::read_dir(out) {
+        Ok(entries) => entries
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| path.is_dir())
+            .collect(),
+        Err(_) => return vec![],
+    };
+    nodes.sort_by_key(|node| (!node.ends_with(AFL_MAIN_NODE), node.clone()));
+    nodes
+}
+
+/// What the files of the node folder `node` are prefixed with once they're collected with those of
+/// other nodes: nothing for main, `<node>-` for secondaries, so names don't clash
+pub fn afl_node_prefix(node: &Path) -> String {
+    match node.file_name() {
+        Some(name) if name != AFL_MAIN_NODE => format!("{}-", name.to_string_lossy()),
+        _ => String::new(),
+    }
+}
+
+/// Where AFL's results end up in a saved state, see `save_fuzz_results`
+pub fn afl_results_dir(saved_states: &Path, state_path: &str) -> PathBuf {
+    saved_states
+        .join(state_path)
+        .join("out_postrun")
+        .join(AFL_MAIN_NODE)
+}
+
+/// The results of every node of the last run of `state_path`, main first
+pub fn afl_results_dirs(saved_states: &Path, state_path: &str) -> Vec<PathBuf> {
+    afl_nodes(&saved_states.join(state_path).join("out_postrun"))
+}
+
+/// Reads a single value from the `fuzzer_stats` in the node folder `node_dir`
+pub fn node_stat(node_dir: &Path, key: &str) -> Option<String> {
+    let stats = fs::read_to_string(node_dir.join("fuzzer_stats")).ok()?;
+    stats.lines().find_map(|line| {
+        let (k, v) = line.split_once(':')?;
+        (k.trim() == key).then(|| v.trim().to_string())
+    })
+}
+
+/// Reads a single value from AFL's `fuzzer_stats` of the main node of the last run of `state_path`
+pub fn fuzzer_stat(saved_states: &Path, state_path: &str, key: &str) -> Option<String> {
+    node_stat(&afl_results_dir(saved_states, state_path), key)
+}
+
+/// Writes `restore.sh` for the given snapshot into the active state dir of `workspace`.
+/// Returns its plan, for the restores that don't go through afl-fuzz, see `RestorePlan::command`.
+pub fn create_restore_sh(
+    afl: &FITMSnapshot,
+    tools: &Toolchain,
+    workspace: &Workspace,
+) -> io::Result<RestorePlan> {
+    let plan = RestorePlan::for_snapshot(afl, tools, workspace)?;
+    plan.write_script(&workspace.active_state.join("restore.sh"))?;
+    Ok(plan)
 }
 
 /// Create the next iteration from a given state directory. If inc_server is set
@@ -200,10 +645,9 @@ pub fn next_state_path(state_path: (u32, u32), cur_is_server: bool) -> (u32, u32
 
 /// @param snapshot_dir: str of path pointing to a dir with depth 1
 /// @return: the most recent timestamp of a successfull criu worker exiting in the criu server log
-pub fn latest_snapshot_time(criu_stderr: &str) -> f64 {
+pub fn latest_snapshot_time(criu_stderr: &str) -> io::Result<f64> {
     let mut timestamp_cleaned = "0";
-    let server_log =
-        fs::read_to_string(criu_stderr).expect("[!] Could not read criu_stderr in count_snapshots");
+    let server_log = fs::read_to_string(criu_stderr)?;
     let lines: Vec<&str> = server_log.split('\n').collect();
     for line in lines {
         // timestamp has constant length - remove it
@@ -217,11 +661,19 @@ pub fn latest_snapshot_time(criu_stderr: &str) -> f64 {
                     timestamp_cleaned = timestamp_cleaned_new;
                 }
             } else {
-                panic!("[!] Criu server failed to create new snapshot. Check active-state dir.")
+                return Err(io::Error::other(format!(
+                    "criu server failed to create a snapshot: {}",
+                    line
+                )));
             }
         }
     }
-    f64::from_str(timestamp_cleaned).expect("[!] Error parsing timestamp str to float")
+    f64::from_str(timestamp_cleaned).map_err(|e| {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            format!("bad timestamp {}: {}", timestamp_cleaned, e),
+        )
+    })
 }
 
 /// @return: a boolean indicating if there is a positivie time difference between old and new
@@ -237,15 +689,24 @@ pub fn positive_time_diff(old: &SystemTime, new: &SystemTime) -> bool {
 /// Sets the PID-counter to a specific target
 /// Assumes no other processes are concurrently spawning/accessing PID-counter
 /// The generated PIDs are not checked against target
-pub fn advance_pid(target: u64) {
+pub fn advance_pid(target: u64) -> io::Result<()> {
     let mut file = fs::OpenOptions::new()
         .write(true)
         .create(true)
-        .open("/proc/sys/kernel/ns_last_pid")
-        .expect("Failed to open ns_last_pid");
+        .truncate(false)
+        .open("/proc/sys/kernel/ns_last_pid")?;
 
     file.write_all((target - 1).to_string().as_bytes())
-        .expect("Writing failed (higher than /proc/sys/kernel/pid_max?)");
+        .map_err(|e| {
+            io::Error::new(
+                e.kind(),
+                format!(
+                    "could not set ns_last_pid to {} (higher than /proc/sys/kernel/pid_max?): {}",
+                    target - 1,
+                    e
+                ),
+            )
+        })
 }
 
 pub fn waitpid(snapshot_pid: libc::pid_t) -> io::Result<ExitStatus> {
@@ -262,29 +723,23 @@ pub fn waitpid(snapshot_pid: libc::pid_t) -> io::Result<ExitStatus> {
         }
     }
     // Casting the waitpid return value to automatically interpret ExitStatus flags
-    Ok(unsafe { std::mem::transmute(status) })
+    Ok(unsafe { std::mem::transmute::<i32, ExitStatus>(status) })
 }
 
-pub fn spawn_criu(criu_path: &str, socket_path: &str) -> io::Result<Child> {
-    let criu_stdout = fs::File::create(CRIU_STDOUT).expect("[!] Could not create criu_stdout");
-    let criu_stderr = fs::File::create(CRIU_STDERR).expect("[!] Could not create criu_stderr");
+/// Starts the criu service of `worker`, on its socket and logging to its `criu_stdout`/`criu_stderr`
+pub fn spawn_criu(criu_path: &Path, worker: &Worker) -> io::Result<Child> {
+    let criu_stdout = fs::File::create(&worker.workspace.criu_stdout)?;
+    let criu_stderr = fs::File::create(&worker.workspace.criu_stderr)?;
     Command::new(criu_path)
-        .args(&[
-            "service",
-            "-v4",
-            "--display-stats",
-            "--address",
-            socket_path,
-        ])
+        .args(["service", "-v4", "--display-stats", "--address"])
+        .arg(&worker.criu_socket)
         .stdout(Stdio::from(criu_stdout))
         .stderr(Stdio::from(criu_stderr))
         .spawn()
 }
 
-pub fn get_filesize(path: &Path) -> u64 {
-    let metadata = fs::metadata(path)
-        .expect("[!] Could not grab metadata for cur_file in utils::get_filesize");
-    metadata.len()
+pub fn get_filesize(path: &Path) -> io::Result<u64> {
+    Ok(fs::metadata(path)?.len())
 }
 
 /// Gets current nanoseconds since UNIX_EPOCH
@@ -306,7 +761,7 @@ pub fn current_millis() -> u64 {
 }
 
 /// see https://arxiv.org/pdf/2002.11331.pdf
-#[derive(Copy, Clone, Debug, Default)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 pub struct RomuRand {
     x_state: u64,
     y_state: u64,
@@ -489,16 +944,20 @@ pub fn jaro(a: &[u8], b: &[u8]) -> f64 {
 #[cfg(test)]
 mod tests {
     use crate::utils;
-    use crate::utils::{latest_snapshot_time, parse_pid, pick_random, RomuRand};
+    use crate::utils::{
+        latest_snapshot_time, map_novelty, parse_pid, pick_random, pick_weighted, RomuRand,
+        SnapshotWeight,
+    };
+    use std::collections::HashSet;
     use std::fs;
     use std::path::Path;
 
     fn setup(root_folder: &str, from_path: &str, from_content_path: &str, content: &str) {
         // setup - require user interaction so we don't delete anything by
         // default Creates necessary files/folders under /tmp
-        fs_extra::dir::create(root_folder, false)
+        fs::create_dir(root_folder)
             .expect("rust_unittest folder already exists, please remove to make this test run");
-        fs_extra::dir::create_all(from_path, true).expect("Could not create test folder");
+        fs::create_dir_all(from_path).expect("Could not create test folder");
         fs::write(from_content_path, content).expect("Could not write to 'from' content.txt");
     }
 
@@ -524,18 +983,64 @@ mod tests {
     #[test]
     fn test_pick_random() {
         let mut rand = RomuRand::preseeded();
-        let random_from_ten = pick_random(&mut rand, &vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9], 3);
+        let random_from_ten = pick_random(&mut rand, &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9], 3);
         println!("Got {:?} from a range of 0..9", random_from_ten);
     }
 
+    #[test]
+    fn test_seeded_pick_random() {
+        let picks = |seed| {
+            let mut rand = RomuRand::new(seed);
+            (0..10)
+                .map(|_| pick_random(&mut rand, &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9], 3))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(picks(1337), picks(1337));
+        assert_ne!(picks(1337), picks(1338));
+    }
+
+    #[test]
+    fn test_pick_weighted() {
+        let mut rand = RomuRand::new(1337);
+        assert_eq!(pick_weighted(&mut rand, &[1.0, 0.0, 2.0], 5), vec![0, 2]);
+
+        let mut counts = [0; 4];
+        for _ in 0..1000 {
+            for idx in pick_weighted(&mut rand, &[1.0, 1.0, 1.0, 10.0], 1) {
+                counts[idx] += 1;
+            }
+        }
+        assert!(counts[3] > 700, "{:?}", counts);
+        assert!(counts[..3].iter().all(|count| *count > 30), "{:?}", counts);
+
+        let map =
+            |edges: &[&str]| Some(edges.iter().map(|e| e.to_string()).collect::<HashSet<_>>());
+        assert_eq!(
+            map_novelty(&[map(&["1", "2"]), map(&["2", "3", "4", "5"]), None]),
+            vec![0.5, 0.75, 0.5]
+        );
+
+        let fresh = SnapshotWeight::default();
+        let fuzzed = SnapshotWeight { fuzzed: 3, ..fresh };
+        let novel = SnapshotWeight {
+            novelty: 1.0,
+            ..fresh
+        };
+        assert!(fuzzed.weight() < fresh.weight());
+        assert!(novel.weight() > fresh.weight());
+    }
+
     #[test]
     fn test_parse_pid() {
-        println!("{:?}", parse_pid().unwrap());
+        println!(
+            "{:?}",
+            parse_pid(&crate::workspace::Workspace::default()).unwrap()
+        );
     }
 
     #[test]
     fn test_latest_snapshot_time() {
-        let count = latest_snapshot_time("criu_stderr");
+        let count = latest_snapshot_time("criu_stderr").unwrap();
         assert_eq!(
             count, 10.672444,
             "Update the expected value if you actually want to test the function"
@@ -548,7 +1053,7 @@ mod tests {
         // using the original folders name as target name
         let root_folder = String::from("/tmp/rust_unittest");
         let from_path = format!("{}/foo/bar", root_folder);
-        let to_path = format!("{}", root_folder);
+        let to_path = root_folder.to_string();
         let from_content_path = format!("{}/foo/bar/content.txt", root_folder);
         let to_content_path = format!("{}/bar/content.txt", root_folder);
         let content = "A simple string.";
@@ -556,11 +1061,11 @@ mod tests {
         setup(&root_folder, &from_path, &from_content_path, content);
 
         // tested function
-        utils::copy(&from_path, &root_folder);
+        utils::copy(&from_path, &root_folder).unwrap();
 
         // Check that the 'from' path does not exist anymore, but the 'to' path
         // does
-        assert_eq!(Path::new(&from_path).exists(), true);
+        assert!(Path::new(&from_path).exists());
         assert!(paths_exist(&root_folder, &to_content_path));
 
         // Check 'to' path is still a directory
@@ -571,17 +1076,120 @@ mod tests {
             .expect("Could not read from expected content.txt");
 
         assert_eq!(result_content, "A simple string.");
+        // Like before, an existing copy is not overwritten
+        assert!(utils::copy(&from_path, &root_folder).is_err());
 
         // teardown
         teardown(&root_folder);
     }
 
+    #[test]
+    fn test_copy_snapshot_base() {
+        use std::os::unix::fs::MetadataExt;
+        use std::time::{Duration, SystemTime};
+
+        let root = Path::new("/tmp/fitm_utils_unittest_base");
+        let _ = fs::remove_dir_all(root);
+        let workspace = crate::workspace::Workspace::new(root);
+        let base = workspace.saved_state("fitm-gen3-state0");
+        fs::create_dir_all(base.join("snapshot")).unwrap();
+        fs::create_dir_all(base.join("fd")).unwrap();
+        for file in ["pipes", "stdout", "stderr", "fd/0"] {
+            fs::write(base.join(file), file).unwrap();
+        }
+        let old = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        fs::File::options()
+            .write(true)
+            .open(base.join("fd/0"))
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+        fs::create_dir_all(&workspace.active_state).unwrap();
+
+        utils::copy_snapshot_base(&workspace, "fitm-gen3-state0").unwrap();
+        for file in ["pipes", "stdout", "stderr", "fd/0", "snapshot"] {
+            assert!(workspace.active_state.join(file).exists(), "{}", file);
+        }
+        // CRIU checks the files it restores
+        let copied = fs::metadata(workspace.active_state.join("fd/0")).unwrap();
+        assert_eq!(copied.modified().unwrap(), old);
+        assert_eq!(copied.uid(), fs::metadata(base.join("fd/0")).unwrap().uid());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn test_cp_recursive() {
+        use std::os::unix::fs::{symlink, FileTypeExt, MetadataExt, PermissionsExt};
+
+        let root = Path::new("/tmp/fitm_cp_recursive_unittest");
+        let _ = fs::remove_dir_all(root);
+        let from = root.join("state");
+        fs::create_dir_all(from.join("snapshot")).unwrap();
+        fs::write(from.join("snapshot/pages-1.img"), vec![1u8; 10000]).unwrap();
+        fs::write(from.join("restore.sh"), "#!/bin/sh").unwrap();
+        fs::set_permissions(from.join("restore.sh"), fs::Permissions::from_mode(0o751)).unwrap();
+        symlink("../../base/snapshot", from.join("snapshot/parent")).unwrap();
+        let fifo = std::ffi::CString::new(from.join("pipe").to_str().unwrap()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+        let mtime = fs::metadata(from.join("restore.sh")).unwrap().mtime() - 1000;
+        let c_path = std::ffi::CString::new(from.join("restore.sh").to_str().unwrap()).unwrap();
+        let times = [libc::timespec {
+            tv_sec: mtime as libc::time_t,
+            tv_nsec: 0,
+        }; 2];
+        assert_eq!(
+            unsafe { libc::utimensat(libc::AT_FDCWD, c_path.as_ptr(), times.as_ptr(), 0) },
+            0
+        );
+
+        // Into an existing dir, and to a new name
+        fs::create_dir(root.join("active")).unwrap();
+        utils::cp_recursive(
+            from.to_str().unwrap(),
+            root.join("active").to_str().unwrap(),
+        )
+        .unwrap();
+        utils::cp_recursive(from.to_str().unwrap(), root.join("copy").to_str().unwrap()).unwrap();
+        for copy in [root.join("active/state"), root.join("copy")] {
+            assert_eq!(
+                fs::read(copy.join("snapshot/pages-1.img")).unwrap(),
+                vec![1u8; 10000]
+            );
+            let meta = fs::metadata(copy.join("restore.sh")).unwrap();
+            assert_eq!(meta.mode() & 0o7777, 0o751);
+            assert_eq!(meta.mtime(), mtime);
+            assert_eq!(
+                fs::read_link(copy.join("snapshot/parent")).unwrap(),
+                Path::new("../../base/snapshot")
+            );
+            assert!(fs::symlink_metadata(copy.join("pipe"))
+                .unwrap()
+                .file_type()
+                .is_fifo());
+        }
+        // Copying again overwrites
+        fs::write(from.join("restore.sh"), "#!/bin/bash").unwrap();
+        utils::cp_recursive(
+            from.to_str().unwrap(),
+            root.join("active").to_str().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(root.join("active/state/restore.sh")).unwrap(),
+            "#!/bin/bash"
+        );
+
+        utils::sync_tree(root).unwrap();
+        fs::remove_dir_all(root).unwrap();
+    }
+
     #[test]
     fn test_mv() {
         // Check that utils::mv moves a folder to a new destination
         let root_folder = String::from("/tmp/rust_unittest");
         let from_path = format!("{}/foo/bar", root_folder);
-        let to_path = format!("{}", root_folder);
+        let to_path = root_folder.to_string();
         let from_content_path = format!("{}/foo/bar/content.txt", root_folder);
         let to_content_path = format!("{}/bar/content.txt", root_folder);
         let content = "A simple string.";
@@ -589,11 +1197,11 @@ mod tests {
         setup(&root_folder, &from_path, &from_content_path, content);
 
         // tested function
-        utils::mv(&from_path, &to_path);
+        utils::mv(&from_path, &to_path).unwrap();
 
         // Check that the 'from' path does not exist anymore, but the 'to' path
         // does
-        assert_eq!(Path::new(&from_path).exists(), false);
+        assert!(!Path::new(&from_path).exists());
         assert!(paths_exist(&root_folder, &to_content_path));
 
         // Check 'to' path is still a directory
@@ -609,6 +1217,37 @@ mod tests {
         teardown(&root_folder);
     }
 
+    #[test]
+    fn test_move_by_copy() {
+        let root = Path::new("/tmp/fitm_move_by_copy_unittest");
+        let _ = fs::remove_dir_all(root);
+        let from = root.join("active-state");
+        fs::create_dir_all(from.join("snapshot")).unwrap();
+        fs::write(from.join("snapshot/pages-1.img"), "pages").unwrap();
+        std::os::unix::fs::symlink("../x", from.join("snapshot/parent")).unwrap();
+        let to = root.join("saved-states/fitm-gen3-state0");
+        fs::create_dir_all(root.join("saved-states")).unwrap();
+
+        assert!(utils::verify_copy(&from, &to).is_err());
+        utils::move_by_copy(&from, &to).unwrap();
+        assert!(!from.exists());
+        assert_eq!(
+            fs::read_to_string(to.join("snapshot/pages-1.img")).unwrap(),
+            "pages"
+        );
+        assert!(!root.join("saved-states/.fitm-gen3-state0.moving").exists());
+
+        // Into an existing dir, and back again
+        utils::mv(to.to_str().unwrap(), root.to_str().unwrap()).unwrap();
+        assert!(root.join("fitm-gen3-state0/snapshot/parent").is_symlink());
+        fs::write(root.join("other"), "x").unwrap();
+        fs::create_dir(&from).unwrap();
+        fs::write(from.join("other"), "xy").unwrap();
+        assert!(utils::verify_copy(&root.join("other"), &from.join("other")).is_err());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
     #[test]
     fn test_remove_dir_all() {
         let root_folder = String::from("/tmp/rust_unittest");
@@ -623,7 +1262,7 @@ mod tests {
         // tested function
         std::fs::remove_dir_all(&foo_path).expect("Tested remove_dir_all failed");
 
-        assert_eq!(Path::new(&foo_path).exists(), false);
+        assert!(!Path::new(&foo_path).exists());
 
         teardown(&root_folder);
     }
@@ -0,0 +1,384 @@
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::{self, Write},
+    ops::Range,
+    os::unix::process::ExitStatusExt,
+    panic::{self, AssertUnwindSafe},
+    path::{self, PathBuf},
+    process::ExitStatus,
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::criu_rpc::CRIU_SERVICE_SOCKET;
+use crate::namespacing;
+use crate::utils::{afl_node_name, RomuRand};
+use crate::workspace::Workspace;
+use crate::{ACTIVE_STATE, CMIN_TMP, CRIU_STDERR, CRIU_STDOUT};
+
+/// Each worker keeps its files in `workers/<id>`
+pub const WORKERS_DIR: &str = "workers";
+/// Targets started from scratch get a pid in the order of ~16k (high, but not higher than a normal pid_max)
+pub const PID_BASE: u64 = 1 << 14;
+/// A small range of random pids above `PID_BASE` allows for running multiple FitM instances.
+/// The workers split it among themselves.
+pub const PID_SPREAD: u64 = 9001;
+/// A forked worker leaves the result of its job here, in its folder
+const RESULT_FILE: &str = "result.json";
+/// Workers other than 0 run their criu service on this socket in their folder
+pub const CRIU_SOCKET: &str = "criu_service.socket";
+
+/// Everything one worker needs to fuzz a snapshot without getting in the way of the others
+#[derive(Clone, Debug, PartialEq)]
+pub struct Worker {
+    pub id: usize,
+    /// `workers/<id>` in the campaign's workspace
+    pub dir: PathBuf,
+    /// The campaign's workspace if there is only one worker. Else the active state and criu output
+    /// are folders and files of the worker's own, see `mount_active_state`
+    pub workspace: Workspace,
+    /// The campaign's active state, ours is bind mounted over it in the worker's namespaces
+    pub mount_point: PathBuf,
+    /// Where the inputs for afl-cmin are collected
+    pub cmin_tmp: PathBuf,
+    /// The criu service of worker 0 listens on `CRIU_SERVICE_SOCKET`, the others on `CRIU_SOCKET`
+    /// in their folder. Absolute, as targets connect to it from their active state.
+    pub criu_socket: PathBuf,
+    /// `init_run` picks target pids from here. Restored targets keep their pid,
+    /// every restore has a PID namespace of its own.
+    pub pid_range: Range<u64>,
+    /// AFL++ secondaries fuzzing a snapshot next to the main node, see `node`
+    pub secondaries: usize,
+}
+
+impl Worker {
+    /// Worker `id` of a pool of `count`, in `workspace`
+    pub fn new(workspace: &Workspace, id: usize, count: usize) -> Self {
+        let dir = workspace.path(WORKERS_DIR).join(id.to_string());
+        let pooled = count > 1;
+        let spread = PID_SPREAD / count.max(1) as u64;
+        let pid_start = PID_BASE + id as u64 * spread;
+        let mut own = workspace.clone();
+        if pooled {
+            own.active_state = dir.join(ACTIVE_STATE);
+        }
+        if id != 0 {
+            own.criu_stdout = dir.join(CRIU_STDOUT);
+            own.criu_stderr = dir.join(CRIU_STDERR);
+        }
+        Worker {
+            id,
+            workspace: own,
+            mount_point: workspace.active_state.clone(),
+            cmin_tmp: if pooled {
+                dir.join(CMIN_TMP)
+            } else {
+                workspace.path(CMIN_TMP)
+            },
+            criu_socket: if id == 0 {
+                PathBuf::from(CRIU_SERVICE_SOCKET)
+            } else {
+                let socket = dir.join(CRIU_SOCKET);
+                path::absolute(&socket).unwrap_or(socket)
+            },
+            pid_range: pid_start..pid_start + spread,
+            secondaries: 0,
+            dir,
+        }
+    }
+
+    /// `count` workers in `workspace`, at least one, each fuzzing with `secondaries` AFL++ secondaries
+    pub fn pool(workspace: &Workspace, count: usize, secondaries: usize) -> Vec<Worker> {
+        let count = count.max(1);
+        (0..count)
+            .map(|id| Worker {
+                secondaries,
+                ..Worker::new(workspace, id, count)
+            })
+            .collect()
+    }
+
+    /// Who runs AFL++ node `node` of our fuzz runs, see `afl_node_name`. Node 0 is the main node, that's us.
+    /// A CRIU image can only be restored once per copy, so each secondary gets an active state of its own
+    /// in our folder, mounted over `mount_point` in its namespace.
+    pub fn node(&self, node: usize) -> Worker {
+        let mut worker = self.clone();
+        if node != 0 {
+            worker.workspace.active_state =
+                self.dir
+                    .join(format!("{}-{}", ACTIVE_STATE, afl_node_name(node)));
+        }
+        worker
+    }
+
+    /// Where all AFL++ nodes of our fuzz runs sync (afl-fuzz `-o`), see `fuzz_run`. It's outside of
+    /// the nodes' active states, so they all see the same folder.
+    pub fn sync_dir(&self) -> PathBuf {
+        self.dir.join("sync")
+    }
+
+    /// Whether our active state is a folder of our own instead of the campaign's
+    pub fn is_pooled(&self) -> bool {
+        self.workspace.active_state != self.mount_point
+    }
+
+    /// Our workspace as seen from our namespaces after `mount_active_state`
+    pub fn mounted(&self) -> Workspace {
+        Workspace {
+            active_state: self.mount_point.clone(),
+            ..self.workspace.clone()
+        }
+    }
+
+    /// Where the snapshots created while fuzzing `job` wait until `process_stage` gives them their ids
+    pub fn new_states(&self, job: usize) -> PathBuf {
+        self.dir.join("new-states").join(job.to_string())
+    }
+
+    /// A random pid for a target started from scratch, see `advance_pid`
+    pub fn pick_pid(&self, rand: &mut RomuRand) -> u64 {
+        self.pid_range.start + rand.below(self.pid_range.end - self.pid_range.start)
+    }
+
+    /// Makes our active state show up at `mount_point`, so paths in CRIU images (which are absolute)
+    /// point into it. Only call this in a fresh mount namespace, see `NamespaceContext`.
+    pub fn mount_active_state(&self) -> io::Result<()> {
+        if !self.is_pooled() {
+            return Ok(());
+        }
+        // It stays empty outside of the workers' namespaces
+        fs::create_dir_all(&self.mount_point)?;
+        namespacing::bind_mount(&self.workspace.active_state, &self.mount_point)
+    }
+}
+
+/// Forks a process that runs `job` and writes its result (or error) to the worker's `RESULT_FILE`
+fn spawn<J, R, E, F>(worker: &Worker, idx: usize, input: J, job: &F) -> io::Result<libc::pid_t>
+where
+    R: Serialize,
+    E: Serialize + fmt::Display,
+    F: Fn(&Worker, usize, J) -> Result<R, E>,
+{
+    fs::create_dir_all(&worker.dir)?;
+    let result_file = worker.dir.join(RESULT_FILE);
+    let _ = fs::remove_file(&result_file);
+    let _ = io::stdout().flush();
+
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => {
+            // Never return into the caller's stack, not even on a panic
+            let res = panic::catch_unwind(AssertUnwindSafe(|| -> io::Result<()> {
+                let res = job(worker, idx, input);
+                if let Err(e) = &res {
+                    println!("[!] Worker {} failed: {}", worker.id, e);
+                }
+                fs::write(&result_file, serde_json::to_vec(&res)?)
+            }));
+            let code = match res {
+                Ok(Ok(())) => 0,
+                Ok(Err(e)) => {
+                    println!("[!] Worker {} could not save its result: {}", worker.id, e);
+                    1
+                }
+                Err(_) => 1,
+            };
+            let _ = io::stdout().flush();
+            unsafe { libc::_exit(code) }
+        }
+        pid => Ok(pid),
+    }
+}
+
+/// Waits for any child to exit
+fn wait_any() -> io::Result<(libc::pid_t, ExitStatus)> {
+    let mut status = 0;
+    loop {
+        let pid = unsafe { libc::waitpid(-1, &mut status, 0) };
+        if pid != -1 {
+            return Ok((pid, ExitStatus::from_raw(status)));
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+fn read_result<R, E>(worker: &Worker, status: ExitStatus) -> Result<R, E>
+where
+    R: DeserializeOwned,
+    E: DeserializeOwned + From<io::Error>,
+{
+    if !status.success() {
+        return Err(
+            io::Error::other(format!("worker {} exited with {}", worker.id, status)).into(),
+        );
+    }
+    let json = fs::read(worker.dir.join(RESULT_FILE))?;
+    serde_json::from_slice(&json).map_err(io::Error::from)?
+}
+
+/// Runs `job(worker, idx, input)` for every input, spread over `workers`.
+/// With more than one worker, each job runs in a forked process of its own, so workers don't share
+/// a working dir or anything else. The results are in the order of `inputs`, no matter who finished first.
+/// If a job fails, no more jobs are started and the running ones are waited for. The results of the
+/// jobs that finished fine come back together with the first error, so their work is not lost.
+pub fn run_jobs<J, R, E, F>(workers: &[Worker], inputs: Vec<J>, job: F) -> (Vec<R>, Option<E>)
+where
+    R: Serialize + DeserializeOwned,
+    E: Serialize + DeserializeOwned + From<io::Error> + fmt::Display,
+    F: Fn(&Worker, usize, J) -> Result<R, E>,
+{
+    if workers.len() <= 1 {
+        let worker = match workers.first() {
+            Some(worker) => worker,
+            None => return (vec![], None),
+        };
+        let mut results = vec![];
+        for (idx, input) in inputs.into_iter().enumerate() {
+            match job(worker, idx, input) {
+                Ok(res) => results.push(res),
+                Err(e) => return (results, Some(e)),
+            }
+        }
+        return (results, None);
+    }
+
+    let mut results: Vec<Option<R>> = inputs.iter().map(|_| None).collect();
+    let mut pending = inputs.into_iter().enumerate();
+    // pid -> (worker, job)
+    let mut running: HashMap<libc::pid_t, (usize, usize)> = HashMap::new();
+    let mut idle: Vec<usize> = (0..workers.len()).rev().collect();
+    let mut failed: Option<E> = None;
+
+    loop {
+        while failed.is_none() && !idle.is_empty() {
+            let (idx, input) = match pending.next() {
+                Some(next) => next,
+                None => break,
+            };
+            let worker = idle.pop().unwrap();
+            match spawn(&workers[worker], idx, input, &job) {
+                Ok(pid) => {
+                    running.insert(pid, (worker, idx));
+                }
+                Err(e) => {
+                    failed = Some(e.into());
+                    idle.push(worker);
+                }
+            }
+        }
+        if running.is_empty() {
+            break;
+        }
+
+        let (pid, status) = match wait_any() {
+            Ok(exited) => exited,
+            // Nothing to wait for anymore, whatever is still running is not ours
+            Err(e) => {
+                failed.get_or_insert(e.into());
+                break;
+            }
+        };
+        let (worker, idx) = match running.remove(&pid) {
+            Some(job) => job,
+            None => continue,
+        };
+        idle.push(worker);
+        match read_result(&workers[worker], status) {
+            Ok(res) => results[idx] = Some(res),
+            Err(e) => {
+                println!("[!] Job {} on worker {} failed: {}", idx, worker, e);
+                failed.get_or_insert(e);
+            }
+        }
+    }
+
+    (results.into_iter().flatten().collect(), failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::FitmError;
+    use std::path::Path;
+
+    #[test]
+    fn test_run_jobs() {
+        let single = Worker::pool(&Workspace::default(), 1, 0);
+        assert_eq!(single.len(), 1);
+        assert!(!single[0].is_pooled());
+        assert_eq!(single[0].workspace, Workspace::default());
+        assert_eq!(single[0].criu_socket, Path::new(CRIU_SERVICE_SOCKET));
+        assert_eq!(single[0].pid_range, PID_BASE..PID_BASE + PID_SPREAD);
+
+        let root = Path::new("/tmp/fitm_workers_unittest");
+        let _ = fs::remove_dir_all(root);
+        let workspace = Workspace::new(root);
+        let workers = Worker::pool(&workspace, 3, 2);
+        assert!(workers.iter().all(Worker::is_pooled));
+        assert_eq!(workers[2].secondaries, 2);
+        // Secondaries restore from their own copy, at the same place
+        assert_eq!(workers[1].node(0), workers[1]);
+        let secondary = workers[1].node(2);
+        assert_eq!(
+            secondary.workspace.active_state,
+            root.join("workers/1/active-state-sec2")
+        );
+        assert_eq!(secondary.mount_point, workers[1].mount_point);
+        assert_eq!(secondary.sync_dir(), root.join("workers/1/sync"));
+        assert_eq!(single[0].node(1).sync_dir(), single[0].sync_dir());
+        assert!(single[0].node(1).is_pooled());
+        assert_eq!(workers[1].dir, root.join("workers/1"));
+        assert_eq!(workers[1].mounted().active_state, workspace.active_state);
+        assert_eq!(workers[1].workspace.saved_states, workspace.saved_states);
+        assert_eq!(
+            workers[1].criu_socket,
+            root.join("workers/1").join(CRIU_SOCKET)
+        );
+        assert_ne!(workers[1].criu_socket, workers[2].criu_socket);
+        // Relative workspaces still hand targets an absolute socket
+        let relative = Worker::new(&Workspace::default(), 1, 2);
+        assert!(relative.criu_socket.is_absolute());
+        assert!(relative
+            .criu_socket
+            .ends_with("workers/1/criu_service.socket"));
+        assert_eq!(workers[0].pid_range.end, workers[1].pid_range.start);
+        assert!(workers[2].pid_range.end <= PID_BASE + PID_SPREAD);
+
+        // Later jobs finish first, the results keep the order of the inputs
+        let inputs: Vec<u64> = (0..7).collect();
+        let (results, failed): (Vec<(usize, usize, u64)>, _) =
+            run_jobs(&workers, inputs.clone(), |worker, idx, input| {
+                std::thread::sleep(std::time::Duration::from_millis(10 * (7 - input)));
+                Ok::<_, FitmError>((worker.id, idx, input * input))
+            });
+        assert_eq!(failed, None);
+        assert_eq!(
+            results.iter().map(|(_, idx, _)| *idx).collect::<Vec<_>>(),
+            inputs.iter().map(|i| *i as usize).collect::<Vec<_>>()
+        );
+        assert_eq!(results[6].2, 36);
+        assert!(results.iter().any(|(worker, _, _)| *worker != 0));
+
+        // Errors of forked jobs come back as they were, next to the results of the jobs that finished
+        let job = |_: &Worker, _, input| {
+            if input == 3 {
+                Err(FitmError::Config("broken".to_string()))
+            } else {
+                Ok(input)
+            }
+        };
+        let (done, failed) = run_jobs(&workers, inputs.clone(), job);
+        assert_eq!(failed, Some(FitmError::Config("broken".to_string())));
+        assert_eq!(done[..3], [0, 1, 2]);
+        assert!(!done.contains(&3));
+        let (done, failed) = run_jobs(&single, inputs, job);
+        assert_eq!(failed, Some(FitmError::Config("broken".to_string())));
+        assert_eq!(done, [0, 1, 2]);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,72 @@
+use std::path::{Path, PathBuf};
+
+use crate::{ACTIVE_STATE, CRIU_STDERR, CRIU_STDOUT, SAVED_STATES};
+
+/// Where a campaign keeps its files. Everything lives below `root`, so several campaigns (or tests)
+/// can share a machine as long as their roots differ.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Workspace {
+    pub root: PathBuf,
+    /// The state that is currently restored or fuzzed, `ACTIVE_STATE` below the root
+    pub active_state: PathBuf,
+    /// One folder per snapshot, `SAVED_STATES` below the root
+    pub saved_states: PathBuf,
+    /// Output of the criu service, see `spawn_criu`
+    pub criu_stdout: PathBuf,
+    pub criu_stderr: PathBuf,
+}
+
+impl Workspace {
+    /// The default layout below `root`
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        let root = root.as_ref();
+        Workspace {
+            root: root.to_path_buf(),
+            active_state: root.join(ACTIVE_STATE),
+            saved_states: root.join(SAVED_STATES),
+            criu_stdout: root.join(CRIU_STDOUT),
+            criu_stderr: root.join(CRIU_STDERR),
+        }
+    }
+
+    /// The folder of `state_path` in `saved_states`
+    pub fn saved_state(&self, state_path: &str) -> PathBuf {
+        self.saved_states.join(state_path)
+    }
+
+    /// Any other file or folder of the campaign, e.g. `FITM_STATE` or `CMIN_TMP`
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.root.join(name)
+    }
+}
+
+impl Default for Workspace {
+    /// The working dir, with paths relative to it
+    fn default() -> Self {
+        Workspace::new("")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FITM_STATE;
+
+    #[test]
+    fn test_workspace_paths() {
+        let cwd = Workspace::default();
+        assert_eq!(cwd.active_state, Path::new(ACTIVE_STATE));
+        assert_eq!(
+            cwd.saved_state("fitm-gen1-state0"),
+            Path::new("saved-states/fitm-gen1-state0")
+        );
+        assert_eq!(cwd.path(FITM_STATE), Path::new(FITM_STATE));
+
+        let tmp = Workspace::new("/tmp/fitm_workspace_unittest");
+        assert_eq!(
+            tmp.criu_stderr,
+            Path::new("/tmp/fitm_workspace_unittest/criu_stderr")
+        );
+        assert_ne!(tmp.active_state, cwd.active_state);
+    }
+}
@@ -1,7 +1,8 @@
 use fitm::get_traces;
+use fitm::workspace::Workspace;
 
 #[test]
 fn test_get_traces() {
-    let traces = get_traces();
+    let traces = get_traces(&Workspace::default(), 1);
     println!("{:?}", traces);
 }
@@ -2,7 +2,11 @@ use fitm::FITMSnapshot;
 mod common;
 
 use crate::common::teardown;
-use fitm::utils;
+use fitm::toolchain::Toolchain;
+use fitm::utils::RomuRand;
+use fitm::workers::Worker;
+use fitm::workspace::Workspace;
+use std::collections::HashMap;
 use std::time::Duration;
 
 static SERVER_BIN: &str = "./tests/targets/pseudoserver_simple";
@@ -13,7 +17,9 @@ static CLIENT_BIN: &str = "./tests/targets/pseudoclient_simple";
 fn repeated_cmin_test_() {
     common::setup();
 
+    let workspace = Workspace::default();
     let server0: FITMSnapshot = FITMSnapshot::new(
+        &workspace,
         1,
         0,
         SERVER_BIN.to_string(),
@@ -22,10 +28,22 @@ fn repeated_cmin_test_() {
         true,
         false,
         None,
-    );
+    )
+    .expect("[!] Could not set up server0");
 
+    let tools = Toolchain::from_cwd().expect("[!] FitM tools missing, run make first");
+    let mut rand = RomuRand::preseeded();
+    let worker = Worker::new(&workspace, 0, 1);
     server0
-        .init_run(false, true, &[])
+        .init_run(
+            &tools,
+            &worker,
+            &mut rand,
+            false,
+            true,
+            &[],
+            &HashMap::new(),
+        )
         .expect("[!] Init run on server0 failed");
 
     // =========== snapshot on gen1 =============
@@ -37,7 +55,13 @@ fn repeated_cmin_test_() {
         .expect("[!] Could not canonicalize tmp-input path");
 
     let server1 = server0
-        .create_next_snapshot(0, input_path.to_str().unwrap())
+        .create_next_snapshot(
+            &tools,
+            &worker,
+            0,
+            input_path.to_str().unwrap(),
+            &workspace.saved_states,
+        )
         .expect("[!] Create_next_snapshot for server0 failed")
         .unwrap();
 
@@ -50,7 +74,13 @@ fn repeated_cmin_test_() {
         .expect("[!] Could not canonicalize tmp-input path");
 
     let _server2 = server1
-        .create_next_snapshot(0, input_path.to_str().unwrap())
+        .create_next_snapshot(
+            &tools,
+            &worker,
+            0,
+            input_path.to_str().unwrap(),
+            &workspace.saved_states,
+        )
         .expect("[!] Create_next_snapshot for server0 failed");
 
     teardown();